@@ -0,0 +1,19 @@
+//! Render time of each canonical benchmark scene. Run with `cargo bench`, or use the `bench`
+//! subcommand to also check the renders against their reference images.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use raytrace_rs::bench::{render, scenes};
+
+fn render_scenes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("scenes");
+    // Each render takes seconds, so the default of 100 samples would take far too long
+    group.sample_size(10);
+    for scene in scenes() {
+        let solver = (scene.build)();
+        group.bench_function(scene.name, |b| b.iter(|| render(&solver)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, render_scenes);
+criterion_main!(benches);
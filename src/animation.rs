@@ -0,0 +1,203 @@
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    camera::Camera,
+    float::{Affine3, Float, Quat, Vec3},
+    sampler::hash,
+    scene::NodeId,
+    transform::AnimatedTransform,
+};
+
+/// Frames to render for an animation
+#[derive(Debug, Clone)]
+pub struct Animation {
+    pub frames: Range<u32>,
+    pub fps: Float,
+    pub noise: NoiseMode,
+}
+
+/// How the sampling noise changes between the frames of an animation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NoiseMode {
+    /// Different noise on every frame, which the eye averages out over time instead of seeing
+    /// a pattern stuck to the screen
+    #[default]
+    Varying,
+    /// The same noise on every frame, for comparing frames with each other
+    Frozen,
+}
+
+impl Animation {
+    pub fn new(frames: Range<u32>, fps: Float) -> Self {
+        Self {
+            frames,
+            fps,
+            noise: NoiseMode::default(),
+        }
+    }
+
+    pub fn with_frozen_noise(mut self) -> Self {
+        self.noise = NoiseMode::Frozen;
+        self
+    }
+
+    /// Time in seconds at the start of `frame`
+    pub fn time(&self, frame: u32) -> Float {
+        frame as Float / self.fps
+    }
+
+    /// Seed to render `frame` with, for an animation rendered with `seed`. Depends only on the
+    /// frame number, so any frame can be rendered again on its own with the same result.
+    pub fn frame_seed(&self, seed: u64, frame: u32) -> u64 {
+        match self.noise {
+            NoiseMode::Varying => hash(&[seed, frame as u64]),
+            NoiseMode::Frozen => seed,
+        }
+    }
+}
+
+/// Scene parameters for a single point in time of an animation. Anything left unset keeps its
+/// value from the previous frame.
+pub struct SceneState<C: Camera> {
+    pub camera: Option<C>,
+    /// New local transforms for nodes of the scene hierarchy
+    pub transforms: Vec<(NodeId, Affine3)>,
+}
+
+impl<C: Camera> Default for SceneState<C> {
+    fn default() -> Self {
+        Self {
+            camera: None,
+            transforms: Vec::new(),
+        }
+    }
+}
+
+impl<C: Camera> SceneState<C> {
+    pub fn with_camera(mut self, camera: C) -> Self {
+        self.camera = Some(camera);
+        self
+    }
+
+    pub fn with_transform(mut self, node: NodeId, transform: Affine3) -> Self {
+        self.transforms.push((node, transform));
+        self
+    }
+}
+
+/// A value which can be blended between two keyframes
+pub trait Interpolate: Copy {
+    fn interpolate(&self, other: &Self, t: Float) -> Self;
+}
+
+impl Interpolate for Float {
+    fn interpolate(&self, other: &Self, t: Float) -> Self {
+        self + (other - self) * t
+    }
+}
+
+impl Interpolate for Vec3 {
+    fn interpolate(&self, other: &Self, t: Float) -> Self {
+        self.lerp(*other, t)
+    }
+}
+
+impl Interpolate for Quat {
+    fn interpolate(&self, other: &Self, t: Float) -> Self {
+        self.slerp(*other, t)
+    }
+}
+
+impl Interpolate for Affine3 {
+    fn interpolate(&self, other: &Self, t: Float) -> Self {
+        AnimatedTransform::new(*self, *other).at(t)
+    }
+}
+
+/// A value animated by linearly interpolating between keyframes. Before the first and after the
+/// last keyframe the value is held constant.
+#[derive(Debug, Clone)]
+pub struct Keyframes<T: Interpolate> {
+    keys: Vec<(Float, T)>,
+}
+
+impl<T: Interpolate> Keyframes<T> {
+    pub fn new() -> Self {
+        Self { keys: Vec::new() }
+    }
+
+    /// Add a keyframe at `time` seconds
+    pub fn with_key(mut self, time: Float, value: T) -> Self {
+        let i = self.keys.partition_point(|(t, _)| *t <= time);
+        self.keys.insert(i, (time, value));
+        self
+    }
+
+    /// The value at `time` seconds, or `None` if there are no keyframes
+    pub fn sample(&self, time: Float) -> Option<T> {
+        let i = self.keys.partition_point(|(t, _)| *t <= time);
+        match (self.keys.get(i.wrapping_sub(1)), self.keys.get(i)) {
+            (Some((t0, v0)), Some((t1, v1))) => Some(v0.interpolate(v1, (time - t0) / (t1 - t0))),
+            (Some((_, v)), None) | (None, Some((_, v))) => Some(*v),
+            (None, None) => None,
+        }
+    }
+}
+
+impl<T: Interpolate> Default for Keyframes<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Path of the file for `frame`, replacing the last run of `#` characters in the file name of
+/// `pattern` with the zero padded frame number. If there are none, the frame number is added
+/// before the extension.
+pub fn frame_path<P: AsRef<Path>>(pattern: P, frame: u32) -> PathBuf {
+    let pattern = pattern.as_ref();
+    let name = pattern
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let name = match name.rfind('#') {
+        Some(end) => {
+            let start = name[..end].trim_end_matches('#').len();
+            let width = end + 1 - start;
+            format!("{}{:0width$}{}", &name[..start], frame, &name[end + 1..])
+        }
+        None => {
+            let stem = pattern
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            match pattern.extension() {
+                Some(ext) => format!("{}_{:04}.{}", stem, frame, ext.to_string_lossy()),
+                None => format!("{}_{:04}", stem, frame),
+            }
+        }
+    };
+
+    pattern.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_paths() {
+        assert_eq!(
+            frame_path("out/img_###.png", 7),
+            Path::new("out/img_007.png")
+        );
+        assert_eq!(frame_path("img_##.png", 1234), Path::new("img_1234.png"));
+        // Only the last run of # in the file name is replaced
+        assert_eq!(frame_path("shot#/a##_b###", 5), Path::new("shot#/a##_b005"));
+        assert_eq!(frame_path("out/img.png", 12), Path::new("out/img_0012.png"));
+        assert_eq!(frame_path("img", 3), Path::new("img_0003"));
+    }
+}
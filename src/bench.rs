@@ -0,0 +1,379 @@
+//! Canonical scenes rendered at fixed seeds and resolutions, so changes to performance and
+//! output can be measured. Each render is timed and compared against a stored reference image,
+//! failing if the mean FLIP difference is over a threshold.
+
+use std::{
+    fmt::Display,
+    io,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use glam::UVec2;
+use image::RgbImage;
+use rand::rngs::SmallRng;
+
+use crate::{
+    camera::PerspectiveCamera,
+    collidable::{Plane, Quad, Sphere},
+    colour::OutputTransform,
+    compare::{compare, ComparisonImage, DEFAULT_PIXELS_PER_DEGREE},
+    float::{Float, Quat, Vec3},
+    material::Material,
+    solver::{Integrator, Solver},
+    tiles::CancellationToken,
+};
+
+/// Seed every benchmark is rendered with
+pub const SEED: u64 = 0;
+/// Where reference images are kept, relative to the crate root
+pub const DEFAULT_REFERENCES: &str = "bench/references";
+/// Largest mean FLIP difference from the reference which still passes. Renders are
+/// deterministic, so this only has to allow for floating point differences between builds.
+pub const DEFAULT_THRESHOLD: Float = 0.01;
+
+pub type BenchSolver = Solver<PerspectiveCamera, SmallRng>;
+
+/// A scene to benchmark, with the settings it is rendered at
+pub struct BenchScene {
+    pub name: &'static str,
+    pub build: fn() -> BenchSolver,
+}
+
+/// Every canonical scene, in the order they are run
+pub fn scenes() -> [BenchScene; 3] {
+    [
+        BenchScene {
+            name: "cornell_box",
+            build: cornell_box,
+        },
+        BenchScene {
+            name: "sphere_grid",
+            build: sphere_grid,
+        },
+        BenchScene {
+            name: "glass_caustic",
+            build: glass_caustic,
+        },
+    ]
+}
+
+/// Render `solver` at the benchmark seed without a progress bar
+pub fn render(solver: &BenchSolver) -> RgbImage {
+    solver
+        .solve_with(SEED, &CancellationToken::new(), |_| {})
+        .to_rgb_image(solver.output_transform)
+}
+
+/// A closed box with red and green side walls, lit by a square light in the ceiling, to
+/// measure diffuse interreflection
+pub fn cornell_box() -> BenchSolver {
+    let camera = PerspectiveCamera {
+        origin: Vec3::new(0.0, 1.0, -1.4),
+        rotation: Quat::IDENTITY,
+        horizontal_fov: 40.0,
+    };
+    let mut solver = Solver::new(camera, UVec2::new(128, 128))
+        .with_samples(32)
+        .with_max_bounces(8);
+    solver.output_transform = OutputTransform::Srgb;
+    solver.sky = |_| Vec3::ZERO;
+
+    let diffuse = |colour: Vec3| {
+        Arc::new(Material {
+            colour,
+            diffusion: 1.0,
+            ..Default::default()
+        })
+    };
+    let white = diffuse(Vec3::splat(0.75));
+    let walls = [
+        // Floor, ceiling and back wall
+        (Vec3::new(0.0, 0.0, 1.0), Vec3::Z, Vec3::X, white.clone()),
+        (Vec3::new(0.0, 2.0, 1.0), Vec3::X, Vec3::Z, white.clone()),
+        (Vec3::new(0.0, 1.0, 2.0), Vec3::Y, Vec3::X, white.clone()),
+        // Left and right walls
+        (
+            Vec3::new(-1.0, 1.0, 1.0),
+            Vec3::Y,
+            Vec3::Z,
+            diffuse(Vec3::new(0.63, 0.065, 0.05)),
+        ),
+        (
+            Vec3::new(1.0, 1.0, 1.0),
+            Vec3::Z,
+            Vec3::Y,
+            diffuse(Vec3::new(0.14, 0.45, 0.091)),
+        ),
+    ];
+    for (origin, u, v, material) in walls {
+        solver.scene.add_object(Quad {
+            origin,
+            u,
+            v,
+            material,
+        });
+    }
+
+    solver.scene.add_object(Quad {
+        origin: Vec3::new(0.0, 1.999, 1.0),
+        u: Vec3::X * 0.6,
+        v: Vec3::Z * 0.6,
+        material: Arc::new(Material {
+            luminance: 3.0,
+            ..Default::default()
+        }),
+    });
+    solver.scene.add_object(Sphere {
+        origin: Vec3::new(-0.45, 0.35, 1.3),
+        radius: 0.35,
+        material: Arc::new(Material::metal(Vec3::splat(0.9), 0.0)),
+    });
+    solver.scene.add_object(Sphere {
+        origin: Vec3::new(0.45, 0.35, 0.7),
+        radius: 0.35,
+        material: white,
+    });
+
+    solver
+}
+
+/// Several hundred spheres of mixed materials, to measure intersection cost in a scene with
+/// many objects
+pub fn sphere_grid() -> BenchSolver {
+    let camera = PerspectiveCamera {
+        origin: Vec3::new(0.0, 1.0, 0.0),
+        rotation: Quat::IDENTITY,
+        horizontal_fov: 70.0,
+    };
+    let mut solver = Solver::new(camera, UVec2::new(192, 108))
+        .with_samples(8)
+        .with_max_bounces(8)
+        .with_clamp(10.0);
+    solver.output_transform = OutputTransform::Srgb;
+
+    let materials = [
+        Arc::new(Material {
+            colour: Vec3::new(0.8, 0.3, 0.3),
+            diffusion: 1.0,
+            ..Default::default()
+        }),
+        Arc::new(Material::metal(Vec3::new(0.9, 0.8, 0.6), 0.2)),
+        Arc::new(Material {
+            refractive_index: 1.5,
+            ..Default::default()
+        }),
+        Arc::new(Material {
+            colour: Vec3::new(0.3, 0.4, 0.8),
+            diffusion: 1.0,
+            ..Default::default()
+        }),
+    ];
+    for z in 0..20 {
+        for x in -10i32..10 {
+            solver.scene.add_object(Sphere {
+                origin: Vec3::new(x as Float * 0.5 + 0.25, 0.2, z as Float * 0.5 + 1.5),
+                radius: 0.2,
+                material: materials[(x + z).rem_euclid(4) as usize].clone(),
+            });
+        }
+    }
+
+    solver.scene.add_object(Sphere {
+        origin: Vec3::new(-3.0, 6.0, 4.0),
+        radius: 1.5,
+        material: Arc::new(Material {
+            luminance: 4.0,
+            ..Default::default()
+        }),
+    });
+    solver.scene.add_object(Plane {
+        origin: Vec3::ZERO,
+        normal: Vec3::Y,
+        material: Arc::new(Material {
+            colour: Vec3::splat(0.5),
+            diffusion: 1.0,
+            ..Default::default()
+        }),
+    });
+
+    solver
+}
+
+/// A glass sphere focusing a small light onto the floor, rendered with photon mapping to
+/// measure caustics
+pub fn glass_caustic() -> BenchSolver {
+    let camera = PerspectiveCamera {
+        origin: Vec3::new(0.0, 1.0, 0.0),
+        rotation: Quat::IDENTITY,
+        horizontal_fov: 60.0,
+    };
+    let mut solver = Solver::new(camera, UVec2::new(160, 160))
+        .with_samples(32)
+        .with_max_bounces(8)
+        .with_clamp(10.0)
+        .with_integrator(Integrator::PhotonMapping {
+            photons: 200_000,
+            radius: 0.05,
+        });
+    solver.output_transform = OutputTransform::Srgb;
+
+    solver.scene.add_object(Sphere {
+        origin: Vec3::new(0.0, 0.8, 3.5),
+        radius: 0.5,
+        material: Arc::new(Material {
+            refractive_index: 1.5,
+            ..Default::default()
+        }),
+    });
+    solver.scene.add_object(Sphere {
+        origin: Vec3::new(0.6, 2.5, 3.5),
+        radius: 0.15,
+        material: Arc::new(Material {
+            luminance: 40.0,
+            ..Default::default()
+        }),
+    });
+    solver.scene.add_object(Plane {
+        origin: Vec3::ZERO,
+        normal: Vec3::Y,
+        material: Arc::new(Material {
+            colour: Vec3::splat(0.8),
+            diffusion: 1.0,
+            ..Default::default()
+        }),
+    });
+
+    solver
+}
+
+/// Settings for [`run_benchmarks`]
+pub struct BenchOptions {
+    /// Directory holding a `<name>.png` reference image for each scene
+    pub references: PathBuf,
+    pub threshold: Float,
+    /// Write the renders as the new reference images instead of comparing against them
+    pub update: bool,
+    /// Only run the scenes with these names, or every scene if empty
+    pub scenes: Vec<String>,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        Self {
+            references: PathBuf::from(DEFAULT_REFERENCES),
+            threshold: DEFAULT_THRESHOLD,
+            update: false,
+            scenes: Vec::new(),
+        }
+    }
+}
+
+/// How a benchmark's render compared to its reference image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BenchOutcome {
+    Passed {
+        mean_flip: Float,
+    },
+    Failed {
+        mean_flip: Float,
+    },
+    /// There was no reference image, or it couldn't be compared with the render
+    MissingReference,
+    /// The render was written as the reference image
+    Updated,
+}
+
+pub struct BenchResult {
+    pub name: &'static str,
+    pub time: Duration,
+    pub outcome: BenchOutcome,
+}
+
+impl BenchResult {
+    pub fn is_failure(&self) -> bool {
+        matches!(
+            self.outcome,
+            BenchOutcome::Failed { .. } | BenchOutcome::MissingReference
+        )
+    }
+}
+
+impl Display for BenchResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:<16}{:>9.3} secs  ",
+            self.name,
+            self.time.as_secs_f64()
+        )?;
+        match self.outcome {
+            BenchOutcome::Passed { mean_flip } => write!(f, "ok      FLIP {:.6}", mean_flip),
+            BenchOutcome::Failed { mean_flip } => write!(f, "FAILED  FLIP {:.6}", mean_flip),
+            BenchOutcome::MissingReference => write!(f, "FAILED  no reference"),
+            BenchOutcome::Updated => write!(f, "reference updated"),
+        }
+    }
+}
+
+/// Render the selected scenes, printing each result as it finishes. A failed render is written
+/// to `bench_<name>.png` in the working directory, with a heatmap of where it differs from the
+/// reference in `bench_<name>_heatmap.png`.
+pub fn run_benchmarks(options: &BenchOptions) -> io::Result<Vec<BenchResult>> {
+    if options.update {
+        std::fs::create_dir_all(&options.references)?;
+    }
+
+    let mut results = Vec::new();
+    for scene in scenes() {
+        if !options.scenes.is_empty() && !options.scenes.iter().any(|s| s == scene.name) {
+            continue;
+        }
+
+        let solver = (scene.build)();
+        let start = Instant::now();
+        let image = render(&solver);
+        let time = start.elapsed();
+
+        let reference_path = options.references.join(format!("{}.png", scene.name));
+        let outcome = if options.update {
+            image.save(&reference_path).map_err(io::Error::other)?;
+            BenchOutcome::Updated
+        } else {
+            let test = ComparisonImage::from_rgb_image(&image);
+            let comparison = ComparisonImage::open(&reference_path)
+                .ok()
+                .and_then(|reference| compare(&reference, &test, DEFAULT_PIXELS_PER_DEGREE).ok());
+            match comparison {
+                Some(c) if c.mean_flip <= options.threshold => BenchOutcome::Passed {
+                    mean_flip: c.mean_flip,
+                },
+                Some(c) => {
+                    c.heatmap()
+                        .save(format!("bench_{}_heatmap.png", scene.name))
+                        .map_err(io::Error::other)?;
+                    BenchOutcome::Failed {
+                        mean_flip: c.mean_flip,
+                    }
+                }
+                None => BenchOutcome::MissingReference,
+            }
+        };
+
+        let result = BenchResult {
+            name: scene.name,
+            time,
+            outcome,
+        };
+        if result.is_failure() {
+            image
+                .save(format!("bench_{}.png", scene.name))
+                .map_err(io::Error::other)?;
+        }
+        println!("{}", result);
+        results.push(result);
+    }
+
+    Ok(results)
+}
@@ -0,0 +1,597 @@
+//! Bounding volume hierarchy over the objects of a scene, so rays only test the objects whose
+//! bounds they pass through. Each leaf holds one object. The tree is updated in place as objects
+//! are added, moved and removed, and only rebuilt when most of the scene changes at once.
+
+use crate::{
+    float::{Affine3, Float, Vec3},
+    ray::Ray,
+    transform::AnimatedTransform,
+};
+
+/// Axis aligned bounding box
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    /// A box containing nothing, which any other box can be added to
+    pub const EMPTY: Self = Self {
+        min: Vec3::splat(Float::INFINITY),
+        max: Vec3::splat(Float::NEG_INFINITY),
+    };
+
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Self { min, max }
+    }
+
+    pub fn from_points<I: IntoIterator<Item = Vec3>>(points: I) -> Self {
+        points.into_iter().fold(Self::EMPTY, |bounds, p| {
+            Self::new(bounds.min.min(p), bounds.max.max(p))
+        })
+    }
+
+    /// A box around a sphere
+    pub fn around(centre: Vec3, radius: Float) -> Self {
+        Self::new(centre - radius, centre + radius)
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        Self::new(self.min.min(other.min), self.max.max(other.max))
+    }
+
+    /// The part of the box inside `other`
+    pub fn intersection(&self, other: &Self) -> Self {
+        Self::new(self.min.max(other.min), self.max.min(other.max))
+    }
+
+    /// The box grown by `by` on every side
+    pub fn expand(&self, by: Vec3) -> Self {
+        Self::new(self.min - by, self.max + by)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.cmpgt(self.max).any()
+    }
+
+    pub fn centre(&self) -> Vec3 {
+        (self.min + self.max) / 2.0
+    }
+
+    pub fn surface_area(&self) -> Float {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let size = self.max - self.min;
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+
+    pub fn corners(&self) -> [Vec3; 8] {
+        [0, 1, 2, 3, 4, 5, 6, 7].map(|i| {
+            Vec3::new(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            )
+        })
+    }
+
+    /// A box around this one after `transform`
+    pub fn transformed(&self, transform: &Affine3) -> Self {
+        Self::from_points(self.corners().map(|p| transform.transform_point3(p)))
+    }
+
+    /// A box around this one wherever `transform` moves it over the shutter interval. Rotating
+    /// keyframes can swing the box outside of both of its end positions, so it's bounded by the
+    /// path of the origin plus the furthest the box reaches from the origin.
+    pub fn animated(&self, transform: &AnimatedTransform) -> Self {
+        if !transform.is_animated() {
+            return self.transformed(&transform.start);
+        }
+        let (s0, _, t0) = transform.start.to_scale_rotation_translation();
+        let (s1, _, t1) = transform.end.to_scale_rotation_translation();
+        let scale = s0.abs().max(s1.abs()).max_element();
+        let reach = scale * (self.centre().length() + (self.max - self.min).length() / 2.0);
+        Self::from_points([t0, t1]).expand(Vec3::splat(reach))
+    }
+
+    /// Distance along a ray from `origin` with reciprocal direction `inv_dir` at which it enters
+    /// the box, if it does within `t_min` to `t_max`
+    fn entry(&self, origin: Vec3, inv_dir: Vec3, t_min: Float, t_max: Float) -> Option<Float> {
+        let t0 = (self.min - origin) * inv_dir;
+        let t1 = (self.max - origin) * inv_dir;
+        let near = t0.min(t1).max_element().max(t_min);
+        // Allow for rounding, so rays grazing the box aren't missed
+        let far = t0.max(t1).min_element() * (1.0 + 4.0 * Float::EPSILON);
+        (near <= far.min(t_max)).then_some(near)
+    }
+}
+
+/// Summary of the shape of a [`Bvh`], for scene reports
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BvhStats {
+    pub nodes: usize,
+    /// Number of nodes from the root to the deepest leaf, inclusive
+    pub depth: usize,
+    /// Expected cost of tracing a ray through the tree under the surface area heuristic, in
+    /// object intersection tests. Unbounded objects, which every ray tests, are included.
+    pub sah_cost: Float,
+}
+
+/// Cost of visiting a node relative to testing an object, for the surface area heuristic
+const TRAVERSAL_COST: Float = 0.125;
+
+#[derive(Clone, Copy)]
+enum NodeKind {
+    Leaf(usize),
+    Inner([usize; 2]),
+    /// A node slot which can be reused
+    Free,
+}
+
+#[derive(Clone, Copy)]
+struct Node {
+    bounds: Aabb,
+    parent: Option<usize>,
+    kind: NodeKind,
+}
+
+/// Hierarchy over objects numbered as in [`Scene::objects`](crate::scene::Scene::objects).
+/// Objects without bounds, such as infinite planes, are kept aside and tested by every ray.
+#[derive(Default)]
+pub struct Bvh {
+    nodes: Vec<Node>,
+    /// Slots in `nodes` which have been freed
+    free: Vec<usize>,
+    root: Option<usize>,
+    /// Leaf of each object, `None` for unbounded objects
+    leaves: Vec<Option<usize>>,
+    unbounded: Vec<usize>,
+}
+
+impl Bvh {
+    /// A tree over objects with `bounds`, split by the surface area heuristic
+    pub fn build(bounds: &[Option<Aabb>]) -> Self {
+        let mut bvh = Self {
+            leaves: vec![None; bounds.len()],
+            ..Default::default()
+        };
+        let mut objects = Vec::new();
+        for (object, b) in bounds.iter().enumerate() {
+            match b {
+                Some(b) => objects.push((object, *b)),
+                None => bvh.unbounded.push(object),
+            }
+        }
+        if !objects.is_empty() {
+            let root = bvh.build_node(&mut objects, None);
+            bvh.root = Some(root);
+        }
+        bvh
+    }
+
+    fn build_node(&mut self, objects: &mut [(usize, Aabb)], parent: Option<usize>) -> usize {
+        let bounds = objects
+            .iter()
+            .fold(Aabb::EMPTY, |bounds, (_, b)| bounds.union(b));
+        if let [(object, _)] = *objects {
+            let leaf = self.allocate(Node {
+                bounds,
+                parent,
+                kind: NodeKind::Leaf(object),
+            });
+            self.leaves[object] = Some(leaf);
+            return leaf;
+        }
+
+        // Try splitting the objects sorted by centre along each axis after each object, and
+        // keep the split with the smallest total of area times objects on either side
+        let mut best = (Float::INFINITY, 0, 0);
+        let mut right_areas = vec![0.0; objects.len()];
+        for axis in 0..3 {
+            objects.sort_by(|a, b| a.1.centre()[axis].total_cmp(&b.1.centre()[axis]));
+            let mut right = Aabb::EMPTY;
+            for i in (1..objects.len()).rev() {
+                right = right.union(&objects[i].1);
+                right_areas[i] = right.surface_area();
+            }
+            let mut left = Aabb::EMPTY;
+            for i in 1..objects.len() {
+                left = left.union(&objects[i - 1].1);
+                let cost = left.surface_area() * i as Float
+                    + right_areas[i] * (objects.len() - i) as Float;
+                if cost < best.0 {
+                    best = (cost, axis, i);
+                }
+            }
+        }
+        let (_, axis, split) = best;
+        objects.sort_by(|a, b| a.1.centre()[axis].total_cmp(&b.1.centre()[axis]));
+
+        let node = self.allocate(Node {
+            bounds,
+            parent,
+            kind: NodeKind::Free,
+        });
+        let (left, right) = objects.split_at_mut(split);
+        let children = [
+            self.build_node(left, Some(node)),
+            self.build_node(right, Some(node)),
+        ];
+        self.nodes[node].kind = NodeKind::Inner(children);
+        node
+    }
+
+    fn allocate(&mut self, node: Node) -> usize {
+        match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    fn release(&mut self, index: usize) {
+        self.nodes[index].kind = NodeKind::Free;
+        self.free.push(index);
+    }
+
+    /// Add an object numbered after all the others
+    pub fn push(&mut self, bounds: Option<Aabb>) {
+        let object = self.leaves.len();
+        let Some(bounds) = bounds else {
+            self.leaves.push(None);
+            self.unbounded.push(object);
+            return;
+        };
+        let leaf = self.allocate(Node {
+            bounds,
+            parent: None,
+            kind: NodeKind::Leaf(object),
+        });
+        self.leaves.push(Some(leaf));
+        self.insert_leaf(leaf);
+    }
+
+    /// Remove `object`, renumbering the last object to take its place
+    pub fn swap_remove(&mut self, object: usize) {
+        match self.leaves[object] {
+            Some(leaf) => {
+                self.remove_leaf(leaf);
+                self.release(leaf);
+            }
+            None => self.unbounded.retain(|&o| o != object),
+        }
+
+        let last = self.leaves.len() - 1;
+        self.leaves.swap_remove(object);
+        if object != last {
+            match self.leaves[object] {
+                Some(leaf) => self.nodes[leaf].kind = NodeKind::Leaf(object),
+                None => {
+                    for o in &mut self.unbounded {
+                        if *o == last {
+                            *o = object;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Change the bounds of `object`, resizing the nodes above it to fit. The tree keeps its
+    /// shape, so it slowly gets worse as objects move far from where they were added.
+    pub fn refit(&mut self, object: usize, bounds: Option<Aabb>) {
+        match (self.leaves[object], bounds) {
+            (Some(leaf), Some(bounds)) => {
+                self.nodes[leaf].bounds = bounds;
+                if let Some(parent) = self.nodes[leaf].parent {
+                    self.refit_ancestors(parent);
+                }
+            }
+            (None, None) => {}
+            (Some(leaf), None) => {
+                self.remove_leaf(leaf);
+                self.release(leaf);
+                self.leaves[object] = None;
+                self.unbounded.push(object);
+            }
+            (None, Some(bounds)) => {
+                self.unbounded.retain(|&o| o != object);
+                let leaf = self.allocate(Node {
+                    bounds,
+                    parent: None,
+                    kind: NodeKind::Leaf(object),
+                });
+                self.leaves[object] = Some(leaf);
+                self.insert_leaf(leaf);
+            }
+        }
+    }
+
+    /// Attach `leaf` beside the node it adds the least area to
+    fn insert_leaf(&mut self, leaf: usize) {
+        let Some(root) = self.root else {
+            self.root = Some(leaf);
+            return;
+        };
+        let bounds = self.nodes[leaf].bounds;
+
+        // Walk down while pairing with a child is cheaper than pairing with the node itself,
+        // counting the area every ancestor grows by
+        let mut sibling = root;
+        while let NodeKind::Inner(children) = self.nodes[sibling].kind {
+            let area = self.nodes[sibling].bounds.surface_area();
+            let combined = self.nodes[sibling].bounds.union(&bounds).surface_area();
+            let here = 2.0 * combined;
+            let inherited = 2.0 * (combined - area);
+            let cost = |child: usize| {
+                let node = &self.nodes[child];
+                let grown = node.bounds.union(&bounds).surface_area();
+                match node.kind {
+                    NodeKind::Inner(_) => grown - node.bounds.surface_area() + inherited,
+                    _ => grown + inherited,
+                }
+            };
+            let costs = children.map(cost);
+            if here <= costs[0] && here <= costs[1] {
+                break;
+            }
+            sibling = if costs[0] <= costs[1] {
+                children[0]
+            } else {
+                children[1]
+            };
+        }
+
+        let old_parent = self.nodes[sibling].parent;
+        let parent = self.allocate(Node {
+            bounds: self.nodes[sibling].bounds.union(&bounds),
+            parent: old_parent,
+            kind: NodeKind::Inner([sibling, leaf]),
+        });
+        self.nodes[sibling].parent = Some(parent);
+        self.nodes[leaf].parent = Some(parent);
+        match old_parent {
+            Some(old_parent) => {
+                self.replace_child(old_parent, sibling, parent);
+                self.refit_ancestors(old_parent);
+            }
+            None => self.root = Some(parent),
+        }
+    }
+
+    /// Detach `leaf`, putting its sibling in place of their parent
+    fn remove_leaf(&mut self, leaf: usize) {
+        let Some(parent) = self.nodes[leaf].parent else {
+            self.root = None;
+            return;
+        };
+        let NodeKind::Inner(children) = self.nodes[parent].kind else {
+            unreachable!("the parent of a leaf is an inner node");
+        };
+        let sibling = if children[0] == leaf {
+            children[1]
+        } else {
+            children[0]
+        };
+
+        let grandparent = self.nodes[parent].parent;
+        self.nodes[sibling].parent = grandparent;
+        match grandparent {
+            Some(grandparent) => {
+                self.replace_child(grandparent, parent, sibling);
+                self.refit_ancestors(grandparent);
+            }
+            None => self.root = Some(sibling),
+        }
+        self.release(parent);
+        self.nodes[leaf].parent = None;
+    }
+
+    fn replace_child(&mut self, node: usize, old: usize, new: usize) {
+        if let NodeKind::Inner(children) = &mut self.nodes[node].kind {
+            for child in children {
+                if *child == old {
+                    *child = new;
+                }
+            }
+        }
+    }
+
+    /// Resize `node` and every node above it to fit their children
+    fn refit_ancestors(&mut self, node: usize) {
+        let mut node = Some(node);
+        while let Some(index) = node {
+            if let NodeKind::Inner([a, b]) = self.nodes[index].kind {
+                self.nodes[index].bounds = self.nodes[a].bounds.union(&self.nodes[b].bounds);
+            }
+            node = self.nodes[index].parent;
+        }
+    }
+
+    /// Call `test` with each object `ray` could hit, nearest boxes first. `test` returns the
+    /// distance to the object's hit if it's closer than any so far, so boxes behind it can be
+    /// skipped.
+    pub fn traverse<F: FnMut(usize) -> Option<Float>>(&self, ray: &Ray, mut test: F) {
+        let mut closest = ray.t_max;
+        for &object in &self.unbounded {
+            if let Some(t) = test(object) {
+                closest = closest.min(t);
+            }
+        }
+
+        let Some(root) = self.root else {
+            return;
+        };
+        let inv_dir = ray.dir.recip();
+        let entry = |node: usize, closest: Float| {
+            self.nodes[node]
+                .bounds
+                .entry(ray.origin, inv_dir, ray.t_min, closest)
+        };
+        let mut stack = Vec::with_capacity(64);
+        if let Some(t) = entry(root, closest) {
+            stack.push((root, t));
+        }
+        while let Some((node, t)) = stack.pop() {
+            if t > closest {
+                continue;
+            }
+            match self.nodes[node].kind {
+                NodeKind::Leaf(object) => {
+                    if let Some(t) = test(object) {
+                        closest = closest.min(t);
+                    }
+                }
+                NodeKind::Inner([a, b]) => {
+                    let (ta, tb) = (entry(a, closest), entry(b, closest));
+                    // Push the further child first so the nearer one is visited first
+                    let mut children = [(a, ta), (b, tb)];
+                    if ta.unwrap_or(Float::INFINITY) < tb.unwrap_or(Float::INFINITY) {
+                        children.swap(0, 1);
+                    }
+                    for (child, t) in children {
+                        if let Some(t) = t {
+                            stack.push((child, t));
+                        }
+                    }
+                }
+                NodeKind::Free => unreachable!("free nodes aren't linked into the tree"),
+            }
+        }
+    }
+
+    /// Number of bytes used by the tree
+    pub fn memory_usage(&self) -> usize {
+        std::mem::size_of_val(self)
+            + self.nodes.capacity() * std::mem::size_of::<Node>()
+            + self.free.capacity() * std::mem::size_of::<usize>()
+            + self.leaves.capacity() * std::mem::size_of::<Option<usize>>()
+            + self.unbounded.capacity() * std::mem::size_of::<usize>()
+    }
+
+    pub fn stats(&self) -> BvhStats {
+        let mut stats = BvhStats {
+            sah_cost: self.unbounded.len() as Float,
+            ..Default::default()
+        };
+        let Some(root) = self.root else {
+            return stats;
+        };
+        let root_area = self.nodes[root].bounds.surface_area();
+        let mut stack = vec![(root, 1)];
+        while let Some((node, depth)) = stack.pop() {
+            stats.nodes += 1;
+            stats.depth = stats.depth.max(depth);
+            // Chance of a ray through the root passing through the node
+            let chance = if root_area > 0.0 {
+                self.nodes[node].bounds.surface_area() / root_area
+            } else {
+                1.0
+            };
+            match self.nodes[node].kind {
+                NodeKind::Leaf(_) => stats.sah_cost += chance,
+                NodeKind::Inner(children) => {
+                    stats.sah_cost += chance * TRAVERSAL_COST;
+                    stack.extend(children.map(|child| (child, depth + 1)));
+                }
+                NodeKind::Free => {}
+            }
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    use super::*;
+
+    fn random_box(rng: &mut SmallRng) -> Aabb {
+        let centre = Vec3::new(rng.gen(), rng.gen(), rng.gen()) * 20.0;
+        Aabb::around(centre, rng.gen_range(0.1..1.0))
+    }
+
+    /// Objects whose boxes `ray` passes through, found by testing every box
+    fn brute_force(bounds: &[Option<Aabb>], ray: &Ray) -> Vec<usize> {
+        let inv_dir = ray.dir.recip();
+        (0..bounds.len())
+            .filter(|&i| {
+                bounds[i]
+                    .is_none_or(|b| b.entry(ray.origin, inv_dir, ray.t_min, ray.t_max).is_some())
+            })
+            .collect()
+    }
+
+    fn visited(bvh: &Bvh, ray: &Ray) -> Vec<usize> {
+        let mut objects = Vec::new();
+        bvh.traverse(ray, |object| {
+            objects.push(object);
+            None
+        });
+        objects.sort();
+        objects
+    }
+
+    fn random_ray(rng: &mut SmallRng) -> Ray {
+        let origin = Vec3::new(rng.gen(), rng.gen(), rng.gen()) * 20.0;
+        let dir = Vec3::new(rng.gen(), rng.gen(), rng.gen()) - 0.5;
+        Ray {
+            origin,
+            dir,
+            time: 0.0,
+            wavelength: None,
+            t_min: 0.0,
+            t_max: Float::INFINITY,
+        }
+    }
+
+    #[test]
+    fn updates_match_a_rebuilt_tree() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut bounds: Vec<Option<Aabb>> = (0..50).map(|_| Some(random_box(&mut rng))).collect();
+        bounds[7] = None;
+        let mut bvh = Bvh::build(&bounds);
+
+        for _ in 0..20 {
+            bounds.push(Some(random_box(&mut rng)));
+            bvh.push(*bounds.last().unwrap());
+        }
+        for object in [3, 7, 40, 0] {
+            bounds.swap_remove(object);
+            bvh.swap_remove(object);
+        }
+        for object in [1, 5, 9] {
+            bounds[object] = Some(random_box(&mut rng));
+            bvh.refit(object, bounds[object]);
+        }
+        bounds[2] = None;
+        bvh.refit(2, None);
+
+        let rebuilt = Bvh::build(&bounds);
+        for _ in 0..200 {
+            let ray = random_ray(&mut rng);
+            let expected = brute_force(&bounds, &ray);
+            assert_eq!(visited(&bvh, &ray), expected);
+            assert_eq!(visited(&rebuilt, &ray), expected);
+        }
+        assert_eq!(bvh.stats().nodes, 2 * (bounds.len() - 1) - 1);
+    }
+
+    #[test]
+    fn sah_build_beats_testing_every_object() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let bounds: Vec<Option<Aabb>> = (0..256).map(|_| Some(random_box(&mut rng))).collect();
+        let stats = Bvh::build(&bounds).stats();
+        assert_eq!(stats.nodes, 511);
+        assert!(stats.depth >= 9 && stats.depth < 32);
+        assert!(stats.sah_cost < 32.0);
+    }
+}
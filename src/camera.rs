@@ -1,33 +1,119 @@
-use glam::{DQuat, DVec2, DVec3, IVec2, UVec2};
-use rand::{Rng, SeedableRng};
+use glam::{IVec2, UVec2};
+use rand::Rng;
 
-use crate::ray::Ray;
+#[cfg(feature = "gpu")]
+use crate::gpu::GpuCamera;
+use crate::{
+    float::{consts::PI, Float, Quat, Vec2, Vec3},
+    lod::LodView,
+    raster::Projection,
+    ray::Ray,
+    sampler::PixelSampler,
+    spectrum::Dispersion,
+    transform::AnimatedTransform,
+};
+
+/// Resolution and shape of the image a camera renders
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameFormat {
+    pub resolution: UVec2,
+    /// Width of each pixel relative to its height, e.g. 2.0 for 2x anamorphic video which is
+    /// stretched horizontally for display
+    pub pixel_aspect: Float,
+}
+
+impl FrameFormat {
+    /// Format with square pixels
+    pub fn new(resolution: UVec2) -> Self {
+        Self {
+            resolution,
+            pixel_aspect: 1.0,
+        }
+    }
+
+    /// Width of the displayed image relative to its height
+    pub fn aspect_ratio(&self) -> Float {
+        self.resolution.x as Float * self.pixel_aspect / self.resolution.y as Float
+    }
+}
 
 pub trait Camera {
-    fn outgoing_ray<R: Rng + SeedableRng>(&self, res: UVec2, pixel: IVec2, rng: &mut R) -> Ray;
+    /// Generate the ray through `pixel` at `time` within the shutter interval
+    fn outgoing_ray<R: Rng>(
+        &self,
+        format: FrameFormat,
+        pixel: IVec2,
+        time: Float,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> Ray;
+
+    /// Generate the ray through `pixel` carrying light of `wavelength` in nanometres, for
+    /// spectral renders. Only lenses whose focus depends on wavelength need to override this.
+    fn outgoing_ray_at_wavelength<R: Rng>(
+        &self,
+        format: FrameFormat,
+        pixel: IVec2,
+        time: Float,
+        wavelength: Float,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> Ray {
+        Ray {
+            wavelength: Some(wavelength),
+            ..self.outgoing_ray(format, pixel, time, sampler)
+        }
+    }
+
+    /// Where the camera sees the scene from at `time` within the shutter interval, for choosing
+    /// levels of detail. `None` if it doesn't have a single viewpoint.
+    fn lod_view(&self, _format: FrameFormat, _time: Float) -> Option<LodView> {
+        None
+    }
+
+    /// The camera as a pinhole projection for rasterizing primary visibility, `None` if it
+    /// can't be rasterized
+    fn raster_projection(&self) -> Option<Projection> {
+        None
+    }
+
+    /// The camera in a form the GPU backend can render, `None` if it isn't supported there
+    #[cfg(feature = "gpu")]
+    fn gpu_camera(&self) -> Option<GpuCamera> {
+        None
+    }
 }
 
 pub struct OrthCamera {
-    pub origin: DVec3,
-    pub rotation: DQuat,
-    pub size: DVec2,
+    pub origin: Vec3,
+    pub rotation: Quat,
+    pub size: Vec2,
 }
 
 impl Camera for OrthCamera {
-    fn outgoing_ray<R: Rng>(&self, res: UVec2, pixel: IVec2, rng: &mut R) -> Ray {
-        let scale_x = self.size.x / res.x as f64;
-        let scale_y = self.size.y / res.y as f64;
+    fn outgoing_ray<R: Rng>(
+        &self,
+        format: FrameFormat,
+        pixel: IVec2,
+        time: Float,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> Ray {
+        let scale_x = self.size.x / format.resolution.x as Float;
+        let scale_y = self.size.y / format.resolution.y as Float;
 
-        let off_x = rng.gen_range(-scale_x / 2.0..scale_x / 2.0);
-        let off_y = rng.gen_range(-scale_y / 2.0..scale_y / 2.0);
+        let jitter = sampler.next_2d() - 0.5;
+        let off_x = jitter.x * scale_x;
+        let off_y = jitter.y * scale_y;
 
         let mut out = Ray {
-            origin: DVec3::new(
-                pixel.x as f64 * scale_x + scale_x / 2.0 - self.size.x / 2.0 + off_x,
-                pixel.y as f64 * scale_y + scale_y / 2.0 - self.size.y / 2.0 + off_y,
+            origin: Vec3::new(
+                pixel.x as Float * scale_x + scale_x / 2.0 - self.size.x / 2.0 + off_x,
+                pixel.y as Float * scale_y + scale_y / 2.0 - self.size.y / 2.0 + off_y,
                 0.0,
             ),
-            dir: DVec3::Z,
+            dir: Vec3::Z,
+            time,
+            wavelength: None,
+            t_min: 0.0,
+            t_max: Float::INFINITY,
         };
 
         out.origin += self.origin;
@@ -38,29 +124,469 @@ impl Camera for OrthCamera {
 }
 
 pub struct PerspectiveCamera {
-    pub origin: DVec3,
-    pub rotation: DQuat,
-    pub horizontal_fov: f64,
+    pub origin: Vec3,
+    pub rotation: Quat,
+    pub horizontal_fov: Float,
 }
 
 impl Camera for PerspectiveCamera {
-    fn outgoing_ray<R: Rng + SeedableRng>(&self, res: UVec2, pixel: IVec2, rng: &mut R) -> Ray {
-        let scale_x = 1.0 / res.x as f64;
-        let scale_y = 1.0 / res.y as f64;
+    fn outgoing_ray<R: Rng>(
+        &self,
+        format: FrameFormat,
+        pixel: IVec2,
+        time: Float,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> Ray {
+        // The image plane is one unit wide, and as high as the aspect ratio of the frame needs
+        let height = 1.0 / format.aspect_ratio();
+        let scale_x = 1.0 / format.resolution.x as Float;
+        let scale_y = height / format.resolution.y as Float;
 
-        let off_x = rng.gen_range(-scale_x / 2.0..scale_x / 2.0);
-        let off_y = rng.gen_range(-scale_y / 2.0..scale_y / 2.0);
+        let jitter = sampler.next_2d() - 0.5;
+        let off_x = jitter.x * scale_x;
+        let off_y = jitter.y * scale_y;
 
-        let target = DVec3::new(
-            pixel.x as f64 * scale_x + scale_x / 2.0 - 0.5 + off_x,
-            pixel.y as f64 * scale_y + scale_y / 2.0 - 0.5 + off_y,
+        let target = Vec3::new(
+            pixel.x as Float * scale_x + scale_x / 2.0 - 0.5 + off_x,
+            pixel.y as Float * scale_y + scale_y / 2.0 - height / 2.0 + off_y,
             0.5 / (self.horizontal_fov.to_radians() / 2.0).tan(),
         )
         .normalize();
 
         Ray {
-            origin: self.origin.clone(),
+            origin: self.origin,
             dir: self.rotation * target,
+            time,
+            wavelength: None,
+            t_min: 0.0,
+            t_max: Float::INFINITY,
+        }
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_camera(&self) -> Option<GpuCamera> {
+        Some(GpuCamera {
+            origin: self.origin,
+            rotation: self.rotation,
+            focal_length: 0.5 / (self.horizontal_fov.to_radians() / 2.0).tan(),
+        })
+    }
+
+    fn lod_view(&self, format: FrameFormat, _time: Float) -> Option<LodView> {
+        Some(LodView {
+            origin: self.origin,
+            pixels_per_radian: format.resolution.x as Float * 0.5
+                / (self.horizontal_fov.to_radians() / 2.0).tan(),
+        })
+    }
+
+    fn raster_projection(&self) -> Option<Projection> {
+        Some(Projection {
+            origin: self.origin,
+            rotation: self.rotation,
+            focal_length: 0.5 / (self.horizontal_fov.to_radians() / 2.0).tan(),
+        })
+    }
+}
+
+/// A perspective camera with a finite aperture, giving depth of field
+pub struct ThinLensCamera {
+    pub origin: Vec3,
+    pub rotation: Quat,
+    pub horizontal_fov: Float,
+    /// Radius of the aperture in world units, zero for a pinhole
+    pub aperture_radius: Float,
+    /// Distance in front of the camera which is in focus
+    pub focus_distance: Float,
+    /// Glass of the lens, `None` for a lens corrected to focus every wavelength together
+    pub chromatic_aberration: Option<LensGlass>,
+}
+
+/// A single lens element, which focuses shorter wavelengths closer than longer ones. Only
+/// affects spectral renders, where out of focus highlights pick up purple and green fringes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LensGlass {
+    pub dispersion: Dispersion,
+    /// Focal length of the lens in world units at [`LensGlass::DESIGN_WAVELENGTH`]. Shorter
+    /// lenses focused at the same distance show stronger aberration.
+    pub focal_length: Float,
+}
+
+impl LensGlass {
+    /// The helium d-line, where glass catalogues quote refractive indices
+    pub const DESIGN_WAVELENGTH: Float = 587.56;
+
+    /// Distance at which light of `wavelength` is in focus for a lens focused at `focus` for
+    /// the design wavelength. Infinite if the lens can't bring it into focus at all.
+    pub fn focus_distance(&self, focus: Float, wavelength: Float) -> Float {
+        let n_design = self.dispersion.refractive_index(Self::DESIGN_WAVELENGTH);
+        let n = self.dispersion.refractive_index(wavelength);
+        // Thin lens equation, with the power of the lens proportional to n - 1 and the
+        // sensor kept where it is for the design wavelength
+        let sensor = 1.0 / (1.0 / self.focal_length - 1.0 / focus);
+        let focal_length = self.focal_length * (n_design - 1.0) / (n - 1.0);
+        let inverse = 1.0 / focal_length - 1.0 / sensor;
+        if inverse > 0.0 {
+            1.0 / inverse
+        } else {
+            Float::INFINITY
+        }
+    }
+}
+
+impl ThinLensCamera {
+    fn lens_ray<R: Rng>(
+        &self,
+        format: FrameFormat,
+        pixel: IVec2,
+        time: Float,
+        focus_distance: Float,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> Ray {
+        let pinhole = PerspectiveCamera {
+            origin: Vec3::ZERO,
+            rotation: Quat::IDENTITY,
+            horizontal_fov: self.horizontal_fov,
+        };
+        let dir = pinhole.outgoing_ray(format, pixel, time, sampler).dir;
+
+        // Aim every point on the aperture at where the pinhole ray crosses the focal plane
+        let lens = sampler.next_2d();
+        let (r, theta) = (lens.x.sqrt() * self.aperture_radius, lens.y * 2.0 * PI);
+        let origin = Vec3::new(r * theta.cos(), r * theta.sin(), 0.0);
+        let dir = if focus_distance.is_finite() {
+            (dir * focus_distance / dir.z - origin).normalize()
+        } else {
+            dir
+        };
+
+        Ray {
+            origin: self.origin + self.rotation * origin,
+            dir: self.rotation * dir,
+            time,
+            wavelength: None,
+            t_min: 0.0,
+            t_max: Float::INFINITY,
+        }
+    }
+}
+
+impl Camera for ThinLensCamera {
+    fn outgoing_ray<R: Rng>(
+        &self,
+        format: FrameFormat,
+        pixel: IVec2,
+        time: Float,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> Ray {
+        self.lens_ray(format, pixel, time, self.focus_distance, sampler)
+    }
+
+    fn outgoing_ray_at_wavelength<R: Rng>(
+        &self,
+        format: FrameFormat,
+        pixel: IVec2,
+        time: Float,
+        wavelength: Float,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> Ray {
+        let focus = match &self.chromatic_aberration {
+            Some(glass) => glass.focus_distance(self.focus_distance, wavelength),
+            None => self.focus_distance,
+        };
+        Ray {
+            wavelength: Some(wavelength),
+            ..self.lens_ray(format, pixel, time, focus, sampler)
+        }
+    }
+
+    fn lod_view(&self, format: FrameFormat, _time: Float) -> Option<LodView> {
+        Some(LodView {
+            origin: self.origin,
+            pixels_per_radian: format.resolution.x as Float * 0.5
+                / (self.horizontal_fov.to_radians() / 2.0).tan(),
+        })
+    }
+}
+
+/// Renders all six axis aligned faces of a cubemap into a single image, e.g. for reflection
+/// probes. The faces are laid out left to right in the order +X, -X, +Y, -Y, +Z, -Z, so the
+/// resolution should be six times as wide as it is high.
+pub struct CubemapCamera {
+    pub origin: Vec3,
+    pub rotation: Quat,
+}
+
+impl CubemapCamera {
+    /// Forward, right and up directions of each face
+    const FACES: [(Vec3, Vec3, Vec3); 6] = [
+        (Vec3::X, Vec3::NEG_Z, Vec3::Y),
+        (Vec3::NEG_X, Vec3::Z, Vec3::Y),
+        (Vec3::Y, Vec3::X, Vec3::NEG_Z),
+        (Vec3::NEG_Y, Vec3::X, Vec3::Z),
+        (Vec3::Z, Vec3::X, Vec3::Y),
+        (Vec3::NEG_Z, Vec3::NEG_X, Vec3::Y),
+    ];
+}
+
+impl Camera for CubemapCamera {
+    fn outgoing_ray<R: Rng>(
+        &self,
+        format: FrameFormat,
+        pixel: IVec2,
+        time: Float,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> Ray {
+        let face_size = format.resolution.y as i32;
+        let face = pixel.x.div_euclid(face_size).clamp(0, 5);
+        let (forward, right, up) = Self::FACES[face as usize];
+
+        // Position within the face from -1 to 1
+        let jitter = sampler.next_2d();
+        let u = ((pixel.x - face * face_size) as Float + jitter.x) / face_size as Float * 2.0 - 1.0;
+        let v = (pixel.y as Float + jitter.y) / face_size as Float * 2.0 - 1.0;
+
+        Ray {
+            origin: self.origin,
+            dir: self.rotation * (forward + right * u + up * v).normalize(),
+            time,
+            wavelength: None,
+            t_min: 0.0,
+            t_max: Float::INFINITY,
+        }
+    }
+}
+
+/// A fisheye lens with an equidistant projection, where the angle from the view direction is
+/// proportional to the distance from the centre of the image. `fov` is the angle in degrees
+/// across the width of the frame, so 180.0 in a square frame gives a circular fisheye whose
+/// corners see slightly behind the camera. Angles are limited to 360 degrees across.
+pub struct FisheyeCamera {
+    pub origin: Vec3,
+    pub rotation: Quat,
+    pub fov: Float,
+}
+
+impl Camera for FisheyeCamera {
+    fn outgoing_ray<R: Rng>(
+        &self,
+        format: FrameFormat,
+        pixel: IVec2,
+        time: Float,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> Ray {
+        // Position on the displayed image from -1 to 1 across its width
+        let jitter = sampler.next_2d();
+        let resolution = Vec2::new(format.resolution.x as Float, format.resolution.y as Float);
+        let p = (Vec2::new(pixel.x as Float, pixel.y as Float) + jitter - resolution / 2.0)
+            * Vec2::new(format.pixel_aspect, 1.0)
+            / (resolution.x * format.pixel_aspect / 2.0);
+
+        let theta = (p.length() * self.fov.to_radians() / 2.0).min(PI);
+        let phi = p.y.atan2(p.x);
+        let dir = Vec3::new(
+            theta.sin() * phi.cos(),
+            theta.sin() * phi.sin(),
+            theta.cos(),
+        );
+
+        Ray {
+            origin: self.origin,
+            dir: self.rotation * dir,
+            time,
+            wavelength: None,
+            t_min: 0.0,
+            t_max: Float::INFINITY,
+        }
+    }
+
+    fn lod_view(&self, format: FrameFormat, _time: Float) -> Option<LodView> {
+        Some(LodView {
+            origin: self.origin,
+            pixels_per_radian: format.resolution.x as Float / self.fov.to_radians(),
+        })
+    }
+}
+
+/// A full 360 by 180 degree panorama in the equirectangular layout used by VR players and
+/// environment maps, so the resolution should be twice as wide as it is high. The centre of
+/// the image looks along the camera's forward direction.
+pub struct EquirectangularCamera {
+    pub origin: Vec3,
+    pub rotation: Quat,
+}
+
+impl Camera for EquirectangularCamera {
+    fn outgoing_ray<R: Rng>(
+        &self,
+        format: FrameFormat,
+        pixel: IVec2,
+        time: Float,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> Ray {
+        let jitter = sampler.next_2d();
+        let uv = (Vec2::new(pixel.x as Float, pixel.y as Float) + jitter)
+            / Vec2::new(format.resolution.x as Float, format.resolution.y as Float);
+
+        Ray {
+            origin: self.origin,
+            dir: self.rotation * equirectangular_dir(uv),
+            time,
+            wavelength: None,
+            t_min: 0.0,
+            t_max: Float::INFINITY,
+        }
+    }
+
+    fn lod_view(&self, format: FrameFormat, _time: Float) -> Option<LodView> {
+        Some(LodView {
+            origin: self.origin,
+            pixels_per_radian: format.resolution.x as Float / (2.0 * PI),
+        })
+    }
+}
+
+/// Omnidirectional stereo: an equirectangular panorama for each eye, with the left eye above
+/// the right, so the resolution should be square. Rays start on a circle of diameter
+/// `eye_separation` around the origin, tangent to it, which gives correct stereo in every
+/// direction around the horizon. The separation shrinks towards the poles, where stereo can't
+/// be right for every head rotation, to avoid the distortion it would otherwise cause.
+pub struct OdsCamera {
+    pub origin: Vec3,
+    pub rotation: Quat,
+    /// Distance between the eyes, 0.064 for an average adult in a scene measured in metres
+    pub eye_separation: Float,
+}
+
+impl Camera for OdsCamera {
+    fn outgoing_ray<R: Rng>(
+        &self,
+        format: FrameFormat,
+        pixel: IVec2,
+        time: Float,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> Ray {
+        // Camera space has y pointing up, so the left eye is the upper half
+        let eye_height = (format.resolution.y / 2).max(1) as i32;
+        let left = pixel.y >= eye_height;
+        let y = pixel.y - if left { eye_height } else { 0 };
+
+        let jitter = sampler.next_2d();
+        let uv = (Vec2::new(pixel.x as Float, y as Float) + jitter)
+            / Vec2::new(format.resolution.x as Float, eye_height as Float);
+        let dir = equirectangular_dir(uv);
+
+        // Eyes sit either side of the view direction, perpendicular to it in the horizontal
+        let longitude = (uv.x - 0.5) * 2.0 * PI;
+        let latitude = (uv.y - 0.5) * PI;
+        let right = Vec3::new(longitude.cos(), 0.0, -longitude.sin());
+        let side = if left { -0.5 } else { 0.5 };
+        let offset = right * side * self.eye_separation * latitude.cos();
+
+        Ray {
+            origin: self.origin + self.rotation * offset,
+            dir: self.rotation * dir,
+            time,
+            wavelength: None,
+            t_min: 0.0,
+            t_max: Float::INFINITY,
+        }
+    }
+}
+
+/// Direction through `uv` on an equirectangular image, where u goes all the way around from
+/// behind and v from straight down to straight up, with the centre looking along +Z
+fn equirectangular_dir(uv: Vec2) -> Vec3 {
+    let longitude = (uv.x - 0.5) * 2.0 * PI;
+    let latitude = (uv.y - 0.5) * PI;
+    Vec3::new(
+        latitude.cos() * longitude.sin(),
+        latitude.sin(),
+        latitude.cos() * longitude.cos(),
+    )
+}
+
+/// Moves another camera over the shutter interval, for motion blur. The transform is applied
+/// on top of the camera's own position and rotation.
+pub struct AnimatedCamera<C: Camera> {
+    pub camera: C,
+    pub transform: AnimatedTransform,
+}
+
+impl<C: Camera> AnimatedCamera<C> {
+    fn transform_ray(&self, ray: Ray, time: Float) -> Ray {
+        let transform = self.transform.at(time);
+
+        Ray {
+            origin: transform.transform_point3(ray.origin),
+            dir: transform.transform_vector3(ray.dir),
+            time,
+            wavelength: ray.wavelength,
+            t_min: ray.t_min,
+            t_max: ray.t_max,
+        }
+    }
+}
+
+impl<C: Camera> Camera for AnimatedCamera<C> {
+    fn outgoing_ray<R: Rng>(
+        &self,
+        format: FrameFormat,
+        pixel: IVec2,
+        time: Float,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> Ray {
+        let ray = self.camera.outgoing_ray(format, pixel, time, sampler);
+        self.transform_ray(ray, time)
+    }
+
+    fn outgoing_ray_at_wavelength<R: Rng>(
+        &self,
+        format: FrameFormat,
+        pixel: IVec2,
+        time: Float,
+        wavelength: Float,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> Ray {
+        let ray = self
+            .camera
+            .outgoing_ray_at_wavelength(format, pixel, time, wavelength, sampler);
+        self.transform_ray(ray, time)
+    }
+
+    fn lod_view(&self, format: FrameFormat, time: Float) -> Option<LodView> {
+        let view = self.camera.lod_view(format, time)?;
+        Some(LodView {
+            origin: self.transform.at(time).transform_point3(view.origin),
+            ..view
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::UVec2;
+
+    use super::*;
+    use crate::float::Affine3;
+
+    #[test]
+    fn animated_lod_view_follows_the_camera() {
+        let camera = AnimatedCamera {
+            camera: PerspectiveCamera {
+                origin: Vec3::ZERO,
+                rotation: Quat::IDENTITY,
+                horizontal_fov: 60.0,
+            },
+            transform: AnimatedTransform::new(
+                Affine3::IDENTITY,
+                Affine3::from_translation(Vec3::new(0.0, 0.0, 10.0)),
+            ),
+        };
+        let format = FrameFormat::new(UVec2::new(64, 64));
+        for (time, z) in [(0.0, 0.0), (0.5, 5.0), (1.0, 10.0)] {
+            let view = camera.lod_view(format, time).unwrap();
+            assert!(view.origin.abs_diff_eq(Vec3::new(0.0, 0.0, z), 1e-4));
         }
     }
 }
@@ -1,27 +1,195 @@
-use glam::DVec3;
-use rand::{Rng, SeedableRng};
+use std::sync::Arc;
 
-use crate::{material::Material, ray::Ray};
+use rand::RngCore;
 
+#[cfg(feature = "gpu")]
+use crate::gpu::{GpuGeometry, GpuShape};
+use crate::{
+    bvh::Aabb,
+    float::{consts::PI, Float, Vec2, Vec3, Vec4},
+    lod::LodView,
+    material::Material,
+    ray::{EpsilonPolicy, Ray},
+    report::{short_type_name, ObjectInfo},
+    stats,
+};
+
+#[derive(Clone)]
 pub struct Collision<'a> {
     pub ray: Ray,
-    pub t: f64,
-    pub normal: DVec3,
+    pub t: Float,
+    /// Normal used for shading, which smooth shaded meshes interpolate and normal maps tilt
+    pub normal: Vec3,
+    /// True normal of the surface, for telling which side of it a ray is on and moving rays
+    /// which leave it off the surface. Points to the same side as `normal`.
+    pub geometric_normal: Vec3,
+    /// Surface coordinates of the hit, usually in the range 0.0 to 1.0
+    pub uv: Vec2,
+    /// Direction of increasing u along the surface, with the sign of the bitangent in w as for
+    /// mesh tangents. Zero if the surface has no UV parameterisation to map textures with.
+    pub tangent: Vec4,
+    /// Offset from the hit point to start reflected rays from. Used by smooth shaded meshes to
+    /// move the start of shadow rays onto the curved surface implied by their normals.
+    pub terminator_offset: Vec3,
     pub material: &'a Material,
 }
 
-pub trait Collideable<R: Rng + SeedableRng> {
-    fn trace(&self, ray: &Ray, rng: &mut R) -> Option<Collision>;
+/// A point chosen uniformly on the surface of an emissive object, to emit photons from
+pub struct EmissionSample<'a> {
+    pub point: Vec3,
+    /// Direction light is emitted around, pointing out of the surface
+    pub normal: Vec3,
+    /// Area of the surface the point was chosen from
+    pub area: Float,
+    /// Whether light is also emitted from the back of the surface
+    pub two_sided: bool,
+    pub material: &'a Material,
 }
 
-pub struct Plane<'a> {
-    pub origin: DVec3,
-    pub normal: DVec3,
-    pub material: &'a Material,
+impl Collision<'_> {
+    /// Start of a ray leaving the surface in direction `dir`. The hit point is moved off the
+    /// surface along the geometric normal, to the side `dir` is on, so the ray can't hit the
+    /// surface again however the hit point was rounded.
+    pub fn spawn_origin(&self, dir: Vec3, epsilon: &EpsilonPolicy) -> Vec3 {
+        let point = self.ray.at(self.t);
+        let offset = self.geometric_normal * epsilon.offset(point);
+        if dir.dot(self.geometric_normal) < 0.0 {
+            point - offset
+        } else {
+            point + offset
+        }
+    }
+}
+
+/// Tangent for a surface with `normal` whose UV coordinates increase along `du` and `dv`, in the
+/// form stored in [`Collision::tangent`]
+pub(crate) fn surface_tangent(normal: Vec3, du: Vec3, dv: Vec3) -> Vec4 {
+    match (du - normal * normal.dot(du)).try_normalize() {
+        Some(tangent) => {
+            let sign = if normal.cross(tangent).dot(dv) < 0.0 {
+                -1.0
+            } else {
+                1.0
+            };
+            tangent.extend(sign)
+        }
+        None => Vec4::ZERO,
+    }
+}
+
+/// Something rays can hit. Objects are shared between the threads of a render and between
+/// renders running at once, so they must be `Send + Sync`.
+pub trait Collideable: Send + Sync {
+    /// Find the closest hit of `ray` within its range. `rng` is only used by objects with
+    /// stochastic surfaces, such as volumes, and can be any generator.
+    fn trace(&self, ray: &Ray, rng: &mut dyn RngCore) -> Option<Collision<'_>>;
+
+    /// Box around the object in local space, so rays which miss it can skip testing it.
+    /// `None` if the object is unbounded, such as an infinite plane, so every ray tests it.
+    fn bounds(&self) -> Option<Aabb> {
+        None
+    }
+
+    /// Index of the level of detail to render as seen from `view`, which is given in the
+    /// object's local space. 0 is the most detailed.
+    fn level_of_detail(&self, _view: &LodView) -> usize {
+        0
+    }
+
+    /// The object to render at level of detail `level`, or `None` to render this object
+    fn at_level_of_detail(&self, _level: usize) -> Option<&dyn Collideable> {
+        None
+    }
+
+    /// Estimated number of bytes used by this object, including any data it owns
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of_val(self)
+    }
+
+    /// A point on the surface chosen from `u` in the unit square, if the object emits light.
+    /// Only objects which can be sampled emit photons for photon mapping.
+    fn sample_emission(&self, _u: Vec2) -> Option<EmissionSample<'_>> {
+        None
+    }
+
+    /// Corners of every triangle of the surface in local space, for rasterizing what the
+    /// camera sees. `None` if the object isn't a triangle mesh, so has to be traced instead.
+    fn raster_triangles(&self) -> Option<Vec<[Vec3; 3]>> {
+        None
+    }
+
+    /// The hit of `ray` with triangle `index` of [`raster_triangles`](Self::raster_triangles),
+    /// if it hits it within its range
+    fn trace_triangle(&self, _ray: &Ray, _index: usize) -> Option<Collision<'_>> {
+        None
+    }
+
+    /// Description of the object for scene reports
+    fn info(&self) -> ObjectInfo {
+        ObjectInfo::new(short_type_name::<Self>())
+    }
+
+    /// The object in a form the GPU backend can render, `None` if it isn't supported there
+    #[cfg(feature = "gpu")]
+    fn gpu_shape(&self) -> Option<GpuShape<'_>> {
+        None
+    }
+}
+
+/// An object shared between scenes, so an asset loaded once can be rendered by several renders
+/// at once. Each scene keeps its own choice of level of detail.
+impl<T: Collideable + ?Sized> Collideable for Arc<T> {
+    fn trace(&self, ray: &Ray, rng: &mut dyn RngCore) -> Option<Collision<'_>> {
+        (**self).trace(ray, rng)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        (**self).bounds()
+    }
+
+    fn level_of_detail(&self, view: &LodView) -> usize {
+        (**self).level_of_detail(view)
+    }
+
+    fn at_level_of_detail(&self, level: usize) -> Option<&dyn Collideable> {
+        (**self).at_level_of_detail(level)
+    }
+
+    fn memory_usage(&self) -> usize {
+        (**self).memory_usage()
+    }
+
+    fn sample_emission(&self, u: Vec2) -> Option<EmissionSample<'_>> {
+        (**self).sample_emission(u)
+    }
+
+    fn raster_triangles(&self) -> Option<Vec<[Vec3; 3]>> {
+        (**self).raster_triangles()
+    }
+
+    fn trace_triangle(&self, ray: &Ray, index: usize) -> Option<Collision<'_>> {
+        (**self).trace_triangle(ray, index)
+    }
+
+    fn info(&self) -> ObjectInfo {
+        (**self).info()
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_shape(&self) -> Option<GpuShape<'_>> {
+        (**self).gpu_shape()
+    }
+}
+
+pub struct Plane {
+    pub origin: Vec3,
+    pub normal: Vec3,
+    pub material: Arc<Material>,
 }
 
-impl<'a, R: Rng + SeedableRng> Collideable<R> for Plane<'a> {
-    fn trace(&self, ray: &Ray, _rng: &mut R) -> Option<Collision> {
+impl Collideable for Plane {
+    fn trace(&self, ray: &Ray, _rng: &mut dyn RngCore) -> Option<Collision<'_>> {
+        stats::record_tests("Plane", 1);
         let numerator = -(ray.origin.x - self.origin.x) * self.normal.x
             - (ray.origin.y - self.origin.y) * self.normal.y
             - (ray.origin.z - self.origin.z) * self.normal.z;
@@ -30,28 +198,53 @@ impl<'a, R: Rng + SeedableRng> Collideable<R> for Plane<'a> {
             ray.dir.x * self.normal.x + ray.dir.y * self.normal.y + ray.dir.z * self.normal.z;
 
         let t = numerator / denominator;
-        if t < 0.0 {
+        if !ray.in_range(t) {
             return None;
         }
 
+        // Measured in world units along two arbitrary directions in the plane
+        let normal = self.normal.normalize();
+        let (u, v) = normal.any_orthonormal_pair();
+        let offset = ray.at(t) - self.origin;
+
         Some(Collision {
             ray: ray.clone(),
             t,
-            normal: self.normal.normalize(),
+            normal,
+            geometric_normal: normal,
+            uv: Vec2::new(offset.dot(u), offset.dot(v)),
+            tangent: surface_tangent(normal, u, v),
+            terminator_offset: Vec3::ZERO,
+            material: &self.material,
+        })
+    }
+
+    fn info(&self) -> ObjectInfo {
+        ObjectInfo::with_material("Plane", &self.material)
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_shape(&self) -> Option<GpuShape<'_>> {
+        Some(GpuShape {
+            geometry: GpuGeometry::Plane {
+                origin: self.origin,
+                normal: self.normal,
+            },
             material: &self.material,
         })
     }
 }
 
-pub struct Sphere<'a> {
-    pub origin: DVec3,
-    pub radius: f64,
-    pub material: &'a Material,
+pub struct Sphere {
+    pub origin: Vec3,
+    pub radius: Float,
+    pub material: Arc<Material>,
 }
 
-impl<'a, R: Rng + SeedableRng> Collideable<R> for Sphere<'a> {
-    fn trace(&self, ray: &Ray, _rng: &mut R) -> Option<Collision> {
-        let off = DVec3::new(
+impl Collideable for Sphere {
+    fn trace(&self, ray: &Ray, _rng: &mut dyn RngCore) -> Option<Collision<'_>> {
+        stats::record_tests("Sphere", 1);
+        let off = Vec3::new(
             ray.origin.x - self.origin.x,
             ray.origin.y - self.origin.y,
             ray.origin.z - self.origin.z,
@@ -68,30 +261,716 @@ impl<'a, R: Rng + SeedableRng> Collideable<R> for Sphere<'a> {
         }
 
         let sqrt_disc = disc.sqrt();
-        let t0 = (-b + sqrt_disc) / (2.0 * a);
-        let t1 = (-b - sqrt_disc) / (2.0 * a);
+        let t0 = (-b - sqrt_disc) / (2.0 * a);
+        let t1 = (-b + sqrt_disc) / (2.0 * a);
 
-        let mut t = None;
+        [t0, t1].into_iter().find(|&t| ray.in_range(t)).map(|t| {
+            let normal = (ray.at(t) - self.origin).normalize();
+            Collision {
+                ray: ray.clone(),
+                t,
+                normal,
+                geometric_normal: normal,
+                uv: Vec2::new(
+                    normal.z.atan2(normal.x) / (2.0 * PI) + 0.5,
+                    (-normal.y).acos() / PI,
+                ),
+                tangent: surface_tangent(normal, Vec3::new(-normal.z, 0.0, normal.x), Vec3::Y),
+                terminator_offset: Vec3::ZERO,
+                material: &self.material,
+            }
+        })
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb::around(self.origin, self.radius))
+    }
 
-        if t0 > 0.0 {
-            t = Some(t0);
+    fn sample_emission(&self, u: Vec2) -> Option<EmissionSample<'_>> {
+        if self.material.luminance <= 0.0 {
+            return None;
         }
+        let z = 1.0 - 2.0 * u.x;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * PI * u.y;
+        let normal = Vec3::new(r * phi.cos(), r * phi.sin(), z);
 
-        if t1 > 0.0 {
-            if let Some(t) = &mut t {
-                *t = t.min(t1);
-            }
+        Some(EmissionSample {
+            point: self.origin + normal * self.radius,
+            normal,
+            area: 4.0 * PI * self.radius * self.radius,
+            two_sided: false,
+            material: &self.material,
+        })
+    }
+
+    fn info(&self) -> ObjectInfo {
+        ObjectInfo::with_material("Sphere", &self.material)
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_shape(&self) -> Option<GpuShape<'_>> {
+        Some(GpuShape {
+            geometry: GpuGeometry::Sphere {
+                origin: self.origin,
+                radius: self.radius,
+            },
+            material: &self.material,
+        })
+    }
+}
+
+/// Box around a disc centred on `centre` facing along `normal`
+fn disc_bounds(centre: Vec3, normal: Vec3, radius: Float) -> Aabb {
+    let n = normal.normalize();
+    let extent = Vec3::new(
+        (1.0 - n.x * n.x).max(0.0).sqrt(),
+        (1.0 - n.y * n.y).max(0.0).sqrt(),
+        (1.0 - n.z * n.z).max(0.0).sqrt(),
+    );
+    Aabb::around(centre, 0.0).expand(extent * radius)
+}
+
+/// Orthonormal frame with its y axis along the axis of a shape, used to trace shapes which are
+/// simplest to describe with their axis pointing up.
+struct AxisFrame {
+    origin: Vec3,
+    x: Vec3,
+    y: Vec3,
+    z: Vec3,
+}
+
+impl AxisFrame {
+    fn new(origin: Vec3, axis: Vec3) -> Self {
+        let y = axis.normalize();
+        let (z, x) = y.any_orthonormal_pair();
+        Self { origin, x, y, z }
+    }
+
+    /// Origin and direction of `ray` in the frame
+    fn to_local(&self, ray: &Ray) -> (Vec3, Vec3) {
+        let o = ray.origin - self.origin;
+        (
+            Vec3::new(o.dot(self.x), o.dot(self.y), o.dot(self.z)),
+            Vec3::new(
+                ray.dir.dot(self.x),
+                ray.dir.dot(self.y),
+                ray.dir.dot(self.z),
+            ),
+        )
+    }
+
+    fn to_world(&self, v: Vec3) -> Vec3 {
+        self.x * v.x + self.y * v.y + self.z * v.z
+    }
+}
+
+/// Angle of `p` around the y axis, mapped to the range 0.0 to 1.0
+fn azimuth(p: Vec3) -> Float {
+    p.z.atan2(p.x) / (2.0 * PI) + 0.5
+}
+
+/// Smallest solution of `a t^2 + b t + c = 0` within the range of `ray` for which `valid`
+/// returns true
+fn smallest_quadratic_root(
+    ray: &Ray,
+    a: Float,
+    b: Float,
+    c: Float,
+    valid: impl Fn(Float) -> bool,
+) -> Option<Float> {
+    let disc = b * b - 4.0 * a * c;
+    if disc < 0.0 || a == 0.0 {
+        return None;
+    }
+
+    let sqrt_disc = disc.sqrt();
+    let (t0, t1) = ((-b - sqrt_disc) / (2.0 * a), (-b + sqrt_disc) / (2.0 * a));
+    [t0.min(t1), t0.max(t1)]
+        .into_iter()
+        .find(|&t| ray.in_range(t) && valid(t))
+}
+
+/// A flat circle facing along `normal`
+pub struct Disc {
+    pub origin: Vec3,
+    pub normal: Vec3,
+    pub radius: Float,
+    pub material: Arc<Material>,
+}
+
+impl Collideable for Disc {
+    fn trace(&self, ray: &Ray, _rng: &mut dyn RngCore) -> Option<Collision<'_>> {
+        stats::record_tests("Disc", 1);
+        let frame = AxisFrame::new(self.origin, self.normal);
+        let (o, d) = frame.to_local(ray);
+
+        let t = -o.y / d.y;
+        if !ray.in_range(t) {
+            return None;
         }
 
-        if let Some(t) = t {
-            Some(Collision {
-                ray: ray.clone(),
+        let p = o + d * t;
+        let r = Vec2::new(p.x, p.z).length();
+        if r > self.radius {
+            return None;
+        }
+
+        Some(Collision {
+            ray: ray.clone(),
+            t,
+            normal: frame.y,
+            geometric_normal: frame.y,
+            uv: Vec2::new(azimuth(p), r / self.radius),
+            tangent: Vec4::ZERO,
+            terminator_offset: Vec3::ZERO,
+            material: &self.material,
+        })
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(disc_bounds(self.origin, self.normal, self.radius))
+    }
+
+    fn sample_emission(&self, u: Vec2) -> Option<EmissionSample<'_>> {
+        if self.material.luminance <= 0.0 {
+            return None;
+        }
+        let frame = AxisFrame::new(self.origin, self.normal);
+        let r = self.radius * u.x.sqrt();
+        let phi = 2.0 * PI * u.y;
+
+        Some(EmissionSample {
+            point: self.origin + frame.to_world(Vec3::new(r * phi.cos(), 0.0, r * phi.sin())),
+            normal: frame.y,
+            area: PI * self.radius * self.radius,
+            two_sided: true,
+            material: &self.material,
+        })
+    }
+
+    fn info(&self) -> ObjectInfo {
+        ObjectInfo::with_material("Disc", &self.material)
+    }
+}
+
+/// A flat rectangle centred on `origin`, reaching `u` and `v` from the centre to the middle of
+/// its edges. `u` and `v` should be perpendicular, and it faces along `u.cross(v)`.
+pub struct Quad {
+    pub origin: Vec3,
+    pub u: Vec3,
+    pub v: Vec3,
+    pub material: Arc<Material>,
+}
+
+impl Quad {
+    pub fn normal(&self) -> Vec3 {
+        self.u.cross(self.v).normalize()
+    }
+
+    /// Corners counter-clockwise when viewed from the front
+    pub fn corners(&self) -> [Vec3; 4] {
+        [
+            self.origin - self.u - self.v,
+            self.origin + self.u - self.v,
+            self.origin + self.u + self.v,
+            self.origin - self.u + self.v,
+        ]
+    }
+}
+
+impl Collideable for Quad {
+    fn trace(&self, ray: &Ray, _rng: &mut dyn RngCore) -> Option<Collision<'_>> {
+        stats::record_tests("Quad", 1);
+        let normal = self.normal();
+        let t = (self.origin - ray.origin).dot(normal) / ray.dir.dot(normal);
+        if !ray.in_range(t) {
+            return None;
+        }
+
+        // Position on the quad in units of its half extents
+        let p = ray.at(t) - self.origin;
+        let a = p.dot(self.u) / self.u.length_squared();
+        let b = p.dot(self.v) / self.v.length_squared();
+        if a.abs() > 1.0 || b.abs() > 1.0 {
+            return None;
+        }
+
+        Some(Collision {
+            ray: ray.clone(),
+            t,
+            normal,
+            geometric_normal: normal,
+            uv: Vec2::new(a + 1.0, b + 1.0) / 2.0,
+            tangent: surface_tangent(normal, self.u, self.v),
+            terminator_offset: Vec3::ZERO,
+            material: &self.material,
+        })
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb::from_points(self.corners()))
+    }
+
+    fn sample_emission(&self, u: Vec2) -> Option<EmissionSample<'_>> {
+        if self.material.luminance <= 0.0 {
+            return None;
+        }
+        Some(EmissionSample {
+            point: self.origin + self.u * (2.0 * u.x - 1.0) + self.v * (2.0 * u.y - 1.0),
+            normal: self.normal(),
+            area: 4.0 * self.u.cross(self.v).length(),
+            two_sided: true,
+            material: &self.material,
+        })
+    }
+
+    fn info(&self) -> ObjectInfo {
+        ObjectInfo::with_material("Quad", &self.material)
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_shape(&self) -> Option<GpuShape<'_>> {
+        Some(GpuShape {
+            geometry: GpuGeometry::Triangles {
+                positions: self.corners().to_vec(),
+                normals: Vec::new(),
+                triangles: vec![[0, 1, 2], [0, 2, 3]],
+            },
+            material: &self.material,
+        })
+    }
+}
+
+/// A cylinder closed at both ends, with its base centred on `origin` and extending `height`
+/// along `axis`
+pub struct Cylinder {
+    pub origin: Vec3,
+    pub axis: Vec3,
+    pub radius: Float,
+    pub height: Float,
+    pub material: Arc<Material>,
+}
+
+impl Collideable for Cylinder {
+    fn trace(&self, ray: &Ray, _rng: &mut dyn RngCore) -> Option<Collision<'_>> {
+        stats::record_tests("Cylinder", 1);
+        let frame = AxisFrame::new(self.origin, self.axis);
+        let (o, d) = frame.to_local(ray);
+
+        // Side, for which v runs from the base to the top
+        let side = smallest_quadratic_root(
+            ray,
+            d.x * d.x + d.z * d.z,
+            2.0 * (o.x * d.x + o.z * d.z),
+            o.x * o.x + o.z * o.z - self.radius * self.radius,
+            |t| (0.0..=self.height).contains(&(o.y + d.y * t)),
+        )
+        .map(|t| {
+            let p = o + d * t;
+            (
                 t,
-                normal: (ray.at(t) - self.origin).normalize(),
-                material: &self.material,
-            })
+                Vec3::new(p.x, 0.0, p.z),
+                Vec2::new(azimuth(p), p.y / self.height),
+            )
+        });
+
+        // Caps, for which v runs from the centre to the edge
+        let caps = [(0.0, -Vec3::Y), (self.height, Vec3::Y)]
+            .into_iter()
+            .filter_map(|(y, normal)| {
+                let t = (y - o.y) / d.y;
+                let p = o + d * t;
+                let r = Vec2::new(p.x, p.z).length();
+                (ray.in_range(t) && r <= self.radius)
+                    .then(|| (t, normal, Vec2::new(azimuth(p), r / self.radius)))
+            });
+
+        let (t, normal, uv) = side
+            .into_iter()
+            .chain(caps)
+            .min_by(|a, b| a.0.total_cmp(&b.0))?;
+        let normal = frame.to_world(normal).normalize();
+
+        Some(Collision {
+            ray: ray.clone(),
+            t,
+            normal,
+            geometric_normal: normal,
+            uv,
+            tangent: Vec4::ZERO,
+            terminator_offset: Vec3::ZERO,
+            material: &self.material,
+        })
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let top = self.origin + self.axis.normalize() * self.height;
+        Some(
+            disc_bounds(self.origin, self.axis, self.radius).union(&disc_bounds(
+                top,
+                self.axis,
+                self.radius,
+            )),
+        )
+    }
+
+    fn info(&self) -> ObjectInfo {
+        ObjectInfo::with_material("Cylinder", &self.material)
+    }
+}
+
+/// A cone with its base centred on `origin`, closed by a disc, and its tip `height` along
+/// `axis`
+pub struct Cone {
+    pub origin: Vec3,
+    pub axis: Vec3,
+    pub radius: Float,
+    pub height: Float,
+    pub material: Arc<Material>,
+}
+
+impl Collideable for Cone {
+    fn trace(&self, ray: &Ray, _rng: &mut dyn RngCore) -> Option<Collision<'_>> {
+        stats::record_tests("Cone", 1);
+        let frame = AxisFrame::new(self.origin, self.axis);
+        let (o, d) = frame.to_local(ray);
+
+        // Points on the side satisfy x^2 + z^2 = (k (h - y))^2
+        let k = self.radius / self.height;
+        let k2 = k * k;
+        let h = self.height - o.y;
+        let side = smallest_quadratic_root(
+            ray,
+            d.x * d.x + d.z * d.z - k2 * d.y * d.y,
+            2.0 * (o.x * d.x + o.z * d.z + k2 * h * d.y),
+            o.x * o.x + o.z * o.z - k2 * h * h,
+            |t| (0.0..=self.height).contains(&(o.y + d.y * t)),
+        )
+        .map(|t| {
+            let p = o + d * t;
+            let radial = Vec2::new(p.x, p.z).normalize_or_zero();
+            (
+                t,
+                Vec3::new(radial.x, k, radial.y),
+                Vec2::new(azimuth(p), p.y / self.height),
+            )
+        });
+
+        let base = {
+            let t = -o.y / d.y;
+            let p = o + d * t;
+            let r = Vec2::new(p.x, p.z).length();
+            (ray.in_range(t) && r <= self.radius)
+                .then(|| (t, -Vec3::Y, Vec2::new(azimuth(p), r / self.radius)))
+        };
+
+        let (t, normal, uv) = side
+            .into_iter()
+            .chain(base)
+            .min_by(|a, b| a.0.total_cmp(&b.0))?;
+        let normal = frame.to_world(normal).normalize();
+
+        Some(Collision {
+            ray: ray.clone(),
+            t,
+            normal,
+            geometric_normal: normal,
+            uv,
+            tangent: Vec4::ZERO,
+            terminator_offset: Vec3::ZERO,
+            material: &self.material,
+        })
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let tip = self.origin + self.axis.normalize() * self.height;
+        Some(disc_bounds(self.origin, self.axis, self.radius).union(&Aabb::new(tip, tip)))
+    }
+
+    fn info(&self) -> ObjectInfo {
+        ObjectInfo::with_material("Cone", &self.material)
+    }
+}
+
+/// A ring shaped tube of radius `minor_radius`, centred on `origin` with its centre line a
+/// circle of radius `major_radius` around `axis`
+pub struct Torus {
+    pub origin: Vec3,
+    pub axis: Vec3,
+    pub major_radius: Float,
+    pub minor_radius: Float,
+    pub material: Arc<Material>,
+}
+
+impl Collideable for Torus {
+    fn trace(&self, ray: &Ray, _rng: &mut dyn RngCore) -> Option<Collision<'_>> {
+        stats::record_tests("Torus", 1);
+        let frame = AxisFrame::new(self.origin, self.axis);
+        let (o, d) = frame.to_local(ray);
+
+        // Only search for roots within the bounding sphere
+        let bound = self.major_radius + self.minor_radius;
+        let a = d.length_squared();
+        let b = 2.0 * o.dot(d);
+        let disc = b * b - 4.0 * a * (o.length_squared() - bound * bound);
+        if disc < 0.0 {
+            return None;
+        }
+        let sqrt_disc = disc.sqrt();
+        let near = ((-b - sqrt_disc) / (2.0 * a)).max(ray.t_min);
+        let far = ((-b + sqrt_disc) / (2.0 * a)).min(ray.t_max);
+        if far <= near {
+            return None;
+        }
+
+        // Substitute the ray into (|p|^2 + R^2 - r^2)^2 = 4 R^2 (x^2 + z^2)
+        let r2 = self.major_radius * self.major_radius;
+        let c = o.length_squared() + r2 - self.minor_radius * self.minor_radius;
+        let coefficients = [
+            a * a,
+            2.0 * a * b,
+            b * b + 2.0 * a * c - 4.0 * r2 * (d.x * d.x + d.z * d.z),
+            2.0 * b * c - 8.0 * r2 * (o.x * d.x + o.z * d.z),
+            c * c - 4.0 * r2 * (o.x * o.x + o.z * o.z),
+        ];
+        let t = polynomial_roots(&coefficients, near, far)
+            .into_iter()
+            .find(|&t| ray.in_range(t))?;
+
+        // The normal points away from the nearest point on the centre line of the tube
+        let p = o + d * t;
+        let centre = Vec3::new(p.x, 0.0, p.z).normalize_or_zero() * self.major_radius;
+        let normal = p - centre;
+        let tube_angle = normal
+            .y
+            .atan2(Vec2::new(p.x, p.z).length() - self.major_radius);
+        let normal = frame.to_world(normal).normalize();
+
+        Some(Collision {
+            ray: ray.clone(),
+            t,
+            normal,
+            geometric_normal: normal,
+            uv: Vec2::new(azimuth(p), tube_angle / (2.0 * PI) + 0.5),
+            tangent: Vec4::ZERO,
+            terminator_offset: Vec3::ZERO,
+            material: &self.material,
+        })
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(
+            disc_bounds(self.origin, self.axis, self.major_radius)
+                .expand(Vec3::splat(self.minor_radius)),
+        )
+    }
+
+    fn info(&self) -> ObjectInfo {
+        ObjectInfo::with_material("Torus", &self.material)
+    }
+}
+
+/// Real roots of the polynomial with `coefficients` (highest power first) between `lo` and
+/// `hi`, in ascending order. The roots of the derivative split the range into sections where
+/// the polynomial is monotonic, each of which can contain at most one root found by bisection.
+fn polynomial_roots(coefficients: &[Float], lo: Float, hi: Float) -> Vec<Float> {
+    let degree = coefficients.len().saturating_sub(1);
+    if degree == 0 {
+        return Vec::new();
+    }
+    if degree == 1 {
+        let t = -coefficients[1] / coefficients[0];
+        return if (lo..=hi).contains(&t) {
+            vec![t]
         } else {
-            None
+            Vec::new()
+        };
+    }
+
+    let evaluate = |t: Float| coefficients.iter().fold(0.0, |acc, c| acc * t + c);
+    let derivative: Vec<Float> = coefficients[..degree]
+        .iter()
+        .enumerate()
+        .map(|(i, c)| c * (degree - i) as Float)
+        .collect();
+
+    let mut bounds = vec![lo];
+    bounds.extend(polynomial_roots(&derivative, lo, hi));
+    bounds.push(hi);
+
+    let mut roots = Vec::new();
+    for section in bounds.windows(2) {
+        let (mut a, mut b) = (section[0], section[1]);
+        let (fa, fb) = (evaluate(a), evaluate(b));
+        if fa == 0.0 {
+            roots.push(a);
+            continue;
+        }
+        if fa.signum() == fb.signum() {
+            continue;
+        }
+
+        let rising = fb > fa;
+        for _ in 0..64 {
+            let mid = (a + b) / 2.0;
+            if (evaluate(mid) < 0.0) == rising {
+                a = mid;
+            } else {
+                b = mid;
+            }
+        }
+        roots.push((a + b) / 2.0);
+    }
+
+    roots.dedup();
+    roots
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    use super::*;
+
+    fn assert_roots(roots: Vec<Float>, expected: &[Float]) {
+        assert_eq!(roots.len(), expected.len(), "{:?}", roots);
+        for (root, expected) in roots.iter().zip(expected) {
+            assert!((root - expected).abs() < 1e-4, "{:?}", roots);
+        }
+    }
+
+    #[test]
+    fn quartic_roots() {
+        // (t - 1)(t - 2)(t - 3)(t - 4)
+        let quartic = [1.0, -10.0, 35.0, -50.0, 24.0];
+        assert_roots(polynomial_roots(&quartic, 0.0, 5.0), &[1.0, 2.0, 3.0, 4.0]);
+        assert_roots(polynomial_roots(&quartic, 1.5, 3.5), &[2.0, 3.0]);
+        // t^4 + 1
+        assert_roots(
+            polynomial_roots(&[1.0, 0.0, 0.0, 0.0, 1.0], -10.0, 10.0),
+            &[],
+        );
+    }
+
+    #[test]
+    fn repeated_and_linear_roots() {
+        // (t - 2)^2, which only touches zero
+        let roots = polynomial_roots(&[1.0, -4.0, 4.0], 0.0, 5.0);
+        assert!(roots.iter().all(|t| (t - 2.0).abs() < 1e-3), "{:?}", roots);
+        assert_roots(polynomial_roots(&[2.0, -3.0], 0.0, 5.0), &[1.5]);
+        assert_roots(polynomial_roots(&[2.0, -3.0], 2.0, 5.0), &[]);
+    }
+
+    #[test]
+    fn torus_hits() {
+        let torus = Torus {
+            origin: Vec3::ZERO,
+            axis: Vec3::Y,
+            major_radius: 2.0,
+            minor_radius: 0.5,
+            material: Arc::new(Material::default()),
+        };
+        let ray = |origin: Vec3, dir: Vec3| Ray {
+            origin,
+            dir,
+            time: 0.0,
+            wavelength: None,
+            t_min: 0.0,
+            t_max: Float::INFINITY,
+        };
+        let mut rng = SmallRng::seed_from_u64(0);
+
+        // Through the tube on the near side
+        let c = torus
+            .trace(&ray(Vec3::new(-5.0, 0.0, 0.0), Vec3::X), &mut rng)
+            .unwrap();
+        assert!((c.t - 2.5).abs() < 1e-4);
+        assert!(c.normal.abs_diff_eq(Vec3::NEG_X, 1e-4));
+
+        // Starting inside the tube, leaving through its inner side
+        let c = torus
+            .trace(&ray(Vec3::new(-2.0, 0.0, 0.0), Vec3::X), &mut rng)
+            .unwrap();
+        assert!((c.t - 0.5).abs() < 1e-4);
+
+        // Down through the hole, and past the outside
+        assert!(torus
+            .trace(&ray(Vec3::new(0.0, 5.0, 0.0), Vec3::NEG_Y), &mut rng)
+            .is_none());
+        assert!(torus
+            .trace(&ray(Vec3::new(-5.0, 1.0, 0.0), Vec3::X), &mut rng)
+            .is_none());
+    }
+
+    #[test]
+    fn bounds_contain_every_hit() {
+        let material = Arc::new(Material::default());
+        let axis = Vec3::new(0.3, 1.0, -0.5);
+        let objects: Vec<Box<dyn Collideable>> = vec![
+            Box::new(Sphere {
+                origin: Vec3::new(0.5, 0.0, 0.0),
+                radius: 1.0,
+                material: material.clone(),
+            }),
+            Box::new(Disc {
+                origin: Vec3::ZERO,
+                normal: axis,
+                radius: 1.5,
+                material: material.clone(),
+            }),
+            Box::new(Quad {
+                origin: Vec3::new(0.0, 1.0, 0.0),
+                u: Vec3::new(1.0, 0.0, 1.0),
+                v: Vec3::new(-0.5, 0.0, 0.5),
+                material: material.clone(),
+            }),
+            Box::new(Cylinder {
+                origin: Vec3::ZERO,
+                axis,
+                radius: 0.5,
+                height: 2.0,
+                material: material.clone(),
+            }),
+            Box::new(Cone {
+                origin: Vec3::ZERO,
+                axis,
+                radius: 1.0,
+                height: 1.5,
+                material: material.clone(),
+            }),
+            Box::new(Torus {
+                origin: Vec3::ZERO,
+                axis,
+                major_radius: 1.0,
+                minor_radius: 0.25,
+                material,
+            }),
+        ];
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        for object in &objects {
+            let bounds = object.bounds().unwrap().expand(Vec3::splat(1e-4));
+            let mut hits = 0;
+            for _ in 0..2000 {
+                // Aimed near the object, so flat shapes are hit often enough
+                let origin = Vec3::new(rng.gen(), rng.gen(), rng.gen()) * 6.0 - 3.0;
+                let target = Vec3::new(rng.gen(), rng.gen(), rng.gen()) * 2.0 - 1.0;
+                let ray = Ray {
+                    origin,
+                    dir: target - origin,
+                    time: 0.0,
+                    wavelength: None,
+                    t_min: 0.0,
+                    t_max: Float::INFINITY,
+                };
+                if let Some(c) = object.trace(&ray, &mut rng) {
+                    let p = ray.at(c.t);
+                    assert!(p.cmpge(bounds.min).all() && p.cmple(bounds.max).all());
+                    hits += 1;
+                }
+            }
+            assert!(hits > 100);
         }
     }
 }
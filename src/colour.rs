@@ -0,0 +1,135 @@
+//! Rendering happens in linear light with sRGB/Rec.709 primaries. Input transforms convert
+//! colour data such as textures into this space, output transforms convert the rendered film
+//! out of it.
+
+use std::path::Path;
+
+use crate::float::{Float, Mat3, Vec3};
+
+/// Linear sRGB to ACEScg (AP1 primaries, D60 white point) including the Bradford chromatic
+/// adaptation from D65.
+const SRGB_TO_ACESCG: Mat3 = Mat3::from_cols_array(&[
+    0.613097, 0.070194, 0.020616, //
+    0.339523, 0.916355, 0.109570, //
+    0.047379, 0.013452, 0.869815, //
+]);
+
+const ACESCG_TO_SRGB: Mat3 = Mat3::from_cols_array(&[
+    1.704859, -0.130077, -0.023964, //
+    -0.621716, 1.140736, -0.128975, //
+    -0.083299, -0.010548, 1.153000, //
+]);
+
+/// How colour data being read into the renderer is encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InputTransform {
+    /// sRGB encoded colour, e.g. most 8 bit colour textures
+    Srgb,
+    /// Linear light with sRGB primaries
+    Linear,
+    /// Linear light with ACEScg primaries
+    AcesCg,
+    /// Non-colour data such as normal or roughness maps, used as-is
+    Raw,
+}
+
+impl InputTransform {
+    /// How images at `path` are usually encoded, judging by the format its extension names:
+    /// linear light for HDR and EXR, sRGB for 8 bit formats such as PNG and JPEG
+    pub fn for_path<P: AsRef<Path>>(path: P) -> Self {
+        match image::ImageFormat::from_path(path) {
+            Ok(image::ImageFormat::Hdr | image::ImageFormat::OpenExr) => InputTransform::Linear,
+            _ => InputTransform::Srgb,
+        }
+    }
+
+    /// Convert an encoded colour to linear working space
+    pub fn to_linear(&self, colour: Vec3) -> Vec3 {
+        match self {
+            InputTransform::Srgb => Vec3::new(
+                srgb_eotf(colour.x),
+                srgb_eotf(colour.y),
+                srgb_eotf(colour.z),
+            ),
+            InputTransform::Linear | InputTransform::Raw => colour,
+            InputTransform::AcesCg => ACESCG_TO_SRGB * colour,
+        }
+    }
+
+    /// Convert an 8 bit encoded colour to linear working space
+    pub fn decode(&self, colour: [u8; 3]) -> Vec3 {
+        self.to_linear(Vec3::new(
+            colour[0] as Float / 255.0,
+            colour[1] as Float / 255.0,
+            colour[2] as Float / 255.0,
+        ))
+    }
+}
+
+/// How the rendered film is encoded when written out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputTransform {
+    /// Linear light with sRGB primaries, i.e. no transform
+    Linear,
+    /// sRGB display encoding
+    Srgb,
+    /// Rec.709 (BT.709) video encoding
+    Rec709,
+    /// Linear light with ACEScg primaries
+    AcesCg,
+}
+
+impl OutputTransform {
+    /// Convert a linear working space colour to the output encoding
+    pub fn apply(&self, colour: Vec3) -> Vec3 {
+        match self {
+            OutputTransform::Linear => colour,
+            OutputTransform::Srgb => Vec3::new(
+                srgb_oetf(colour.x),
+                srgb_oetf(colour.y),
+                srgb_oetf(colour.z),
+            ),
+            OutputTransform::Rec709 => Vec3::new(
+                rec709_oetf(colour.x),
+                rec709_oetf(colour.y),
+                rec709_oetf(colour.z),
+            ),
+            OutputTransform::AcesCg => SRGB_TO_ACESCG * colour,
+        }
+    }
+
+    /// The transform with the same primaries but no display encoding, for formats which store
+    /// scene-linear values such as OpenEXR
+    pub fn scene_linear(&self) -> Self {
+        match self {
+            OutputTransform::Linear | OutputTransform::Srgb | OutputTransform::Rec709 => {
+                OutputTransform::Linear
+            }
+            OutputTransform::AcesCg => OutputTransform::AcesCg,
+        }
+    }
+}
+
+fn srgb_eotf(v: Float) -> Float {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn srgb_oetf(v: Float) -> Float {
+    if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn rec709_oetf(v: Float) -> Float {
+    if v < 0.018 {
+        v * 4.5
+    } else {
+        1.099 * v.powf(0.45) - 0.099
+    }
+}
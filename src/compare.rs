@@ -0,0 +1,486 @@
+//! Metrics for comparing two renders of the same scene, e.g. to check a new sampler converges
+//! faster than an old one at equal time.
+
+use std::{error::Error, fmt::Display, path::Path};
+
+use image::RgbImage;
+
+use crate::{
+    colour::{InputTransform, OutputTransform},
+    float::{consts::PI, Float, Mat3, Vec3},
+};
+
+/// Linear sRGB to CIE XYZ, D65 white point
+const SRGB_TO_XYZ: Mat3 = Mat3::from_cols_array(&[
+    0.4124564, 0.2126729, 0.0193339, //
+    0.3575761, 0.7151522, 0.1191920, //
+    0.1804375, 0.0721750, 0.9503041, //
+]);
+
+/// Pixels per degree of visual angle at the default viewing conditions of FLIP, a 0.7m wide
+/// 4K monitor viewed from 0.7m
+pub const DEFAULT_PIXELS_PER_DEGREE: Float = 67.0;
+
+/// An image to compare, as display encoded sRGB
+pub struct ComparisonImage {
+    pub width: u32,
+    pub height: u32,
+    pixels: Vec<Vec3>,
+}
+
+impl ComparisonImage {
+    pub fn from_rgb_image(image: &RgbImage) -> Self {
+        Self {
+            width: image.width(),
+            height: image.height(),
+            pixels: image
+                .pixels()
+                .map(|p| Vec3::new(p[0] as Float, p[1] as Float, p[2] as Float) / 255.0)
+                .collect(),
+        }
+    }
+
+    /// Open an image file. EXR files are assumed to be linear and are sRGB encoded, while
+    /// other formats are assumed to be sRGB encoded already.
+    pub fn open<P: AsRef<Path>>(path: P) -> image::ImageResult<Self> {
+        let path = path.as_ref();
+        let linear = path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("exr"));
+        let image = image::open(path)?.to_rgb32f();
+
+        Ok(Self {
+            width: image.width(),
+            height: image.height(),
+            pixels: image
+                .pixels()
+                .map(|p| {
+                    let v = Vec3::new(p[0] as Float, p[1] as Float, p[2] as Float);
+                    let v = if linear {
+                        OutputTransform::Srgb.apply(v)
+                    } else {
+                        v
+                    };
+                    v.clamp(Vec3::ZERO, Vec3::ONE)
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Returned when comparing images which aren't the same size
+#[derive(Debug, Clone, Copy)]
+pub struct SizeMismatchError {
+    pub reference: (u32, u32),
+    pub test: (u32, u32),
+}
+
+impl Display for SizeMismatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "reference is {}x{} but test image is {}x{}",
+            self.reference.0, self.reference.1, self.test.0, self.test.1
+        )
+    }
+}
+
+impl Error for SizeMismatchError {}
+
+/// Difference between a test image and a reference
+pub struct Comparison {
+    pub width: u32,
+    pub height: u32,
+    /// Root mean square error of the encoded colour channels
+    pub rmse: Float,
+    /// Mean of the per-pixel FLIP errors
+    pub mean_flip: Float,
+    /// Perceived difference of each pixel from 0.0 (identical) to 1.0, row by row
+    pub flip: Vec<Float>,
+}
+
+impl Comparison {
+    /// The per-pixel FLIP errors, coloured with the magma colour map
+    pub fn heatmap(&self) -> RgbImage {
+        RgbImage::from_fn(self.width, self.height, |x, y| {
+            let v = magma(self.flip[(y * self.width + x) as usize]);
+            image::Rgb(
+                v.to_array()
+                    .map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8),
+            )
+        })
+    }
+}
+
+/// Compare `test` to `reference`, with FLIP errors for the viewing conditions given by
+/// `pixels_per_degree`
+pub fn compare(
+    reference: &ComparisonImage,
+    test: &ComparisonImage,
+    pixels_per_degree: Float,
+) -> Result<Comparison, SizeMismatchError> {
+    if (reference.width, reference.height) != (test.width, test.height) {
+        return Err(SizeMismatchError {
+            reference: (reference.width, reference.height),
+            test: (test.width, test.height),
+        });
+    }
+
+    let squared_error: Float = reference
+        .pixels
+        .iter()
+        .zip(&test.pixels)
+        .map(|(r, t)| (*r - *t).length_squared())
+        .sum();
+    let rmse = (squared_error / (reference.pixels.len() * 3).max(1) as Float).sqrt();
+
+    let flip = flip(reference, test, pixels_per_degree);
+    let mean_flip = flip.iter().sum::<Float>() / flip.len().max(1) as Float;
+
+    Ok(Comparison {
+        width: reference.width,
+        height: reference.height,
+        rmse,
+        mean_flip,
+        flip,
+    })
+}
+
+/// A single channel image, read with its edges extended
+struct Channel {
+    width: usize,
+    height: usize,
+    values: Vec<Float>,
+}
+
+impl Channel {
+    fn get(&self, x: isize, y: isize) -> Float {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self.values[y * self.width + x]
+    }
+
+    /// Convolve with the separable kernel `kx` along rows and `ky` along columns. Both kernels
+    /// have odd lengths and are centred.
+    fn convolve(&self, kx: &[Float], ky: &[Float]) -> Channel {
+        let pass = |channel: &Channel, kernel: &[Float], horizontal: bool| {
+            let r = (kernel.len() / 2) as isize;
+            let mut values = Vec::with_capacity(channel.values.len());
+            for y in 0..channel.height as isize {
+                for x in 0..channel.width as isize {
+                    let mut sum = 0.0;
+                    for (i, k) in kernel.iter().enumerate() {
+                        let d = i as isize - r;
+                        sum += k * if horizontal {
+                            channel.get(x + d, y)
+                        } else {
+                            channel.get(x, y + d)
+                        };
+                    }
+                    values.push(sum);
+                }
+            }
+            Channel { values, ..*channel }
+        };
+
+        pass(&pass(self, kx, true), ky, false)
+    }
+}
+
+/// Per-pixel errors from LDR-FLIP, "FLIP: A Difference Evaluator for Alternating Images"
+/// (Andersson et al. 2020), which combines colour differences after filtering by the contrast
+/// sensitivity of the eye with differences in edges and points
+fn flip(reference: &ComparisonImage, test: &ComparisonImage, ppd: Float) -> Vec<Float> {
+    const QC: Float = 0.7;
+    const QF: Float = 0.5;
+    const PC: Float = 0.4;
+    const PT: Float = 0.95;
+
+    let white = SRGB_TO_XYZ * Vec3::ONE;
+    let to_ycxcz = |image: &ComparisonImage| {
+        let ycxcz: Vec<Vec3> = image
+            .pixels
+            .iter()
+            .map(|&p| xyz_to_ycxcz(SRGB_TO_XYZ * InputTransform::Srgb.to_linear(p), white))
+            .collect();
+        [0, 1, 2].map(|c| Channel {
+            width: image.width as usize,
+            height: image.height as usize,
+            values: ycxcz.iter().map(|v| v[c]).collect(),
+        })
+    };
+    let reference = to_ycxcz(reference);
+    let test = to_ycxcz(test);
+
+    // Colour differences, after removing detail the eye can't see at this distance
+    let hunt_lab = |ycxcz: &[Channel; 3]| -> Vec<Vec3> {
+        let filtered = csf_filter(ycxcz, ppd);
+        (0..filtered[0].values.len())
+            .map(|i| {
+                let v = Vec3::new(
+                    filtered[0].values[i],
+                    filtered[1].values[i],
+                    filtered[2].values[i],
+                );
+                let linear = SRGB_TO_XYZ.inverse() * ycxcz_to_xyz(v, white);
+                hunt(xyz_to_lab(
+                    SRGB_TO_XYZ * linear.clamp(Vec3::ZERO, Vec3::ONE),
+                    white,
+                ))
+            })
+            .collect()
+    };
+    let reference_lab = hunt_lab(&reference);
+    let test_lab = hunt_lab(&test);
+
+    let lab = |rgb: Vec3| hunt(xyz_to_lab(SRGB_TO_XYZ * rgb, white));
+    let cmax = hyab(lab(Vec3::Y), lab(Vec3::Z)).powf(QC);
+    let colour_error = reference_lab.iter().zip(&test_lab).map(|(r, t)| {
+        let e = hyab(*r, *t).powf(QC);
+        // Compress the large differences, which are all equally noticeable
+        if e < PC * cmax {
+            PT / (PC * cmax) * e
+        } else {
+            PT + (e - PC * cmax) / (cmax - PC * cmax) * (1.0 - PT)
+        }
+    });
+
+    // Feature differences, from the achromatic channel
+    let features = |ycxcz: &[Channel; 3]| {
+        let luminance = Channel {
+            values: ycxcz[0].values.iter().map(|y| (y + 16.0) / 116.0).collect(),
+            ..ycxcz[0]
+        };
+        feature_magnitudes(&luminance, ppd)
+    };
+    let (reference_edges, reference_points) = features(&reference);
+    let (test_edges, test_points) = features(&test);
+
+    colour_error
+        .enumerate()
+        .map(|(i, colour)| {
+            let edge = (reference_edges[i] - test_edges[i]).abs();
+            let point = (reference_points[i] - test_points[i]).abs();
+            let feature = (edge.max(point) / (2.0 as Float).sqrt()).powf(QF);
+            colour.powf(1.0 - feature)
+        })
+        .collect()
+}
+
+/// Filter each channel of an image in YCxCz by the contrast sensitivity function of the eye
+fn csf_filter(ycxcz: &[Channel; 3], ppd: Float) -> [Channel; 3] {
+    // Weights and widths of the one or two Gaussians making up each channel's filter
+    const CSF: [[(Float, Float); 2]; 3] = [
+        [(1.0, 0.0047), (0.0, 1e-5)],
+        [(1.0, 0.0053), (0.0, 1e-5)],
+        [(34.1, 0.04), (13.5, 0.025)],
+    ];
+
+    let max_b = CSF.iter().flatten().map(|(_, b)| *b).fold(0.0, Float::max);
+    let radius = (3.0 * (max_b / (2.0 * PI * PI)).sqrt() * ppd).ceil() as isize;
+
+    let mut filtered = [0, 1, 2].map(|c| Channel {
+        values: vec![0.0; ycxcz[c].values.len()],
+        ..ycxcz[c]
+    });
+    for c in 0..3 {
+        // Each Gaussian is separable, but their sum isn't, so they're filtered separately.
+        // The sum of the whole 2D filter is normalised to 1.
+        let kernels: Vec<Vec<Float>> = CSF[c]
+            .iter()
+            .map(|&(a, b)| {
+                (-radius..=radius)
+                    .map(|x| {
+                        let x = x as Float / ppd;
+                        // The square root of the 2D weight a·sqrt(π/b) on each axis, so the
+                        // product of the two passes has that weight
+                        (a * (PI / b).sqrt()).sqrt() * (-PI * PI * x * x / b).exp()
+                    })
+                    .collect()
+            })
+            .collect();
+        let total: Float = kernels
+            .iter()
+            .map(|k| k.iter().sum::<Float>().powi(2))
+            .sum();
+
+        for kernel in &kernels {
+            if kernel.iter().all(|k| *k == 0.0) {
+                continue;
+            }
+            let kernel: Vec<Float> = kernel.iter().map(|k| k / total.sqrt()).collect();
+            let pass = ycxcz[c].convolve(&kernel, &kernel);
+            for (f, v) in filtered[c].values.iter_mut().zip(pass.values) {
+                *f += v;
+            }
+        }
+    }
+
+    filtered
+}
+
+/// Magnitudes of the edge and point detector responses at each pixel
+fn feature_magnitudes(luminance: &Channel, ppd: Float) -> (Vec<Float>, Vec<Float>) {
+    const GW: Float = 0.082;
+
+    let sd = 0.5 * GW * ppd;
+    let radius = (3.0 * sd).ceil() as isize;
+    let offsets: Vec<Float> = (-radius..=radius).map(|x| x as Float).collect();
+    let gaussian: Vec<Float> = offsets
+        .iter()
+        .map(|x| (-x * x / (2.0 * sd * sd)).exp())
+        .collect();
+
+    // Positive and negative weights each sum to one, so the kernels don't respond to flat
+    // regions
+    let normalise = |kernel: Vec<Float>| -> Vec<Float> {
+        let positive: Float = kernel.iter().filter(|k| **k > 0.0).sum();
+        let negative: Float = -kernel.iter().filter(|k| **k < 0.0).sum::<Float>();
+        kernel
+            .into_iter()
+            .map(|k| if k > 0.0 { k / positive } else { k / negative })
+            .collect()
+    };
+    let smooth: Vec<Float> = {
+        let sum: Float = gaussian.iter().sum();
+        gaussian.iter().map(|g| g / sum).collect()
+    };
+    let edge = normalise(offsets.iter().zip(&gaussian).map(|(x, g)| -x * g).collect());
+    let point = normalise(
+        offsets
+            .iter()
+            .zip(&gaussian)
+            .map(|(x, g)| (x * x / (sd * sd) - 1.0) * g)
+            .collect(),
+    );
+
+    let magnitude = |kernel: &[Float]| -> Vec<Float> {
+        let dx = luminance.convolve(kernel, &smooth);
+        let dy = luminance.convolve(&smooth, kernel);
+        dx.values
+            .iter()
+            .zip(&dy.values)
+            .map(|(x, y)| (x * x + y * y).sqrt())
+            .collect()
+    };
+
+    (magnitude(&edge), magnitude(&point))
+}
+
+/// A linearised version of CIELAB, so filtering is done in a space where colours add linearly
+fn xyz_to_ycxcz(xyz: Vec3, white: Vec3) -> Vec3 {
+    let v = xyz / white;
+    Vec3::new(116.0 * v.y - 16.0, 500.0 * (v.x - v.y), 200.0 * (v.y - v.z))
+}
+
+fn ycxcz_to_xyz(ycxcz: Vec3, white: Vec3) -> Vec3 {
+    let y = (ycxcz.x + 16.0) / 116.0;
+    Vec3::new(ycxcz.y / 500.0 + y, y, y - ycxcz.z / 200.0) * white
+}
+
+fn xyz_to_lab(xyz: Vec3, white: Vec3) -> Vec3 {
+    let delta: Float = 6.0 / 29.0;
+    let f = |t: Float| {
+        if t > delta.powi(3) {
+            t.cbrt()
+        } else {
+            t / (3.0 * delta * delta) + 4.0 / 29.0
+        }
+    };
+    let v = xyz / white;
+    let (fx, fy, fz) = (f(v.x), f(v.y), f(v.z));
+    Vec3::new(116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
+
+/// Scale chroma by lightness, as colours are harder to tell apart when dark
+fn hunt(lab: Vec3) -> Vec3 {
+    Vec3::new(lab.x, 0.01 * lab.x * lab.y, 0.01 * lab.x * lab.z)
+}
+
+/// Colour difference suited to large differences, the lightness difference plus the
+/// Euclidean distance between the chroma
+fn hyab(a: Vec3, b: Vec3) -> Float {
+    let d = a - b;
+    d.x.abs() + (d.y * d.y + d.z * d.z).sqrt()
+}
+
+/// Polynomial fit of the magma colour map, mapping 0.0 to 1.0 from black through purple to
+/// pale yellow
+fn magma(t: Float) -> Vec3 {
+    const C: [Vec3; 7] = [
+        Vec3::new(
+            -0.002136485053939582,
+            -0.000749655052795221,
+            -0.005386127855323933,
+        ),
+        Vec3::new(0.2516605407371642, 0.6775232436837668, 2.494026599312351),
+        Vec3::new(8.353717279216625, -3.577719514958484, 0.3144679030132573),
+        Vec3::new(-27.66873308576866, 14.26473078096533, -13.64921318813922),
+        Vec3::new(52.17613981234068, -27.94360607168351, 12.94416944238394),
+        Vec3::new(-50.76852536473588, 29.04658282127291, 4.23415299384598),
+        Vec3::new(18.65570506591883, -11.48977351997711, -5.601961508734096),
+    ];
+
+    let t = t.clamp(0.0, 1.0);
+    C.iter().rev().fold(Vec3::ZERO, |acc, c| acc * t + *c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(width: u32, height: u32, f: impl Fn(u32, u32) -> [u8; 3]) -> ComparisonImage {
+        ComparisonImage::from_rgb_image(&RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb(f(x, y))
+        }))
+    }
+
+    #[test]
+    fn identical_images_match() {
+        let reference = image(16, 12, |x, y| [(x * 16) as u8, (y * 20) as u8, 128]);
+        let comparison = compare(&reference, &reference, DEFAULT_PIXELS_PER_DEGREE).unwrap();
+        assert_eq!(comparison.rmse, 0.0);
+        assert!(comparison.mean_flip < 1e-6);
+        assert!(comparison.flip.iter().all(|f| (0.0..=1.0).contains(f)));
+    }
+
+    #[test]
+    fn larger_differences_score_higher() {
+        let grey = image(16, 16, |_, _| [128; 3]);
+        let slightly_lighter = image(16, 16, |_, _| [136; 3]);
+        let white = image(16, 16, |_, _| [255; 3]);
+
+        let small = compare(&grey, &slightly_lighter, DEFAULT_PIXELS_PER_DEGREE).unwrap();
+        let large = compare(&grey, &white, DEFAULT_PIXELS_PER_DEGREE).unwrap();
+        assert!(small.mean_flip > 0.0);
+        assert!(small.mean_flip < large.mean_flip);
+        assert!(small.rmse < large.rmse);
+    }
+
+    #[test]
+    fn size_mismatch_is_an_error() {
+        let error = compare(
+            &image(4, 4, |_, _| [0; 3]),
+            &image(4, 5, |_, _| [0; 3]),
+            DEFAULT_PIXELS_PER_DEGREE,
+        )
+        .err()
+        .unwrap();
+        assert_eq!((error.reference, error.test), ((4, 4), (4, 5)));
+    }
+
+    #[test]
+    fn csf_filter_preserves_flat_channels() {
+        let flat = [0.7, -0.2, 0.3].map(|v| Channel {
+            width: 9,
+            height: 7,
+            values: vec![v; 63],
+        });
+        let filtered = csf_filter(&flat, DEFAULT_PIXELS_PER_DEGREE);
+        for (before, after) in flat.iter().zip(&filtered) {
+            for (b, a) in before.values.iter().zip(&after.values) {
+                assert!((a - b).abs() < 1e-6, "{} became {}", b, a);
+            }
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use rand::RngCore;
+
+use crate::{
+    bvh::Aabb,
+    collidable::{Collideable, Collision},
+    float::Float,
+    ray::Ray,
+    report::ObjectInfo,
+};
+
+/// Boolean operation used to combine the two children of a [`Csg`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsgOp {
+    /// Inside either child
+    Union,
+    /// Inside both children
+    Intersection,
+    /// Inside the first child but not the second
+    Difference,
+}
+
+impl CsgOp {
+    fn contains(&self, in_a: bool, in_b: bool) -> bool {
+        match self {
+            CsgOp::Union => in_a || in_b,
+            CsgOp::Intersection => in_a && in_b,
+            CsgOp::Difference => in_a && !in_b,
+        }
+    }
+}
+
+/// Two closed objects combined with a boolean operation, e.g. a lens made from the
+/// intersection of two spheres. Surfaces of `b` which bound a difference take the material of
+/// `b`, with their normals flipped to face out of the result.
+pub struct Csg<A, B> {
+    pub op: CsgOp,
+    pub a: A,
+    pub b: B,
+}
+
+impl<A, B> Csg<A, B> {
+    pub fn union(a: A, b: B) -> Self {
+        Self {
+            op: CsgOp::Union,
+            a,
+            b,
+        }
+    }
+
+    pub fn intersection(a: A, b: B) -> Self {
+        Self {
+            op: CsgOp::Intersection,
+            a,
+            b,
+        }
+    }
+
+    pub fn difference(a: A, b: B) -> Self {
+        Self {
+            op: CsgOp::Difference,
+            a,
+            b,
+        }
+    }
+}
+
+/// Every point where `ray` crosses the surface of `object`, nearest first, along with whether
+/// the ray starts inside it
+fn crossings<'a, C: Collideable + ?Sized>(
+    object: &'a C,
+    ray: &Ray,
+    rng: &mut dyn RngCore,
+) -> (bool, Vec<Collision<'a>>) {
+    const MAX_CROSSINGS: usize = 32;
+
+    // Whether the ray starts inside depends on the crossings beyond its end too
+    let mut remaining = Ray {
+        t_max: Float::INFINITY,
+        ..ray.clone()
+    };
+    let mut hits: Vec<Collision<'a>> = Vec::new();
+    while hits.len() < MAX_CROSSINGS {
+        let Some(mut c) = object.trace(&remaining, rng) else {
+            break;
+        };
+        c.ray = ray.clone();
+        remaining.t_min = c.t;
+        hits.push(c);
+    }
+
+    let starts_inside = hits
+        .first()
+        .is_some_and(|c| c.geometric_normal.dot(ray.dir) > 0.0);
+    (starts_inside, hits)
+}
+
+impl<A: Collideable, B: Collideable> Collideable for Csg<A, B> {
+    fn trace(&self, ray: &Ray, rng: &mut dyn RngCore) -> Option<Collision<'_>> {
+        let (mut in_a, a) = crossings(&self.a, ray, rng);
+        let (mut in_b, b) = crossings(&self.b, ray, rng);
+
+        let mut a = a.into_iter().peekable();
+        let mut b = b.into_iter().peekable();
+        let mut inside = self.op.contains(in_a, in_b);
+
+        // Walk both lists of crossings in order until the combined shape is entered or left
+        loop {
+            let from_a = match (a.peek(), b.peek()) {
+                (Some(ca), Some(cb)) => ca.t <= cb.t,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => return None,
+            };
+
+            let mut c = if from_a { a.next()? } else { b.next()? };
+            let entering = c.geometric_normal.dot(ray.dir) < 0.0;
+            if from_a {
+                in_a = entering;
+            } else {
+                in_b = entering;
+            }
+
+            let now_inside = self.op.contains(in_a, in_b);
+            if now_inside != inside {
+                if !from_a && self.op == CsgOp::Difference {
+                    c.normal = -c.normal;
+                    c.geometric_normal = -c.geometric_normal;
+                }
+                return (c.t <= ray.t_max).then_some(c);
+            }
+            inside = now_inside;
+        }
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        match self.op {
+            CsgOp::Union => Some(self.a.bounds()?.union(&self.b.bounds()?)),
+            CsgOp::Intersection => match (self.a.bounds(), self.b.bounds()) {
+                (Some(a), Some(b)) => Some(a.intersection(&b)),
+                (a, b) => a.or(b),
+            },
+            CsgOp::Difference => self.a.bounds(),
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of_val(self) - std::mem::size_of::<A>() - std::mem::size_of::<B>()
+            + self.a.memory_usage()
+            + self.b.memory_usage()
+    }
+
+    fn info(&self) -> ObjectInfo {
+        let a = self.a.info();
+        let b = self.b.info();
+        ObjectInfo {
+            triangles: a.triangles + b.triangles,
+            emissive: a.emissive || b.emissive,
+            materials: [a.materials, b.materials].concat(),
+            ..ObjectInfo::new("Csg")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    use super::*;
+    use crate::{collidable::Sphere, float::Vec3, material::Material, sdf::Sdf};
+
+    fn sphere(z: Float, radius: Float) -> Sphere {
+        Sphere {
+            origin: Vec3::new(0.0, 0.0, z),
+            radius,
+            material: Arc::new(Material::default()),
+        }
+    }
+
+    fn sdf_ball(z: Float, radius: Float) -> Sdf<impl Fn(Vec3) -> Float + Send + Sync> {
+        let centre = Vec3::new(0.0, 0.0, z);
+        Sdf::new(
+            move |p: Vec3| (p - centre).length() - radius,
+            centre,
+            radius * 1.1,
+            Arc::new(Material::default()),
+        )
+    }
+
+    /// Along +z from z = -5
+    fn ray() -> Ray {
+        Ray {
+            origin: Vec3::new(0.0, 0.0, -5.0),
+            dir: Vec3::Z,
+            time: 0.0,
+            wavelength: None,
+            t_min: 0.0,
+            t_max: Float::INFINITY,
+        }
+    }
+
+    fn trace<C: Collideable>(object: &C) -> Option<Float> {
+        object
+            .trace(&ray(), &mut SmallRng::seed_from_u64(0))
+            .map(|c| c.t)
+    }
+
+    #[test]
+    fn overlapping_spheres() {
+        let a = || sphere(0.0, 1.0);
+        let b = || sphere(1.5, 1.0);
+        let t = trace(&Csg::union(a(), b())).unwrap();
+        assert!((t - 4.0).abs() < 1e-6);
+        let t = trace(&Csg::intersection(a(), b())).unwrap();
+        assert!((t - 5.5).abs() < 1e-6);
+        let t = trace(&Csg::difference(b(), a())).unwrap();
+        assert!((t - 6.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn disjoint_intersection_is_empty() {
+        assert_eq!(
+            trace(&Csg::intersection(sphere(0.0, 1.0), sphere(3.0, 0.5))),
+            None
+        );
+        assert_eq!(
+            trace(&Csg::intersection(sdf_ball(0.0, 1.0), sphere(3.0, 0.5))),
+            None
+        );
+    }
+
+    #[test]
+    fn sdf_child_crossings() {
+        let ball = sdf_ball(0.0, 1.0);
+        let (inside, hits) = crossings(&ball, &ray(), &mut SmallRng::seed_from_u64(0));
+        assert!(!inside);
+        assert_eq!(hits.len(), 2);
+        assert!((hits[0].t - 4.0).abs() < 1e-3);
+        assert!((hits[1].t - 6.0).abs() < 1e-3);
+
+        let t = trace(&Csg::intersection(sdf_ball(0.0, 1.0), sphere(1.5, 1.0))).unwrap();
+        assert!((t - 5.5).abs() < 1e-3);
+        let t = trace(&Csg::difference(sdf_ball(0.0, 1.0), sphere(-1.5, 1.0))).unwrap();
+        assert!((t - 4.5).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,61 @@
+use glam::IVec2;
+
+use crate::{
+    film::{Aov, Film},
+    float::{Float, Vec3},
+};
+
+/// Denoise the beauty pass of `film` with Intel Open Image Denoise. The albedo and normal
+/// AOVs are used to guide the denoiser when the film records them, which preserves much more
+/// detail, so they should be enabled when denoising.
+///
+/// Returns a copy of the film with the denoised beauty pass.
+pub fn denoise(film: &Film) -> Result<Film, oidn::Error> {
+    let pixels: Vec<IVec2> = film.data_pixels().collect();
+    let flatten = |values: &mut dyn Iterator<Item = Vec3>| -> Vec<f32> {
+        values
+            .flat_map(|v| [v.x as f32, v.y as f32, v.z as f32])
+            .collect()
+    };
+
+    let colour = flatten(&mut pixels.iter().map(|&p| film.get(p)));
+    let albedo = film
+        .aovs()
+        .any(|a| a == Aov::Albedo)
+        .then(|| flatten(&mut pixels.iter().filter_map(|&p| film.get_aov(Aov::Albedo, p))));
+    let normal = film
+        .aovs()
+        .any(|a| a == Aov::Normal)
+        .then(|| flatten(&mut pixels.iter().filter_map(|&p| film.get_aov(Aov::Normal, p))));
+
+    let device = oidn::Device::new()?;
+    let mut filter = oidn::RayTracing::try_new(&device)?;
+    filter
+        .hdr(true)
+        .image_dimensions(film.data_size.x as usize, film.data_size.y as usize);
+
+    // OIDN can only use normals alongside albedo
+    match (&albedo, &normal) {
+        (Some(albedo), Some(normal)) => {
+            filter.albedo_normal(albedo, normal);
+        }
+        (Some(albedo), None) => {
+            filter.albedo(albedo);
+        }
+        _ => {}
+    }
+
+    let mut output = vec![0.0; colour.len()];
+    filter.filter(&colour, &mut output)?;
+    device.get_error()?;
+
+    let mut denoised = film.clone();
+    for (pixel, v) in pixels.iter().zip(output.chunks_exact(3)) {
+        denoised.set(
+            *pixel,
+            Vec3::new(v[0] as Float, v[1] as Float, v[2] as Float),
+        );
+    }
+
+    Ok(denoised)
+}
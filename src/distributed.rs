@@ -0,0 +1,485 @@
+//! Rendering across several machines. Workers are started with the same scene and settings
+//! as the coordinator and wait for connections. The coordinator splits the film into tiles,
+//! hands them out to every worker it can reach, renders tiles itself while it waits, and
+//! merges the returned tiles into the film.
+//!
+//! Each pixel's random numbers only depend on the seed and its position, so the result is the
+//! same as rendering on a single machine, apart from pixel values being sent at 32 bit
+//! precision.
+
+use std::{
+    collections::VecDeque,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use glam::{IVec2, UVec2};
+use indicatif::ProgressBar;
+use rand::{Rng, SeedableRng};
+
+use crate::{
+    camera::Camera,
+    film::{Aov, Film},
+    float::{Float, Vec3},
+    material::Material,
+    sampler::{hash, PixelSampler},
+    solver::{Integrator, Solver},
+    tiles::{generate_tiles, Tile},
+};
+
+/// Sent by the coordinator at the start of every connection
+const MAGIC: &[u8; 4] = b"RTRC";
+const PROTOCOL_VERSION: u32 = 1;
+/// How long to wait for a worker to respond, including rendering a tile, before giving up on
+/// it and putting its tile back on the queue
+const WORKER_TIMEOUT: Duration = Duration::from_secs(300);
+/// Pixels along each axis of the grid of camera rays traced for the job fingerprint
+const FINGERPRINT_GRID: i32 = 8;
+/// Sent in place of a tile to tell a worker the render is finished
+const NO_MORE_TILES: Tile = Tile {
+    origin: UVec2::ZERO,
+    size: UVec2::ZERO,
+};
+
+/// A tile rendered by a worker, with the beauty pass followed by each AOV of every pixel
+struct TileResult {
+    tile: Tile,
+    values: Vec<Vec3>,
+}
+
+/// Wait for coordinators on `addr` and render the tiles they send, one connection at a time.
+/// Only returns if listening fails.
+pub fn serve<C: Camera, R: Rng + SeedableRng, A: ToSocketAddrs>(
+    solver: &Solver<C, R>,
+    addr: A,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    println!("Waiting for work on {}...", listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let peer = stream.peer_addr()?;
+        println!("Rendering for {}...", peer);
+        match serve_connection(solver, stream) {
+            Ok(tiles) => println!("Rendered {} tiles for {}", tiles, peer),
+            Err(e) => eprintln!("Connection to {} failed: {}", peer, e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Render tiles for one coordinator until it has no more, returning the number rendered
+fn serve_connection<C: Camera, R: Rng + SeedableRng>(
+    solver: &Solver<C, R>,
+    stream: TcpStream,
+) -> io::Result<usize> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    let version = read_u32(&mut reader)?;
+    if &magic != MAGIC || version != PROTOCOL_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a compatible coordinator",
+        ));
+    }
+    let fingerprint = read_u64(&mut reader)?;
+    let seed = read_u64(&mut reader)?;
+
+    // Refuse work for a different scene, which would silently produce a broken image
+    let accepted = fingerprint == job_fingerprint(solver);
+    writer.write_all(&[u8::from(accepted)])?;
+    writer.flush()?;
+    if !accepted {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "coordinator has a different scene or settings",
+        ));
+    }
+
+    let frame = solver.start_frame(seed);
+    let mut film = solver.new_film();
+    let aovs: Vec<Aov> = film.aovs().collect();
+    let mut rendered = 0;
+    loop {
+        let tile = read_tile(&mut reader)?;
+        if tile == NO_MORE_TILES {
+            return Ok(rendered);
+        }
+
+        solver.render_tile(&mut film, &tile, seed, &frame);
+        write_tile(&mut writer, &tile)?;
+        for pixel in tile_pixels(&film, &tile) {
+            write_vec3(&mut writer, film.get(pixel))?;
+            for &aov in &aovs {
+                let value = film.get_aov(aov, pixel).expect("Film records its own AOVs");
+                write_vec3(&mut writer, value)?;
+            }
+        }
+        writer.flush()?;
+        rendered += 1;
+    }
+}
+
+/// Render the full film with the help of `workers`, given as `host:port` addresses. Tiles are
+/// also rendered locally, and any tiles a worker fails to return are rendered by another
+/// worker or locally, so the render completes even if no workers can be reached.
+pub fn coordinate<C: Camera, R: Rng + SeedableRng, A: AsRef<str>>(
+    solver: &Solver<C, R>,
+    seed: u64,
+    workers: &[A],
+) -> Film {
+    let frame = solver.start_frame(seed);
+    let mut film = solver.new_film();
+    let tiles = generate_tiles(film.data_size, solver.tile_size, solver.tile_order);
+    let total = tiles.len();
+    let aov_count = film.aovs().count();
+    let fingerprint = job_fingerprint(solver);
+
+    let queue = Arc::new(Mutex::new(VecDeque::from(tiles)));
+    let (sender, receiver) = mpsc::channel();
+    for worker in workers {
+        let worker = worker.as_ref().to_owned();
+        let queue = queue.clone();
+        let sender = sender.clone();
+        thread::spawn(move || {
+            let result = run_worker(&worker, fingerprint, seed, aov_count, &queue, &sender);
+            if let Err(e) = result {
+                eprintln!("Worker '{}' failed: {}", worker, e);
+            }
+        });
+    }
+    drop(sender);
+
+    let bar = ProgressBar::new(film.data_size.x as u64 * film.data_size.y as u64);
+    let mut completed = 0;
+    while completed < total {
+        // Merge everything the workers have returned before doing more work locally
+        while let Ok(result) = receiver.try_recv() {
+            merge_tile(&mut film, &result);
+            bar.inc(result.tile.pixel_count());
+            completed += 1;
+        }
+        if completed == total {
+            break;
+        }
+
+        let next = queue
+            .lock()
+            .expect("Worker threads don't panic")
+            .pop_front();
+        match next {
+            Some(tile) => {
+                solver.render_tile(&mut film, &tile, seed, &frame);
+                bar.inc(tile.pixel_count());
+                completed += 1;
+            }
+            // Everything left is being rendered by workers, which may still hand tiles back
+            None => {
+                if let Ok(result) = receiver.recv_timeout(Duration::from_millis(100)) {
+                    merge_tile(&mut film, &result);
+                    bar.inc(result.tile.pixel_count());
+                    completed += 1;
+                }
+            }
+        }
+    }
+    bar.finish();
+
+    if let Some(pass) = &solver.firefly_pass {
+        solver.remove_fireflies(&mut film, pass, seed, &frame);
+    }
+
+    film
+}
+
+/// Send tiles from `queue` to a worker until the queue is empty. A tile which the worker
+/// doesn't return is put back on the queue for someone else.
+fn run_worker(
+    addr: &str,
+    fingerprint: u64,
+    seed: u64,
+    aov_count: usize,
+    queue: &Mutex<VecDeque<Tile>>,
+    results: &mpsc::Sender<TileResult>,
+) -> io::Result<()> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_nodelay(true)?;
+    // A worker which stops responding would otherwise hold its tile forever
+    stream.set_read_timeout(Some(WORKER_TIMEOUT))?;
+    stream.set_write_timeout(Some(WORKER_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&PROTOCOL_VERSION.to_le_bytes())?;
+    writer.write_all(&fingerprint.to_le_bytes())?;
+    writer.write_all(&seed.to_le_bytes())?;
+    writer.flush()?;
+
+    let mut accepted = [0];
+    reader.read_exact(&mut accepted)?;
+    if accepted[0] == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "worker has a different scene or settings",
+        ));
+    }
+
+    loop {
+        let next = queue
+            .lock()
+            .expect("Worker threads don't panic")
+            .pop_front();
+        let Some(tile) = next else {
+            write_tile(&mut writer, &NO_MORE_TILES)?;
+            return writer.flush();
+        };
+
+        match render_remote(&mut reader, &mut writer, &tile, aov_count) {
+            Ok(result) => {
+                if results.send(result).is_err() {
+                    return Ok(());
+                }
+            }
+            Err(e) => {
+                queue
+                    .lock()
+                    .expect("Worker threads don't panic")
+                    .push_front(tile);
+                return Err(e);
+            }
+        }
+    }
+}
+
+fn render_remote<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    tile: &Tile,
+    aov_count: usize,
+) -> io::Result<TileResult> {
+    write_tile(writer, tile)?;
+    writer.flush()?;
+
+    let returned = read_tile(reader)?;
+    if returned != *tile {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "worker returned the wrong tile",
+        ));
+    }
+
+    let count = tile.pixel_count() as usize * (1 + aov_count);
+    let values = (0..count)
+        .map(|_| read_vec3(reader))
+        .collect::<io::Result<_>>()?;
+    Ok(TileResult {
+        tile: *tile,
+        values,
+    })
+}
+
+fn merge_tile(film: &mut Film, result: &TileResult) {
+    let aovs: Vec<Aov> = film.aovs().collect();
+    let pixels: Vec<IVec2> = tile_pixels(film, &result.tile).collect();
+    for (pixel, values) in pixels.into_iter().zip(result.values.chunks(1 + aovs.len())) {
+        film.set(pixel, values[0]);
+        for (&aov, &value) in aovs.iter().zip(&values[1..]) {
+            film.set_aov(aov, pixel, value);
+        }
+    }
+}
+
+/// Film pixels covered by `tile`, row by row
+fn tile_pixels(film: &Film, tile: &Tile) -> impl Iterator<Item = IVec2> {
+    let origin = film.data_origin + tile.origin.as_ivec2();
+    let size = tile.size.as_ivec2();
+    (0..size.y).flat_map(move |y| (0..size.x).map(move |x| origin + IVec2::new(x, y)))
+}
+
+/// Summary of the settings and scene which affect the rendered pixels, so workers can check
+/// they were started with the same ones as the coordinator. The camera and geometry are
+/// summarised by where a grid of camera rays hits the scene, and every material is included.
+fn job_fingerprint<C: Camera, R: Rng + SeedableRng>(solver: &Solver<C, R>) -> u64 {
+    // Camera rays are traced against the meshes the render will use
+    solver.select_levels_of_detail();
+    let film = solver.new_film();
+    let report = solver.scene.report();
+    let mut values = vec![
+        film.data_size.x as u64,
+        film.data_size.y as u64,
+        film.data_origin.x as u64,
+        film.data_origin.y as u64,
+        (solver.pixel_aspect * 1e6).round() as u64,
+        solver.samples,
+        solver.max_bounces,
+        solver.roulette_depth.map_or(u64::MAX, |d| d),
+        solver.spectral as u64,
+        match solver.integrator {
+            Integrator::PathTracing => 0,
+            Integrator::PhotonMapping { photons, radius } => {
+                hash(&[photons, (radius * 1e6).round() as u64])
+            }
+        },
+        report.object_count() as u64,
+        report.triangles as u64,
+        report.lights as u64,
+        report.nodes as u64,
+    ];
+    values.extend(film.aovs().map(|aov| aov as u64));
+    values.extend(camera_ray_values(solver));
+    for object in solver.scene.objects() {
+        for material in object.collidable.info().materials {
+            values.extend(material_values(&material));
+        }
+    }
+    hash(&values)
+}
+
+/// Fixed point value of `v`, so slightly different rounding between builds is ignored
+fn quantise(v: Float) -> u64 {
+    (v * 1e4).round() as i64 as u64
+}
+
+/// Origins and directions of camera rays through a grid of pixels across the image, and the
+/// distances to what they hit
+fn camera_ray_values<C: Camera, R: Rng + SeedableRng>(solver: &Solver<C, R>) -> Vec<u64> {
+    let format = solver.frame_format();
+    let time = (solver.shutter.0 + solver.shutter.1) / 2.0;
+    let mut rng = R::seed_from_u64(0);
+
+    let mut values = Vec::new();
+    for y in 0..FINGERPRINT_GRID {
+        for x in 0..FINGERPRINT_GRID {
+            let pixel =
+                (IVec2::new(x, y) * 2 + 1) * format.resolution.as_ivec2() / (FINGERPRINT_GRID * 2);
+            let mut sampler = PixelSampler::new(solver.sampler, pixel, 0, 0, 1, &mut rng);
+            let ray = solver
+                .camera
+                .outgoing_ray(format, pixel, time, &mut sampler);
+            let t = solver
+                .scene
+                .trace(&ray, &mut rng)
+                .map_or(Float::INFINITY, |c| c.t);
+            values.extend(
+                [ray.origin, ray.dir]
+                    .iter()
+                    .flat_map(|v| v.to_array())
+                    .chain([t])
+                    .map(quantise),
+            );
+        }
+    }
+    values
+}
+
+/// Parameters of `material` which affect how it's shaded
+fn material_values(material: &Material) -> Vec<u64> {
+    let mut values: Vec<u64> = [
+        material.diffusion,
+        material.fuzz,
+        material.transmission_diffusion,
+        material.refractive_index,
+        material.luminance,
+    ]
+    .into_iter()
+    .chain(material.colour.to_array())
+    .chain(material.absorption.to_array())
+    .map(quantise)
+    .collect();
+    values.extend([
+        material.dispersion.is_some() as u64,
+        material.phase.is_some() as u64,
+        material.subsurface.is_some() as u64,
+        material.shadow_catcher as u64,
+    ]);
+    values.extend(
+        material
+            .textures()
+            .flat_map(|texture| [texture.width as u64, texture.height as u64]),
+    );
+    values
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_tile<R: Read>(reader: &mut R) -> io::Result<Tile> {
+    Ok(Tile {
+        origin: UVec2::new(read_u32(reader)?, read_u32(reader)?),
+        size: UVec2::new(read_u32(reader)?, read_u32(reader)?),
+    })
+}
+
+fn write_tile<W: Write>(writer: &mut W, tile: &Tile) -> io::Result<()> {
+    for v in [tile.origin.x, tile.origin.y, tile.size.x, tile.size.y] {
+        writer.write_all(&v.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Pixel values are sent as 32 bit floats whatever the precision of the renderer
+fn read_vec3<R: Read>(reader: &mut R) -> io::Result<Vec3> {
+    let mut v = [0.0; 3];
+    for c in &mut v {
+        *c = f32::from_bits(read_u32(reader)?) as Float;
+    }
+    Ok(Vec3::from_array(v))
+}
+
+fn write_vec3<W: Write>(writer: &mut W, v: Vec3) -> io::Result<()> {
+    for c in v.to_array() {
+        writer.write_all(&(c as f32).to_le_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::SmallRng;
+
+    use super::*;
+    use crate::{camera::PerspectiveCamera, collidable::Sphere, float::Quat};
+
+    fn solver(camera_x: Float, colour: Vec3) -> Solver<PerspectiveCamera, SmallRng> {
+        let camera = PerspectiveCamera {
+            origin: Vec3::new(camera_x, 0.0, 0.0),
+            rotation: Quat::IDENTITY,
+            horizontal_fov: 60.0,
+        };
+        let mut solver = Solver::new(camera, UVec2::new(32, 24));
+        solver.scene.add_object(Sphere {
+            origin: Vec3::new(0.0, 0.0, 3.0),
+            radius: 1.0,
+            material: Arc::new(Material {
+                colour,
+                ..Default::default()
+            }),
+        });
+        solver
+    }
+
+    #[test]
+    fn fingerprint_covers_camera_and_materials() {
+        let fingerprint = job_fingerprint(&solver(0.0, Vec3::ONE));
+        assert_eq!(fingerprint, job_fingerprint(&solver(0.0, Vec3::ONE)));
+        assert_ne!(fingerprint, job_fingerprint(&solver(0.1, Vec3::ONE)));
+        assert_ne!(fingerprint, job_fingerprint(&solver(0.0, Vec3::X)));
+    }
+}
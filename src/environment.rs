@@ -0,0 +1,440 @@
+use std::path::Path;
+
+use crate::{
+    colour::InputTransform,
+    float::{consts::PI, Float, Vec2, Vec3},
+};
+
+fn luminance(v: Vec3) -> Float {
+    v.dot(Vec3::new(0.2126, 0.7152, 0.0722))
+}
+
+/// A distant disc light, such as the sun
+#[derive(Debug, Clone, Copy)]
+pub struct Sun {
+    /// Normalised direction towards the sun
+    pub direction: Vec3,
+    /// Angular radius of the disc in radians. The real sun is about 0.0047.
+    pub angular_radius: Float,
+    /// Radiance of every point on the disc
+    pub radiance: Vec3,
+}
+
+impl Sun {
+    fn cos_max(&self) -> Float {
+        self.angular_radius.cos()
+    }
+
+    fn solid_angle(&self) -> Float {
+        2.0 * PI * (1.0 - self.cos_max())
+    }
+
+    pub fn radiance(&self, dir: Vec3) -> Vec3 {
+        if dir.dot(self.direction) >= self.cos_max() {
+            self.radiance
+        } else {
+            Vec3::ZERO
+        }
+    }
+
+    /// Uniformly sample a direction within the disc
+    fn sample(&self, u: Vec2) -> Vec3 {
+        let cos_theta = 1.0 - u.x * (1.0 - self.cos_max());
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * u.y;
+
+        let (tangent, bitangent) = self.direction.any_orthonormal_pair();
+        tangent * phi.cos() * sin_theta
+            + bitangent * phi.sin() * sin_theta
+            + self.direction * cos_theta
+    }
+
+    fn pdf(&self, dir: Vec3) -> Float {
+        if dir.dot(self.direction) >= self.cos_max() {
+            1.0 / self.solid_angle()
+        } else {
+            0.0
+        }
+    }
+}
+
+/// An HDR image of the surroundings in latitude-longitude layout, with +y at the top row.
+/// Directions are importance sampled by luminance.
+pub struct EnvironmentMap {
+    pub width: usize,
+    pub height: usize,
+    pixels: Vec<Vec3>,
+    /// Multiplier applied to every pixel
+    pub intensity: Float,
+    /// Rotation around the y axis in radians
+    pub rotation: Float,
+    /// Cumulative distribution of each row, then of the rows themselves
+    conditional: Vec<Vec<Float>>,
+    marginal: Vec<Float>,
+    /// Luminance integrated over the sphere, without the intensity
+    power: Float,
+}
+
+impl EnvironmentMap {
+    pub fn new(width: usize, height: usize, pixels: Vec<Vec3>) -> Self {
+        assert_eq!(pixels.len(), width * height, "Wrong number of pixels");
+        let mut map = Self {
+            width,
+            height,
+            pixels,
+            intensity: 1.0,
+            rotation: 0.0,
+            conditional: Vec::new(),
+            marginal: Vec::new(),
+            power: 0.0,
+        };
+        map.build_distribution();
+        map
+    }
+
+    /// Load a map from an image, converting it to linear values with `transform`.
+    /// [`InputTransform::for_path`] gives the usual transform for the image's format.
+    pub fn open<P: AsRef<Path>>(path: P, transform: InputTransform) -> image::ImageResult<Self> {
+        let image = image::open(path)?.to_rgb32f();
+        let pixels = image
+            .pixels()
+            .map(|p| {
+                transform.to_linear(Vec3::new(p.0[0] as Float, p.0[1] as Float, p.0[2] as Float))
+            })
+            .collect();
+        Ok(Self::new(
+            image.width() as usize,
+            image.height() as usize,
+            pixels,
+        ))
+    }
+
+    pub fn with_intensity(mut self, intensity: Float) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    pub fn with_rotation(mut self, rotation: Float) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    /// Polar angle of the centre of `row` from +y
+    fn theta(&self, row: usize) -> Float {
+        (row as Float + 0.5) / self.height as Float * PI
+    }
+
+    /// Solid angle covered by a pixel in `row`
+    fn pixel_solid_angle(&self, row: usize) -> Float {
+        2.0 * PI * PI / (self.width * self.height) as Float * self.theta(row).sin()
+    }
+
+    fn direction(&self, uv: Vec2) -> Vec3 {
+        let theta = uv.y * PI;
+        let phi = uv.x * 2.0 * PI + self.rotation;
+        Vec3::new(
+            theta.sin() * phi.cos(),
+            theta.cos(),
+            theta.sin() * phi.sin(),
+        )
+    }
+
+    fn pixel(&self, dir: Vec3) -> (usize, usize) {
+        let theta = dir.y.clamp(-1.0, 1.0).acos();
+        let phi = dir.z.atan2(dir.x) - self.rotation;
+        let u = (phi / (2.0 * PI)).rem_euclid(1.0);
+        let v = theta / PI;
+        (
+            ((u * self.width as Float) as usize).min(self.width - 1),
+            ((v * self.height as Float) as usize).min(self.height - 1),
+        )
+    }
+
+    /// Radiance arriving from `dir`, which must be normalised
+    pub fn radiance(&self, dir: Vec3) -> Vec3 {
+        let (x, y) = self.pixel(dir);
+        self.pixels[y * self.width + x] * self.intensity
+    }
+
+    /// Approximate total power, used to choose between the map and a sun
+    fn power(&self) -> Float {
+        self.power * self.intensity
+    }
+
+    fn build_distribution(&mut self) {
+        let mut row_sums = Vec::with_capacity(self.height);
+        self.conditional = (0..self.height)
+            .map(|y| {
+                let sin_theta = self.theta(y).sin();
+                let mut total = 0.0;
+                let cdf: Vec<Float> = self.pixels[y * self.width..(y + 1) * self.width]
+                    .iter()
+                    .map(|&p| {
+                        total += luminance(p).max(0.0) * sin_theta;
+                        total
+                    })
+                    .collect();
+                row_sums.push(total);
+                cdf
+            })
+            .collect();
+
+        self.power = (0..self.height)
+            .map(|y| {
+                self.pixels[y * self.width..(y + 1) * self.width]
+                    .iter()
+                    .map(|&p| luminance(p))
+                    .sum::<Float>()
+                    * self.pixel_solid_angle(y)
+            })
+            .sum();
+
+        let mut total = 0.0;
+        self.marginal = row_sums
+            .iter()
+            .map(|s| {
+                total += s;
+                total
+            })
+            .collect();
+    }
+
+    /// Probability of choosing the pixel at (`x`, `y`)
+    fn pixel_probability(&self, x: usize, y: usize) -> Float {
+        let total = self.marginal.last().copied().unwrap_or(0.0);
+        if total <= 0.0 {
+            return 1.0 / (self.width * self.height) as Float;
+        }
+        let row = &self.conditional[y];
+        let previous = if x == 0 { 0.0 } else { row[x - 1] };
+        (row[x] - previous) / total
+    }
+
+    fn sample(&self, u: Vec2) -> Vec3 {
+        // Choose an entry of a cumulative distribution, also returning how far through the
+        // entry `u` landed so it can be reused to place the sample within the pixel
+        let pick = |cdf: &[Float], u: Float| {
+            let target = u * cdf.last().copied().unwrap_or(0.0);
+            let i = cdf.partition_point(|&c| c <= target).min(cdf.len() - 1);
+            let start = if i == 0 { 0.0 } else { cdf[i - 1] };
+            let fraction = if cdf[i] > start {
+                ((target - start) / (cdf[i] - start)).clamp(0.0, 1.0)
+            } else {
+                0.5
+            };
+            (i, fraction)
+        };
+        let total = self.marginal.last().copied().unwrap_or(0.0);
+        if total <= 0.0 {
+            // Nothing to importance sample, so fall back to uniform over the image
+            return self.direction(u);
+        }
+
+        let (y, fy) = pick(&self.marginal, u.x);
+        let (x, fx) = pick(&self.conditional[y], u.y);
+        self.direction(Vec2::new(
+            (x as Float + fx) / self.width as Float,
+            (y as Float + fy) / self.height as Float,
+        ))
+    }
+
+    fn pdf(&self, dir: Vec3) -> Float {
+        let (x, y) = self.pixel(dir);
+        let sin_theta = self.theta(y).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        self.pixel_probability(x, y) * (self.width * self.height) as Float
+            / (2.0 * PI * PI * sin_theta)
+    }
+
+    /// Remove the sun from the map and return it as a separate light with the same direction,
+    /// size and power, so it can be sampled precisely and adjusted independently. Pixels within
+    /// 10 degrees of the brightest pixel which are brighter than `threshold` are treated as
+    /// part of the sun, and are replaced with the average of the rest of that region.
+    pub fn extract_sun(&mut self, threshold: Float) -> Option<Sun> {
+        let brightest = (0..self.pixels.len())
+            .max_by(|&a, &b| luminance(self.pixels[a]).total_cmp(&luminance(self.pixels[b])))?;
+        if luminance(self.pixels[brightest]) <= threshold {
+            return None;
+        }
+
+        let centre_uv = |i: usize| {
+            Vec2::new(
+                ((i % self.width) as Float + 0.5) / self.width as Float,
+                ((i / self.width) as Float + 0.5) / self.height as Float,
+            )
+        };
+        let centre = self.direction(centre_uv(brightest));
+        let cos_region = (10.0 as Float).to_radians().cos();
+
+        let mut sun_pixels = Vec::new();
+        let mut sky = (Vec3::ZERO, 0.0);
+        let mut power = Vec3::ZERO;
+        let mut solid_angle = 0.0;
+        let mut direction = Vec3::ZERO;
+        for i in 0..self.pixels.len() {
+            let dir = self.direction(centre_uv(i));
+            if dir.dot(centre) < cos_region {
+                continue;
+            }
+            let pixel_solid_angle = self.pixel_solid_angle(i / self.width);
+            if luminance(self.pixels[i]) > threshold {
+                power += self.pixels[i] * pixel_solid_angle;
+                solid_angle += pixel_solid_angle;
+                direction += dir * luminance(self.pixels[i]) * pixel_solid_angle;
+                sun_pixels.push(i);
+            } else {
+                sky.0 += self.pixels[i] * pixel_solid_angle;
+                sky.1 += pixel_solid_angle;
+            }
+        }
+
+        let fill = if sky.1 > 0.0 {
+            sky.0 / sky.1
+        } else {
+            Vec3::ZERO
+        };
+        for &i in &sun_pixels {
+            self.pixels[i] = fill;
+        }
+        self.build_distribution();
+
+        Some(Sun {
+            direction: direction.normalize(),
+            angular_radius: (1.0 - solid_angle / (2.0 * PI)).acos(),
+            radiance: (power / solid_angle - fill).max(Vec3::ZERO) * self.intensity,
+        })
+    }
+
+    /// Bytes used by the pixels and their sampling distribution
+    pub fn memory_usage(&self) -> usize {
+        self.pixels.capacity() * std::mem::size_of::<Vec3>()
+            + self.conditional.capacity() * std::mem::size_of::<Vec<Float>>()
+            + self
+                .conditional
+                .iter()
+                .map(|row| row.capacity() * std::mem::size_of::<Float>())
+                .sum::<usize>()
+            + self.marginal.capacity() * std::mem::size_of::<Float>()
+    }
+}
+
+/// Distant lighting surrounding the scene, made of an environment map, a sun, or both. Both
+/// are sampled directly at diffuse surfaces and combined with multiple importance sampling.
+#[derive(Default)]
+pub struct Environment {
+    pub map: Option<EnvironmentMap>,
+    pub sun: Option<Sun>,
+}
+
+impl Environment {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_map(mut self, map: EnvironmentMap) -> Self {
+        self.map = Some(map);
+        self
+    }
+
+    pub fn with_sun(mut self, sun: Sun) -> Self {
+        self.sun = Some(sun);
+        self
+    }
+
+    /// Bytes used by the environment map, if any
+    pub fn memory_usage(&self) -> usize {
+        std::mem::size_of_val(self) + self.map.as_ref().map_or(0, EnvironmentMap::memory_usage)
+    }
+
+    /// Radiance arriving from `dir`, which must be normalised
+    pub fn radiance(&self, dir: Vec3) -> Vec3 {
+        self.map
+            .as_ref()
+            .map(|m| m.radiance(dir))
+            .unwrap_or(Vec3::ZERO)
+            + self.sun.map(|s| s.radiance(dir)).unwrap_or(Vec3::ZERO)
+    }
+
+    /// Chance of sampling the sun rather than the map, based on their power but never so low
+    /// that either is starved of samples
+    fn sun_probability(&self) -> Float {
+        match (&self.map, &self.sun) {
+            (Some(map), Some(sun)) => {
+                let sun_power = luminance(sun.radiance) * sun.solid_angle();
+                let total = sun_power + map.power();
+                if total > 0.0 {
+                    (sun_power / total).clamp(0.1, 0.9)
+                } else {
+                    0.5
+                }
+            }
+            (None, Some(_)) => 1.0,
+            _ => 0.0,
+        }
+    }
+
+    /// Sample a direction towards the environment, returning it with its probability density
+    /// per unit solid angle
+    pub fn sample(&self, u_light: Float, u: Vec2) -> Option<(Vec3, Float)> {
+        let dir = if u_light < self.sun_probability() {
+            self.sun?.sample(u)
+        } else {
+            self.map.as_ref()?.sample(u)
+        };
+
+        let pdf = self.pdf(dir);
+        (pdf > 0.0).then_some((dir, pdf))
+    }
+
+    /// Probability density of `sample` choosing `dir`
+    pub fn pdf(&self, dir: Vec3) -> Float {
+        let p_sun = self.sun_probability();
+        self.sun.map(|s| s.pdf(dir) * p_sun).unwrap_or(0.0)
+            + self
+                .map
+                .as_ref()
+                .map(|m| m.pdf(dir) * (1.0 - p_sun))
+                .unwrap_or(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_usage_includes_distribution() {
+        let map = EnvironmentMap::new(8, 4, vec![Vec3::ONE; 32]);
+        let pixels = 32 * std::mem::size_of::<Vec3>();
+        // At least one cumulative value per pixel and per row
+        let distribution = (32 + 4) * std::mem::size_of::<Float>();
+        assert!(map.memory_usage() >= pixels + distribution);
+
+        let environment = Environment::new().with_map(map);
+        assert!(environment.memory_usage() > pixels + distribution);
+        assert!(Environment::new().memory_usage() < environment.memory_usage());
+    }
+
+    #[test]
+    fn open_decodes_with_the_input_transform() {
+        assert_eq!(InputTransform::for_path("sky.exr"), InputTransform::Linear);
+        assert_eq!(InputTransform::for_path("sky.HDR"), InputTransform::Linear);
+        assert_eq!(InputTransform::for_path("sky.png"), InputTransform::Srgb);
+
+        let path = std::env::temp_dir().join("raytrace_rs_environment_test.png");
+        image::RgbImage::from_pixel(4, 2, image::Rgb([128, 128, 128]))
+            .save(&path)
+            .unwrap();
+        let srgb = EnvironmentMap::open(&path, InputTransform::for_path(&path)).unwrap();
+        let linear = EnvironmentMap::open(&path, InputTransform::Linear).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let encoded = 128.0 / 255.0;
+        let decoded = InputTransform::Srgb.to_linear(Vec3::splat(encoded));
+        assert!((srgb.radiance(Vec3::Y).x - decoded.x).abs() < 1e-4);
+        assert!(decoded.x < 0.25);
+        assert!((linear.radiance(Vec3::Y).x - encoded).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,285 @@
+use std::path::Path;
+
+use exr::prelude::{
+    ChannelDescription, Encoding, GetPixel, Image, ImageAttributes, IntegerBounds, Layer,
+    LayerAttributes, RgbaChannels, SampleType, SpecificChannels, Vec2, WritableImage,
+};
+use glam::{IVec2, UVec2};
+use image::{RgbImage, RgbaImage};
+
+use crate::{
+    colour::OutputTransform,
+    float::{Float, Vec3},
+};
+
+/// Auxiliary output, recorded at the first surface hit by each camera ray
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aov {
+    /// Distance from the camera, infinite where nothing was hit
+    Depth,
+    /// World space surface normal
+    Normal,
+    /// Surface colour, independent of lighting
+    Albedo,
+    /// Opacity of the pixel, 0.0 where only the background is seen. Stored in every channel.
+    /// The beauty pass is premultiplied by it, as when rendering with a transparent background.
+    Alpha,
+}
+
+impl Aov {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Depth => "depth",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::Alpha => "alpha",
+        }
+    }
+}
+
+/// Linear HDR radiance accumulated by a render, along with any AOVs.
+///
+/// Pixel coordinates are in image space (y pointing down) relative to the top left of the
+/// display window, which is the frame that ends up in the final image. The data window is the
+/// region that was actually rendered, and can extend beyond the display window when rendering
+/// with overscan.
+#[derive(Clone)]
+pub struct Film {
+    pub display_size: UVec2,
+    pub data_origin: IVec2,
+    pub data_size: UVec2,
+    /// Width of each pixel relative to its height when displayed. Images are written with one
+    /// sample per pixel regardless, so players have to stretch non-square pixels themselves.
+    pub pixel_aspect: Float,
+    pixels: Vec<Vec3>,
+    aovs: Vec<(Aov, Vec<Vec3>)>,
+}
+
+impl Film {
+    /// Create a black film covering the display window plus `overscan` pixels on each side
+    pub fn new(display_size: UVec2, overscan: UVec2, aovs: &[Aov]) -> Self {
+        let data_size = display_size + overscan * 2;
+        let len = data_size.x as usize * data_size.y as usize;
+        Self {
+            display_size,
+            data_origin: -overscan.as_ivec2(),
+            data_size,
+            pixel_aspect: 1.0,
+            pixels: vec![Vec3::ZERO; len],
+            aovs: aovs
+                .iter()
+                .map(|&aov| (aov, vec![Vec3::ZERO; len]))
+                .collect(),
+        }
+    }
+
+    /// Number of bytes needed for a film with a data window of `data_size` and `aovs` AOVs
+    pub fn memory_required(data_size: UVec2, aovs: usize) -> usize {
+        data_size.x as usize * data_size.y as usize * std::mem::size_of::<Vec3>() * (1 + aovs)
+    }
+
+    /// The AOVs recorded by this film
+    pub fn aovs(&self) -> impl Iterator<Item = Aov> + '_ {
+        self.aovs.iter().map(|(aov, _)| *aov)
+    }
+
+    /// Whether `pixel` lies within the data window
+    pub fn contains(&self, pixel: IVec2) -> bool {
+        let p = pixel - self.data_origin;
+        p.x >= 0 && p.y >= 0 && (p.x as u32) < self.data_size.x && (p.y as u32) < self.data_size.y
+    }
+
+    /// Every pixel in the data window, row by row
+    pub fn data_pixels(&self) -> impl Iterator<Item = IVec2> + '_ {
+        (0..self.data_size.y as i32).flat_map(move |y| {
+            (0..self.data_size.x as i32).map(move |x| self.data_origin + IVec2::new(x, y))
+        })
+    }
+
+    pub fn get(&self, pixel: IVec2) -> Vec3 {
+        self.pixels[self.index(pixel)]
+    }
+
+    pub fn set(&mut self, pixel: IVec2, value: Vec3) {
+        let i = self.index(pixel);
+        self.pixels[i] = value;
+    }
+
+    /// Get the value of an AOV at `pixel`, if the film records it. Depth is stored in every
+    /// channel.
+    pub fn get_aov(&self, aov: Aov, pixel: IVec2) -> Option<Vec3> {
+        let i = self.index(pixel);
+        self.aovs
+            .iter()
+            .find(|(a, _)| *a == aov)
+            .map(|(_, values)| values[i])
+    }
+
+    /// Set the value of an AOV at `pixel`, doing nothing if the film doesn't record it
+    pub fn set_aov(&mut self, aov: Aov, pixel: IVec2, value: Vec3) {
+        let i = self.index(pixel);
+        if let Some((_, values)) = self.aovs.iter_mut().find(|(a, _)| *a == aov) {
+            values[i] = value;
+        }
+    }
+
+    fn index(&self, pixel: IVec2) -> usize {
+        assert!(self.contains(pixel), "Pixel {pixel} is outside the film");
+        let p = (pixel - self.data_origin).as_uvec2();
+        p.y as usize * self.data_size.x as usize + p.x as usize
+    }
+
+    /// Convert the display window to an 8 bit image
+    pub fn to_rgb_image(&self, transform: OutputTransform) -> RgbImage {
+        RgbImage::from_fn(self.display_size.x, self.display_size.y, |x, y| {
+            let v = transform.apply(self.get(IVec2::new(x as i32, y as i32)));
+            image::Rgb([
+                (v.x.clamp(0.0, 1.0) * 255.0) as u8,
+                (v.y.clamp(0.0, 1.0) * 255.0) as u8,
+                (v.z.clamp(0.0, 1.0) * 255.0) as u8,
+            ])
+        })
+    }
+
+    /// Convert the display window to an 8 bit image with the alpha AOV as its alpha channel,
+    /// or fully opaque if the film doesn't record it. Colours are unpremultiplied, as PNG
+    /// expects.
+    pub fn to_rgba_image(&self, transform: OutputTransform) -> RgbaImage {
+        RgbaImage::from_fn(self.display_size.x, self.display_size.y, |x, y| {
+            let pixel = IVec2::new(x as i32, y as i32);
+            let alpha = self.get_aov(Aov::Alpha, pixel).map_or(1.0, |a| a.x);
+            let colour = if alpha > 0.0 {
+                self.get(pixel) / alpha
+            } else {
+                Vec3::ZERO
+            };
+            let v = transform.apply(colour);
+            image::Rgba([
+                (v.x.clamp(0.0, 1.0) * 255.0) as u8,
+                (v.y.clamp(0.0, 1.0) * 255.0) as u8,
+                (v.z.clamp(0.0, 1.0) * 255.0) as u8,
+                (alpha.clamp(0.0, 1.0) * 255.0) as u8,
+            ])
+        })
+    }
+
+    /// Convert the display window of an AOV to an 8 bit image for previewing. Depth is
+    /// normalised to the furthest visible point and normals are mapped from [-1, 1] to [0, 1].
+    pub fn aov_image(&self, aov: Aov, transform: OutputTransform) -> Option<RgbImage> {
+        let (_, values) = self.aovs.iter().find(|(a, _)| *a == aov)?;
+
+        let max_depth = values
+            .iter()
+            .map(|v| v.x)
+            .filter(|d| d.is_finite())
+            .fold(0.0, Float::max);
+
+        Some(RgbImage::from_fn(
+            self.display_size.x,
+            self.display_size.y,
+            |x, y| {
+                let v = values[self.index(IVec2::new(x as i32, y as i32))];
+                let v = match aov {
+                    Aov::Depth if v.x.is_finite() => v / max_depth.max(Float::EPSILON),
+                    Aov::Depth => Vec3::ONE,
+                    Aov::Normal => v * 0.5 + 0.5,
+                    Aov::Albedo => transform.apply(v),
+                    Aov::Alpha => v,
+                };
+                image::Rgb([
+                    (v.x.clamp(0.0, 1.0) * 255.0) as u8,
+                    (v.y.clamp(0.0, 1.0) * 255.0) as u8,
+                    (v.z.clamp(0.0, 1.0) * 255.0) as u8,
+                ])
+            },
+        ))
+    }
+
+    /// Write the full data window to an OpenEXR file, with the display window set to the
+    /// output frame so any overscan is preserved. Each AOV is written as an extra layer,
+    /// except alpha which is written as the alpha channel of the beauty layer. Values stay
+    /// scene-linear, so only the primaries of `transform` are applied and not its display
+    /// encoding.
+    pub fn write_exr<P: AsRef<Path>>(
+        &self,
+        path: P,
+        transform: OutputTransform,
+    ) -> exr::error::Result<()> {
+        let transform = transform.scene_linear();
+        let alpha = self
+            .aovs
+            .iter()
+            .find(|(aov, _)| *aov == Aov::Alpha)
+            .map(|(_, values)| values.as_slice());
+        let mut layers = vec![self.exr_layer("beauty", &self.pixels, alpha, transform)];
+        for (aov, values) in &self.aovs {
+            // Only colour data goes through the output transform
+            let transform = match aov {
+                Aov::Albedo => transform,
+                Aov::Depth | Aov::Normal => OutputTransform::Linear,
+                Aov::Alpha => continue,
+            };
+            layers.push(self.exr_layer(aov.name(), values, None, transform));
+        }
+
+        let display_window = IntegerBounds::new(
+            (0, 0),
+            (self.display_size.x as usize, self.display_size.y as usize),
+        );
+        let mut attributes = ImageAttributes::new(display_window);
+        attributes.pixel_aspect = self.pixel_aspect as f32;
+        let image = Image::from_layers(attributes, layers);
+
+        image.write().to_file(path)
+    }
+
+    fn exr_layer<'a>(
+        &self,
+        name: &str,
+        values: &'a [Vec3],
+        alpha: Option<&'a [Vec3]>,
+        transform: OutputTransform,
+    ) -> Layer<SpecificChannels<ExrPixels<'a>, RgbaChannels>> {
+        let channel = |name| ChannelDescription::named(name, SampleType::F32);
+        Layer::new(
+            (self.data_size.x as usize, self.data_size.y as usize),
+            LayerAttributes::named(name)
+                .with_position(Vec2(self.data_origin.x, self.data_origin.y)),
+            Encoding::FAST_LOSSLESS,
+            SpecificChannels::new(
+                (
+                    channel("R"),
+                    channel("G"),
+                    channel("B"),
+                    alpha.map(|_| channel("A")),
+                ),
+                ExrPixels {
+                    values,
+                    alpha,
+                    width: self.data_size.x as usize,
+                    transform,
+                },
+            ),
+        )
+    }
+}
+
+/// Source of pixel data for one layer of an EXR file. Alpha is only written if the layer has
+/// an alpha channel.
+struct ExrPixels<'a> {
+    values: &'a [Vec3],
+    alpha: Option<&'a [Vec3]>,
+    width: usize,
+    transform: OutputTransform,
+}
+
+impl<'a> GetPixel for ExrPixels<'a> {
+    type Pixel = (f32, f32, f32, f32);
+
+    fn get_pixel(&self, pos: Vec2<usize>) -> Self::Pixel {
+        let i = pos.y() * self.width + pos.x();
+        let v = self.transform.apply(self.values[i]);
+        let alpha = self.alpha.map_or(1.0, |a| a[i].x);
+        (v.x as f32, v.y as f32, v.z as f32, alpha as f32)
+    }
+}
@@ -0,0 +1,23 @@
+//! Precision of the maths used for rendering. Everything is f64 by default, or f32 with the
+//! `f32` feature, which is faster but less accurate in large scenes and deep bounces.
+
+#[cfg(not(feature = "f32"))]
+mod precision {
+    pub use glam::{
+        DAffine3 as Affine3, DMat3 as Mat3, DQuat as Quat, DVec2 as Vec2, DVec3 as Vec3,
+        DVec4 as Vec4,
+    };
+    pub use std::f64::consts;
+
+    pub type Float = f64;
+}
+
+#[cfg(feature = "f32")]
+mod precision {
+    pub use glam::{Affine3A as Affine3, Mat3, Quat, Vec2, Vec3, Vec4};
+    pub use std::f32::consts;
+
+    pub type Float = f32;
+}
+
+pub use precision::*;
@@ -0,0 +1,434 @@
+use std::{borrow::Cow, collections::HashMap, fmt::Display, path::Path, sync::Arc};
+
+use gltf::{buffer::Source, camera::Projection, image::Source as ImageSource, mesh::Mode, Gltf};
+
+use crate::{
+    camera::{OrthCamera, PerspectiveCamera},
+    colour::InputTransform,
+    float::{Affine3, Float, Quat, Vec2, Vec3, Vec4},
+    material::Material,
+    mesh::Mesh,
+    scene::{NodeId, Scene},
+    texture::Texture,
+};
+
+/// A camera found in a glTF file, positioned in world space
+pub enum ImportedCamera {
+    Perspective(PerspectiveCamera),
+    Orthographic(OrthCamera),
+}
+
+/// Everything imported from a glTF file which doesn't live in the [`Scene`]
+#[derive(Default)]
+pub struct ImportedGltf {
+    pub cameras: Vec<ImportedCamera>,
+    /// The scene node created for each glTF node, by glTF node index
+    pub nodes: HashMap<usize, NodeId>,
+}
+
+#[derive(Debug)]
+pub enum GltfError {
+    Gltf(gltf::Error),
+    Io(std::io::Error),
+    /// Buffers embedded as data URIs aren't supported, so the file should be exported as
+    /// .glb or .gltf with a separate .bin
+    UnsupportedUri(String),
+    MissingBinaryChunk,
+}
+
+impl Display for GltfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GltfError::Gltf(e) => write!(f, "Invalid glTF: {}", e),
+            GltfError::Io(e) => write!(f, "Failed to read buffer: {}", e),
+            GltfError::UnsupportedUri(uri) => write!(f, "Unsupported buffer URI '{}'", uri),
+            GltfError::MissingBinaryChunk => write!(f, "GLB file has no binary chunk"),
+        }
+    }
+}
+
+impl std::error::Error for GltfError {}
+
+impl From<gltf::Error> for GltfError {
+    fn from(e: gltf::Error) -> Self {
+        GltfError::Gltf(e)
+    }
+}
+
+impl From<std::io::Error> for GltfError {
+    fn from(e: std::io::Error) -> Self {
+        GltfError::Io(e)
+    }
+}
+
+/// Mirrors the z axis. glTF is right handed, while cameras here look along +z with +x to the
+/// right, so everything is mirrored on import to keep images the right way round.
+fn mirror(v: Vec3) -> Vec3 {
+    Vec3::new(v.x, v.y, -v.z)
+}
+
+/// Load the default scene of a .gltf or .glb file into `scene`. Each glTF node becomes a node
+/// of the scene hierarchy, with its meshes attached to it. Only triangle primitives are
+/// imported, and materials are approximated from their base colour, metallic and roughness
+/// factors with their base colour and normal textures. A warning is printed for each texture
+/// which can't be used.
+pub fn import_gltf<P: AsRef<Path>>(path: P, scene: &mut Scene) -> Result<ImportedGltf, GltfError> {
+    let path = path.as_ref();
+    let gltf = Gltf::open(path)?;
+    let buffers = load_buffers(&gltf, path)?;
+
+    let mut textures = TextureLoader {
+        buffers: &buffers,
+        dir: path.parent().unwrap_or(Path::new(".")),
+        decoded: HashMap::new(),
+    };
+    let materials: Vec<Arc<Material>> = gltf
+        .materials()
+        .map(|material| convert_material(material, &mut textures))
+        .collect();
+    let default_material = Arc::new(Material {
+        colour: Vec3::splat(0.8),
+        diffusion: 1.0,
+        ..Default::default()
+    });
+
+    let mut imported = ImportedGltf::default();
+    let Some(gltf_scene) = gltf.default_scene().or_else(|| gltf.scenes().next()) else {
+        return Ok(imported);
+    };
+
+    // Walk the hierarchy depth first, so parents are always added before their children
+    let mut stack: Vec<(gltf::Node<'_>, Option<NodeId>)> =
+        gltf_scene.nodes().map(|n| (n, None)).collect();
+    while let Some((node, parent)) = stack.pop() {
+        // Mirroring a rotation reverses its angle and mirrors its axis
+        let (translation, [x, y, z, w], scale) = node.transform().decomposed();
+        let local = Affine3::from_scale_rotation_translation(
+            Vec3::from_array(scale.map(|c| c as Float)),
+            Quat::from_xyzw(-x as Float, -y as Float, z as Float, w as Float),
+            mirror(Vec3::from_array(translation.map(|c| c as Float))),
+        );
+        let id = scene.add_node(parent, local);
+        imported.nodes.insert(node.index(), id);
+
+        if let Some(mesh) = node.mesh() {
+            for primitive in mesh.primitives() {
+                if primitive.mode() != Mode::Triangles {
+                    continue;
+                }
+                let material = match primitive.material().index() {
+                    Some(i) => materials[i].clone(),
+                    None => default_material.clone(),
+                };
+                if let Some(mesh) = convert_primitive(&primitive, &buffers, material) {
+                    scene.add_object_to(id, mesh);
+                }
+            }
+        }
+
+        if let Some(camera) = node.camera() {
+            let (_, rotation, origin) = scene
+                .node(id)
+                .world_transform()
+                .start
+                .to_scale_rotation_translation();
+            imported.cameras.push(match camera.projection() {
+                Projection::Perspective(p) => {
+                    let aspect = p.aspect_ratio().unwrap_or(1.0) as Float;
+                    let half_height = (p.yfov() as Float / 2.0).tan();
+                    ImportedCamera::Perspective(PerspectiveCamera {
+                        origin,
+                        rotation,
+                        horizontal_fov: (half_height * aspect).atan().to_degrees() * 2.0,
+                    })
+                }
+                Projection::Orthographic(o) => ImportedCamera::Orthographic(OrthCamera {
+                    origin,
+                    rotation,
+                    size: Vec2::new(o.xmag() as Float, o.ymag() as Float) * 2.0,
+                }),
+            });
+        }
+
+        stack.extend(node.children().map(|c| (c, Some(id))));
+    }
+
+    Ok(imported)
+}
+
+/// Read every buffer, either from the GLB binary chunk or from files next to the glTF
+fn load_buffers(gltf: &Gltf, path: &Path) -> Result<Vec<Vec<u8>>, GltfError> {
+    gltf.buffers()
+        .map(|buffer| match buffer.source() {
+            Source::Bin => gltf.blob.clone().ok_or(GltfError::MissingBinaryChunk),
+            Source::Uri(uri) if uri.starts_with("data:") || uri.contains("://") => {
+                Err(GltfError::UnsupportedUri(uri.to_string()))
+            }
+            Source::Uri(uri) => {
+                let file = path.parent().unwrap_or(Path::new(".")).join(uri);
+                Ok(std::fs::read(file)?)
+            }
+        })
+        .collect()
+}
+
+/// Approximate a PBR material. Dielectrics are fully diffuse, and metals are reflective with
+/// their roughness.
+fn convert_material(
+    material: gltf::Material<'_>,
+    textures: &mut TextureLoader<'_>,
+) -> Arc<Material> {
+    let name = material
+        .name()
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("#{}", material.index().unwrap_or_default()));
+    let pbr = material.pbr_metallic_roughness();
+    let colour_texture = pbr.base_color_texture().and_then(|info| {
+        textures.load(
+            &name,
+            "base colour",
+            info.texture(),
+            info.tex_coord(),
+            InputTransform::Srgb,
+        )
+    });
+    let normal_map = material.normal_texture().and_then(|info| {
+        textures.load(
+            &name,
+            "normal",
+            info.texture(),
+            info.tex_coord(),
+            InputTransform::Raw,
+        )
+    });
+    let ignored = [
+        (
+            "metallic-roughness",
+            pbr.metallic_roughness_texture().is_some(),
+        ),
+        ("emissive", material.emissive_texture().is_some()),
+        ("occlusion", material.occlusion_texture().is_some()),
+    ];
+    for (slot, _) in ignored.into_iter().filter(|&(_, present)| present) {
+        warn_texture(&name, slot, "not supported");
+    }
+
+    let [r, g, b, _] = pbr.base_color_factor();
+    let metallic = pbr.metallic_factor() as Float;
+    let roughness = pbr.roughness_factor() as Float;
+    let emissive = Vec3::from_array(material.emissive_factor().map(|c| c as Float));
+    let colour = Vec3::new(r as Float, g as Float, b as Float);
+
+    Arc::new(Material {
+        colour,
+        colour_texture,
+        normal_map,
+        diffusion: 1.0 - metallic * (1.0 - roughness),
+        // Emission is the colour scaled by the luminance, so only its brightness is kept
+        luminance: emissive.max_element() / colour.max_element().max(Float::EPSILON),
+        ..Default::default()
+    })
+}
+
+fn warn_texture(material: &str, slot: &str, reason: &str) {
+    eprintln!(
+        "Ignoring the {} texture of material '{}': {}",
+        slot, material, reason
+    );
+}
+
+/// Decodes the images of textures, sharing each between the materials which use it
+struct TextureLoader<'a> {
+    buffers: &'a [Vec<u8>],
+    /// Directory external images are relative to
+    dir: &'a Path,
+    /// Textures by glTF image index and the transform they were decoded with
+    decoded: HashMap<(usize, InputTransform), Arc<Texture>>,
+}
+
+impl TextureLoader<'_> {
+    /// The `slot` texture of `material`, decoded with `transform`, or `None` with a warning if
+    /// it can't be used
+    fn load(
+        &mut self,
+        material: &str,
+        slot: &str,
+        texture: gltf::Texture<'_>,
+        tex_coord: u32,
+        transform: InputTransform,
+    ) -> Option<Arc<Texture>> {
+        // Meshes are only imported with their first set of UV coordinates
+        if tex_coord != 0 {
+            warn_texture(material, slot, "uses a second set of UV coordinates");
+            return None;
+        }
+        let source = texture.source();
+        if let Some(texture) = self.decoded.get(&(source.index(), transform)) {
+            return Some(texture.clone());
+        }
+
+        let bytes = match source.source() {
+            ImageSource::View { view, .. } => {
+                let range = view.offset()..view.offset() + view.length();
+                match self.buffers[view.buffer().index()].get(range) {
+                    Some(bytes) => Cow::Borrowed(bytes),
+                    None => {
+                        warn_texture(material, slot, "image data is outside its buffer");
+                        return None;
+                    }
+                }
+            }
+            ImageSource::Uri { uri, .. } if uri.starts_with("data:") || uri.contains("://") => {
+                warn_texture(material, slot, &format!("unsupported image URI '{}'", uri));
+                return None;
+            }
+            ImageSource::Uri { uri, .. } => match std::fs::read(self.dir.join(uri)) {
+                Ok(bytes) => Cow::Owned(bytes),
+                Err(e) => {
+                    warn_texture(material, slot, &format!("failed to read '{}': {}", uri, e));
+                    return None;
+                }
+            },
+        };
+        let image = match image::load_from_memory(&bytes) {
+            Ok(image) => image,
+            Err(e) => {
+                warn_texture(material, slot, &e.to_string());
+                return None;
+            }
+        };
+
+        let texture = Arc::new(Texture::from_image(&image, transform));
+        self.decoded
+            .insert((source.index(), transform), texture.clone());
+        Some(texture)
+    }
+}
+
+fn convert_primitive(
+    primitive: &gltf::Primitive<'_>,
+    buffers: &[Vec<u8>],
+    material: Arc<Material>,
+) -> Option<Mesh> {
+    let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|b| &b[..]));
+    let to_dvec3 = |v: [f32; 3]| mirror(Vec3::from_array(v.map(|c| c as Float)));
+
+    let positions: Vec<Vec3> = reader.read_positions()?.map(to_dvec3).collect();
+    let normals: Vec<Vec3> = reader
+        .read_normals()
+        .map(|n| n.map(to_dvec3).collect())
+        .unwrap_or_default();
+    let uvs: Vec<Vec2> = reader
+        .read_tex_coords(0)
+        .map(|uv| {
+            uv.into_f32()
+                .map(|[u, v]| Vec2::new(u as Float, v as Float))
+                .collect()
+        })
+        .unwrap_or_default();
+    // Mirroring flips the handedness of the tangent frame
+    let tangents: Vec<Vec4> = reader
+        .read_tangents()
+        .map(|t| {
+            t.map(|[x, y, z, w]| {
+                mirror(Vec3::new(x as Float, y as Float, z as Float)).extend(-w as Float)
+            })
+            .collect()
+        })
+        .unwrap_or_default();
+
+    let indices: Vec<u32> = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect(),
+        None => (0..positions.len() as u32).collect(),
+    };
+    // Mirroring also flips the winding order
+    let triangles = indices
+        .chunks_exact(3)
+        .map(|t| [t[0], t[2], t[1]])
+        .collect();
+
+    let mut mesh = Mesh {
+        positions,
+        normals,
+        uvs,
+        tangents,
+        triangles,
+        material,
+    };
+    if mesh.tangents.is_empty() {
+        mesh.generate_tangents();
+    }
+    Some(mesh)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn imports_base_colour_and_normal_textures() {
+        let dir = std::env::temp_dir().join("raytrace_rs_gltf_textures_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let values: [f32; 15] = [
+            0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0,
+        ];
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        std::fs::write(dir.join("triangle.bin"), bytes).unwrap();
+        for (name, pixel) in [
+            ("colour.png", [128, 128, 128]),
+            ("normal.png", [128, 128, 255]),
+        ] {
+            image::RgbImage::from_pixel(1, 1, image::Rgb(pixel))
+                .save(dir.join(name))
+                .unwrap();
+        }
+        let path = dir.join("triangle.gltf");
+        std::fs::write(
+            &path,
+            r#"{
+                "asset": {"version": "2.0"},
+                "scene": 0,
+                "scenes": [{"nodes": [0]}],
+                "nodes": [{"mesh": 0}],
+                "meshes": [{"primitives": [
+                    {"attributes": {"POSITION": 0, "TEXCOORD_0": 1}, "material": 0}
+                ]}],
+                "materials": [{
+                    "name": "textured",
+                    "pbrMetallicRoughness": {"baseColorTexture": {"index": 0}},
+                    "normalTexture": {"index": 1},
+                    "occlusionTexture": {"index": 0}
+                }],
+                "textures": [{"source": 0}, {"source": 1}],
+                "images": [{"uri": "colour.png"}, {"uri": "normal.png"}],
+                "buffers": [{"uri": "triangle.bin", "byteLength": 60}],
+                "bufferViews": [
+                    {"buffer": 0, "byteOffset": 0, "byteLength": 36},
+                    {"buffer": 0, "byteOffset": 36, "byteLength": 24}
+                ],
+                "accessors": [
+                    {"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                        "min": [0, 0, 0], "max": [1, 1, 0]},
+                    {"bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC2"}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let mut scene = Scene::new();
+        import_gltf(&path, &mut scene).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let material = scene.objects()[0].collidable.info().materials[0].clone();
+        let encoded = 128.0 / 255.0;
+        let colour = material.colour_at(Vec2::new(0.5, 0.5));
+        let decoded = InputTransform::Srgb.to_linear(Vec3::splat(encoded));
+        assert!(colour.abs_diff_eq(decoded, 1e-4));
+        let normal = material
+            .normal_map
+            .as_ref()
+            .unwrap()
+            .sample(Vec2::new(0.5, 0.5));
+        assert!(normal.abs_diff_eq(Vec3::new(encoded, encoded, 1.0), 1e-4));
+    }
+}
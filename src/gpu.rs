@@ -0,0 +1,513 @@
+use std::{error::Error, fmt::Display};
+
+use glam::IVec2;
+use indicatif::ProgressBar;
+use rand::{Rng, SeedableRng};
+use wgpu::util::DeviceExt;
+
+use crate::{
+    camera::Camera,
+    film::{Aov, Film},
+    float::{consts::PI, Affine3, Float, Quat, Vec3},
+    material::Material,
+    sampler::hash,
+    solver::{Integrator, Solver},
+};
+
+/// Samples of every pixel rendered by each dispatch of the shader. Kept low so a single
+/// dispatch doesn't run for long enough for the driver to reset the GPU.
+const SAMPLES_PER_PASS: u64 = 4;
+
+/// Resolution of the latitude-longitude image the sky is baked into
+const SKY_SIZE: [u32; 2] = [256, 128];
+
+/// Geometry of an object in the object's local space, in the forms the GPU can intersect
+pub enum GpuGeometry {
+    Sphere {
+        origin: Vec3,
+        radius: Float,
+    },
+    Plane {
+        origin: Vec3,
+        normal: Vec3,
+    },
+    Triangles {
+        positions: Vec<Vec3>,
+        /// One per position, or empty for flat shading
+        normals: Vec<Vec3>,
+        triangles: Vec<[u32; 3]>,
+    },
+}
+
+/// An object as uploaded to the GPU, returned by [`Collideable::gpu_shape`]
+///
+/// [`Collideable::gpu_shape`]: crate::collidable::Collideable::gpu_shape
+pub struct GpuShape<'a> {
+    pub geometry: GpuGeometry,
+    pub material: &'a Material,
+}
+
+/// A pinhole camera as uploaded to the GPU, returned by [`Camera::gpu_camera`]
+#[derive(Debug, Clone, Copy)]
+pub struct GpuCamera {
+    pub origin: Vec3,
+    pub rotation: Quat,
+    /// Distance from the origin to the image plane, for an image plane one unit wide
+    pub focal_length: Float,
+}
+
+#[derive(Debug)]
+pub enum GpuError {
+    /// No GPU adapter is available
+    NoAdapter,
+    RequestDevice(wgpu::RequestDeviceError),
+    /// The scene or settings use a feature the GPU backend doesn't implement
+    Unsupported(String),
+    /// Reading the rendered image back from the GPU failed
+    Map(wgpu::BufferAsyncError),
+}
+
+impl Display for GpuError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GpuError::NoAdapter => write!(f, "no GPU adapter found"),
+            GpuError::RequestDevice(e) => write!(f, "failed to open GPU device: {}", e),
+            GpuError::Unsupported(feature) => write!(f, "{} isn't supported on the GPU", feature),
+            GpuError::Map(e) => write!(f, "failed to read back render: {}", e),
+        }
+    }
+}
+
+impl Error for GpuError {}
+
+/// The scene flattened into a single array of vectors for the shader. See `gpu.wgsl` for the
+/// layout of each kind of object.
+#[derive(Default)]
+struct SceneBuffer<'a> {
+    spheres: Vec<[f32; 4]>,
+    planes: Vec<[f32; 4]>,
+    triangles: Vec<[f32; 4]>,
+    materials: Vec<&'a Material>,
+}
+
+impl<'a> SceneBuffer<'a> {
+    /// Add `shape`, positioned in world space by `transform`
+    fn add(&mut self, shape: GpuShape<'a>, transform: Affine3) -> Result<(), GpuError> {
+        let material = self.material_index(shape.material)?;
+        let normal_matrix = transform.matrix3.inverse().transpose();
+
+        match shape.geometry {
+            GpuGeometry::Sphere { origin, radius } => {
+                let scale = transform.matrix3.col(0).length();
+                if (1..3).any(|i| (transform.matrix3.col(i).length() - scale).abs() > 1e-6) {
+                    return Err(GpuError::Unsupported("Non-uniformly scaled sphere".into()));
+                }
+                let origin = transform.transform_point3(origin);
+                self.spheres.push(vec4(origin, (radius * scale) as f32));
+                self.spheres.push([material, 0.0, 0.0, 0.0]);
+            }
+            GpuGeometry::Plane { origin, normal } => {
+                let origin = transform.transform_point3(origin);
+                self.planes.push(vec4(origin, material));
+                self.planes.push(vec4(normal_matrix * normal, 0.0));
+            }
+            GpuGeometry::Triangles {
+                positions,
+                normals,
+                triangles,
+            } => {
+                let smooth = if normals.is_empty() { 0.0 } else { 1.0 };
+                for [i0, i1, i2] in triangles {
+                    let p = |i: u32| transform.transform_point3(positions[i as usize]);
+                    let n = |i: u32| {
+                        let normal = normals.get(i as usize).copied().unwrap_or(Vec3::ZERO);
+                        vec4(normal_matrix * normal, 0.0)
+                    };
+                    self.triangles.push(vec4(p(i0), material));
+                    self.triangles.push(vec4(p(i1), smooth));
+                    self.triangles.push(vec4(p(i2), 0.0));
+                    self.triangles.extend([n(i0), n(i1), n(i2)]);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Index of `material`, bitcast to a float for storing alongside positions
+    fn material_index(&mut self, material: &'a Material) -> Result<f32, GpuError> {
+        if material.phase.is_some() {
+            return Err(GpuError::Unsupported("Volume scattering".into()));
+        }
+
+        let index = match self
+            .materials
+            .iter()
+            .position(|m| std::ptr::eq(*m, material))
+        {
+            Some(i) => i,
+            None => {
+                self.materials.push(material);
+                self.materials.len() - 1
+            }
+        };
+        Ok(f32::from_bits(index as u32))
+    }
+}
+
+/// Render the film of `solver` on the GPU. Only spheres, planes and triangle meshes lit by
+/// the sky are supported, with a perspective camera and without motion blur; anything else
+/// returns [`GpuError::Unsupported`] so the caller can fall back to the CPU.
+///
+/// Random numbers are generated on the GPU rather than by the solver's sampler, so the
+/// result has the same noise level as a CPU render with the random sampler but isn't
+/// identical to it.
+pub fn render<C: Camera, R: Rng + SeedableRng>(
+    solver: &Solver<C, R>,
+    seed: u64,
+) -> Result<Film, GpuError> {
+    solver.select_levels_of_detail();
+    let camera = solver
+        .camera
+        .gpu_camera()
+        .ok_or_else(|| GpuError::Unsupported("Camera".into()))?;
+    if solver.environment.is_some() {
+        return Err(GpuError::Unsupported("Environment map".into()));
+    }
+    if solver.atmosphere.is_some() {
+        return Err(GpuError::Unsupported("Atmosphere".into()));
+    }
+    if solver.spectral {
+        return Err(GpuError::Unsupported("Spectral rendering".into()));
+    }
+    if solver.integrator != Integrator::PathTracing {
+        return Err(GpuError::Unsupported("Photon mapping".into()));
+    }
+    if solver.transparent_background || solver.aovs.contains(&Aov::Alpha) {
+        return Err(GpuError::Unsupported("Transparent background".into()));
+    }
+
+    let blur = solver.shutter.0 != solver.shutter.1;
+    let time = (solver.shutter.0 + solver.shutter.1) / 2.0;
+    let mut scene = SceneBuffer::default();
+    for object in solver.scene.objects() {
+        let shape = object.rendered().gpu_shape().ok_or_else(|| {
+            GpuError::Unsupported(format!("Object '{}'", object.rendered().info().kind))
+        })?;
+        if shape.material.normal_map.is_some() || shape.material.bump_map.is_some() {
+            return Err(GpuError::Unsupported("Normal and bump mapping".into()));
+        }
+        if shape.material.colour_texture.is_some() {
+            return Err(GpuError::Unsupported("Colour textures".into()));
+        }
+        if shape.material.fuzz > 0.0 {
+            return Err(GpuError::Unsupported("Fuzzy reflections".into()));
+        }
+        if shape.material.subsurface.is_some() {
+            return Err(GpuError::Unsupported("Subsurface scattering".into()));
+        }
+        let transform = match object.node {
+            Some(node) => {
+                let world = solver.scene.node(node).world_transform();
+                if blur && world.is_animated() {
+                    return Err(GpuError::Unsupported("Motion blur".into()));
+                }
+                world.at(time)
+            }
+            None => Affine3::IDENTITY,
+        };
+        scene.add(shape, transform)?;
+    }
+
+    let film = solver.new_film();
+    pollster::block_on(Gpu::new())?.render(solver, &camera, &scene, film, seed)
+}
+
+struct Gpu {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+}
+
+impl Gpu {
+    async fn new() -> Result<Self, GpuError> {
+        let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                ..Default::default()
+            })
+            .await
+            .ok_or(GpuError::NoAdapter)?;
+
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: Some("raytrace"),
+                    required_features: wgpu::Features::empty(),
+                    required_limits: adapter.limits(),
+                    memory_hints: wgpu::MemoryHints::Performance,
+                },
+                None,
+            )
+            .await
+            .map_err(GpuError::RequestDevice)?;
+
+        Ok(Self { device, queue })
+    }
+
+    fn render<C: Camera, R: Rng + SeedableRng>(
+        &self,
+        solver: &Solver<C, R>,
+        camera: &GpuCamera,
+        scene: &SceneBuffer<'_>,
+        mut film: Film,
+        seed: u64,
+    ) -> Result<Film, GpuError> {
+        let pixels = film.data_size.x as u64 * film.data_size.y as u64;
+
+        // Scene data, in the order of the offsets in the parameters
+        let mut data = Vec::new();
+        let mut offsets = [0u32; 4];
+        let objects = [&scene.spheres, &scene.planes, &scene.triangles];
+        for (offset, items) in offsets.iter_mut().zip(objects) {
+            *offset = data.len() as u32;
+            data.extend_from_slice(items);
+        }
+        offsets[3] = data.len() as u32;
+        for m in &scene.materials {
+            data.push(vec4(m.colour, m.diffusion as f32));
+            data.push(vec4(m.absorption, m.refractive_index as f32));
+            data.push([
+                m.luminance as f32,
+                m.transmission_diffusion as f32,
+                0.0,
+                0.0,
+            ]);
+        }
+        let sky_offset = data.len() as u32;
+        data.extend(bake_sky(solver.sky));
+
+        let scene_buffer = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("scene"),
+                contents: &to_bytes(data.iter().flatten().map(|v| v.to_bits())),
+                usage: wgpu::BufferUsages::STORAGE,
+            });
+        let params_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("params"),
+            size: 8 * 16,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let storage = |label, size| {
+            self.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            })
+        };
+        let accum_buffer = storage("accum", pixels * 16);
+        let aov_buffer = storage("aovs", pixels * 3 * 16);
+
+        let shader = self
+            .device
+            .create_shader_module(wgpu::include_wgsl!("gpu.wgsl"));
+        let pipeline = self
+            .device
+            .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("path trace"),
+                layout: None,
+                module: &shader,
+                entry_point: Some("main"),
+                compilation_options: Default::default(),
+                cache: None,
+            });
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                params_buffer.as_entire_binding(),
+                scene_buffer.as_entire_binding(),
+                accum_buffer.as_entire_binding(),
+                aov_buffer.as_entire_binding(),
+            ]
+            .into_iter()
+            .enumerate()
+            .map(|(i, resource)| wgpu::BindGroupEntry {
+                binding: i as u32,
+                resource,
+            })
+            .collect::<Vec<_>>(),
+        });
+
+        let origin = film.data_origin;
+        let size = film.data_size;
+        let counts = [
+            scene.spheres.len() / 2,
+            scene.planes.len() / 2,
+            scene.triangles.len() / 6,
+        ]
+        .map(|n| n as u32);
+        let gpu_seed = hash(&[seed]) as u32;
+
+        let bar = ProgressBar::new(solver.samples);
+        for first in (0..solver.samples).step_by(SAMPLES_PER_PASS as usize) {
+            let samples = SAMPLES_PER_PASS.min(solver.samples - first);
+            let params = [
+                vec4(camera.origin, camera.focal_length as f32).map(f32::to_bits),
+                [
+                    camera.rotation.x,
+                    camera.rotation.y,
+                    camera.rotation.z,
+                    camera.rotation.w,
+                ]
+                .map(|c| (c as f32).to_bits()),
+                [
+                    solver.resolution.x,
+                    solver.resolution.y,
+                    SKY_SIZE[0],
+                    SKY_SIZE[1],
+                ],
+                [origin.x, origin.y, size.x as i32, size.y as i32].map(|c| c as u32),
+                [
+                    counts[0],
+                    counts[1],
+                    counts[2],
+                    solver.max_bounces.min(u32::MAX as u64) as u32,
+                ],
+                offsets,
+                [
+                    first as u32,
+                    samples as u32,
+                    solver
+                        .roulette_depth
+                        .map_or(u32::MAX, |d| d.min(u32::MAX as u64 - 1) as u32),
+                    gpu_seed,
+                ],
+                [
+                    sky_offset,
+                    solver.clamp.map_or(0.0, |c| c as f32).to_bits(),
+                    (solver.frame_format().aspect_ratio() as f32).to_bits(),
+                    0,
+                ],
+            ];
+            self.queue
+                .write_buffer(&params_buffer, 0, &to_bytes(params.into_iter().flatten()));
+
+            let mut encoder = self.device.create_command_encoder(&Default::default());
+            {
+                let mut pass = encoder.begin_compute_pass(&Default::default());
+                pass.set_pipeline(&pipeline);
+                pass.set_bind_group(0, &bind_group, &[]);
+                pass.dispatch_workgroups(size.x.div_ceil(8), size.y.div_ceil(8), 1);
+            }
+            self.queue.submit([encoder.finish()]);
+            self.device.poll(wgpu::Maintain::Wait);
+            bar.inc(samples);
+        }
+        bar.finish();
+
+        let accum = self.read_buffer(&accum_buffer)?;
+        let aovs = self.read_buffer(&aov_buffer)?;
+
+        let samples = solver.samples as Float;
+        for y in 0..size.y {
+            for x in 0..size.x {
+                let i = (y * size.x + x) as usize;
+                let pixel = origin + IVec2::new(x as i32, y as i32);
+                let [depth, hits, ..] = aovs[i * 3];
+                let depth = if hits > 0.0 {
+                    depth / hits
+                } else {
+                    Float::INFINITY
+                };
+
+                film.set(pixel, from_vec4(accum[i]) / samples);
+                film.set_aov(Aov::Depth, pixel, Vec3::splat(depth));
+                film.set_aov(
+                    Aov::Normal,
+                    pixel,
+                    from_vec4(aovs[i * 3 + 1]).normalize_or_zero(),
+                );
+                film.set_aov(Aov::Albedo, pixel, from_vec4(aovs[i * 3 + 2]) / samples);
+            }
+        }
+
+        // Outliers are re-rendered on the CPU
+        if let Some(pass) = &solver.firefly_pass {
+            let frame = solver.start_frame(seed);
+            solver.remove_fireflies(&mut film, pass, seed, &frame);
+        }
+
+        Ok(film)
+    }
+
+    /// Copy `buffer` back from the GPU as an array of vectors
+    fn read_buffer(&self, buffer: &wgpu::Buffer) -> Result<Vec<[Float; 4]>, GpuError> {
+        let staging = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback"),
+            size: buffer.size(),
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, buffer.size());
+        self.queue.submit([encoder.finish()]);
+
+        let slice = staging.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        receiver
+            .recv()
+            .expect("Map callback is called by poll")
+            .map_err(GpuError::Map)?;
+
+        let bytes = slice.get_mapped_range();
+        let values = bytes
+            .chunks_exact(16)
+            .map(|v| {
+                std::array::from_fn(|i| {
+                    f32::from_ne_bytes(v[i * 4..i * 4 + 4].try_into().unwrap()) as Float
+                })
+            })
+            .collect();
+        drop(bytes);
+        staging.unmap();
+
+        Ok(values)
+    }
+}
+
+/// Evaluate `sky` over a latitude-longitude image, with +y at the top
+fn bake_sky(sky: fn(Vec3) -> Vec3) -> impl Iterator<Item = [f32; 4]> {
+    let [width, height] = SKY_SIZE;
+    (0..height).flat_map(move |y| {
+        (0..width).map(move |x| {
+            let phi = ((x as Float + 0.5) / width as Float - 0.5) * 2.0 * PI;
+            let theta = (y as Float + 0.5) / height as Float * PI;
+            let dir = Vec3::new(
+                theta.sin() * phi.cos(),
+                theta.cos(),
+                theta.sin() * phi.sin(),
+            );
+            vec4(sky(dir), 0.0)
+        })
+    })
+}
+
+fn vec4(v: Vec3, w: f32) -> [f32; 4] {
+    [v.x as f32, v.y as f32, v.z as f32, w]
+}
+
+fn from_vec4(v: [Float; 4]) -> Vec3 {
+    Vec3::new(v[0], v[1], v[2])
+}
+
+fn to_bytes(words: impl Iterator<Item = u32>) -> Vec<u8> {
+    words.flat_map(u32::to_ne_bytes).collect()
+}
@@ -0,0 +1,385 @@
+// Path tracer run by the GPU backend. Follows the same rules as the CPU solver for the
+// subset of the scene it supports, with the whole path traced in a loop as WGSL can't
+// recurse.
+
+struct Params {
+    // xyz origin, w distance to the image plane for a unit wide image
+    camera_origin: vec4<f32>,
+    camera_rotation: vec4<f32>,
+    // xy display resolution, zw size of the baked sky
+    resolution: vec4<u32>,
+    // xy origin and zw size of the film's data window
+    data_window: vec4<i32>,
+    // Number of spheres, planes and triangles, then the maximum bounces
+    counts: vec4<u32>,
+    // Offsets of the spheres, planes, triangles and materials in the scene buffer
+    offsets: vec4<u32>,
+    // First sample index of this pass, samples in this pass, roulette depth and seed
+    sampling: vec4<u32>,
+    // Offset of the sky in the scene buffer, then the bits of the sample clamp and of the
+    // aspect ratio of the frame
+    extra: vec4<u32>,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> scene: array<vec4<f32>>;
+// Sum of the samples of each pixel
+@group(0) @binding(2) var<storage, read_write> accum: array<vec4<f32>>;
+// Depth sum and hit count, normal sum and albedo sum of each pixel
+@group(0) @binding(3) var<storage, read_write> aovs: array<vec4<f32>>;
+
+const PI: f32 = 3.14159265358979;
+const NO_MATERIAL: u32 = 0xffffffffu;
+const NO_ROULETTE: u32 = 0xffffffffu;
+const MAX_MEDIA: u32 = 4u;
+
+var<private> rng_state: u32;
+
+fn pcg(v: u32) -> u32 {
+    let state = v * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+fn random() -> f32 {
+    rng_state = pcg(rng_state);
+    return f32(rng_state >> 8u) / 16777216.0;
+}
+
+// Uniformly distributed direction on the unit sphere
+fn random_unit_vector() -> vec3<f32> {
+    let z = 1.0 - 2.0 * random();
+    let r = sqrt(max(1.0 - z * z, 0.0));
+    let phi = 2.0 * PI * random();
+    return vec3<f32>(r * cos(phi), r * sin(phi), z);
+}
+
+// Rotate `v` by the quaternion `q`, using the same formula as glam so quaternions which aren't
+// normalised give the same result as on the CPU
+fn rotate(q: vec4<f32>, v: vec3<f32>) -> vec3<f32> {
+    return v * (q.w * q.w - dot(q.xyz, q.xyz)) + q.xyz * (dot(v, q.xyz) * 2.0)
+        + cross(q.xyz, v) * (q.w * 2.0);
+}
+
+struct Hit {
+    t: f32,
+    normal: vec3<f32>,
+    material: u32,
+}
+
+fn intersect_sphere(hit: ptr<function, Hit>, origin: vec3<f32>, dir: vec3<f32>, i: u32) {
+    let base = params.offsets.x + i * 2u;
+    let sphere = scene[base];
+    let off = origin - sphere.xyz;
+
+    let a = dot(dir, dir);
+    let b = 2.0 * dot(off, dir);
+    let c = dot(off, off) - sphere.w * sphere.w;
+    let disc = b * b - 4.0 * a * c;
+    if disc < 0.0 {
+        return;
+    }
+
+    let sqrt_disc = sqrt(disc);
+    let t0 = (-b + sqrt_disc) / (2.0 * a);
+    let t1 = (-b - sqrt_disc) / (2.0 * a);
+    var t = t0;
+    if t1 > 0.0 && t1 < t0 {
+        t = t1;
+    }
+    if t > 0.0 && t < (*hit).t {
+        (*hit).t = t;
+        (*hit).normal = normalize(origin + dir * t - sphere.xyz);
+        (*hit).material = bitcast<u32>(scene[base + 1u].x);
+    }
+}
+
+fn intersect_plane(hit: ptr<function, Hit>, origin: vec3<f32>, dir: vec3<f32>, i: u32) {
+    let base = params.offsets.y + i * 2u;
+    let plane = scene[base];
+    let normal = scene[base + 1u].xyz;
+
+    let t = dot(plane.xyz - origin, normal) / dot(dir, normal);
+    if t >= 0.0 && t < (*hit).t {
+        (*hit).t = t;
+        (*hit).normal = normalize(normal);
+        (*hit).material = bitcast<u32>(plane.w);
+    }
+}
+
+fn intersect_triangle(hit: ptr<function, Hit>, origin: vec3<f32>, dir: vec3<f32>, i: u32) {
+    let base = params.offsets.z + i * 6u;
+    let p0 = scene[base];
+    let p1 = scene[base + 1u];
+    let p2 = scene[base + 2u].xyz;
+    let edge1 = p1.xyz - p0.xyz;
+    let edge2 = p2 - p0.xyz;
+
+    // Möller-Trumbore
+    let pvec = cross(dir, edge2);
+    let det = dot(edge1, pvec);
+    if abs(det) < 1e-12 {
+        return;
+    }
+    let inv_det = 1.0 / det;
+
+    let tvec = origin - p0.xyz;
+    let u = dot(tvec, pvec) * inv_det;
+    if u < 0.0 || u > 1.0 {
+        return;
+    }
+    let qvec = cross(tvec, edge1);
+    let v = dot(dir, qvec) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return;
+    }
+
+    let t = dot(edge2, qvec) * inv_det;
+    if t > 0.0 && t < (*hit).t {
+        (*hit).t = t;
+        (*hit).material = bitcast<u32>(p0.w);
+        if p1.w > 0.0 {
+            (*hit).normal = normalize(
+                scene[base + 3u].xyz * (1.0 - u - v) + scene[base + 4u].xyz * u
+                    + scene[base + 5u].xyz * v
+            );
+        } else {
+            (*hit).normal = normalize(cross(edge1, edge2));
+        }
+    }
+}
+
+fn trace(origin: vec3<f32>, dir: vec3<f32>) -> Hit {
+    var hit = Hit(3.0e38, vec3<f32>(0.0), NO_MATERIAL);
+    for (var i = 0u; i < params.counts.x; i++) {
+        intersect_sphere(&hit, origin, dir, i);
+    }
+    for (var i = 0u; i < params.counts.y; i++) {
+        intersect_plane(&hit, origin, dir, i);
+    }
+    for (var i = 0u; i < params.counts.z; i++) {
+        intersect_triangle(&hit, origin, dir, i);
+    }
+    return hit;
+}
+
+fn sky(dir: vec3<f32>) -> vec3<f32> {
+    let d = normalize(dir);
+    let size = params.resolution.zw;
+    let u = atan2(d.z, d.x) / (2.0 * PI) + 0.5;
+    let v = acos(clamp(d.y, -1.0, 1.0)) / PI;
+    let x = min(u32(u * f32(size.x)), size.x - 1u);
+    let y = min(u32(v * f32(size.y)), size.y - 1u);
+    return scene[params.extra.x + y * size.x + x].xyz;
+}
+
+struct Material {
+    colour: vec3<f32>,
+    diffusion: f32,
+    absorption: vec3<f32>,
+    refractive_index: f32,
+    luminance: f32,
+    transmission_diffusion: f32,
+}
+
+fn material(i: u32) -> Material {
+    let base = params.offsets.w + i * 3u;
+    let a = scene[base];
+    let b = scene[base + 1u];
+    let c = scene[base + 2u];
+    return Material(a.xyz, a.w, b.xyz, b.w, c.x, c.y);
+}
+
+// Refractive index of the innermost medium, or of air if not inside any
+fn current_ior(media: array<u32, MAX_MEDIA>, depth: u32) -> f32 {
+    if depth == 0u {
+        return 1.0;
+    }
+    return material(media[depth - 1u]).refractive_index;
+}
+
+struct FirstHit {
+    depth: f32,
+    normal: vec3<f32>,
+    albedo: vec3<f32>,
+    hit: bool,
+}
+
+var<private> first_hit: FirstHit;
+
+fn radiance(camera_origin: vec3<f32>, camera_dir: vec3<f32>) -> vec3<f32> {
+    var origin = camera_origin;
+    var dir = camera_dir;
+    var throughput = vec3<f32>(1.0);
+    var result = vec3<f32>(0.0);
+    var media: array<u32, MAX_MEDIA>;
+    var depth = 0u;
+
+    for (var bounce = 0u; ; bounce++) {
+        let hit = trace(origin, dir);
+
+        if bounce == 0u {
+            if hit.material == NO_MATERIAL {
+                first_hit = FirstHit(0.0, vec3<f32>(0.0), clamp(sky(dir), vec3<f32>(0.0), vec3<f32>(1.0)), false);
+            } else {
+                first_hit = FirstHit(hit.t * length(dir), hit.normal, material(hit.material).colour, true);
+            }
+        }
+
+        // Beer-Lambert absorption by the medium the ray is travelling through
+        var transmittance = vec3<f32>(1.0);
+        if depth > 0u {
+            let absorption = material(media[depth - 1u]).absorption;
+            if any(absorption != vec3<f32>(0.0)) {
+                if hit.material == NO_MATERIAL {
+                    transmittance = vec3<f32>(0.0);
+                } else {
+                    transmittance = exp(-absorption * hit.t * length(dir));
+                }
+            }
+        }
+
+        if hit.material == NO_MATERIAL {
+            return result + throughput * sky(dir) * transmittance;
+        }
+        if bounce >= params.counts.w {
+            return result;
+        }
+
+        let m = material(hit.material);
+        var survival = 1.0;
+        if params.sampling.z != NO_ROULETTE && bounce >= params.sampling.z {
+            survival = clamp(max(m.colour.x, max(m.colour.y, m.colour.z)), 0.05, 0.95);
+            if random() > survival {
+                return result + throughput * m.colour * m.luminance * transmittance;
+            }
+        }
+        result += throughput * m.colour * m.luminance * transmittance;
+        throughput *= m.colour * transmittance / survival;
+
+        // Snell's law, with the Fresnel equations choosing between reflection and refraction
+        var n1: f32;
+        var n2: f32;
+        var directed_normal: vec3<f32>;
+        // Media on the far side of the surface, used if the ray is transmitted
+        var transmitted_media = media;
+        var transmitted_depth = depth;
+        let entering = dot(hit.normal, dir) < 0.0;
+        if entering {
+            n1 = current_ior(media, depth);
+            n2 = m.refractive_index;
+            directed_normal = -hit.normal;
+
+            if depth < MAX_MEDIA {
+                transmitted_media[depth] = hit.material;
+                transmitted_depth++;
+            }
+        } else {
+            for (var i = i32(depth) - 1; i >= 0; i--) {
+                if media[i] == hit.material {
+                    for (var j = u32(i); j + 1u < depth; j++) {
+                        transmitted_media[j] = media[j + 1u];
+                    }
+                    transmitted_depth--;
+                    break;
+                }
+            }
+
+            n1 = m.refractive_index;
+            n2 = current_ior(transmitted_media, transmitted_depth);
+            directed_normal = hit.normal;
+        }
+
+        let unit_dir = normalize(dir);
+        let cosi = clamp(dot(unit_dir, directed_normal), -1.0, 1.0);
+        let sin_a2 = n1 / n2 * sqrt(max(1.0 - cosi * cosi, 0.0));
+        var transmit = false;
+        var cost = 0.0;
+        if sin_a2 <= 1.0 {
+            cost = sqrt(max(1.0 - sin_a2 * sin_a2, 0.0));
+            let rs = pow(abs((n1 * cosi - n2 * cost) / (n1 * cosi + n2 * cost)), 2.0);
+            let rp = pow(abs((n1 * cost - n2 * cosi) / (n1 * cost + n2 * cosi)), 2.0);
+            transmit = random() >= (rs + rp) / 2.0;
+        }
+
+        let hit_t = hit.t;
+        if transmit {
+            let hit_pos = origin + dir * hit_t * 1.0001;
+            // Rotate the normal about `dir × normal` by the transmission angle
+            let angle = asin(sin_a2);
+            let axis = cross(dir, directed_normal);
+            let outgoing = rotate(vec4<f32>(axis * sin(angle / 2.0), cos(angle / 2.0)), directed_normal);
+            let diffuse_target = directed_normal + random_unit_vector();
+            origin = hit_pos;
+            dir = mix(outgoing, diffuse_target, m.transmission_diffusion);
+            media = transmitted_media;
+            depth = transmitted_depth;
+        } else {
+            let hit_pos = origin + dir * hit_t * 0.9999;
+            let reflect_target = dir + hit.normal * 2.0;
+            var diffuse_target = random_unit_vector();
+            if dot(hit_pos + hit.normal, origin) > 0.0 {
+                diffuse_target += hit.normal;
+            } else {
+                diffuse_target -= hit.normal;
+            }
+            origin = hit_pos;
+            dir = mix(reflect_target, diffuse_target, m.diffusion);
+        }
+    }
+
+    return result;
+}
+
+@compute @workgroup_size(8, 8)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = vec2<u32>(params.data_window.zw);
+    if id.x >= size.x || id.y >= size.y {
+        return;
+    }
+    let index = id.y * size.x + id.x;
+
+    // Image space has y pointing down while the camera has it pointing up
+    let img_pixel = params.data_window.xy + vec2<i32>(id.xy);
+    let pixel = vec2<f32>(f32(img_pixel.x), f32(i32(params.resolution.y) - img_pixel.y - 1));
+    // The image plane is one unit wide, and as high as the aspect ratio of the frame needs
+    let plane_size = vec2<f32>(1.0, 1.0 / bitcast<f32>(params.extra.z));
+    let scale = plane_size / vec2<f32>(params.resolution.xy);
+
+    var sum = vec3<f32>(0.0);
+    var depth = vec2<f32>(0.0);
+    var normal = vec3<f32>(0.0);
+    var albedo = vec3<f32>(0.0);
+    let clamp_value = bitcast<f32>(params.extra.y);
+
+    for (var s = 0u; s < params.sampling.y; s++) {
+        let sample_index = params.sampling.x + s;
+        rng_state = pcg(index ^ pcg(sample_index ^ pcg(params.sampling.w)));
+
+        let jitter = vec2<f32>(random(), random()) - 0.5;
+        let target_point = vec3<f32>(
+            (pixel + 0.5 + jitter) * scale - plane_size / 2.0,
+            params.camera_origin.w,
+        );
+        let dir = rotate(params.camera_rotation, normalize(target_point));
+
+        var sample = radiance(params.camera_origin.xyz, dir);
+        let brightest = max(sample.x, max(sample.y, sample.z));
+        if clamp_value > 0.0 && brightest > clamp_value {
+            // Scale rather than clamp each channel so the hue is preserved
+            sample *= clamp_value / brightest;
+        }
+        sum += sample;
+
+        if first_hit.hit {
+            depth += vec2<f32>(first_hit.depth, 1.0);
+            normal += first_hit.normal;
+        }
+        albedo += first_hit.albedo;
+    }
+
+    accum[index] += vec4<f32>(sum, 0.0);
+    aovs[index * 3u] += vec4<f32>(depth, 0.0, 0.0);
+    aovs[index * 3u + 1u] += vec4<f32>(normal, 0.0);
+    aovs[index * 3u + 2u] += vec4<f32>(albedo, 0.0);
+}
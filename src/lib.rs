@@ -0,0 +1,46 @@
+// Constants and conversions are written for f64
+#![cfg_attr(
+    feature = "f32",
+    allow(clippy::excessive_precision, clippy::unnecessary_cast)
+)]
+
+pub mod animation;
+pub mod bench;
+pub mod bvh;
+pub mod camera;
+pub mod collidable;
+pub mod colour;
+pub mod compare;
+pub mod csg;
+#[cfg(feature = "denoise")]
+pub mod denoise;
+pub mod distributed;
+pub mod environment;
+pub mod film;
+pub mod float;
+#[cfg(feature = "gltf")]
+pub mod gltf_import;
+#[cfg(feature = "gpu")]
+pub mod gpu;
+pub mod lighting;
+pub mod lod;
+pub mod material;
+pub mod memory;
+pub mod mesh;
+pub mod overlay;
+pub mod photon;
+pub mod probes;
+pub mod raster;
+pub mod ray;
+pub mod render;
+pub mod report;
+pub mod sampler;
+pub mod scene;
+pub mod sdf;
+pub mod solver;
+pub mod spectrum;
+pub mod stats;
+pub mod texture;
+pub mod tiles;
+pub mod transform;
+pub mod volume;
@@ -0,0 +1,164 @@
+//! Studio lighting presets built from softboxes, for lighting products and portraits without
+//! placing emissive rectangles by hand
+
+use std::sync::Arc;
+
+use rand::RngCore;
+
+use crate::{
+    bvh::Aabb,
+    collidable::{Collideable, Collision, EmissionSample, Quad},
+    float::{Float, Quat, Vec2, Vec3},
+    material::Material,
+    ray::Ray,
+    report::ObjectInfo,
+    scene::Scene,
+};
+
+/// A rectangular light which only emits from its front. The back is black, like the housing
+/// of a real softbox, so it doesn't light the scene behind it.
+pub struct Softbox {
+    pub quad: Quad,
+    /// Material seen from behind
+    pub back: Arc<Material>,
+}
+
+impl Softbox {
+    /// A softbox `size` wide and high, centred on `position` and facing `target`, with its
+    /// width kept horizontal
+    pub fn new(position: Vec3, target: Vec3, size: Vec2, colour: Vec3, luminance: Float) -> Self {
+        let normal = (target - position).normalize();
+        let right = Vec3::Y.cross(normal).try_normalize().unwrap_or(Vec3::X);
+        let up = normal.cross(right);
+
+        Self {
+            quad: Quad {
+                origin: position,
+                u: right * size.x / 2.0,
+                v: up * size.y / 2.0,
+                material: Arc::new(Material {
+                    colour,
+                    diffusion: 1.0,
+                    luminance,
+                    ..Default::default()
+                }),
+            },
+            back: Arc::new(Material {
+                colour: Vec3::ZERO,
+                diffusion: 1.0,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// A softbox `distance` from `subject` facing it. `azimuth` is the angle in degrees around
+    /// the subject from the direction of `camera`, positive to the camera's left, and
+    /// `elevation` the angle in degrees above the subject.
+    pub fn around(
+        subject: Vec3,
+        camera: Vec3,
+        azimuth: Float,
+        elevation: Float,
+        distance: Float,
+        size: Vec2,
+        luminance: Float,
+    ) -> Self {
+        let front = (camera - subject) * Vec3::new(1.0, 0.0, 1.0);
+        let front = front.try_normalize().unwrap_or(Vec3::NEG_Z);
+        let dir = Quat::from_rotation_y(-azimuth.to_radians()) * front;
+        let elevation = elevation.to_radians();
+        let dir = dir * elevation.cos() + Vec3::Y * elevation.sin();
+
+        Self::new(
+            subject + dir * distance,
+            subject,
+            size,
+            Vec3::ONE,
+            luminance,
+        )
+    }
+}
+
+impl Collideable for Softbox {
+    fn trace(&self, ray: &Ray, rng: &mut dyn RngCore) -> Option<Collision<'_>> {
+        let mut collision = self.quad.trace(ray, rng)?;
+        if ray.dir.dot(collision.geometric_normal) > 0.0 {
+            collision.material = &self.back;
+        }
+        Some(collision)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.quad.bounds()
+    }
+
+    fn sample_emission(&self, u: Vec2) -> Option<EmissionSample<'_>> {
+        let sample = Collideable::sample_emission(&self.quad, u)?;
+        Some(EmissionSample {
+            two_sided: false,
+            ..sample
+        })
+    }
+
+    fn info(&self) -> ObjectInfo {
+        ObjectInfo::with_material("Softbox", &self.quad.material)
+    }
+}
+
+/// The classic three point setup: a key light to one side of the camera, a larger and dimmer
+/// fill on the other side to soften the shadows, and a strip light behind the subject to
+/// separate its outline from the background
+pub struct StudioLighting {
+    pub key: Softbox,
+    pub fill: Softbox,
+    pub rim: Softbox,
+}
+
+impl StudioLighting {
+    /// Light `subject` as seen from `camera`. `size` is the width of the key light and
+    /// `distance` how far it is from the subject, while `intensity` is the luminance of the key
+    /// light. The other lights are scaled from these.
+    pub fn three_point(
+        subject: Vec3,
+        camera: Vec3,
+        size: Float,
+        distance: Float,
+        intensity: Float,
+    ) -> Self {
+        Self {
+            key: Softbox::around(
+                subject,
+                camera,
+                45.0,
+                30.0,
+                distance,
+                Vec2::new(size, size * 0.75),
+                intensity,
+            ),
+            fill: Softbox::around(
+                subject,
+                camera,
+                -60.0,
+                10.0,
+                distance * 1.25,
+                Vec2::new(size * 1.5, size * 1.125),
+                intensity * 0.25,
+            ),
+            rim: Softbox::around(
+                subject,
+                camera,
+                160.0,
+                35.0,
+                distance,
+                Vec2::new(size * 0.3, size * 1.5),
+                intensity * 0.75,
+            ),
+        }
+    }
+
+    pub fn add_to(self, scene: &mut Scene) {
+        scene.add_object(self.key);
+        scene.add_object(self.fill);
+        scene.add_object(self.rim);
+    }
+}
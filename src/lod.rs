@@ -0,0 +1,195 @@
+//! Levels of detail, so objects far from the camera are rendered from simpler meshes. Meshes
+//! have no BVH over their triangles, so every ray which reaches one tests every triangle, and a
+//! detailed asset in the background can cost more than the rest of the scene.
+
+use std::sync::Arc;
+
+use rand::RngCore;
+
+#[cfg(feature = "gpu")]
+use crate::gpu::GpuShape;
+use crate::{
+    bvh::Aabb,
+    collidable::{Collideable, Collision},
+    float::{Float, Vec3},
+    mesh::Mesh,
+    ray::Ray,
+    report::ObjectInfo,
+};
+
+/// Where an object is seen from, for choosing its level of detail
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LodView {
+    pub origin: Vec3,
+    /// Pixels covered by one radian of view at the centre of the image
+    pub pixels_per_radian: Float,
+}
+
+/// Versions of a mesh in decreasing detail, of which the one suiting its size on screen is
+/// rendered. The size is the projected diagonal of the bounding box of the most detailed
+/// mesh, in pixels. The level is chosen by each scene the mesh is in, so scenes rendered at
+/// once from different cameras can share the same asset. Outside a scene, e.g. as part of a
+/// [`Csg`](crate::csg::Csg), the most detailed level is rendered.
+#[derive(Clone)]
+pub struct LodMesh {
+    /// Each mesh with the screen size it's used below, most detailed first
+    levels: Vec<(Arc<Mesh>, Float)>,
+    centre: Vec3,
+    radius: Float,
+}
+
+impl LodMesh {
+    /// An object rendered from `mesh` until coarser levels are added
+    pub fn new<M: Into<Arc<Mesh>>>(mesh: M) -> Self {
+        let mesh = mesh.into();
+        let (min, max) = mesh.positions.iter().fold(
+            (
+                Vec3::splat(Float::INFINITY),
+                Vec3::splat(Float::NEG_INFINITY),
+            ),
+            |(min, max), &p| (min.min(p), max.max(p)),
+        );
+        Self {
+            centre: (min + max) / 2.0,
+            radius: (max - min).length() / 2.0,
+            levels: vec![(mesh, Float::INFINITY)],
+        }
+    }
+
+    /// Render `mesh` instead when the object is smaller than `screen_size` pixels, unless a
+    /// coarser level applies. Levels can be added in any order.
+    pub fn with_level<M: Into<Arc<Mesh>>>(mut self, mesh: M, screen_size: Float) -> Self {
+        self.levels.push((mesh.into(), screen_size));
+        self.levels.sort_by(|a, b| {
+            b.1.total_cmp(&a.1)
+                .then(b.0.triangles.len().cmp(&a.0.triangles.len()))
+        });
+        self
+    }
+
+    /// Size of the object on screen in pixels, as seen from `view`
+    pub fn screen_size(&self, view: &LodView) -> Float {
+        let distance = (self.centre - view.origin).length();
+        if distance <= self.radius {
+            return Float::INFINITY;
+        }
+        2.0 * (self.radius / distance).asin() * view.pixels_per_radian
+    }
+
+    /// The mesh of `level`, 0 being the most detailed
+    pub fn level(&self, level: usize) -> &Mesh {
+        &self.levels[level.min(self.levels.len() - 1)].0
+    }
+
+    fn mesh(&self) -> &Mesh {
+        self.level(0)
+    }
+}
+
+impl Collideable for LodMesh {
+    fn trace(&self, ray: &Ray, rng: &mut dyn RngCore) -> Option<Collision<'_>> {
+        self.mesh().trace(ray, rng)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.levels
+            .iter()
+            .map(|(mesh, _)| mesh.bounds())
+            .reduce(|a, b| Some(a?.union(&b?)))?
+    }
+
+    fn level_of_detail(&self, view: &LodView) -> usize {
+        let size = self.screen_size(view);
+        self.levels
+            .iter()
+            .rposition(|&(_, screen_size)| size < screen_size)
+            .unwrap_or(0)
+    }
+
+    fn at_level_of_detail(&self, level: usize) -> Option<&dyn Collideable> {
+        Some(self.level(level))
+    }
+
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of_val(self)
+            + self
+                .levels
+                .iter()
+                .map(|(mesh, _)| Collideable::memory_usage(&**mesh))
+                .sum::<usize>()
+    }
+
+    fn raster_triangles(&self) -> Option<Vec<[Vec3; 3]>> {
+        self.mesh().raster_triangles()
+    }
+
+    fn trace_triangle(&self, ray: &Ray, index: usize) -> Option<Collision<'_>> {
+        self.mesh().trace_triangle(ray, index)
+    }
+
+    fn info(&self) -> ObjectInfo {
+        ObjectInfo {
+            kind: "LodMesh",
+            ..Collideable::info(self.mesh())
+        }
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_shape(&self) -> Option<GpuShape<'_>> {
+        Collideable::gpu_shape(self.mesh())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use glam::UVec2;
+    use rand::rngs::SmallRng;
+
+    use super::*;
+    use crate::{
+        camera::PerspectiveCamera, float::Quat, material::Material, solver::Solver,
+        tiles::CancellationToken,
+    };
+
+    fn solver<C: Collideable + 'static>(
+        z: Float,
+        object: C,
+    ) -> Solver<PerspectiveCamera, SmallRng> {
+        let camera = PerspectiveCamera {
+            origin: Vec3::new(0.0, 0.0, z),
+            rotation: Quat::IDENTITY,
+            horizontal_fov: 60.0,
+        };
+        let mut solver = Solver::new(camera, UVec2::new(16, 16)).with_samples(1);
+        solver.scene.add_object(object);
+        solver
+    }
+
+    #[test]
+    fn cameras_sharing_a_mesh_select_levels_separately() {
+        let material = Arc::new(Material::default());
+        let fine = Arc::new(Mesh::uv_sphere(Vec3::ZERO, 1.0, 16, 8, material.clone()));
+        let coarse = Arc::new(Mesh::uv_sphere(Vec3::ZERO, 1.0, 4, 2, material));
+        let mesh = Arc::new(LodMesh::new(fine.clone()).with_level(coarse.clone(), 8.0));
+
+        let near = solver(-3.0, mesh.clone());
+        let far = solver(-30.0, mesh);
+        let render = |solver: &Solver<_, _>| {
+            solver
+                .solve_with(3, &CancellationToken::new(), |_| {})
+                .to_rgb_image(solver.output_transform)
+        };
+        let (near_image, far_image) = thread::scope(|s| {
+            let near_image = s.spawn(|| render(&near));
+            let far_image = s.spawn(|| render(&far));
+            (near_image.join().unwrap(), far_image.join().unwrap())
+        });
+
+        assert_eq!(near.scene.objects()[0].level_of_detail(), 0);
+        assert_eq!(far.scene.objects()[0].level_of_detail(), 1);
+        assert_eq!(near_image, render(&solver(-3.0, fine)));
+        assert_eq!(far_image, render(&solver(-30.0, coarse)));
+    }
+}
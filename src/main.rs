@@ -1,107 +1,335 @@
+// Constants and conversions are written for f64
+#![cfg_attr(
+    feature = "f32",
+    allow(clippy::excessive_precision, clippy::unnecessary_cast)
+)]
+
 use rand::rngs::SmallRng;
-use std::time::Instant;
+use std::{
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
 
-use glam::{DQuat, DVec3, EulerRot, UVec2};
-use image::ImageOutputFormat;
+use glam::{EulerRot, UVec2};
+use image::{DynamicImage, ImageOutputFormat};
 
-use crate::{
+#[cfg(feature = "denoise")]
+use raytrace_rs::denoise;
+#[cfg(feature = "gltf")]
+use raytrace_rs::gltf_import;
+use raytrace_rs::{
+    animation::{frame_path, Animation, SceneState},
+    bench::{run_benchmarks, BenchOptions},
     camera::PerspectiveCamera,
     collidable::{Plane, Sphere},
+    colour::{InputTransform, OutputTransform},
+    compare::{compare, ComparisonImage, DEFAULT_PIXELS_PER_DEGREE},
+    distributed,
+    environment::{Environment, EnvironmentMap},
+    film::{Aov, Film},
+    float::{consts, Float, Quat, Vec3},
+    lighting::StudioLighting,
     material::Material,
-    solver::Solver,
+    overlay::BurnIn,
+    probes::write_probes,
+    sampler::Sampler,
+    solver::{Integrator, PrimaryVisibility, Solver},
+    spectrum::Dispersion,
 };
 
-pub mod camera;
-pub mod collidable;
-pub mod material;
-pub mod ray;
-pub mod solver;
-
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).is_some_and(|a| a == "compare") {
+        compare_renders(&args[2..]);
+        return;
+    }
+    if args.get(1).is_some_and(|a| a == "bench") {
+        bench(&args[2..]);
+        return;
+    }
+
     let cam = PerspectiveCamera {
-        origin: DVec3::new(0.0, 1.0, 0.0),
-        rotation: DQuat::from_euler(EulerRot::YXZ, 0.0, 0.0, 0.0),
+        origin: Vec3::new(0.0, 1.0, 0.0),
+        rotation: Quat::from_euler(EulerRot::YXZ, 0.0, 0.0, 0.0),
         horizontal_fov: 60.0,
     };
 
-    let mut solver: Solver<'_, _, SmallRng> = Solver::new(cam, UVec2::new(1000, 1000))
+    let mut solver: Solver<_, SmallRng> = Solver::new(cam, UVec2::new(1000, 1000))
         .with_samples(500)
-        .with_max_bounces(10);
+        .with_max_bounces(10)
+        .with_russian_roulette(5)
+        .with_clamp(10.0)
+        .with_sampler(Sampler::Sobol)
+        .with_overscan(5.0)
+        .with_aov(Aov::Albedo)
+        .with_aov(Aov::Normal)
+        .with_aov(Aov::Depth);
 
     let left_sphere = Sphere {
-        origin: DVec3::new(-1.0, 0.7, 3.0),
+        origin: Vec3::new(-1.0, 0.7, 3.0),
         radius: 0.7,
-        material: &Material {
-            colour: DVec3::new(0.55, 0.55, 0.95),
+        material: Arc::new(Material {
+            colour: Vec3::new(0.55, 0.55, 0.95),
             diffusion: 1.0,
             refractive_index: 0.0,
             luminance: 0.0,
-        },
+            ..Default::default()
+        }),
     };
     let middle_sphere = Sphere {
-        origin: DVec3::new(0.0, 1.7, 3.0),
+        origin: Vec3::new(0.0, 1.7, 3.0),
         radius: 0.7,
-        material: &Material {
-            colour: DVec3::new(0.95, 0.95, 0.95),
+        material: Arc::new(Material {
+            colour: Vec3::new(0.95, 0.95, 0.95),
             diffusion: 0.0,
             refractive_index: 0.0,
             luminance: 0.0,
-        },
+            ..Default::default()
+        }),
     };
     let right_sphere = Sphere {
-        origin: DVec3::new(1.0, 0.8, 3.0),
+        origin: Vec3::new(1.0, 0.8, 3.0),
         radius: 0.7,
-        material: &Material {
-            colour: DVec3::new(0.95, 0.55, 0.55),
+        material: Arc::new(Material {
+            colour: Vec3::new(0.95, 0.55, 0.55),
             diffusion: 0.5,
             refractive_index: 0.0,
             luminance: 0.0,
-        },
+            ..Default::default()
+        }),
     };
 
     let front_sphere = Sphere {
-        origin: DVec3::new(0.0, 0.8, 2.5),
+        origin: Vec3::new(0.0, 0.8, 2.5),
         radius: 0.5,
-        material: &Material {
-            colour: DVec3::ONE,
+        material: Arc::new(Material {
+            colour: Vec3::ONE,
             diffusion: 0.0,
             refractive_index: 3.0,
+            dispersion: Some(Dispersion::Cauchy { a: 2.9, b: 0.03 }),
             luminance: 0.0,
-        },
+            ..Default::default()
+        }),
     };
 
     let light_sphere = Sphere {
-        origin: DVec3::new(-0.5, 0.3, 2.5),
+        origin: Vec3::new(-0.5, 0.3, 2.5),
         radius: 0.3,
-        material: &Material {
-            colour: DVec3::new(1.0, 1.0, 1.0),
+        material: Arc::new(Material {
+            colour: Vec3::new(1.0, 1.0, 1.0),
             diffusion: 0.0,
             refractive_index: 0.0,
             luminance: 3.0,
-        },
+            ..Default::default()
+        }),
     };
 
     let plane = Plane {
-        origin: DVec3::ZERO,
-        normal: DVec3::Y,
-        material: &Material {
-            colour: DVec3::new(0.3, 0.75, 0.3),
+        origin: Vec3::ZERO,
+        normal: Vec3::Y,
+        material: Arc::new(Material {
+            colour: Vec3::new(0.3, 0.75, 0.3),
             diffusion: 1.0,
             refractive_index: 0.0,
             luminance: 0.0,
-        },
+            // Keep only the shadows on the ground, for compositing over a photograph
+            shadow_catcher: args.iter().any(|a| a == "--shadow-catcher"),
+            ..Default::default()
+        }),
     };
 
-    solver.objects.push(&left_sphere);
-    solver.objects.push(&middle_sphere);
-    solver.objects.push(&right_sphere);
-    solver.objects.push(&front_sphere);
-    solver.objects.push(&light_sphere);
-    solver.objects.push(&plane);
+    solver.scene.add_object(left_sphere);
+    solver.scene.add_object(middle_sphere);
+    solver.scene.add_object(right_sphere);
+    solver.scene.add_object(front_sphere);
+    solver.scene.add_object(light_sphere);
+    solver.scene.add_object(plane);
+
+    if args.iter().any(|a| a == "--studio") {
+        StudioLighting::three_point(
+            Vec3::new(0.0, 1.0, 3.0),
+            solver.camera.origin,
+            1.5,
+            3.0,
+            4.0,
+        )
+        .add_to(&mut solver.scene);
+    }
+
+    if let Some(i) = args.iter().position(|a| a == "--environment") {
+        let path = args.get(i + 1).expect("--environment takes an image path");
+        let mut map = EnvironmentMap::open(path, InputTransform::for_path(path)).unwrap();
+        // Split the sun out of the map so it can be sampled precisely
+        let mut environment = Environment::new();
+        if let Some(sun) = map.extract_sun(100.0) {
+            environment = environment.with_sun(sun);
+        }
+        solver.environment = Some(environment.with_map(map));
+    }
+
+    #[cfg(feature = "gltf")]
+    if let Some(i) = args.iter().position(|a| a == "--gltf") {
+        let path = args.get(i + 1).expect("--gltf takes a file path");
+        let imported = gltf_import::import_gltf(path, &mut solver.scene).unwrap();
+        // Render from the first perspective camera in the file, if there is one
+        if let Some(camera) = imported.cameras.into_iter().find_map(|c| match c {
+            gltf_import::ImportedCamera::Perspective(camera) => Some(camera),
+            gltf_import::ImportedCamera::Orthographic(_) => None,
+        }) {
+            solver.camera = camera;
+        }
+    }
+
+    if args.iter().any(|a| a == "--preview") {
+        solver.primary_visibility = PrimaryVisibility::PerPixel;
+    }
+
+    if let Some(i) = args.iter().position(|a| a == "--pixel-aspect") {
+        solver.pixel_aspect = args
+            .get(i + 1)
+            .and_then(|a| a.parse().ok())
+            .expect("--pixel-aspect takes the width of a pixel relative to its height");
+    }
+
+    // Look caustics up from a photon map, e.g. `--photons 1000000 0.05`
+    if let Some(i) = args.iter().position(|a| a == "--photons") {
+        let usage = "--photons takes a number of photons and a gather radius";
+        solver.integrator = Integrator::PhotonMapping {
+            photons: args.get(i + 1).and_then(|a| a.parse().ok()).expect(usage),
+            radius: args.get(i + 2).and_then(|a| a.parse().ok()).expect(usage),
+        };
+    }
+
+    if args.iter().any(|a| a == "--spectral") {
+        solver.spectral = true;
+    }
+
+    if args
+        .iter()
+        .any(|a| a == "--transparent" || a == "--shadow-catcher")
+    {
+        solver = solver.with_transparent_background();
+    }
+
+    if args.iter().any(|a| a == "--stats") {
+        solver.stats = true;
+    }
+    if let Some(i) = args.iter().position(|a| a == "--stats-json") {
+        let path = args.get(i + 1).expect("--stats-json takes an output path");
+        solver = solver.with_stats_json(path);
+    }
+
+    // Label review renders with the scene name, frame, sample count and time
+    let burn_in = args.iter().position(|a| a == "--burn-in").map(|i| {
+        let scene = args.get(i + 1).expect("--burn-in takes a scene name");
+        BurnIn::new(scene.as_str(), solver.samples)
+    });
+
+    if args.iter().any(|a| a == "--report") {
+        println!("{}", solver.scene.report());
+        return;
+    }
+
+    match solver.prepare() {
+        Ok(report) => println!("Memory usage: {}", report),
+        Err(e) => {
+            eprintln!("Failed to prepare scene: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    // Render tiles for a coordinator started with `--workers`
+    if let Some(i) = args.iter().position(|a| a == "--worker") {
+        let addr = args
+            .get(i + 1)
+            .expect("--worker takes an address to listen on");
+        if let Err(e) = distributed::serve(&solver, addr.as_str()) {
+            eprintln!("Failed to listen on '{}': {}", addr, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let workers: Option<Vec<&str>> = args.iter().position(|a| a == "--workers").map(|i| {
+        args.get(i + 1)
+            .expect("--workers takes a comma separated list of host:port addresses")
+            .split(',')
+            .collect()
+    });
+
+    if let Some(i) = args.iter().position(|a| a == "--bake-probes") {
+        let dest = args.get(i + 1).expect("--bake-probes takes an output path");
+        let positions: Vec<Vec3> = args
+            .windows(2)
+            .filter(|w| w[0] == "--probe")
+            .map(|w| {
+                let coords: Vec<Float> = w[1].split(',').filter_map(|c| c.parse().ok()).collect();
+                match coords[..] {
+                    [x, y, z] => Vec3::new(x, y, z),
+                    _ => panic!("--probe takes a position as x,y,z"),
+                }
+            })
+            .collect();
+
+        println!("Baking {} probes...", positions.len());
+        let probes = solver.bake_probes(&positions, 4096, 0);
+        write_probes(dest, &probes).unwrap();
+        println!("Probes written to '{}'", dest);
+        return;
+    }
+
+    if let Some(i) = args.iter().position(|a| a == "--animate") {
+        let frames: u32 = args
+            .get(i + 1)
+            .and_then(|f| f.parse().ok())
+            .expect("--animate takes a number of frames");
+        let mut animation = Animation::new(0..frames, 24.0);
+        if args.iter().any(|a| a == "--frozen-noise") {
+            animation = animation.with_frozen_noise();
+        }
+        let output_transform = solver.output_transform;
+        let dir = "frames";
+        std::fs::create_dir_all(dir).unwrap();
+
+        // Orbit the camera around the spheres
+        let centre = Vec3::new(0.0, 1.0, 3.0);
+        let orbit = 3.0;
+        println!("Rendering {} frames to '{}'...", frames, dir);
+        solver.solve_animation(
+            0,
+            &animation,
+            |_, time| {
+                let angle = (time * consts::FRAC_PI_4).sin() * 0.5;
+                let rotation = Quat::from_euler(EulerRot::YXZ, angle, 0.0, 0.0);
+                SceneState::default().with_camera(PerspectiveCamera {
+                    origin: centre - rotation * Vec3::Z * orbit,
+                    rotation,
+                    horizontal_fov: 60.0,
+                })
+            },
+            |frame, film| {
+                let dest = frame_path(format!("{}/img_####.png", dir), frame);
+                let burn_in = burn_in
+                    .clone()
+                    .map(|burn_in| burn_in.with_frame(frame).with_timestamp(SystemTime::now()));
+                output_image(film, output_transform, burn_in.as_ref())
+                    .save(&dest)
+                    .unwrap();
+                film.write_exr(dest.with_extension("exr"), output_transform)
+                    .unwrap();
+                println!("Frame written to '{}'", dest.display());
+            },
+        );
+        return;
+    }
 
     println!("Beginning render...");
     let start = Instant::now();
-    let img = solver.solve(0);
+    let film = match &workers {
+        Some(workers) => distributed::coordinate(&solver, 0, workers),
+        #[cfg(feature = "gpu")]
+        None if args.iter().any(|a| a == "--gpu") => solver.solve_film_gpu(0),
+        None => solver.solve_film(0),
+    };
     let fin = Instant::now();
     println!(
         "Render complete in {} secs.",
@@ -111,6 +339,126 @@ fn main() {
     let dest = "img.png";
     println!("Writing to {}...", dest);
     let mut out_file = std::fs::File::create(dest).unwrap();
-    img.write_to(&mut out_file, ImageOutputFormat::Png).unwrap();
+    output_image(&film, solver.output_transform, burn_in.as_ref())
+        .write_to(&mut out_file, ImageOutputFormat::Png)
+        .unwrap();
+    println!("File written to '{}'", dest);
+
+    for aov in film.aovs() {
+        let dest = format!("img_{}.png", aov.name());
+        println!("Writing to {}...", dest);
+        film.aov_image(aov, solver.output_transform)
+            .expect("Film records this AOV")
+            .save(&dest)
+            .unwrap();
+        println!("File written to '{}'", dest);
+    }
+
+    #[cfg(feature = "denoise")]
+    {
+        let dest = "img_denoised.png";
+        println!("Denoising...");
+        let denoised = denoise::denoise(&film).unwrap();
+        println!("Writing to {}...", dest);
+        output_image(&denoised, solver.output_transform, burn_in.as_ref())
+            .save(dest)
+            .unwrap();
+        println!("File written to '{}'", dest);
+    }
+
+    let dest = "img.exr";
+    println!("Writing to {}...", dest);
+    film.write_exr(dest, solver.output_transform).unwrap();
     println!("File written to '{}'", dest);
 }
+
+/// The display window of `film` as an 8 bit image, with an alpha channel if the film has one
+fn output_image(film: &Film, transform: OutputTransform, burn_in: Option<&BurnIn>) -> DynamicImage {
+    if film.aovs().any(|aov| aov == Aov::Alpha) {
+        let mut image = film.to_rgba_image(transform);
+        if let Some(burn_in) = burn_in {
+            burn_in.apply(&mut image);
+        }
+        image.into()
+    } else {
+        let mut image = film.to_rgb_image(transform);
+        if let Some(burn_in) = burn_in {
+            burn_in.apply(&mut image);
+        }
+        image.into()
+    }
+}
+
+/// `compare <reference> <test> [heatmap]`: print the difference between two renders and
+/// optionally write a heatmap of where they differ
+fn compare_renders(args: &[String]) {
+    let (Some(reference), Some(test)) = (args.first(), args.get(1)) else {
+        eprintln!("Usage: compare <reference> <test> [heatmap]");
+        std::process::exit(1);
+    };
+    let open = |path: &String| {
+        ComparisonImage::open(path).unwrap_or_else(|e| {
+            eprintln!("Failed to open '{}': {}", path, e);
+            std::process::exit(1);
+        })
+    };
+
+    let comparison = match compare(&open(reference), &open(test), DEFAULT_PIXELS_PER_DEGREE) {
+        Ok(comparison) => comparison,
+        Err(e) => {
+            eprintln!("Failed to compare: {}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("RMSE: {:.6}", comparison.rmse);
+    println!("Mean FLIP: {:.6}", comparison.mean_flip);
+
+    if let Some(dest) = args.get(2) {
+        comparison.heatmap().save(dest).unwrap();
+        println!("Heatmap written to '{}'", dest);
+    }
+}
+
+/// `bench [--update] [--threshold <flip>] [--references <dir>] [scene...]`: render the
+/// canonical benchmark scenes, reporting how long each took and whether it matches its
+/// reference image. Exits with an error if any don't.
+fn bench(args: &[String]) {
+    let mut options = BenchOptions {
+        update: args.iter().any(|a| a == "--update"),
+        ..Default::default()
+    };
+    let mut values = Vec::new();
+    if let Some(i) = args.iter().position(|a| a == "--threshold") {
+        options.threshold = args
+            .get(i + 1)
+            .and_then(|a| a.parse().ok())
+            .expect("--threshold takes the largest mean FLIP difference which passes");
+        values.push(i + 1);
+    }
+    if let Some(i) = args.iter().position(|a| a == "--references") {
+        options.references = args
+            .get(i + 1)
+            .expect("--references takes a directory")
+            .into();
+        values.push(i + 1);
+    }
+    options.scenes = args
+        .iter()
+        .enumerate()
+        .filter(|(i, a)| !a.starts_with("--") && !values.contains(i))
+        .map(|(_, a)| a.clone())
+        .collect();
+
+    let results = match run_benchmarks(&options) {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("Failed to write images: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let failed = results.iter().filter(|r| r.is_failure()).count();
+    if failed > 0 {
+        eprintln!("{} of {} scenes failed", failed, results.len());
+        std::process::exit(1);
+    }
+}
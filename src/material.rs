@@ -1,8 +1,201 @@
-use glam::DVec3;
+use std::sync::Arc;
 
+use crate::{
+    float::{Float, Vec2, Vec3, Vec4},
+    spectrum::Dispersion,
+    texture::{BumpMap, Texture},
+    volume::PhaseFunction,
+};
+
+#[derive(Debug)]
 pub struct Material {
-    pub colour: DVec3,
-    pub diffusion: f64,
-    pub refractive_index: f64,
-    pub luminance: f64,
+    pub colour: Vec3,
+    /// Base colour texture, multiplied by `colour` at the UV coordinates of each hit. Colour
+    /// textures are usually loaded with [`InputTransform::Srgb`](crate::colour::InputTransform).
+    pub colour_texture: Option<Arc<Texture>>,
+    /// Roughness of reflections, from mirror-like (0.0) to fully diffuse (1.0)
+    pub diffusion: Float,
+    /// Roughness of metallic reflections, which are scattered around the mirror direction by
+    /// up to this fraction of its length, as for brushed or bead blasted metal. Reflections
+    /// scattered into the surface are absorbed.
+    pub fuzz: Float,
+    /// Roughness of transmission through refractive materials, e.g. for frosted glass
+    pub transmission_diffusion: Float,
+    pub refractive_index: Float,
+    /// Wavelength dependent refractive index, used instead of `refractive_index` when
+    /// rendering spectrally
+    pub dispersion: Option<Dispersion>,
+    /// Fraction of each colour channel absorbed per unit distance travelled inside the material
+    pub absorption: Vec3,
+    pub luminance: Float,
+    /// Scatter light as a participating medium instead of a surface, used by volumes
+    pub phase: Option<PhaseFunction>,
+    /// Scatter light travelling inside the material, for translucent materials such as skin,
+    /// wax, marble and jade. Only refractive materials are entered by light, and `absorption`
+    /// is the absorption of the medium.
+    pub subsurface: Option<Subsurface>,
+    /// When rendering with a transparent background, show only the shadows and reflections
+    /// other objects cast onto this surface where the camera sees it directly, so the render
+    /// can be composited over a photograph of the real surface
+    pub shadow_catcher: bool,
+    /// Tangent space normal map with +Y up, as in glTF. Only affects surfaces with a tangent.
+    pub normal_map: Option<Arc<Texture>>,
+    /// Only affects surfaces with a tangent, and is applied on top of any normal map
+    pub bump_map: Option<BumpMap>,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            colour: Vec3::ONE,
+            colour_texture: None,
+            diffusion: 0.0,
+            fuzz: 0.0,
+            transmission_diffusion: 0.0,
+            refractive_index: 0.0,
+            dispersion: None,
+            absorption: Vec3::ZERO,
+            luminance: 0.0,
+            phase: None,
+            subsurface: None,
+            shadow_catcher: false,
+            normal_map: None,
+            bump_map: None,
+        }
+    }
+}
+
+/// Scattering inside a translucent material, simulated by following light on a random walk
+/// through it until it leaves through the surface
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Subsurface {
+    /// Chance of each colour channel scattering per unit distance
+    pub scattering: Vec3,
+    /// Henyey-Greenstein asymmetry of each scattering event, from -1.0 to 1.0. Positive values
+    /// scatter forwards.
+    pub anisotropy: Float,
+}
+
+impl Material {
+    /// Every texture the material samples
+    pub fn textures(&self) -> impl Iterator<Item = &Arc<Texture>> {
+        self.colour_texture
+            .iter()
+            .chain(&self.normal_map)
+            .chain(self.bump_map.iter().map(|bump| &bump.texture))
+    }
+
+    /// Colour of the surface at `uv`, after applying the colour texture
+    pub fn colour_at(&self, uv: Vec2) -> Vec3 {
+        match &self.colour_texture {
+            Some(texture) => self.colour * texture.sample(uv),
+            None => self.colour,
+        }
+    }
+
+    /// An opaque metal of the given `colour`, with mirror-like reflections when `fuzz` is 0.0
+    /// getting rougher towards 1.0
+    pub fn metal(colour: Vec3, fuzz: Float) -> Self {
+        Self {
+            colour,
+            fuzz,
+            ..Default::default()
+        }
+    }
+
+    /// Marble, measured by Jensen et al. in "A Practical Model for Subsurface Light Transport",
+    /// for a scene with `units_per_mm` world units per millimetre
+    pub fn marble(units_per_mm: Float) -> Self {
+        Self::measured_subsurface(
+            Vec3::new(2.19, 2.62, 3.00),
+            Vec3::new(0.0021, 0.0041, 0.0071),
+            units_per_mm,
+        )
+    }
+
+    /// Skin, measured by Jensen et al. in "A Practical Model for Subsurface Light Transport",
+    /// for a scene with `units_per_mm` world units per millimetre
+    pub fn skin(units_per_mm: Float) -> Self {
+        Self::measured_subsurface(
+            Vec3::new(0.74, 0.88, 1.01),
+            Vec3::new(0.032, 0.17, 0.48),
+            units_per_mm,
+        )
+    }
+
+    /// A smooth translucent material from reduced scattering and absorption coefficients per
+    /// millimetre, as they are usually published
+    fn measured_subsurface(scattering: Vec3, absorption: Vec3, units_per_mm: Float) -> Self {
+        Self {
+            refractive_index: 1.3,
+            absorption: absorption / units_per_mm,
+            subsurface: Some(Subsurface {
+                scattering: scattering / units_per_mm,
+                anisotropy: 0.0,
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Refractive index for light of `wavelength` nanometres, or the fixed refractive index if
+    /// the material isn't dispersive or the light has no wavelength
+    pub fn refractive_index_at(&self, wavelength: Option<Float>) -> Float {
+        match (self.dispersion, wavelength) {
+            (Some(dispersion), Some(wavelength)) => dispersion.refractive_index(wavelength),
+            _ => self.refractive_index,
+        }
+    }
+
+    /// The normal used for shading at `uv` on a surface with geometric `normal` and `tangent`,
+    /// after applying the normal and bump maps. The w component of `tangent` is the sign of
+    /// the bitangent, and a zero tangent leaves the normal unchanged.
+    pub fn shading_normal(&self, normal: Vec3, tangent: Vec4, uv: Vec2) -> Vec3 {
+        if self.normal_map.is_none() && self.bump_map.is_none() {
+            return normal;
+        }
+        let tangent_dir = tangent.truncate();
+        let tangent_dir = (tangent_dir - normal * normal.dot(tangent_dir)).normalize_or_zero();
+        if tangent_dir == Vec3::ZERO {
+            return normal;
+        }
+        let bitangent = normal.cross(tangent_dir) * tangent.w.signum();
+
+        let mut shading = normal;
+        if let Some(map) = &self.normal_map {
+            let n = map.sample(uv) * 2.0 - 1.0;
+            shading = (tangent_dir * n.x + bitangent * n.y + normal * n.z).normalize_or_zero();
+        }
+        if let Some(bump) = &self.bump_map {
+            let slope = bump.texture.gradient(uv) * bump.strength;
+            shading -= tangent_dir * slope.x + bitangent * slope.y;
+        }
+
+        shading.try_normalize().unwrap_or(normal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colour_texture_tints_the_colour() {
+        let texture = Arc::new(Texture::new(
+            2,
+            1,
+            vec![Vec3::new(1.0, 0.5, 0.0), Vec3::new(0.0, 0.5, 1.0)],
+        ));
+        let material = Material {
+            colour: Vec3::splat(0.5),
+            colour_texture: Some(texture.clone()),
+            ..Default::default()
+        };
+
+        let left = material.colour_at(Vec2::new(0.25, 0.5));
+        assert!(left.abs_diff_eq(Vec3::new(0.5, 0.25, 0.0), 1e-4));
+        let right = material.colour_at(Vec2::new(0.75, 0.5));
+        assert!(right.abs_diff_eq(Vec3::new(0.0, 0.25, 0.5), 1e-4));
+        assert!(material.textures().any(|t| Arc::ptr_eq(t, &texture)));
+        assert_eq!(Material::default().colour_at(Vec2::ZERO), Vec3::ONE);
+    }
 }
@@ -0,0 +1,69 @@
+use std::{error::Error, fmt::Display};
+
+/// Estimated memory used by the parts of a render
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryReport {
+    /// Objects, materials, textures, the transform hierarchy and the BVH over the objects
+    pub scene: usize,
+    /// The environment map and its sampling distribution
+    pub environment: usize,
+    /// Accumulated pixel data
+    pub film: usize,
+}
+
+impl MemoryReport {
+    pub fn total(&self) -> usize {
+        self.scene + self.environment + self.film
+    }
+}
+
+impl Display for MemoryReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "scene {}, environment {}, film {}, total {}",
+            format_bytes(self.scene),
+            format_bytes(self.environment),
+            format_bytes(self.film),
+            format_bytes(self.total())
+        )
+    }
+}
+
+/// Returned when preparing a render which would use more memory than its budget allows
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryBudgetError {
+    pub report: MemoryReport,
+    pub budget: usize,
+}
+
+impl Display for MemoryBudgetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "render requires {} but the memory budget is {} ({})",
+            format_bytes(self.report.total()),
+            format_bytes(self.budget),
+            self.report
+        )
+    }
+}
+
+impl Error for MemoryBudgetError {}
+
+pub fn format_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
@@ -0,0 +1,870 @@
+use std::{
+    hint::black_box,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use glam::Vec3A;
+use rand::RngCore;
+
+#[cfg(feature = "gpu")]
+use crate::gpu::{GpuGeometry, GpuShape};
+use crate::{
+    bvh::Aabb,
+    collidable::{surface_tangent, Collideable, Collision},
+    float::{consts::PI, Float, Vec2, Vec3, Vec4},
+    material::Material,
+    ray::Ray,
+    report::ObjectInfo,
+    sampler::hash,
+    stats,
+};
+
+/// A triangle mesh. When vertex normals are given the mesh is smooth shaded by interpolating
+/// them across each triangle, otherwise each triangle is flat.
+pub struct Mesh {
+    pub positions: Vec<Vec3>,
+    /// One per position, or empty for flat shading
+    pub normals: Vec<Vec3>,
+    /// One per position, or empty if the mesh has no UVs
+    pub uvs: Vec<Vec2>,
+    /// One per position, or empty. The w component is the sign of the bitangent, which is
+    /// `normal.cross(tangent) * w` as in glTF.
+    pub tangents: Vec<Vec4>,
+    /// Indices of the corners of each triangle, counter-clockwise when viewed from the front
+    pub triangles: Vec<[u32; 3]>,
+    pub material: Arc<Material>,
+}
+
+impl Mesh {
+    /// A smooth shaded sphere made of `segments` slices around its axis and `rings` slices
+    /// from pole to pole
+    pub fn uv_sphere(
+        origin: Vec3,
+        radius: Float,
+        segments: u32,
+        rings: u32,
+        material: Arc<Material>,
+    ) -> Self {
+        let segments = segments.max(3);
+        let rings = rings.max(2);
+
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        let mut uvs = Vec::new();
+        for ring in 0..=rings {
+            let v = ring as Float / rings as Float;
+            let polar = v * PI;
+            for segment in 0..=segments {
+                let u = segment as Float / segments as Float;
+                let azimuth = u * 2.0 * PI;
+                let normal = Vec3::new(
+                    polar.sin() * azimuth.cos(),
+                    -polar.cos(),
+                    polar.sin() * azimuth.sin(),
+                );
+                positions.push(origin + normal * radius);
+                normals.push(normal);
+                uvs.push(Vec2::new(u, v));
+            }
+        }
+
+        let row = segments + 1;
+        let mut triangles = Vec::new();
+        for ring in 0..rings {
+            for segment in 0..segments {
+                let a = ring * row + segment;
+                let b = a + row;
+                if ring != 0 {
+                    triangles.push([a, b, a + 1]);
+                }
+                if ring != rings - 1 {
+                    triangles.push([a + 1, b, b + 1]);
+                }
+            }
+        }
+
+        let mut mesh = Self {
+            positions,
+            normals,
+            uvs,
+            tangents: Vec::new(),
+            triangles,
+            material,
+        };
+        mesh.generate_tangents();
+        mesh
+    }
+
+    /// Generate tangents from the normals and UVs with MikkTSpace, so normal maps baked by
+    /// other tools shade the same here. Returns false and leaves the tangents empty if the
+    /// mesh has no normals or UVs.
+    pub fn generate_tangents(&mut self) -> bool {
+        if self.normals.is_empty() || self.uvs.is_empty() {
+            self.tangents.clear();
+            return false;
+        }
+
+        let mut geometry = TangentGeometry {
+            mesh: self,
+            tangents: vec![Vec4::ZERO; self.positions.len()],
+        };
+        if !mikktspace::generate_tangents(&mut geometry) {
+            self.tangents.clear();
+            return false;
+        }
+
+        self.tangents = geometry.tangents;
+        true
+    }
+
+    /// Store the mesh in compressed form, with positions and UVs quantized to 16 bits within
+    /// their bounds, normals and tangents octahedral encoded, and 16-bit indices when there
+    /// are few enough vertices. Roughly a fifth of the size, at the cost of some precision.
+    pub fn quantize(&self) -> QuantizedMesh {
+        let positions = Quantized::new(
+            &self
+                .positions
+                .iter()
+                .map(|p| p.to_array())
+                .collect::<Vec<_>>(),
+        );
+        let uvs = Quantized::new(&self.uvs.iter().map(|uv| uv.to_array()).collect::<Vec<_>>());
+        let triangles = if self.positions.len() <= u16::MAX as usize + 1 {
+            Indices::U16(self.triangles.iter().map(|t| t.map(|i| i as u16)).collect())
+        } else {
+            Indices::U32(self.triangles.clone())
+        };
+
+        QuantizedMesh {
+            positions,
+            normals: self.normals.iter().map(|&n| encode_octahedral(n)).collect(),
+            uvs,
+            tangents: self.tangents.iter().map(|&t| encode_tangent(t)).collect(),
+            triangles,
+            material: self.material.clone(),
+        }
+    }
+
+    /// Wrap the mesh so triangles can be tested in single precision first, see
+    /// [`MixedPrecisionMesh`]
+    pub fn with_mixed_precision(self) -> MixedPrecisionMesh {
+        MixedPrecisionMesh::new(self)
+    }
+}
+
+impl TriangleSource for Mesh {
+    fn triangle_count(&self) -> usize {
+        self.triangles.len()
+    }
+
+    fn triangle(&self, i: usize) -> [usize; 3] {
+        self.triangles[i].map(|i| i as usize)
+    }
+
+    fn position(&self, i: usize) -> Vec3 {
+        self.positions[i]
+    }
+
+    fn normal(&self, i: usize) -> Option<Vec3> {
+        self.normals.get(i).copied()
+    }
+
+    fn uv(&self, i: usize) -> Option<Vec2> {
+        self.uvs.get(i).copied()
+    }
+
+    fn tangent(&self, i: usize) -> Option<Vec4> {
+        self.tangents.get(i).copied()
+    }
+}
+
+impl Collideable for Mesh {
+    fn trace(&self, ray: &Ray, _rng: &mut dyn RngCore) -> Option<Collision<'_>> {
+        trace_triangles(self, ray, &self.material)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb::from_points(self.positions.iter().copied()))
+    }
+
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of_val(self)
+            + self.positions.capacity() * std::mem::size_of::<Vec3>()
+            + self.normals.capacity() * std::mem::size_of::<Vec3>()
+            + self.uvs.capacity() * std::mem::size_of::<Vec2>()
+            + self.tangents.capacity() * std::mem::size_of::<Vec4>()
+            + self.triangles.capacity() * std::mem::size_of::<[u32; 3]>()
+    }
+
+    fn raster_triangles(&self) -> Option<Vec<[Vec3; 3]>> {
+        Some(raster_triangles(self))
+    }
+
+    fn trace_triangle(&self, ray: &Ray, index: usize) -> Option<Collision<'_>> {
+        trace_triangle(self, ray, &self.material, index)
+    }
+
+    fn info(&self) -> ObjectInfo {
+        ObjectInfo {
+            triangles: self.triangles.len(),
+            ..ObjectInfo::with_material("Mesh", &self.material)
+        }
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_shape(&self) -> Option<GpuShape<'_>> {
+        Some(GpuShape {
+            geometry: GpuGeometry::Triangles {
+                positions: self.positions.clone(),
+                normals: self.normals.clone(),
+                triangles: self.triangles.clone(),
+            },
+            material: &self.material,
+        })
+    }
+}
+
+/// A [`Mesh`] in compressed form, made with [`Mesh::quantize`]. Vertices are decompressed as
+/// each triangle is intersected, so it renders the same way as the original, slightly slower.
+pub struct QuantizedMesh {
+    positions: Quantized<3>,
+    normals: Vec<[i16; 2]>,
+    uvs: Quantized<2>,
+    tangents: Vec<[i16; 2]>,
+    triangles: Indices,
+    pub material: Arc<Material>,
+}
+
+impl QuantizedMesh {
+    /// Decompress back into a [`Mesh`]
+    pub fn dequantize(&self) -> Mesh {
+        let vertices = 0..self.positions.values.len();
+        Mesh {
+            positions: vertices.clone().map(|i| self.position(i)).collect(),
+            normals: self.normals.iter().map(|&n| decode_octahedral(n)).collect(),
+            uvs: vertices.filter_map(|i| self.uv(i)).collect(),
+            tangents: self.tangents.iter().map(|&t| decode_tangent(t)).collect(),
+            triangles: (0..self.triangle_count())
+                .map(|i| self.triangle(i).map(|i| i as u32))
+                .collect(),
+            material: self.material.clone(),
+        }
+    }
+}
+
+impl TriangleSource for QuantizedMesh {
+    fn triangle_count(&self) -> usize {
+        match &self.triangles {
+            Indices::U16(t) => t.len(),
+            Indices::U32(t) => t.len(),
+        }
+    }
+
+    fn triangle(&self, i: usize) -> [usize; 3] {
+        match &self.triangles {
+            Indices::U16(t) => t[i].map(|i| i as usize),
+            Indices::U32(t) => t[i].map(|i| i as usize),
+        }
+    }
+
+    fn position(&self, i: usize) -> Vec3 {
+        Vec3::from_array(self.positions.get(i))
+    }
+
+    fn normal(&self, i: usize) -> Option<Vec3> {
+        self.normals.get(i).map(|&n| decode_octahedral(n))
+    }
+
+    fn uv(&self, i: usize) -> Option<Vec2> {
+        (!self.uvs.values.is_empty()).then(|| Vec2::from_array(self.uvs.get(i)))
+    }
+
+    fn tangent(&self, i: usize) -> Option<Vec4> {
+        self.tangents.get(i).map(|&t| decode_tangent(t))
+    }
+}
+
+impl Collideable for QuantizedMesh {
+    fn trace(&self, ray: &Ray, _rng: &mut dyn RngCore) -> Option<Collision<'_>> {
+        trace_triangles(self, ray, &self.material)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb::from_points(
+            (0..self.positions.values.len()).map(|i| self.position(i)),
+        ))
+    }
+
+    fn memory_usage(&self) -> usize {
+        let indices = match &self.triangles {
+            Indices::U16(t) => t.capacity() * std::mem::size_of::<[u16; 3]>(),
+            Indices::U32(t) => t.capacity() * std::mem::size_of::<[u32; 3]>(),
+        };
+        std::mem::size_of_val(self)
+            + self.positions.values.capacity() * std::mem::size_of::<[u16; 3]>()
+            + self.normals.capacity() * std::mem::size_of::<[i16; 2]>()
+            + self.uvs.values.capacity() * std::mem::size_of::<[u16; 2]>()
+            + self.tangents.capacity() * std::mem::size_of::<[i16; 2]>()
+            + indices
+    }
+
+    fn raster_triangles(&self) -> Option<Vec<[Vec3; 3]>> {
+        Some(raster_triangles(self))
+    }
+
+    fn trace_triangle(&self, ray: &Ray, index: usize) -> Option<Collision<'_>> {
+        trace_triangle(self, ray, &self.material, index)
+    }
+
+    fn info(&self) -> ObjectInfo {
+        ObjectInfo {
+            triangles: self.triangle_count(),
+            ..ObjectInfo::with_material("QuantizedMesh", &self.material)
+        }
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_shape(&self) -> Option<GpuShape<'_>> {
+        let mesh = self.dequantize();
+        Some(GpuShape {
+            geometry: GpuGeometry::Triangles {
+                positions: mesh.positions,
+                normals: mesh.normals,
+                triangles: mesh.triangles,
+            },
+            material: &self.material,
+        })
+    }
+}
+
+/// How a [`MixedPrecisionMesh`] finds the closest triangle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriangleKernel {
+    /// Test every triangle at full precision, as [`Mesh`] does
+    Exact,
+    /// Test every triangle in single precision with some tolerance, and repeat the test at full
+    /// precision only for triangles which might be the closest hit
+    Mixed,
+}
+
+/// Time taken by each [`TriangleKernel`] to trace the same rays through a mesh
+#[derive(Debug, Clone, Copy)]
+pub struct KernelTimings {
+    pub exact: Duration,
+    pub mixed: Duration,
+}
+
+impl KernelTimings {
+    pub fn fastest(&self) -> TriangleKernel {
+        if self.mixed < self.exact {
+            TriangleKernel::Mixed
+        } else {
+            TriangleKernel::Exact
+        }
+    }
+}
+
+/// A [`Mesh`] with a single precision copy of its positions, made with
+/// [`Mesh::with_mixed_precision`]. With the [`TriangleKernel::Mixed`] kernel most triangles
+/// are rejected by the cheaper single precision test, and hits are always found at full
+/// precision, so it renders exactly the same as the original mesh.
+///
+/// Whether that is faster depends on the mesh and the machine, so [`Self::select_kernel`] can
+/// time both kernels and keep the faster one. With the `f32` feature both kernels run in single
+/// precision and there's nothing to gain.
+pub struct MixedPrecisionMesh {
+    pub mesh: Mesh,
+    pub kernel: TriangleKernel,
+    positions: Vec<Vec3A>,
+}
+
+impl MixedPrecisionMesh {
+    /// Relative tolerance of the single precision test, far larger than its rounding error
+    const TOLERANCE: f32 = 1e-4;
+
+    pub fn new(mesh: Mesh) -> Self {
+        let positions = mesh
+            .positions
+            .iter()
+            .map(|p| Vec3A::new(p.x as f32, p.y as f32, p.z as f32))
+            .collect();
+        Self {
+            mesh,
+            kernel: TriangleKernel::Mixed,
+            positions,
+        }
+    }
+
+    /// Trace `rays` rays from around the mesh through its bounds with each kernel, and use
+    /// whichever was faster from now on
+    pub fn select_kernel(&mut self, rays: u64) -> KernelTimings {
+        let (min, max) = self.mesh.positions.iter().fold(
+            (
+                Vec3::splat(Float::INFINITY),
+                Vec3::splat(Float::NEG_INFINITY),
+            ),
+            |(min, max), &p| (min.min(p), max.max(p)),
+        );
+        let centre = (min + max) / 2.0;
+        let radius = (max - min).length().max(Float::EPSILON);
+
+        let unit = |i: u64, j: u64| (hash(&[i, j]) >> 11) as Float / (1u64 << 53) as Float;
+        let rays: Vec<Ray> = (0..rays)
+            .map(|i| {
+                let from = Vec3::new(unit(i, 0), unit(i, 1), unit(i, 2)) * 2.0 - 1.0;
+                let origin = centre + from.normalize_or_zero() * radius;
+                let target = min + (max - min) * Vec3::new(unit(i, 3), unit(i, 4), unit(i, 5));
+                Ray {
+                    origin,
+                    dir: (target - origin).normalize_or_zero(),
+                    time: 0.0,
+                    wavelength: None,
+                    t_min: 0.0,
+                    t_max: Float::INFINITY,
+                }
+            })
+            .collect();
+
+        let time = |kernel| {
+            let start = Instant::now();
+            for ray in &rays {
+                black_box(self.closest_triangle(ray, kernel));
+            }
+            start.elapsed()
+        };
+        let timings = KernelTimings {
+            exact: time(TriangleKernel::Exact),
+            mixed: time(TriangleKernel::Mixed),
+        };
+
+        self.kernel = timings.fastest();
+        timings
+    }
+
+    fn closest_triangle(&self, ray: &Ray, kernel: TriangleKernel) -> Option<TriangleHit> {
+        match kernel {
+            TriangleKernel::Exact => closest_triangle(&self.mesh, ray),
+            TriangleKernel::Mixed => self.closest_triangle_mixed(ray),
+        }
+    }
+
+    fn closest_triangle_mixed(&self, ray: &Ray) -> Option<TriangleHit> {
+        let origin = Vec3A::new(
+            ray.origin.x as f32,
+            ray.origin.y as f32,
+            ray.origin.z as f32,
+        );
+        let dir = Vec3A::new(ray.dir.x as f32, ray.dir.y as f32, ray.dir.z as f32);
+
+        stats::record_tests(
+            "Triangle (single precision)",
+            self.mesh.triangles.len() as u64,
+        );
+        let mut closest: Option<TriangleHit> = None;
+        for &triangle in &self.mesh.triangles {
+            let corners = triangle.map(|i| i as usize);
+            let best = closest.map_or(ray.t_max as f32, |(_, t, _)| t as f32);
+            if !self.might_hit(origin, dir, corners, best) {
+                continue;
+            }
+
+            stats::record_tests("Triangle", 1);
+            let positions = corners.map(|c| self.mesh.positions[c]);
+            if let Some((t, barycentric)) = intersect(ray, positions) {
+                if closest.is_none_or(|(_, best, _)| t < best) {
+                    closest = Some((corners, t, barycentric));
+                }
+            }
+        }
+        closest
+    }
+
+    /// Conservative single precision Möller-Trumbore test. False only if the triangle is
+    /// certainly missed or certainly further than `best`, and true whenever it's too close to
+    /// call, so the full precision test makes the decision.
+    fn might_hit(&self, origin: Vec3A, dir: Vec3A, corners: [usize; 3], best: f32) -> bool {
+        let [p0, p1, p2] = corners.map(|c| self.positions[c]);
+        let edge1 = p1 - p0;
+        let edge2 = p2 - p0;
+
+        let pvec = dir.cross(edge2);
+        let det = edge1.dot(pvec);
+        let scale = edge1.length() * pvec.length();
+        if det.abs() <= scale * Self::TOLERANCE {
+            // Nearly parallel, so the single precision result can't be trusted
+            return true;
+        }
+        let inv_det = 1.0 / det;
+
+        let tvec = origin - p0;
+        let u = tvec.dot(pvec) * inv_det;
+        let qvec = tvec.cross(edge1);
+        let v = dir.dot(qvec) * inv_det;
+        let t = edge2.dot(qvec) * inv_det;
+
+        // Rounding errors in u and v grow as the ray approaches the plane of the triangle
+        let tolerance = Self::TOLERANCE * scale / det.abs();
+        let t_tolerance = tolerance * (tvec.length() + edge1.length() + edge2.length());
+        u >= -tolerance
+            && v >= -tolerance
+            && u + v <= 1.0 + tolerance
+            && t > -t_tolerance
+            && t <= best + best * tolerance + t_tolerance
+    }
+}
+
+impl Collideable for MixedPrecisionMesh {
+    fn trace(&self, ray: &Ray, _rng: &mut dyn RngCore) -> Option<Collision<'_>> {
+        let hit = self.closest_triangle(ray, self.kernel)?;
+        Some(triangle_collision(
+            &self.mesh,
+            ray,
+            &self.mesh.material,
+            hit,
+        ))
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.mesh.bounds()
+    }
+
+    fn memory_usage(&self) -> usize {
+        Collideable::memory_usage(&self.mesh) + std::mem::size_of_val(self)
+            - std::mem::size_of::<Mesh>()
+            + self.positions.capacity() * std::mem::size_of::<Vec3A>()
+    }
+
+    fn raster_triangles(&self) -> Option<Vec<[Vec3; 3]>> {
+        Some(raster_triangles(&self.mesh))
+    }
+
+    fn trace_triangle(&self, ray: &Ray, index: usize) -> Option<Collision<'_>> {
+        trace_triangle(&self.mesh, ray, &self.mesh.material, index)
+    }
+
+    fn info(&self) -> ObjectInfo {
+        ObjectInfo {
+            triangles: self.mesh.triangles.len(),
+            ..ObjectInfo::with_material("MixedPrecisionMesh", &self.mesh.material)
+        }
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_shape(&self) -> Option<GpuShape<'_>> {
+        Collideable::gpu_shape(&self.mesh)
+    }
+}
+
+enum Indices {
+    U16(Vec<[u16; 3]>),
+    U32(Vec<[u32; 3]>),
+}
+
+/// Vectors stored as 16-bit fractions of their bounding box
+struct Quantized<const N: usize> {
+    min: [Float; N],
+    step: [Float; N],
+    values: Vec<[u16; N]>,
+}
+
+impl<const N: usize> Quantized<N> {
+    fn new(values: &[[Float; N]]) -> Self {
+        let mut min = [Float::INFINITY; N];
+        let mut max = [Float::NEG_INFINITY; N];
+        for v in values {
+            for c in 0..N {
+                min[c] = min[c].min(v[c]);
+                max[c] = max[c].max(v[c]);
+            }
+        }
+        if values.is_empty() {
+            min = [0.0; N];
+            max = [0.0; N];
+        }
+
+        let step: [Float; N] = std::array::from_fn(|c| (max[c] - min[c]) / u16::MAX as Float);
+        Self {
+            min,
+            step,
+            values: values
+                .iter()
+                .map(|v| {
+                    std::array::from_fn(|c| {
+                        if step[c] > 0.0 {
+                            ((v[c] - min[c]) / step[c]).round() as u16
+                        } else {
+                            0
+                        }
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    fn get(&self, i: usize) -> [Float; N] {
+        let v = self.values[i];
+        std::array::from_fn(|c| self.min[c] + v[c] as Float * self.step[c])
+    }
+}
+
+/// Map a unit vector onto an octahedron unfolded into a square, which spreads precision evenly
+/// over all directions
+fn encode_octahedral(n: Vec3) -> [i16; 2] {
+    let n = n / (n.x.abs() + n.y.abs() + n.z.abs());
+    let p = if n.z >= 0.0 {
+        Vec2::new(n.x, n.y)
+    } else {
+        (1.0 - Vec2::new(n.y, n.x).abs()) * Vec2::new(n.x, n.y).signum()
+    };
+    (p * i16::MAX as Float).round().to_array().map(|c| c as i16)
+}
+
+fn decode_octahedral([x, y]: [i16; 2]) -> Vec3 {
+    let p = Vec2::new(x as Float, y as Float) / i16::MAX as Float;
+    let z = 1.0 - p.x.abs() - p.y.abs();
+    let p = if z >= 0.0 {
+        p
+    } else {
+        (1.0 - Vec2::new(p.y, p.x).abs()) * p.signum()
+    };
+    Vec3::new(p.x, p.y, z).normalize()
+}
+
+/// Tangents are octahedral encoded, with the bitangent sign in the lowest bit of y
+fn encode_tangent(t: Vec4) -> [i16; 2] {
+    let [x, y] = encode_octahedral(t.truncate());
+    [x, (y & !1) | i16::from(t.w < 0.0)]
+}
+
+fn decode_tangent([x, y]: [i16; 2]) -> Vec4 {
+    let w = if y & 1 == 1 { -1.0 } else { 1.0 };
+    decode_octahedral([x, y & !1]).extend(w)
+}
+
+/// Vertex data of a triangle mesh, however it's stored
+trait TriangleSource {
+    fn triangle_count(&self) -> usize;
+    fn triangle(&self, i: usize) -> [usize; 3];
+    fn position(&self, i: usize) -> Vec3;
+    /// `None` if the mesh is flat shaded
+    fn normal(&self, i: usize) -> Option<Vec3>;
+    fn uv(&self, i: usize) -> Option<Vec2>;
+    /// `None` if the mesh has no tangents
+    fn tangent(&self, i: usize) -> Option<Vec4>;
+}
+
+/// Closest triangle hit by a ray: its corners, the distance along the ray and the barycentric
+/// coordinates of the hit
+type TriangleHit = ([usize; 3], Float, Vec3);
+
+/// Find the closest triangle hit by `ray`
+fn trace_triangles<'a, M: TriangleSource>(
+    mesh: &M,
+    ray: &Ray,
+    material: &'a Arc<Material>,
+) -> Option<Collision<'a>> {
+    let hit = closest_triangle(mesh, ray)?;
+    Some(triangle_collision(mesh, ray, material, hit))
+}
+
+fn closest_triangle<M: TriangleSource>(mesh: &M, ray: &Ray) -> Option<TriangleHit> {
+    stats::record_tests("Triangle", mesh.triangle_count() as u64);
+    (0..mesh.triangle_count())
+        .filter_map(|i| {
+            let corners = mesh.triangle(i);
+            intersect(ray, corners.map(|c| mesh.position(c))).map(|(t, b)| (corners, t, b))
+        })
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+fn raster_triangles<M: TriangleSource>(mesh: &M) -> Vec<[Vec3; 3]> {
+    (0..mesh.triangle_count())
+        .map(|i| mesh.triangle(i).map(|c| mesh.position(c)))
+        .collect()
+}
+
+/// Hit of `ray` with a single triangle, for resolving a rasterized pixel
+fn trace_triangle<'a, M: TriangleSource>(
+    mesh: &M,
+    ray: &Ray,
+    material: &'a Arc<Material>,
+    index: usize,
+) -> Option<Collision<'a>> {
+    let corners = mesh.triangle(index);
+    let (t, barycentric) = intersect(ray, corners.map(|c| mesh.position(c)))?;
+    Some(triangle_collision(
+        mesh,
+        ray,
+        material,
+        (corners, t, barycentric),
+    ))
+}
+
+/// Surface details of a triangle hit found by [`closest_triangle`]
+fn triangle_collision<'a, M: TriangleSource>(
+    mesh: &M,
+    ray: &Ray,
+    material: &'a Arc<Material>,
+    (corners, t, barycentric): TriangleHit,
+) -> Collision<'a> {
+    let positions = corners.map(|c| mesh.position(c));
+    let weights = barycentric.to_array();
+    let point = ray.at(t);
+    let (normal, terminator_offset) = match corners.map(|c| mesh.normal(c)) {
+        [Some(n0), Some(n1), Some(n2)] => {
+            let normals = [n0, n1, n2];
+            (
+                (0..3).map(|i| normals[i] * weights[i]).sum(),
+                terminator_offset(positions, normals, point, weights),
+            )
+        }
+        _ => {
+            let [p0, p1, p2] = positions;
+            ((p1 - p0).cross(p2 - p0), Vec3::ZERO)
+        }
+    };
+
+    let uvs = match corners.map(|c| mesh.uv(c)) {
+        [Some(uv0), Some(uv1), Some(uv2)] => [uv0, uv1, uv2],
+        _ => [Vec2::ZERO, Vec2::X, Vec2::Y],
+    };
+    let uv = uvs[0] * weights[0] + uvs[1] * weights[1] + uvs[2] * weights[2];
+
+    let normal = normal.normalize();
+    let [p0, p1, p2] = positions;
+    let face_normal = (p1 - p0).cross(p2 - p0).normalize();
+    let geometric_normal = if face_normal.dot(normal) < 0.0 {
+        -face_normal
+    } else {
+        face_normal
+    };
+
+    let tangent = match corners.map(|c| mesh.tangent(c)) {
+        [Some(t0), Some(t1), Some(t2)] => {
+            let xyz = t0.truncate() * weights[0]
+                + t1.truncate() * weights[1]
+                + t2.truncate() * weights[2];
+            xyz.extend(t0.w)
+        }
+        _ => {
+            // Solve for the directions in which u and v increase across the triangle
+            let (e1, e2) = (p1 - p0, p2 - p0);
+            let (d1, d2) = (uvs[1] - uvs[0], uvs[2] - uvs[0]);
+            let det = d1.x * d2.y - d2.x * d1.y;
+            if det == 0.0 {
+                Vec4::ZERO
+            } else {
+                let du = (e1 * d2.y - e2 * d1.y) / det;
+                let dv = (e2 * d1.x - e1 * d2.x) / det;
+                surface_tangent(normal, du, dv)
+            }
+        }
+    };
+
+    Collision {
+        ray: ray.clone(),
+        t,
+        normal,
+        geometric_normal,
+        uv,
+        tangent,
+        terminator_offset,
+        material,
+    }
+}
+
+/// Intersect `ray` with a single triangle, returning the distance along the ray and the
+/// barycentric coordinates of the hit
+fn intersect(ray: &Ray, [p0, p1, p2]: [Vec3; 3]) -> Option<(Float, Vec3)> {
+    let edge1 = p1 - p0;
+    let edge2 = p2 - p0;
+
+    // Möller-Trumbore
+    let pvec = ray.dir.cross(edge2);
+    let det = edge1.dot(pvec);
+    if det.abs() < Float::EPSILON {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+
+    let tvec = ray.origin - p0;
+    let u = tvec.dot(pvec) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+
+    let qvec = tvec.cross(edge1);
+    let v = ray.dir.dot(qvec) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let t = edge2.dot(qvec) * inv_det;
+    ray.in_range(t).then(|| (t, Vec3::new(1.0 - u - v, u, v)))
+}
+
+/// Offset from `point` on a triangle to the surface implied by its vertex normals, from
+/// "Hacking the Shadow Terminator" (Hanika 2021). Each corner's tangent plane is used to
+/// lift the point if it lies beneath it, and the results are blended by the barycentric
+/// coordinates so the offset is zero at the corners and continuous across edges.
+fn terminator_offset(
+    positions: [Vec3; 3],
+    normals: [Vec3; 3],
+    point: Vec3,
+    weights: [Float; 3],
+) -> Vec3 {
+    (0..3)
+        .map(|i| {
+            let below = (point - positions[i]).dot(normals[i]).min(0.0);
+            -normals[i] * below * weights[i]
+        })
+        .sum()
+}
+
+/// Adapter for generating the tangents of a [`Mesh`] with the reference MikkTSpace
+/// implementation. Corners shared between triangles get the same tangent as long as they
+/// share a position, normal and UV, which is always true for an indexed mesh.
+struct TangentGeometry<'a> {
+    mesh: &'a Mesh,
+    tangents: Vec<Vec4>,
+}
+
+impl TangentGeometry<'_> {
+    fn index(&self, face: usize, vert: usize) -> usize {
+        self.mesh.triangles[face][vert] as usize
+    }
+}
+
+impl mikktspace::Geometry for TangentGeometry<'_> {
+    fn num_faces(&self) -> usize {
+        self.mesh.triangles.len()
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.mesh.positions[self.index(face, vert)]
+            .to_array()
+            .map(|c| c as f32)
+    }
+
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.mesh.normals[self.index(face, vert)]
+            .to_array()
+            .map(|c| c as f32)
+    }
+
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        self.mesh.uvs[self.index(face, vert)]
+            .to_array()
+            .map(|c| c as f32)
+    }
+
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        let i = self.index(face, vert);
+        self.tangents[i] = Vec4::from_array(tangent.map(|c| c as Float));
+    }
+}
@@ -0,0 +1,197 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use image::{ImageBuffer, Pixel};
+
+/// Width and height of a glyph of the built in font, in font pixels
+const GLYPH_SIZE: (u32, u32) = (5, 7);
+
+/// Information burnt into the corner of review renders, so frames can be identified once
+/// they've been exported and passed around
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BurnIn {
+    pub scene: String,
+    /// Frame number, for frames of an animation
+    pub frame: Option<u32>,
+    pub samples: u64,
+    pub timestamp: SystemTime,
+}
+
+impl BurnIn {
+    /// Burn-in for a still image rendered now
+    pub fn new<S: Into<String>>(scene: S, samples: u64) -> Self {
+        Self {
+            scene: scene.into(),
+            frame: None,
+            samples,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub fn with_frame(mut self, frame: u32) -> Self {
+        self.frame = Some(frame);
+        self
+    }
+
+    pub fn with_timestamp(mut self, timestamp: SystemTime) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// The line of text drawn onto the image
+    pub fn text(&self) -> String {
+        let mut fields = vec![self.scene.clone()];
+        if let Some(frame) = self.frame {
+            fields.push(format!("frame {:04}", frame));
+        }
+        fields.push(format!("{} spp", self.samples));
+        fields.push(format_timestamp(self.timestamp));
+        fields.join("  ")
+    }
+
+    /// Draw the text along the bottom left of `image` on a darkened band. The text is scaled
+    /// up with the image so it stays legible at high resolutions, and is cut off at the right
+    /// edge if the image is too narrow for it. The band is made opaque in images with alpha.
+    pub fn apply<P: Pixel<Subpixel = u8>>(&self, image: &mut ImageBuffer<P, Vec<u8>>) {
+        let scale = (image.height() / 270).max(1);
+        let padding = 2 * scale;
+        let band_height = (GLYPH_SIZE.1 * scale + 2 * padding).min(image.height());
+        let top = image.height() - band_height;
+
+        for y in top..image.height() {
+            for x in 0..image.width() {
+                image
+                    .get_pixel_mut(x, y)
+                    .apply_with_alpha(|c| (c as u32 * 2 / 5) as u8, |_| u8::MAX);
+            }
+        }
+
+        let white = [u8::MAX; 4];
+        let white = &white[..P::CHANNEL_COUNT as usize];
+        let advance = (GLYPH_SIZE.0 + 1) * scale;
+        for (i, c) in self.text().chars().enumerate() {
+            let left = padding + i as u32 * advance;
+            if left >= image.width() {
+                break;
+            }
+
+            for (row, bits) in glyph(c).into_iter().enumerate() {
+                for column in 0..GLYPH_SIZE.0 {
+                    if bits & (1 << (GLYPH_SIZE.0 - 1 - column)) == 0 {
+                        continue;
+                    }
+                    for dy in 0..scale {
+                        for dx in 0..scale {
+                            let x = left + column * scale + dx;
+                            let y = top + padding + row as u32 * scale + dy;
+                            if x < image.width() && y < image.height() {
+                                image.put_pixel(x, y, *P::from_slice(white));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Format `time` as an ISO 8601 date and time in UTC, e.g. `2024-03-01 14:05:09 UTC`
+pub fn format_timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, seconds) = (seconds / 86400, seconds % 86400);
+
+    // Days since the epoch to a proleptic Gregorian date (Hinnant's civil_from_days)
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Rows of the 5x7 bitmap for `c`, top first with the leftmost column in bit 4.
+/// Lower case letters use the upper case glyphs and unknown characters show as `?`.
+fn glyph(c: char) -> [u8; 7] {
+    match c.to_ascii_uppercase() {
+        ' ' => [0; 7],
+        '0' => [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e],
+        '1' => [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        '2' => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f],
+        '3' => [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e],
+        '4' => [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02],
+        '5' => [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e],
+        '6' => [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e],
+        '7' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e],
+        '9' => [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c],
+        'A' => [0x0e, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11],
+        'B' => [0x1e, 0x11, 0x11, 0x1e, 0x11, 0x11, 0x1e],
+        'C' => [0x0e, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0e],
+        'D' => [0x1c, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1c],
+        'E' => [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x1f],
+        'F' => [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x10],
+        'G' => [0x0e, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0f],
+        'H' => [0x11, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11],
+        'I' => [0x0e, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0c],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1f],
+        'M' => [0x11, 0x1b, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0e, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e],
+        'P' => [0x1e, 0x11, 0x11, 0x1e, 0x10, 0x10, 0x10],
+        'Q' => [0x0e, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0d],
+        'R' => [0x1e, 0x11, 0x11, 0x1e, 0x14, 0x12, 0x11],
+        'S' => [0x0f, 0x10, 0x10, 0x0e, 0x01, 0x01, 0x1e],
+        'T' => [0x1f, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0a, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0a],
+        'X' => [0x11, 0x11, 0x0a, 0x04, 0x0a, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0a, 0x04, 0x04, 0x04],
+        'Z' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1f],
+        ':' => [0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x0c, 0x00],
+        '-' => [0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x00],
+        '+' => [0x00, 0x04, 0x04, 0x1f, 0x04, 0x04, 0x00],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0c, 0x04, 0x08],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1f],
+        '#' => [0x0a, 0x0a, 0x1f, 0x0a, 0x1f, 0x0a, 0x0a],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        _ => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn timestamps() {
+        let at = |seconds| format_timestamp(UNIX_EPOCH + Duration::from_secs(seconds));
+        assert_eq!(at(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(at(1709301909), "2024-03-01 14:05:09 UTC");
+        // Leap days, including 2100 which isn't a leap year
+        assert_eq!(at(951782400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(at(4107542399), "2100-02-28 23:59:59 UTC");
+    }
+}
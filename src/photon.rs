@@ -0,0 +1,250 @@
+//! Photon mapping for caustics, the light focused onto diffuse surfaces by mirrors and glass.
+//! Path tracing only finds a caustic when a path leaving a diffuse surface happens to reach a
+//! light through the specular surfaces, so the caustics of small lights stay noisy for
+//! thousands of samples. Instead, photons are traced from the lights at the start of a render,
+//! and those reaching a diffuse surface through only specular bounces are stored. Paths look
+//! the caustics up wherever they hit a diffuse surface, and ignore the lights they then reach
+//! through only specular bounces, whose light is already in the map.
+
+use std::collections::HashMap;
+
+use glam::{IVec2, IVec3};
+use rand::{Rng, SeedableRng};
+
+use crate::{
+    camera::Camera,
+    float::{consts::PI, Float, Vec2, Vec3},
+    material::Material,
+    ray::Ray,
+    sampler::{hash, PixelSampler},
+    solver::{random_unit_vector, Interface, Solver},
+};
+
+/// Light which reached a diffuse surface through only specular bounces
+#[derive(Debug, Clone, Copy)]
+struct Photon {
+    position: Vec3,
+    /// Direction the photon was travelling in
+    dir: Vec3,
+    power: Vec3,
+}
+
+/// Photons stored in a grid of cells as wide as the lookup radius, so each lookup only searches
+/// the cells around it
+pub struct PhotonMap {
+    radius: Float,
+    cells: HashMap<IVec3, Vec<Photon>>,
+    /// Addresses of the materials of the objects photons were emitted from
+    emitters: Vec<usize>,
+    len: usize,
+}
+
+impl PhotonMap {
+    fn new(radius: Float) -> Self {
+        Self {
+            radius,
+            cells: HashMap::new(),
+            emitters: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// Number of photons stored
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn cell(&self, point: Vec3) -> IVec3 {
+        (point / self.radius).floor().as_ivec3()
+    }
+
+    fn insert(&mut self, photon: Photon) {
+        let cell = self.cell(photon.position);
+        self.cells.entry(cell).or_default().push(photon);
+        self.len += 1;
+    }
+
+    /// Light arriving per unit area at `point` on the side of the surface `normal` faces,
+    /// averaged over the photons within the lookup radius
+    pub fn irradiance(&self, point: Vec3, normal: Vec3) -> Vec3 {
+        let centre = self.cell(point);
+        let mut power = Vec3::ZERO;
+
+        for z in -1..=1 {
+            for y in -1..=1 {
+                for x in -1..=1 {
+                    let Some(photons) = self.cells.get(&(centre + IVec3::new(x, y, z))) else {
+                        continue;
+                    };
+                    for photon in photons {
+                        if photon.dir.dot(normal) < 0.0
+                            && photon.position.distance_squared(point) < self.radius * self.radius
+                        {
+                            power += photon.power;
+                        }
+                    }
+                }
+            }
+        }
+
+        power / (PI * self.radius * self.radius)
+    }
+
+    /// Whether photons were emitted from objects made of `material`
+    pub fn emits(&self, material: &Material) -> bool {
+        self.emitters
+            .contains(&(material as *const Material as usize))
+    }
+}
+
+/// Whether caustics are stored on and looked up from surfaces of `material`, which must be
+/// opaque and fully diffuse
+pub(crate) fn gathers_photons(material: &Material) -> bool {
+    material.diffusion >= 1.0
+        && material.refractive_index == 0.0
+        && material.dispersion.is_none()
+        && material.phase.is_none()
+}
+
+/// Emit `photons` photons from the lights of the scene at the middle of the shutter interval,
+/// in proportion to their power, and store those which reach a diffuse surface through only
+/// specular bounces in a map looked up within `radius`
+pub(crate) fn trace_photons<C: Camera, R: Rng + SeedableRng>(
+    solver: &Solver<C, R>,
+    photons: u64,
+    radius: Float,
+    seed: u64,
+) -> PhotonMap {
+    let time = (solver.shutter.0 + solver.shutter.1) / 2.0;
+    let mut map = PhotonMap::new(radius);
+
+    // Power emitted by each object, from Lambertian emission over its whole surface
+    let mut emitters = Vec::new();
+    for index in 0..solver.scene.objects().len() {
+        let Some(sample) = solver.scene.sample_emission(index, Vec2::splat(0.5), time) else {
+            continue;
+        };
+        let sides = if sample.two_sided { 2.0 } else { 1.0 };
+        let power = sample.material.colour * sample.material.luminance * PI * sample.area * sides;
+        emitters.push((index, power));
+        map.emitters
+            .push(sample.material as *const Material as usize);
+    }
+    let total: Float = emitters.iter().map(|(_, power)| power.dot(Vec3::ONE)).sum();
+    if total <= 0.0 {
+        return map;
+    }
+
+    for i in 0..photons {
+        let mut rng = R::seed_from_u64(hash(&[seed, i]));
+        let mut sampler =
+            PixelSampler::new(solver.sampler, IVec2::ZERO, seed, i, photons, &mut rng);
+
+        let mut pick = sampler.next_1d() * total;
+        let &(index, power) = emitters
+            .iter()
+            .find(|(_, power)| {
+                pick -= power.dot(Vec3::ONE);
+                pick < 0.0
+            })
+            .unwrap_or(&emitters[emitters.len() - 1]);
+        let probability = power.dot(Vec3::ONE) / total;
+
+        let Some(sample) = solver.scene.sample_emission(index, sampler.next_2d(), time) else {
+            continue;
+        };
+        let normal = if sample.two_sided && sampler.next_1d() < 0.5 {
+            -sample.normal
+        } else {
+            sample.normal
+        };
+        let dir = (normal + random_unit_vector(sampler.next_2d())).normalize_or_zero();
+        let mut ray = Ray {
+            origin: sample.point + normal * solver.epsilon.offset(sample.point),
+            dir: if dir == Vec3::ZERO { normal } else { dir },
+            time,
+            wavelength: None,
+            t_min: 0.0,
+            t_max: Float::INFINITY,
+        };
+        let weight = solver.sample_wavelength(&mut ray, &mut sampler);
+
+        let power = power * weight / (probability * photons as Float);
+        trace_photon(solver, ray, power, &mut sampler, &mut map);
+    }
+
+    map
+}
+
+/// Follow a photon through specular bounces, storing it if it reaches a diffuse surface after
+/// at least one of them
+fn trace_photon<C: Camera, R: Rng + SeedableRng>(
+    solver: &Solver<C, R>,
+    mut ray: Ray,
+    mut power: Vec3,
+    sampler: &mut PixelSampler<'_, R>,
+    map: &mut PhotonMap,
+) {
+    let mut media: Vec<&Material> = Vec::new();
+    let mut specular = false;
+
+    for _ in 0..solver.max_bounces {
+        let Some(c) = solver.scene.trace(&ray, sampler.rng()) else {
+            return;
+        };
+
+        // Scattering inside translucent materials is left to path tracing
+        if let Some(medium) = media.last() {
+            if medium.subsurface.is_some() {
+                return;
+            }
+            power *= (-medium.absorption * c.t * ray.dir.length()).exp();
+        }
+        if c.material.phase.is_some() {
+            return;
+        }
+
+        if gathers_photons(c.material) {
+            if specular {
+                map.insert(Photon {
+                    position: c.ray.at(c.t),
+                    dir: ray.dir.normalize(),
+                    power,
+                });
+            }
+            return;
+        }
+
+        // Only specular bounces are followed, the same way paths are scattered
+        let interface = Interface::new(&c, &media, ray.wavelength);
+        let (dir, origin) = match interface.sample_transmission(&c, sampler) {
+            Some(_) if c.material.transmission_diffusion > 0.0 => return,
+            Some(transmission_angle) => {
+                let dir = interface.refract(&c, transmission_angle);
+                media = interface.transmitted_media;
+                (dir, c.spawn_origin(dir, &solver.epsilon))
+            }
+            None if c.material.diffusion > 0.0 || c.material.fuzz > 0.0 => return,
+            None => {
+                let dir = ray.dir + c.normal * 2.0;
+                let origin = c.spawn_origin(dir, &solver.epsilon) + c.terminator_offset;
+                (dir, origin)
+            }
+        };
+
+        power *= c.material.colour_at(c.uv);
+        specular = true;
+        ray = Ray {
+            origin,
+            dir,
+            time: ray.time,
+            wavelength: ray.wavelength,
+            t_min: 0.0,
+            t_max: Float::INFINITY,
+        };
+    }
+}
@@ -0,0 +1,104 @@
+use std::{io::Write, path::Path};
+
+use crate::float::{
+    consts::{FRAC_PI_4, PI},
+    Float, Vec3,
+};
+
+/// Convolution of each spherical harmonic band with the clamped cosine lobe, turning radiance
+/// coefficients into irradiance coefficients (Ramamoorthi & Hanrahan 2001)
+const COSINE_LOBE: [Float; 3] = [PI, 2.0 * PI / 3.0, FRAC_PI_4];
+
+/// Irradiance arriving at a point from every direction, stored as 3rd order (L2) spherical
+/// harmonics. Directions are in scene space with y up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IrradianceProbe {
+    pub position: Vec3,
+    /// Coefficients ordered by band, then from -l to l within each band. The cosine lobe is
+    /// already applied, so [`irradiance`](Self::irradiance) only has to evaluate them.
+    pub coefficients: [Vec3; 9],
+}
+
+impl IrradianceProbe {
+    /// Project radiance samples from uniformly distributed directions around `position`
+    pub fn from_samples(position: Vec3, samples: &[(Vec3, Vec3)]) -> Self {
+        let mut coefficients = [Vec3::ZERO; 9];
+        for &(dir, radiance) in samples {
+            for (c, y) in coefficients.iter_mut().zip(sh_basis(dir)) {
+                *c += radiance * y;
+            }
+        }
+
+        // Each sample covers an equal share of the sphere
+        let weight = 4.0 * PI / samples.len().max(1) as Float;
+        for (i, c) in coefficients.iter_mut().enumerate() {
+            *c *= weight * COSINE_LOBE[band(i)];
+        }
+
+        Self {
+            position,
+            coefficients,
+        }
+    }
+
+    /// Irradiance on a surface facing `normal`. Multiply by albedo / π for diffuse shading.
+    pub fn irradiance(&self, normal: Vec3) -> Vec3 {
+        self.coefficients
+            .iter()
+            .zip(sh_basis(normal.normalize()))
+            .map(|(c, y)| *c * y)
+            .sum::<Vec3>()
+            .max(Vec3::ZERO)
+    }
+}
+
+/// Write `probes` as JSON, each with its position and nine RGB coefficients
+pub fn write_probes<P: AsRef<Path>>(path: P, probes: &[IrradianceProbe]) -> std::io::Result<()> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let vec3 = |v: Vec3| format!("[{}, {}, {}]", v.x, v.y, v.z);
+
+    writeln!(file, "{{")?;
+    writeln!(file, "  \"basis\": \"real_sh_l2\",")?;
+    writeln!(file, "  \"up\": \"+y\",")?;
+    writeln!(file, "  \"probes\": [")?;
+    for (i, probe) in probes.iter().enumerate() {
+        let coefficients: Vec<String> = probe.coefficients.iter().map(|&c| vec3(c)).collect();
+        writeln!(file, "    {{")?;
+        writeln!(file, "      \"position\": {},", vec3(probe.position))?;
+        writeln!(
+            file,
+            "      \"irradiance_sh\": [{}]",
+            coefficients.join(", ")
+        )?;
+        let separator = if i + 1 < probes.len() { "," } else { "" };
+        writeln!(file, "    }}{}", separator)?;
+    }
+    writeln!(file, "  ]")?;
+    writeln!(file, "}}")?;
+
+    file.flush()
+}
+
+/// Band of the `i`th coefficient
+fn band(i: usize) -> usize {
+    match i {
+        0 => 0,
+        1..=3 => 1,
+        _ => 2,
+    }
+}
+
+/// Real spherical harmonics up to band 2 evaluated in direction `d`
+fn sh_basis(d: Vec3) -> [Float; 9] {
+    [
+        0.282095,
+        0.488603 * d.y,
+        0.488603 * d.z,
+        0.488603 * d.x,
+        1.092548 * d.x * d.y,
+        1.092548 * d.y * d.z,
+        0.315392 * (3.0 * d.z * d.z - 1.0),
+        1.092548 * d.x * d.z,
+        0.546274 * (d.x * d.x - d.y * d.y),
+    ]
+}
@@ -0,0 +1,305 @@
+//! Software rasterizer for the primary visibility of previews. Triangle meshes are rasterized
+//! into a visibility buffer holding the triangle seen through the centre of each pixel, so
+//! finding what a camera ray hits costs a single triangle intersection instead of a test
+//! against every triangle of the scene. Objects which aren't meshes are still traced, but only
+//! up to the rasterized surface.
+
+use glam::{IVec2, UVec2};
+use rand::RngCore;
+
+use crate::{
+    camera::FrameFormat,
+    collidable::Collision,
+    float::{Affine3, Float, Quat, Vec2, Vec3},
+    ray::Ray,
+    scene::Scene,
+};
+
+/// Distance in front of the camera at which triangles are clipped
+const NEAR: Float = 1e-4;
+
+/// A pinhole camera in a form which can be rasterized
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Projection {
+    pub origin: Vec3,
+    pub rotation: Quat,
+    /// Distance from the pinhole to an image plane one unit wide
+    pub focal_length: Float,
+}
+
+impl Projection {
+    /// Ray through the centre of `pixel`, with y pointing up
+    pub fn ray(&self, format: FrameFormat, pixel: IVec2, time: Float) -> Ray {
+        let height = 1.0 / format.aspect_ratio();
+        let target = Vec3::new(
+            (pixel.x as Float + 0.5) / format.resolution.x as Float - 0.5,
+            (pixel.y as Float + 0.5) * height / format.resolution.y as Float - height / 2.0,
+            self.focal_length,
+        );
+
+        Ray {
+            origin: self.origin,
+            dir: self.rotation * target.normalize(),
+            time,
+            wavelength: None,
+            t_min: 0.0,
+            t_max: Float::INFINITY,
+        }
+    }
+
+    /// Position of `point`, given in camera space, on the image in pixels. Pixel centres are
+    /// at half integers.
+    fn project(&self, format: FrameFormat, point: Vec3) -> Vec2 {
+        let height = 1.0 / format.aspect_ratio();
+        let image = point.truncate() * self.focal_length / point.z;
+        Vec2::new(
+            (image.x + 0.5) * format.resolution.x as Float,
+            (image.y + height / 2.0) * format.resolution.y as Float / height,
+        )
+    }
+}
+
+/// The object and triangle nearest the camera through the centre of each pixel of a region of
+/// the image
+pub struct VisibilityBuffer {
+    projection: Projection,
+    format: FrameFormat,
+    time: Float,
+    /// First pixel covered, with y pointing up
+    origin: IVec2,
+    size: UVec2,
+    /// Index of the object in the scene and of the triangle within it, row by row
+    hits: Vec<Option<(usize, usize)>>,
+    /// Whether each object of the scene was rasterized, so is already accounted for in `hits`
+    rasterized: Vec<bool>,
+}
+
+impl VisibilityBuffer {
+    /// Rasterize every triangle mesh of `scene` as positioned at `time`, over the `size`
+    /// pixels from `origin`
+    pub fn new(
+        scene: &Scene,
+        projection: Projection,
+        format: FrameFormat,
+        origin: IVec2,
+        size: UVec2,
+        time: Float,
+    ) -> Self {
+        let mut buffer = Self {
+            projection,
+            format,
+            time,
+            origin,
+            size,
+            hits: vec![None; size.x as usize * size.y as usize],
+            rasterized: Vec::with_capacity(scene.objects().len()),
+        };
+        // Inverse distance of the nearest hit so far, which interpolates linearly across the
+        // image unlike the distance itself. Zero where nothing has been hit.
+        let mut inverse_depth = vec![0.0; buffer.hits.len()];
+
+        let view =
+            Affine3::from_rotation_translation(projection.rotation, projection.origin).inverse();
+        for (index, object) in scene.objects().iter().enumerate() {
+            let Some(triangles) = object.rendered().raster_triangles() else {
+                buffer.rasterized.push(false);
+                continue;
+            };
+            buffer.rasterized.push(true);
+
+            let to_camera = match object.node {
+                Some(node) => view * scene.node(node).world_transform().at(time),
+                None => view,
+            };
+            for (triangle, corners) in triangles.into_iter().enumerate() {
+                let (polygon, len) = clip_near(corners.map(|p| to_camera.transform_point3(p)));
+                for i in 1..len.saturating_sub(1) {
+                    buffer.fill(
+                        [polygon[0], polygon[i], polygon[i + 1]],
+                        (index, triangle),
+                        &mut inverse_depth,
+                    );
+                }
+            }
+        }
+
+        buffer
+    }
+
+    /// Record `hit` at the pixels whose centres are covered by the triangle with `corners` in
+    /// camera space, where it's nearer than what was there already
+    fn fill(&mut self, corners: [Vec3; 3], hit: (usize, usize), inverse_depth: &mut [Float]) {
+        let [a, b, c] = corners.map(|p| self.projection.project(self.format, p));
+        let area = edge(a, b, c);
+        if area == 0.0 || !area.is_finite() {
+            return;
+        }
+        let inverse_z = corners.map(|p| 1.0 / p.z);
+
+        // Pixels of the buffer whose centres are within the triangle's bounds
+        let min = a.min(b).min(c) - 0.5;
+        let max = a.max(b).max(c) - 0.5;
+        let first = (min.ceil().as_ivec2() - self.origin).max(IVec2::ZERO);
+        let last = (max.floor().as_ivec2() - self.origin).min(self.size.as_ivec2() - 1);
+
+        for y in first.y..=last.y {
+            for x in first.x..=last.x {
+                let pixel = self.origin + IVec2::new(x, y);
+                let centre = Vec2::new(pixel.x as Float, pixel.y as Float) + 0.5;
+                let weights =
+                    [edge(b, c, centre), edge(c, a, centre), edge(a, b, centre)].map(|w| w / area);
+                if weights.iter().any(|&w| w < 0.0) {
+                    continue;
+                }
+
+                let depth = (0..3).map(|i| weights[i] * inverse_z[i]).sum::<Float>();
+                let i = y as usize * self.size.x as usize + x as usize;
+                if depth > inverse_depth[i] {
+                    inverse_depth[i] = depth;
+                    self.hits[i] = Some(hit);
+                }
+            }
+        }
+    }
+
+    /// Find what the ray through the centre of `pixel`, with y pointing up, hits. Returns the
+    /// ray and its collision, or `None` if the pixel is outside the buffer.
+    pub fn trace<'a>(
+        &self,
+        scene: &'a Scene,
+        pixel: IVec2,
+        rng: &mut dyn RngCore,
+    ) -> Option<(Ray, Option<Collision<'a>>)> {
+        let offset = pixel - self.origin;
+        if offset.min_element() < 0
+            || offset.x >= self.size.x as i32
+            || offset.y >= self.size.y as i32
+        {
+            return None;
+        }
+        let ray = self.projection.ray(self.format, pixel, self.time);
+
+        let hit = match self.hits[offset.y as usize * self.size.x as usize + offset.x as usize] {
+            None => None,
+            Some((object, triangle)) => match scene.trace_triangle(object, triangle, &ray) {
+                Some(c) => Some(c),
+                // The centre is on the edge of the triangle and rounding made the ray miss it
+                None => {
+                    let collision = scene.trace(&ray, rng);
+                    return Some((ray, collision));
+                }
+            },
+        };
+
+        // Anything else has to be nearer than the rasterized surface to be seen
+        let nearer = Ray {
+            t_max: hit.as_ref().map_or(ray.t_max, |c| c.t),
+            ..ray.clone()
+        };
+        let other = scene.trace_where(&nearer, rng, |i| !self.rasterized[i]);
+        Some((ray, other.or(hit)))
+    }
+}
+
+/// Twice the signed area of the triangle `a`, `b`, `p`
+fn edge(a: Vec2, b: Vec2, p: Vec2) -> Float {
+    (b - a).perp_dot(p - a)
+}
+
+/// The part of a triangle in camera space in front of the near plane, as a convex polygon of
+/// up to four corners and the number of corners
+fn clip_near(corners: [Vec3; 3]) -> ([Vec3; 4], usize) {
+    let mut polygon = [Vec3::ZERO; 4];
+    let mut len = 0;
+    for i in 0..3 {
+        let (p, q) = (corners[i], corners[(i + 1) % 3]);
+        if p.z >= NEAR {
+            polygon[len] = p;
+            len += 1;
+        }
+        if (p.z >= NEAR) != (q.z >= NEAR) {
+            polygon[len] = p.lerp(q, (NEAR - p.z) / (q.z - p.z));
+            len += 1;
+        }
+    }
+    (polygon, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    use super::*;
+    use crate::{collidable::Sphere, material::Material, mesh::Mesh};
+
+    #[test]
+    fn matches_traced_visibility() {
+        let material = Arc::new(Material::default());
+        let mut scene = Scene::new();
+        scene.add_object(Mesh::uv_sphere(
+            Vec3::new(-0.5, 0.0, 4.0),
+            1.0,
+            16,
+            8,
+            material.clone(),
+        ));
+        // A mesh on a node, partly in front of an analytic sphere
+        let node = scene.add_node(
+            None,
+            Affine3::from_scale_rotation_translation(
+                Vec3::splat(0.5),
+                Quat::from_rotation_y(0.3),
+                Vec3::new(0.8, 0.2, 3.0),
+            ),
+        );
+        scene.add_object_to(
+            node,
+            Mesh::uv_sphere(Vec3::ZERO, 1.0, 12, 6, material.clone()),
+        );
+        scene.add_object(Sphere {
+            origin: Vec3::new(0.9, 0.0, 3.5),
+            radius: 0.6,
+            material: material.clone(),
+        });
+        // Crosses the near plane, so has to be clipped
+        scene.add_object(Mesh {
+            positions: vec![
+                Vec3::new(-5.0, -1.0, -2.0),
+                Vec3::new(5.0, -1.0, -2.0),
+                Vec3::new(0.0, -1.0, 20.0),
+            ],
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            tangents: Vec::new(),
+            triangles: vec![[0, 2, 1]],
+            material,
+        });
+
+        let projection = Projection {
+            origin: Vec3::ZERO,
+            rotation: Quat::IDENTITY,
+            focal_length: 0.8,
+        };
+        let format = FrameFormat::new(UVec2::new(48, 32));
+        let origin = IVec2::new(-2, -2);
+        let size = UVec2::new(52, 36);
+        let buffer = VisibilityBuffer::new(&scene, projection, format, origin, size, 0.0);
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        for y in 0..size.y as i32 {
+            for x in 0..size.x as i32 {
+                let pixel = origin + IVec2::new(x, y);
+                let (ray, hit) = buffer.trace(&scene, pixel, &mut rng).unwrap();
+                let traced = scene.trace(&ray, &mut rng);
+                match (hit, traced) {
+                    (Some(a), Some(b)) => assert!((a.t - b.t).abs() < 1e-6, "{:?}", pixel),
+                    (None, None) => {}
+                    _ => panic!("visibility differs at {:?}", pixel),
+                }
+            }
+        }
+        assert!(buffer.trace(&scene, origin - 1, &mut rng).is_none());
+    }
+}
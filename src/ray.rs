@@ -1,13 +1,55 @@
-use glam::DVec3;
+use crate::float::{Float, Vec3};
 
 #[derive(Debug, Clone)]
 pub struct Ray {
-    pub origin: DVec3,
-    pub dir: DVec3,
+    pub origin: Vec3,
+    pub dir: Vec3,
+    /// Time within the shutter interval, from 0.0 to 1.0
+    pub time: Float,
+    /// Wavelength of the light carried in nanometres, only set in spectral mode
+    pub wavelength: Option<Float>,
+    /// Hits at or before this distance along the ray are ignored. Distances are in multiples
+    /// of `dir`, like the `t` of a collision.
+    pub t_min: Float,
+    /// Hits beyond this distance along the ray are ignored
+    pub t_max: Float,
 }
 
 impl Ray {
-    pub fn at(&self, t: f64) -> DVec3 {
+    pub fn at(&self, t: Float) -> Vec3 {
         self.origin + self.dir * t
     }
+
+    /// Whether a hit at distance `t` along the ray is within its range
+    pub fn in_range(&self, t: Float) -> bool {
+        t > self.t_min && t <= self.t_max
+    }
+}
+
+/// How far rays leaving a surface start from it, so that rounding errors in the hit point don't
+/// make them hit the same surface again. Too small an offset gives shadow acne, too large one
+/// lets light leak through thin objects and into corners.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EpsilonPolicy {
+    /// Smallest offset, in world units
+    pub absolute: Float,
+    /// Offset per unit of the largest coordinate of the hit point, since rounding errors grow
+    /// with the distance from the origin
+    pub relative: Float,
+}
+
+impl EpsilonPolicy {
+    /// Distance to move away from a surface at `point`
+    pub fn offset(&self, point: Vec3) -> Float {
+        self.absolute + self.relative * point.abs().max_element()
+    }
+}
+
+impl Default for EpsilonPolicy {
+    fn default() -> Self {
+        Self {
+            absolute: 1e-5,
+            relative: 32.0 * Float::EPSILON,
+        }
+    }
 }
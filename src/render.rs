@@ -0,0 +1,276 @@
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use futures_core::Stream;
+use rand::{Rng, SeedableRng};
+
+use crate::{
+    camera::Camera,
+    film::Film,
+    solver::{FrameState, Solver},
+    tiles::{generate_tiles, CancellationToken, Tile},
+};
+
+/// Progress made by one step of a [`Render`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderEvent {
+    /// A tile has been rendered into the film
+    Tile {
+        tile: Tile,
+        completed: usize,
+        total: usize,
+    },
+    /// The firefly pass has finished, re-rendering `pixels` pixels
+    FireflyPass { pixels: usize },
+}
+
+/// A render in progress. Each call to [`step`](Render::step) renders one tile, then the
+/// firefly pass if enabled, and the film can be inspected in between.
+pub struct Render<'a, C: Camera, R: Rng + SeedableRng> {
+    solver: &'a Solver<C, R>,
+    frame: FrameState,
+    film: Film,
+    seed: u64,
+    tiles: Vec<Tile>,
+    completed: usize,
+    firefly_pass_done: bool,
+}
+
+impl<'a, C: Camera, R: Rng + SeedableRng> Render<'a, C, R> {
+    pub(crate) fn new(solver: &'a Solver<C, R>, seed: u64) -> Self {
+        let frame = solver.start_frame(seed);
+        let film = solver.new_film();
+        let tiles = generate_tiles(film.data_size, solver.tile_size, solver.tile_order);
+        Self {
+            solver,
+            frame,
+            film,
+            seed,
+            tiles,
+            completed: 0,
+            firefly_pass_done: solver.firefly_pass.is_none(),
+        }
+    }
+
+    /// The film rendered so far. Tiles which haven't been rendered yet are black.
+    pub fn film(&self) -> &Film {
+        &self.film
+    }
+
+    pub fn into_film(self) -> Film {
+        self.film
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.completed == self.tiles.len() && self.firefly_pass_done
+    }
+
+    /// Do the next piece of work, returning `None` once the render is finished
+    pub fn step(&mut self) -> Option<RenderEvent> {
+        if let Some(&tile) = self.tiles.get(self.completed) {
+            self.solver
+                .render_tile(&mut self.film, &tile, self.seed, &self.frame);
+            self.completed += 1;
+            return Some(RenderEvent::Tile {
+                tile,
+                completed: self.completed,
+                total: self.tiles.len(),
+            });
+        }
+
+        if !self.firefly_pass_done {
+            self.firefly_pass_done = true;
+            let pass = self.solver.firefly_pass.expect("Checked when created");
+            let pixels =
+                self.solver
+                    .remove_fireflies(&mut self.film, &pass, self.seed, &self.frame);
+            return Some(RenderEvent::FireflyPass { pixels });
+        }
+
+        None
+    }
+}
+
+/// A render running on a worker thread, as a [`Stream`] of the events of a [`Render`]. The
+/// photon map, the visibility buffer and every tile are rendered on the worker, so polling
+/// never blocks the executor, and the task is woken as each tile finishes.
+///
+/// Dropping the stream cancels the render after the current tile.
+pub struct RenderStream {
+    progress: Arc<Mutex<Progress>>,
+    cancel: CancellationToken,
+}
+
+/// What the worker of a [`RenderStream`] has done that the stream hasn't returned yet
+#[derive(Default)]
+struct Progress {
+    events: VecDeque<RenderEvent>,
+    film: Option<Film>,
+    finished: bool,
+    waker: Option<Waker>,
+}
+
+impl RenderStream {
+    pub(crate) fn new<C, R>(solver: Arc<Solver<C, R>>, seed: u64) -> Self
+    where
+        C: Camera + Send + Sync + 'static,
+        R: Rng + SeedableRng + 'static,
+    {
+        let progress = Arc::new(Mutex::new(Progress::default()));
+        let cancel = CancellationToken::new();
+
+        let worker = WorkerGuard(progress.clone());
+        let cancelled = cancel.clone();
+        thread::spawn(move || {
+            let mut render = Render::new(&solver, seed);
+            while !cancelled.is_cancelled() {
+                let Some(event) = render.step() else {
+                    break;
+                };
+                let mut progress = worker.lock();
+                progress.events.push_back(event);
+                progress.wake();
+            }
+            worker.lock().film = Some(render.into_film());
+        });
+
+        Self { progress, cancel }
+    }
+
+    /// Stop the render after the current tile. The stream ends once the worker has stopped.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// The rendered film, once the stream has ended
+    pub fn into_film(self) -> Option<Film> {
+        lock(&self.progress).film.take()
+    }
+}
+
+impl Drop for RenderStream {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+impl Stream for RenderStream {
+    type Item = RenderEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut progress = lock(&self.progress);
+        if let Some(event) = progress.events.pop_front() {
+            Poll::Ready(Some(event))
+        } else if progress.finished {
+            Poll::Ready(None)
+        } else {
+            progress.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Progress {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Held by the worker of a [`RenderStream`], marking the render finished when the worker stops,
+/// even if it panics, so the stream can't wait forever
+struct WorkerGuard(Arc<Mutex<Progress>>);
+
+impl WorkerGuard {
+    fn lock(&self) -> MutexGuard<'_, Progress> {
+        lock(&self.0)
+    }
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        let mut progress = self.lock();
+        progress.finished = true;
+        progress.wake();
+    }
+}
+
+fn lock(progress: &Mutex<Progress>) -> MutexGuard<'_, Progress> {
+    progress.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        task::Wake,
+        thread::{self, Thread},
+    };
+
+    use glam::UVec2;
+    use rand::rngs::SmallRng;
+
+    use super::*;
+    use crate::{
+        camera::PerspectiveCamera,
+        collidable::Sphere,
+        float::{Quat, Vec3},
+        material::Material,
+    };
+
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    #[test]
+    fn stream_matches_blocking_render() {
+        let camera = PerspectiveCamera {
+            origin: Vec3::ZERO,
+            rotation: Quat::IDENTITY,
+            horizontal_fov: 60.0,
+        };
+        let mut solver = Solver::<_, SmallRng>::new(camera, UVec2::new(24, 16))
+            .with_samples(2)
+            .with_tile_size(8);
+        solver.scene.add_object(Sphere {
+            origin: Vec3::new(0.0, 0.0, 3.0),
+            radius: 1.0,
+            material: Arc::new(Material::default()),
+        });
+        let solver = Arc::new(solver);
+        let expected = solver
+            .solve_with(7, &CancellationToken::new(), |_| {})
+            .to_rgb_image(solver.output_transform);
+
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut stream = solver.clone().render_stream(7);
+        let mut tiles = 0;
+        loop {
+            match Pin::new(&mut stream).poll_next(&mut cx) {
+                Poll::Ready(Some(RenderEvent::Tile {
+                    completed, total, ..
+                })) => {
+                    tiles += 1;
+                    assert_eq!((completed, total), (tiles, 6));
+                }
+                Poll::Ready(Some(RenderEvent::FireflyPass { .. })) => unreachable!(),
+                Poll::Ready(None) => break,
+                Poll::Pending => thread::park(),
+            }
+        }
+
+        assert_eq!(tiles, 6);
+        let film = stream.into_film().unwrap();
+        assert_eq!(film.to_rgb_image(solver.output_transform), expected);
+    }
+}
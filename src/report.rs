@@ -0,0 +1,97 @@
+use std::{collections::BTreeMap, fmt::Display, sync::Arc};
+
+use crate::{bvh::BvhStats, material::Material, memory::format_bytes};
+
+/// Description of a single object, used to build a [`SceneReport`]
+#[derive(Debug, Clone, Default)]
+pub struct ObjectInfo {
+    /// Name of the type of object, e.g. "Sphere"
+    pub kind: &'static str,
+    pub triangles: usize,
+    /// Whether the object emits light
+    pub emissive: bool,
+    /// Materials the object is made of, which may be shared with other objects
+    pub materials: Vec<Arc<Material>>,
+}
+
+impl ObjectInfo {
+    pub fn new(kind: &'static str) -> Self {
+        Self {
+            kind,
+            ..Default::default()
+        }
+    }
+
+    /// Info for an object made of a single `material`
+    pub fn with_material(kind: &'static str, material: &Arc<Material>) -> Self {
+        Self {
+            kind,
+            emissive: material.luminance > 0.0,
+            materials: vec![material.clone()],
+            ..Default::default()
+        }
+    }
+}
+
+/// Summary of the complexity of a scene, to help understand render performance before
+/// rendering it
+#[derive(Debug, Clone, Default)]
+pub struct SceneReport {
+    /// Number of objects of each kind
+    pub objects: BTreeMap<&'static str, usize>,
+    pub triangles: usize,
+    pub lights: usize,
+    /// Bytes used by the distinct textures of every object, set by
+    /// [`Scene::report`](crate::scene::Scene::report)
+    pub texture_memory: usize,
+    pub nodes: usize,
+    /// Length of the longest chain of parented nodes
+    pub hierarchy_depth: usize,
+    /// Shape of the BVH over the objects, set by [`Scene::report`](crate::scene::Scene::report)
+    pub bvh: BvhStats,
+    pub memory: usize,
+}
+
+impl SceneReport {
+    pub fn add_object(&mut self, info: &ObjectInfo) {
+        *self.objects.entry(info.kind).or_default() += 1;
+        self.triangles += info.triangles;
+        if info.emissive {
+            self.lights += 1;
+        }
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.objects.values().sum()
+    }
+}
+
+impl Display for SceneReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Objects: {}", self.object_count())?;
+        for (kind, count) in &self.objects {
+            writeln!(f, "  {kind}: {count}")?;
+        }
+        writeln!(f, "Triangles: {}", self.triangles)?;
+        writeln!(f, "Lights: {}", self.lights)?;
+        writeln!(f, "Texture memory: {}", format_bytes(self.texture_memory))?;
+        writeln!(
+            f,
+            "BVH: {} nodes (max depth {}, SAH cost {:.2})",
+            self.bvh.nodes, self.bvh.depth, self.bvh.sah_cost
+        )?;
+        writeln!(
+            f,
+            "Nodes: {} (max depth {})",
+            self.nodes, self.hierarchy_depth
+        )?;
+        write!(f, "Scene memory: {}", format_bytes(self.memory))
+    }
+}
+
+/// The name of a type without its module path or generic parameters
+pub fn short_type_name<T: ?Sized>() -> &'static str {
+    let name = std::any::type_name::<T>();
+    let name = &name[..name.find('<').unwrap_or(name.len())];
+    name.rsplit("::").next().unwrap_or(name)
+}
@@ -0,0 +1,274 @@
+use glam::IVec2;
+use rand::Rng;
+
+use crate::float::{Float, Vec2};
+
+/// Strategy used to generate the random numbers for each sample of a pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sampler {
+    /// Independent uniform random numbers
+    Random,
+    /// Jittered samples, one per stratum of a grid covering the sample domain
+    Stratified,
+    /// Halton sequence, with a random per-pixel rotation of each dimension
+    Halton,
+    /// Scrambled (0,2)-sequence Sobol points, padded across dimensions
+    Sobol,
+}
+
+/// Primes used as the bases of each Halton dimension. Dimensions beyond these fall back to
+/// uniform random numbers.
+const PRIMES: [u64; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+/// Generates the sample values for a single sample of a single pixel. Each call to `next_1d`
+/// or `next_2d` consumes the next dimension of the sample vector. Structured samplers are
+/// scrambled by the pixel and a seed, so renders with different seeds get different noise.
+pub struct PixelSampler<'a, R: Rng> {
+    sampler: Sampler,
+    pixel_hash: u64,
+    index: u64,
+    samples: u64,
+    dimension: u64,
+    rng: &'a mut R,
+}
+
+impl<'a, R: Rng> PixelSampler<'a, R> {
+    pub fn new(
+        sampler: Sampler,
+        pixel: IVec2,
+        seed: u64,
+        index: u64,
+        samples: u64,
+        rng: &'a mut R,
+    ) -> Self {
+        Self {
+            sampler,
+            pixel_hash: hash(&[seed, pixel.x as u64, pixel.y as u64]),
+            index,
+            samples: samples.max(1),
+            dimension: 0,
+            rng,
+        }
+    }
+
+    /// The underlying random number generator, for consumers that need unstructured randomness
+    pub fn rng(&mut self) -> &mut R {
+        self.rng
+    }
+
+    pub fn next_1d(&mut self) -> Float {
+        let dim = self.dimension;
+        self.dimension += 1;
+        let seed = hash(&[self.pixel_hash, dim]);
+
+        match self.sampler {
+            Sampler::Random => self.rng.gen_range(0.0..1.0),
+            Sampler::Stratified => {
+                let stratum = permute(self.index % self.samples, self.samples, seed);
+                (stratum as Float + self.rng.gen_range(0.0..1.0)) / self.samples as Float
+            }
+            Sampler::Halton => match PRIMES.get(dim as usize) {
+                Some(&base) => rotate(radical_inverse(base, self.index), seed),
+                None => self.rng.gen_range(0.0..1.0),
+            },
+            Sampler::Sobol => {
+                let index = permute(self.index % self.samples, self.samples, seed);
+                let bits = van_der_corput(index as u32) ^ (seed >> 32) as u32;
+                to_unit(bits)
+            }
+        }
+    }
+
+    pub fn next_2d(&mut self) -> Vec2 {
+        match self.sampler {
+            Sampler::Random | Sampler::Halton => Vec2::new(self.next_1d(), self.next_1d()),
+            Sampler::Stratified => {
+                let dim = self.dimension;
+                self.dimension += 2;
+                let seed = hash(&[self.pixel_hash, dim]);
+
+                let nx = (self.samples as Float).sqrt().ceil() as u64;
+                let ny = self.samples.div_ceil(nx);
+                let stratum = permute(self.index % (nx * ny), nx * ny, seed);
+
+                Vec2::new(
+                    ((stratum % nx) as Float + self.rng.gen_range(0.0..1.0)) / nx as Float,
+                    ((stratum / nx) as Float + self.rng.gen_range(0.0..1.0)) / ny as Float,
+                )
+            }
+            Sampler::Sobol => {
+                let dim = self.dimension;
+                self.dimension += 2;
+                let seed = hash(&[self.pixel_hash, dim]);
+
+                let index = permute(self.index % self.samples, self.samples, seed) as u32;
+                Vec2::new(
+                    to_unit(van_der_corput(index) ^ seed as u32),
+                    to_unit(sobol_2(index) ^ (seed >> 32) as u32),
+                )
+            }
+        }
+    }
+}
+
+/// Hashes a list of values into a well distributed 64 bit value.
+pub fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0x9E37_79B9_7F4A_7C15, |h, v| {
+        let mut x = h ^ v.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        x ^ (x >> 31)
+    })
+}
+
+/// Returns the `i`th element of a random permutation of `0..n` determined by `seed`
+/// (Kensler, "Correlated Multi-Jittered Sampling").
+fn permute(i: u64, n: u64, seed: u64) -> u64 {
+    if n <= 1 {
+        return 0;
+    }
+
+    let seed = seed as u32;
+    let l = n as u32;
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+
+    let mut v = i as u32;
+    loop {
+        v ^= seed;
+        v = v.wrapping_mul(0xe170893d);
+        v ^= seed >> 16;
+        v ^= (v & w) >> 4;
+        v ^= seed >> 8;
+        v = v.wrapping_mul(0x0929eb3f);
+        v ^= seed >> 23;
+        v ^= (v & w) >> 1;
+        v = v.wrapping_mul(1 | seed >> 27);
+        v = v.wrapping_mul(0x6935fa69);
+        v ^= (v & w) >> 11;
+        v = v.wrapping_mul(0x74dcb303);
+        v ^= (v & w) >> 2;
+        v = v.wrapping_mul(0x9e501cc3);
+        v ^= (v & w) >> 2;
+        v = v.wrapping_mul(0xc860a3df);
+        v &= w;
+        v ^= v >> 5;
+        if v < l {
+            break;
+        }
+    }
+
+    (v.wrapping_add(seed) % l) as u64
+}
+
+fn radical_inverse(base: u64, mut i: u64) -> Float {
+    let inv_base = 1.0 / base as Float;
+    let mut inv = inv_base;
+    let mut out = 0.0;
+    while i > 0 {
+        out += (i % base) as Float * inv;
+        i /= base;
+        inv *= inv_base;
+    }
+    out
+}
+
+/// Cranley-Patterson rotation of a sample value by a random offset derived from `seed`
+fn rotate(value: Float, seed: u64) -> Float {
+    (value + to_unit(seed as u32)).fract()
+}
+
+fn van_der_corput(i: u32) -> u32 {
+    i.reverse_bits()
+}
+
+/// Second dimension of the Sobol sequence
+fn sobol_2(mut i: u32) -> u32 {
+    let mut v = 1 << 31;
+    let mut out = 0;
+    while i != 0 {
+        if i & 1 != 0 {
+            out ^= v;
+        }
+        i >>= 1;
+        v ^= v >> 1;
+    }
+    out
+}
+
+fn to_unit(bits: u32) -> Float {
+    bits as Float / (u32::MAX as Float + 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    use super::*;
+
+    /// The first `samples` 2D samples of a pixel, each from a new sample vector
+    fn samples_2d(sampler: Sampler, samples: u64) -> Vec<Vec2> {
+        let mut rng = SmallRng::seed_from_u64(0);
+        (0..samples)
+            .map(|i| {
+                PixelSampler::new(sampler, IVec2::new(3, 5), 7, i, samples, &mut rng).next_2d()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn low_discrepancy_sequences() {
+        assert_eq!(radical_inverse(2, 1), 0.5);
+        assert_eq!(radical_inverse(2, 3), 0.75);
+        assert!((radical_inverse(3, 5) - 7.0 / 9.0).abs() < 1e-6);
+        let sobol: Vec<u32> = (0..4).map(sobol_2).collect();
+        assert_eq!(sobol, [0, 1 << 31, 3 << 30, 1 << 30]);
+    }
+
+    #[test]
+    fn stratified_and_sobol_cover_every_stratum() {
+        for sampler in [Sampler::Stratified, Sampler::Sobol] {
+            let mut covered = [false; 16];
+            for sample in samples_2d(sampler, 16) {
+                assert!(sample.min_element() >= 0.0 && sample.max_element() < 1.0);
+                let cell = (sample * 4.0).floor();
+                covered[cell.y as usize * 4 + cell.x as usize] = true;
+            }
+            assert!(covered.iter().all(|&c| c), "{:?} missed a stratum", sampler);
+        }
+    }
+
+    #[test]
+    fn halton_dimensions_are_evenly_spread() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        // The first 16 samples in base 2 fall one in each sixteenth, and the first 9 in base 3
+        // one in each ninth
+        let mut sixteenths = [false; 16];
+        let mut ninths = [false; 9];
+        for i in 0..16 {
+            let mut sampler = PixelSampler::new(Sampler::Halton, IVec2::ZERO, 0, i, 16, &mut rng);
+            sixteenths[(sampler.next_1d() * 16.0) as usize] = true;
+            let y = sampler.next_1d();
+            if i < 9 {
+                ninths[(y * 9.0) as usize] = true;
+            }
+        }
+        assert!(sixteenths.iter().chain(&ninths).all(|&c| c));
+    }
+
+    #[test]
+    fn seeds_change_the_samples() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut first =
+            |seed| PixelSampler::new(Sampler::Sobol, IVec2::ZERO, seed, 0, 16, &mut rng).next_2d();
+        assert_eq!(first(1), first(1));
+        assert_ne!(first(1), first(2));
+    }
+}
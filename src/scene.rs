@@ -0,0 +1,700 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use rand::RngCore;
+
+use crate::{
+    bvh::{Aabb, Bvh},
+    collidable::{Collideable, Collision, EmissionSample},
+    float::{Affine3, Float, Vec2, Vec4},
+    lod::LodView,
+    ray::Ray,
+    report::{ObjectInfo, SceneReport},
+    stats,
+    texture::distinct_memory_usage,
+    transform::AnimatedTransform,
+};
+
+/// Handle to a node in a [`Scene`]'s transform hierarchy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId(usize);
+
+/// Handle to an object in a [`Scene`], which stays valid when other objects are removed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjectId(u64);
+
+/// A transform in the scene hierarchy. The world transform of a node is the transform of its
+/// parent followed by its own local transform.
+pub struct Node {
+    pub parent: Option<NodeId>,
+    local: AnimatedTransform,
+    world: AnimatedTransform,
+    /// Inverse of the world transform at the start of the shutter interval
+    inverse: Affine3,
+}
+
+impl Node {
+    pub fn local_transform(&self) -> AnimatedTransform {
+        self.local
+    }
+
+    pub fn world_transform(&self) -> AnimatedTransform {
+        self.world
+    }
+
+    /// Inverse of the world transform at `time`
+    fn inverse_at(&self, time: Float) -> Affine3 {
+        if self.world.is_animated() {
+            self.world.at(time).inverse()
+        } else {
+            self.inverse
+        }
+    }
+}
+
+/// An object in the scene, optionally attached to a node of the hierarchy. Objects without a
+/// node are positioned directly in world space.
+pub struct SceneObject {
+    pub node: Option<NodeId>,
+    pub collidable: Box<dyn Collideable>,
+    /// Level of detail chosen for this scene's camera. It's kept here rather than on the
+    /// collidable, which may be shared with scenes rendered from other cameras.
+    level: AtomicUsize,
+}
+
+impl SceneObject {
+    fn new(node: Option<NodeId>, collidable: Box<dyn Collideable>) -> Self {
+        Self {
+            node,
+            collidable,
+            level: AtomicUsize::new(0),
+        }
+    }
+
+    /// Index of the selected level of detail, 0 being the most detailed
+    pub fn level_of_detail(&self) -> usize {
+        self.level.load(Ordering::Relaxed)
+    }
+
+    /// The object as rendered at its selected level of detail
+    pub fn rendered(&self) -> &dyn Collideable {
+        self.collidable
+            .at_level_of_detail(self.level_of_detail())
+            .unwrap_or(&*self.collidable)
+    }
+}
+
+/// A set of edits to apply to a scene at once with [`Scene::apply`], e.g. from an interactive
+/// editor
+#[derive(Default)]
+pub struct SceneDiff {
+    /// New local transforms for nodes of the hierarchy
+    pub transforms: Vec<(NodeId, AnimatedTransform)>,
+    pub added: Vec<SceneObject>,
+    pub removed: Vec<ObjectId>,
+}
+
+impl SceneDiff {
+    pub fn with_transform<T: Into<AnimatedTransform>>(
+        mut self,
+        node: NodeId,
+        transform: T,
+    ) -> Self {
+        self.transforms.push((node, transform.into()));
+        self
+    }
+
+    /// Add an object positioned directly in world space
+    pub fn with_object<C: Collideable + 'static>(mut self, collidable: C) -> Self {
+        self.added
+            .push(SceneObject::new(None, Box::new(collidable)));
+        self
+    }
+
+    /// Add an object positioned relative to `node`
+    pub fn with_object_at<C: Collideable + 'static>(mut self, node: NodeId, collidable: C) -> Self {
+        self.added
+            .push(SceneObject::new(Some(node), Box::new(collidable)));
+        self
+    }
+
+    pub fn without_object(mut self, id: ObjectId) -> Self {
+        self.removed.push(id);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transforms.is_empty() && self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+pub struct Scene {
+    nodes: Vec<Node>,
+    objects: Vec<SceneObject>,
+    /// Handle of each object, in the same order
+    object_ids: Vec<ObjectId>,
+    /// Index of each object in `objects` by its handle
+    object_index: HashMap<ObjectId, usize>,
+    next_object_id: u64,
+    /// Hierarchy over the world space bounds of the objects, numbered as in `objects`
+    bvh: Bvh,
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scene {
+    pub fn new() -> Self {
+        Self {
+            nodes: Vec::new(),
+            objects: Vec::new(),
+            object_ids: Vec::new(),
+            object_index: HashMap::new(),
+            next_object_id: 0,
+            bvh: Bvh::default(),
+        }
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn node(&self, id: NodeId) -> &Node {
+        &self.nodes[id.0]
+    }
+
+    pub fn objects(&self) -> &[SceneObject] {
+        &self.objects
+    }
+
+    /// Add a node to the hierarchy, positioned by `transform` relative to its parent. Either
+    /// a `Affine3` or an `AnimatedTransform` for motion blur can be used.
+    pub fn add_node<T: Into<AnimatedTransform>>(
+        &mut self,
+        parent: Option<NodeId>,
+        transform: T,
+    ) -> NodeId {
+        let id = NodeId(self.nodes.len());
+        self.nodes.push(Node {
+            parent,
+            local: transform.into(),
+            world: AnimatedTransform::fixed(Affine3::IDENTITY),
+            inverse: Affine3::IDENTITY,
+        });
+        self.update_world_transforms(&[id]);
+        id
+    }
+
+    /// Change the local transform of a node, moving all of its descendants with it.
+    pub fn set_transform<T: Into<AnimatedTransform>>(&mut self, id: NodeId, transform: T) {
+        self.nodes[id.0].local = transform.into();
+        let moved = self.update_world_transforms(&[id]);
+        self.refit_objects(&moved);
+    }
+
+    /// Add an object positioned directly in world space
+    pub fn add_object<C: Collideable + 'static>(&mut self, collidable: C) -> ObjectId {
+        self.push_object(SceneObject::new(None, Box::new(collidable)))
+    }
+
+    /// Add an object positioned relative to `node`
+    pub fn add_object_to<C: Collideable + 'static>(
+        &mut self,
+        node: NodeId,
+        collidable: C,
+    ) -> ObjectId {
+        self.push_object(SceneObject::new(Some(node), Box::new(collidable)))
+    }
+
+    fn push_object(&mut self, object: SceneObject) -> ObjectId {
+        let id = ObjectId(self.next_object_id);
+        self.next_object_id += 1;
+        self.bvh.push(self.world_bounds(&object));
+        self.object_index.insert(id, self.objects.len());
+        self.objects.push(object);
+        self.object_ids.push(id);
+        id
+    }
+
+    /// Remove an object, returning it if it was in the scene. The last object in
+    /// [`objects`](Self::objects) takes its place.
+    pub fn remove_object(&mut self, id: ObjectId) -> Option<SceneObject> {
+        let i = self.object_index.remove(&id)?;
+        self.object_ids.swap_remove(i);
+        self.bvh.swap_remove(i);
+        if let Some(&moved) = self.object_ids.get(i) {
+            self.object_index.insert(moved, i);
+        }
+        Some(self.objects.swap_remove(i))
+    }
+
+    /// Apply all the edits in `diff`, returning the handles of the added objects in order.
+    /// Only the moved nodes and their descendants have their world transforms recomputed, and
+    /// only the parts of the BVH above the changed objects are updated. If most of the scene
+    /// changes the BVH is rebuilt instead, as a tree updated in place splits the objects less
+    /// evenly.
+    pub fn apply(&mut self, diff: SceneDiff) -> Vec<ObjectId> {
+        let mut changed = diff.removed.len() + diff.added.len();
+        for &id in &diff.removed {
+            self.remove_object(id);
+        }
+
+        let moved: Vec<NodeId> = diff.transforms.iter().map(|&(id, _)| id).collect();
+        for (id, transform) in diff.transforms {
+            self.nodes[id.0].local = transform;
+        }
+        let moved = self.update_world_transforms(&moved);
+        changed += self.refit_objects(&moved);
+
+        let added = diff
+            .added
+            .into_iter()
+            .map(|object| self.push_object(object))
+            .collect();
+        if changed * 2 > self.objects.len() {
+            self.rebuild_bvh();
+        }
+        added
+    }
+
+    /// Bounds of `object` in world space over the whole shutter interval, `None` if it's
+    /// unbounded
+    fn world_bounds(&self, object: &SceneObject) -> Option<Aabb> {
+        let bounds = object.collidable.bounds()?;
+        Some(match object.node {
+            Some(node) => bounds.animated(&self.nodes[node.0].world),
+            None => bounds,
+        })
+    }
+
+    /// Update the bounds in the BVH of the objects attached to nodes flagged in `moved`,
+    /// returning how many there were
+    fn refit_objects(&mut self, moved: &[bool]) -> usize {
+        let mut refitted = 0;
+        for (i, object) in self.objects.iter().enumerate() {
+            if object.node.is_some_and(|node| moved[node.0]) {
+                self.bvh.refit(i, self.world_bounds(object));
+                refitted += 1;
+            }
+        }
+        refitted
+    }
+
+    /// Build the BVH again from scratch, splitting the objects by the surface area heuristic
+    pub fn rebuild_bvh(&mut self) {
+        let bounds: Vec<_> = self.objects.iter().map(|o| self.world_bounds(o)).collect();
+        self.bvh = Bvh::build(&bounds);
+    }
+
+    /// Estimated number of bytes used by the objects, their textures, the transform hierarchy
+    /// and the BVH
+    pub fn memory_usage(&self) -> usize {
+        std::mem::size_of_val(self)
+            + self.nodes.capacity() * std::mem::size_of::<Node>()
+            + self.objects.capacity() * std::mem::size_of::<SceneObject>()
+            + self.object_ids.capacity() * std::mem::size_of::<ObjectId>()
+            + self.object_index.capacity() * std::mem::size_of::<(ObjectId, usize)>()
+            + self.bvh.memory_usage()
+            + self
+                .objects
+                .iter()
+                .map(|o| o.collidable.memory_usage())
+                .sum::<usize>()
+            + self.texture_memory(&self.object_infos())
+    }
+
+    fn object_infos(&self) -> Vec<ObjectInfo> {
+        self.objects.iter().map(|o| o.collidable.info()).collect()
+    }
+
+    /// Bytes used by the distinct textures of the objects described by `infos`
+    fn texture_memory(&self, infos: &[ObjectInfo]) -> usize {
+        distinct_memory_usage(
+            infos
+                .iter()
+                .flat_map(|info| &info.materials)
+                .flat_map(|material| material.textures()),
+        )
+    }
+
+    /// Summarise the complexity of the scene
+    pub fn report(&self) -> SceneReport {
+        let mut report = SceneReport {
+            nodes: self.nodes.len(),
+            hierarchy_depth: (0..self.nodes.len())
+                .map(|i| self.depth(NodeId(i)))
+                .max()
+                .unwrap_or(0),
+            memory: self.memory_usage(),
+            ..Default::default()
+        };
+
+        let infos = self.object_infos();
+        for info in &infos {
+            report.add_object(info);
+        }
+        report.texture_memory = self.texture_memory(&infos);
+        report.bvh = self.bvh.stats();
+
+        report
+    }
+
+    /// Number of nodes from `id` up to the root, inclusive
+    fn depth(&self, id: NodeId) -> usize {
+        let mut depth = 1;
+        let mut node = &self.nodes[id.0];
+        while let Some(parent) = node.parent {
+            depth += 1;
+            node = &self.nodes[parent.0];
+        }
+        depth
+    }
+
+    /// Choose the level of detail of every object as seen from `view` at `time` within the
+    /// shutter interval
+    pub fn select_levels_of_detail(&self, view: &LodView, time: Float) {
+        for object in &self.objects {
+            let view = match object.node {
+                Some(node) => LodView {
+                    origin: self.nodes[node.0]
+                        .inverse_at(time)
+                        .transform_point3(view.origin),
+                    ..*view
+                },
+                None => *view,
+            };
+            let level = object.collidable.level_of_detail(&view);
+            object.level.store(level, Ordering::Relaxed);
+        }
+    }
+
+    /// A point on the emissive surface of the object at `index` in [`objects`](Self::objects),
+    /// chosen from `u` in the unit square and positioned in world space at `time`
+    pub fn sample_emission(
+        &self,
+        index: usize,
+        u: Vec2,
+        time: Float,
+    ) -> Option<EmissionSample<'_>> {
+        let object = &self.objects[index];
+        let sample = object.rendered().sample_emission(u)?;
+        let Some(node) = object.node else {
+            return Some(sample);
+        };
+
+        let transform = self.nodes[node.0].world.at(time);
+        let normal_matrix = transform.matrix3.inverse().transpose();
+        let normal = normal_matrix * sample.normal;
+        Some(EmissionSample {
+            point: transform.transform_point3(sample.point),
+            normal: normal.normalize(),
+            // Areas scale with the determinant, less the stretching along the normal
+            area: sample.area * transform.matrix3.determinant().abs() * normal.length(),
+            ..sample
+        })
+    }
+
+    /// Find the closest collision of `ray` with any object in the scene
+    pub fn trace(&self, ray: &Ray, rng: &mut dyn RngCore) -> Option<Collision<'_>> {
+        self.trace_where(ray, rng, |_| true)
+    }
+
+    /// Find the closest collision of `ray` with the objects whose index in
+    /// [`objects`](Self::objects) passes `include`
+    pub fn trace_where<F: Fn(usize) -> bool>(
+        &self,
+        ray: &Ray,
+        rng: &mut dyn RngCore,
+        include: F,
+    ) -> Option<Collision<'_>> {
+        // Primitives count their own intersection tests, as meshes test many triangles
+        stats::record(|s| s.rays += 1);
+
+        let mut closest: Option<Collision<'_>> = None;
+        self.bvh.traverse(ray, |i| {
+            if !include(i) {
+                return None;
+            }
+            let c = self.trace_object(&self.objects[i], ray, |c, ray| c.trace(ray, rng))?;
+            if closest.as_ref().is_some_and(|closest| closest.t <= c.t) {
+                return None;
+            }
+            let t = c.t;
+            closest = Some(c);
+            Some(t)
+        });
+        closest.map(|c| Self::apply_shading_normal(c, ray))
+    }
+
+    /// The hit of `ray` with one triangle of the object at `index` in [`objects`](Self::objects),
+    /// numbered as in [`Collideable::raster_triangles`]
+    pub fn trace_triangle(
+        &self,
+        index: usize,
+        triangle: usize,
+        ray: &Ray,
+    ) -> Option<Collision<'_>> {
+        self.trace_object(&self.objects[index], ray, |c, ray| {
+            c.trace_triangle(ray, triangle)
+        })
+        .map(|c| Self::apply_shading_normal(c, ray))
+    }
+
+    /// Normal and bump maps only change shading, so ignore them where they would turn the
+    /// surface to face the other way
+    fn apply_shading_normal<'a>(mut c: Collision<'a>, ray: &Ray) -> Collision<'a> {
+        let shading = c.material.shading_normal(c.normal, c.tangent, c.uv);
+        if shading.dot(ray.dir).signum() == c.geometric_normal.dot(ray.dir).signum() {
+            c.normal = shading;
+        }
+        c
+    }
+
+    /// Intersect `ray` with `object` using `trace`, which is given the ray in the object's
+    /// local space
+    fn trace_object<'a, F>(
+        &self,
+        object: &'a SceneObject,
+        ray: &Ray,
+        trace: F,
+    ) -> Option<Collision<'a>>
+    where
+        F: FnOnce(&'a dyn Collideable, &Ray) -> Option<Collision<'a>>,
+    {
+        let Some(node) = object.node else {
+            return trace(object.rendered(), ray);
+        };
+        let inverse = self.nodes[node.0].inverse_at(ray.time);
+
+        // Trace in the object's local space. The direction is deliberately left
+        // unnormalised so that distances along the ray are the same in both spaces.
+        let local_ray = Ray {
+            origin: inverse.transform_point3(ray.origin),
+            dir: inverse.transform_vector3(ray.dir),
+            time: ray.time,
+            wavelength: ray.wavelength,
+            t_min: ray.t_min,
+            t_max: ray.t_max,
+        };
+
+        let mut c = trace(object.rendered(), &local_ray)?;
+        c.ray = ray.clone();
+        c.normal = (inverse.matrix3.transpose() * c.normal).normalize();
+        c.geometric_normal = (inverse.matrix3.transpose() * c.geometric_normal).normalize();
+        c.terminator_offset = inverse.matrix3.inverse() * c.terminator_offset;
+        if c.tangent != Vec4::ZERO {
+            // Mirroring transforms flip the handedness of the tangent frame
+            let to_world = inverse.matrix3.inverse();
+            let sign = c.tangent.w * to_world.determinant().signum();
+            c.tangent = (to_world * c.tangent.truncate())
+                .normalize_or_zero()
+                .extend(sign);
+        }
+        Some(c)
+    }
+
+    /// Recompute the world transforms of the `moved` nodes and all of their descendants,
+    /// returning which nodes were updated. Nodes can only be parented to nodes that already
+    /// exist, so parents are always updated before children.
+    fn update_world_transforms(&mut self, moved: &[NodeId]) -> Vec<bool> {
+        let mut dirty = vec![false; self.nodes.len()];
+        let Some(first) = moved.iter().map(|id| id.0).min() else {
+            return dirty;
+        };
+        for id in moved {
+            dirty[id.0] = true;
+        }
+
+        for i in first..self.nodes.len() {
+            let parent_dirty = self.nodes[i].parent.is_some_and(|p| dirty[p.0]);
+            if !parent_dirty && !dirty[i] {
+                continue;
+            }
+            dirty[i] = true;
+
+            let parent = self.nodes[i]
+                .parent
+                .map(|p| self.nodes[p.0].world)
+                .unwrap_or(AnimatedTransform::fixed(Affine3::IDENTITY));
+
+            let node = &mut self.nodes[i];
+            node.world = parent * node.local;
+            node.inverse = node.world.start.inverse();
+        }
+        dirty
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        collidable::{Plane, Sphere},
+        float::Vec3,
+        material::Material,
+        mesh::Mesh,
+        texture::{BumpMap, Texture},
+    };
+
+    #[test]
+    fn shared_textures_are_counted_once() {
+        let normals = Arc::new(Texture::new(16, 16, vec![Vec3::Z; 256]));
+        let heights = Arc::new(Texture::new(2, 2, vec![Vec3::ZERO; 4]));
+        let material = Arc::new(Material {
+            normal_map: Some(normals.clone()),
+            bump_map: Some(BumpMap {
+                texture: heights.clone(),
+                strength: 1.0,
+            }),
+            ..Default::default()
+        });
+
+        let mut scene = Scene::new();
+        let empty = scene.memory_usage();
+        for z in 0..3 {
+            scene.add_object(Sphere {
+                origin: Vec3::new(0.0, 0.0, z as Float),
+                radius: 0.5,
+                material: material.clone(),
+            });
+        }
+        let report = scene.report();
+
+        let textures = normals.memory_usage() + heights.memory_usage();
+        assert_eq!(report.texture_memory, textures);
+        assert!(scene.memory_usage() >= empty + textures);
+        assert!(scene.memory_usage() < empty + textures * 2);
+    }
+
+    #[test]
+    fn report_describes_the_bvh() {
+        let material = Arc::new(Material::default());
+        let mut scene = Scene::new();
+        scene.add_object(Mesh::uv_sphere(Vec3::ZERO, 1.0, 8, 4, material.clone()));
+        scene.add_object(Sphere {
+            origin: Vec3::X,
+            radius: 1.0,
+            material: material.clone(),
+        });
+        scene.add_object(Plane {
+            origin: Vec3::NEG_Y,
+            normal: Vec3::Y,
+            material,
+        });
+
+        let report = scene.report();
+        assert_eq!(report.bvh.nodes, 3);
+        assert_eq!(report.bvh.depth, 2);
+        // The plane is always tested, the root always visited, and each leaf's box covers
+        // 24 of the root's 32 square units
+        let expected = 1.0 + 0.125 + 2.0 * 24.0 / 32.0;
+        assert!((report.bvh.sah_cost - expected).abs() < 1e-4);
+        assert!(report
+            .to_string()
+            .contains("BVH: 3 nodes (max depth 2, SAH cost 2.62)"));
+    }
+
+    #[test]
+    fn stats_count_primitive_tests_once() {
+        let material = Arc::new(Material::default());
+        let mut scene = Scene::new();
+        scene.add_object(Mesh::uv_sphere(Vec3::ZERO, 1.0, 8, 4, material.clone()));
+        scene.add_object(Sphere {
+            origin: Vec3::new(1.5, 1.5, 0.0),
+            radius: 1.0,
+            material,
+        });
+        let triangles = scene.report().triangles as u64;
+
+        // Passes through the box around the mesh without hitting it, then hits the sphere, so
+        // both have to be tested
+        stats::start();
+        let ray = Ray {
+            origin: Vec3::new(0.9, 0.9, -5.0),
+            dir: Vec3::Z,
+            time: 0.0,
+            wavelength: None,
+            t_min: 0.0,
+            t_max: Float::INFINITY,
+        };
+        scene.trace(&ray, &mut SmallRng::seed_from_u64(0));
+        let stats = stats::finish().unwrap();
+
+        assert_eq!(stats.rays, 1);
+        assert_eq!(stats.intersection_tests.get("Sphere"), Some(&1));
+        assert_eq!(stats.intersection_tests.get("Triangle"), Some(&triangles));
+        assert_eq!(stats.intersection_test_count(), triangles + 1);
+    }
+
+    #[test]
+    fn edits_keep_the_bvh_in_step_with_the_objects() {
+        let material = Arc::new(Material::default());
+        let sphere = |origin| Sphere {
+            origin,
+            radius: 0.4,
+            material: material.clone(),
+        };
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut scene = Scene::new();
+        let parent = scene.add_node(None, Affine3::IDENTITY);
+        let child = scene.add_node(Some(parent), Affine3::from_translation(Vec3::X * 2.0));
+        let mut ids = Vec::new();
+        for i in 0..20 {
+            let origin = Vec3::new(rng.gen(), rng.gen(), rng.gen()) * 10.0;
+            ids.push(match i % 3 {
+                0 => scene.add_object(sphere(origin)),
+                1 => scene.add_object_to(parent, sphere(origin)),
+                _ => scene.add_object_to(child, sphere(origin)),
+            });
+        }
+        scene.add_object(Plane {
+            origin: Vec3::new(0.0, -1.0, 0.0),
+            normal: Vec3::Y,
+            material: material.clone(),
+        });
+
+        scene.apply(
+            SceneDiff::default()
+                .with_transform(
+                    parent,
+                    AnimatedTransform::new(
+                        Affine3::from_rotation_y(0.5),
+                        Affine3::from_translation(Vec3::new(1.0, 3.0, 0.0)),
+                    ),
+                )
+                .without_object(ids[4])
+                .without_object(ids[11])
+                .with_object_at(child, sphere(Vec3::new(5.0, 5.0, 5.0))),
+        );
+        assert_eq!(scene.objects().len(), 20);
+        assert!(scene.remove_object(ids[4]).is_none());
+
+        for _ in 0..500 {
+            let ray = Ray {
+                origin: Vec3::new(rng.gen(), rng.gen(), rng.gen()) * 10.0 - 2.0,
+                dir: Vec3::new(rng.gen(), rng.gen(), rng.gen()) - 0.5,
+                time: rng.gen(),
+                wavelength: None,
+                t_min: 0.0,
+                t_max: Float::INFINITY,
+            };
+            let every_object = scene
+                .objects
+                .iter()
+                .filter_map(|o| scene.trace_object(o, &ray, |c, ray| c.trace(ray, &mut rng)))
+                .map(|c| c.t)
+                .min_by(Float::total_cmp);
+            let traced = scene.trace(&ray, &mut rng).map(|c| c.t);
+            assert_eq!(traced, every_object);
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::sync::Arc;
+
+use rand::RngCore;
+
+use crate::{
+    bvh::Aabb,
+    collidable::{surface_tangent, Collideable, Collision},
+    float::{consts::PI, Float, Vec2, Vec3},
+    material::Material,
+    ray::Ray,
+    report::ObjectInfo,
+    stats,
+};
+
+/// A surface defined by a signed distance function, which gives the distance from a point to
+/// the nearest point on the surface, negative inside. Rendered by sphere tracing, so the
+/// function must never overestimate the distance.
+pub struct Sdf<F> {
+    pub distance: F,
+    /// Centre of a sphere containing the whole surface, used to limit how far rays march
+    pub centre: Vec3,
+    pub radius: Float,
+    /// Most steps taken along a ray before giving up
+    pub max_steps: u32,
+    /// Distance from the surface at which a ray is considered to have hit it
+    pub epsilon: Float,
+    pub material: Arc<Material>,
+}
+
+impl<F: Fn(Vec3) -> Float> Sdf<F> {
+    pub fn new(distance: F, centre: Vec3, radius: Float, material: Arc<Material>) -> Self {
+        Self {
+            distance,
+            centre,
+            radius,
+            max_steps: 256,
+            epsilon: 1e-4,
+            material,
+        }
+    }
+
+    pub fn with_max_steps(mut self, max_steps: u32) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    pub fn with_epsilon(mut self, epsilon: Float) -> Self {
+        self.epsilon = epsilon;
+        self
+    }
+
+    /// Normal from the gradient of the distance function, estimated with four samples
+    /// arranged in a tetrahedron
+    fn normal(&self, p: Vec3) -> Vec3 {
+        let h = self.epsilon;
+        [
+            Vec3::new(1.0, -1.0, -1.0),
+            Vec3::new(-1.0, -1.0, 1.0),
+            Vec3::new(-1.0, 1.0, -1.0),
+            Vec3::new(1.0, 1.0, 1.0),
+        ]
+        .into_iter()
+        .map(|k| k * (self.distance)(p + k * h))
+        .sum::<Vec3>()
+        .normalize_or_zero()
+    }
+}
+
+/// The Mandelbulb fractal of the given `power`, which fits within a sphere of radius 1.2
+/// around the origin. Higher `iterations` add more detail but are slower to render.
+pub fn mandelbulb(
+    power: Float,
+    iterations: u32,
+    material: Arc<Material>,
+) -> Sdf<impl Fn(Vec3) -> Float + Send + Sync> {
+    Sdf::new(
+        move |p| mandelbulb_distance(p, power, iterations),
+        Vec3::ZERO,
+        1.2,
+        material,
+    )
+}
+
+/// Distance estimate for the Mandelbulb, with its poles along the y axis
+fn mandelbulb_distance(p: Vec3, power: Float, iterations: u32) -> Float {
+    let c = Vec3::new(p.x, p.z, p.y);
+    let mut z = c;
+    let mut dr = 1.0;
+    let mut r = z.length();
+
+    for _ in 0..iterations {
+        r = z.length();
+        if !(1e-12..=2.0).contains(&r) {
+            break;
+        }
+
+        let theta = (z.z / r).acos() * power;
+        let phi = z.y.atan2(z.x) * power;
+        dr = r.powf(power - 1.0) * power * dr + 1.0;
+
+        z = Vec3::new(
+            theta.sin() * phi.cos(),
+            phi.sin() * theta.sin(),
+            theta.cos(),
+        ) * r.powf(power)
+            + c;
+    }
+
+    0.5 * r.max(1e-12).ln() * r / dr
+}
+
+impl<F: Fn(Vec3) -> Float + Send + Sync> Collideable for Sdf<F> {
+    fn trace(&self, ray: &Ray, _rng: &mut dyn RngCore) -> Option<Collision<'_>> {
+        stats::record_tests("Sdf", 1);
+        // Only march through the part of the ray inside the bounding sphere
+        let speed = ray.dir.length();
+        let dir = ray.dir / speed;
+        let off = ray.origin - self.centre;
+        let b = off.dot(dir);
+        let disc = b * b - (off.length_squared() - self.radius * self.radius);
+        if disc < 0.0 {
+            return None;
+        }
+        let far = (-b + disc.sqrt()).min(ray.t_max * speed);
+        let near = ray.t_min.max(0.0) * speed;
+        if far <= near {
+            return None;
+        }
+
+        // Hits right next to the origin are ignored so rays leaving the surface don't
+        // immediately hit it again
+        let min_distance = self.epsilon * 10.0;
+        let entry = -b - disc.sqrt();
+        let mut distance = entry.max(near);
+        // A ray resuming from `t_min` may start on the surface it last hit, e.g. when a CSG
+        // looks for the next crossing, so it has to leave the surface before it can hit it
+        let mut leaving = near >= entry;
+        for _ in 0..self.max_steps {
+            let p = ray.origin + dir * distance;
+            // Rays can start inside, so march by the distance to the surface from either side
+            let d = (self.distance)(p).abs();
+            leaving &= d < self.epsilon;
+            let t = distance / speed;
+            if d < self.epsilon && distance > min_distance && !leaving && ray.in_range(t) {
+                let normal = self.normal(p);
+                let local = (p - self.centre).normalize_or_zero();
+                return Some(Collision {
+                    ray: ray.clone(),
+                    t,
+                    normal,
+                    geometric_normal: normal,
+                    uv: Vec2::new(
+                        local.z.atan2(local.x) / (2.0 * PI) + 0.5,
+                        (-local.y).acos() / PI,
+                    ),
+                    tangent: surface_tangent(normal, Vec3::new(-local.z, 0.0, local.x), Vec3::Y),
+                    terminator_offset: Vec3::ZERO,
+                    material: &self.material,
+                });
+            }
+
+            distance += d.max(self.epsilon);
+            if distance > far {
+                return None;
+            }
+        }
+
+        None
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb::around(self.centre, self.radius))
+    }
+
+    fn info(&self) -> ObjectInfo {
+        ObjectInfo::with_material("Sdf", &self.material)
+    }
+}
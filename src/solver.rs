@@ -1,39 +1,226 @@
-use std::f64::consts::PI;
+use std::{marker::PhantomData, path::PathBuf, sync::Arc};
 
-use glam::{DQuat, DVec3, IVec2, UVec2};
+use glam::{IVec2, UVec2};
 use image::RgbImage;
 use indicatif::ProgressBar;
 use rand::{Rng, SeedableRng};
 
 use crate::{
-    camera::Camera,
-    collidable::{Collideable, Collision},
-    ray::Ray,
+    animation::{Animation, SceneState},
+    camera::{Camera, FrameFormat},
+    collidable::Collision,
+    colour::OutputTransform,
+    environment::Environment,
+    film::{Aov, Film},
+    float::{consts::PI, Float, Quat, Vec2, Vec3},
+    material::Material,
+    memory::{MemoryBudgetError, MemoryReport},
+    photon::{gathers_photons, trace_photons, PhotonMap},
+    probes::IrradianceProbe,
+    raster::VisibilityBuffer,
+    ray::{EpsilonPolicy, Ray},
+    render::{Render, RenderEvent, RenderStream},
+    sampler::{hash, PixelSampler, Sampler},
+    scene::Scene,
+    spectrum::{sample_wavelength, wavelength_weight},
+    stats,
+    tiles::{CancellationToken, Tile, TileOrder, TileProgress},
+    transform::AnimatedTransform,
+    volume::{sample_distance, Atmosphere, PhaseFunction},
 };
 
-pub struct Solver<'a, C: Camera, R: Rng + SeedableRng> {
+/// Settings for re-rendering outlier pixels once the image is complete
+#[derive(Debug, Clone, Copy)]
+pub struct FireflyPass {
+    /// How many times brighter than the median of its neighbours a pixel has to be
+    pub threshold: Float,
+    pub samples: u64,
+}
+
+/// How the surfaces directly visible to the camera are found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrimaryVisibility {
+    /// Trace a new camera ray for every sample, giving anti-aliasing, depth of field and
+    /// motion blur
+    PerSample,
+    /// Find the first hit once per pixel, through its centre, and only path trace the
+    /// secondary bounces. Triangle meshes are rasterized rather than traced when the camera
+    /// allows it, so this is much faster for previews of large meshes, at the cost of aliased
+    /// edges and no camera or motion blur.
+    PerPixel,
+}
+
+/// How the light reaching the camera is estimated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Integrator {
+    /// Path trace everything from the camera
+    PathTracing,
+    /// Path trace, but look caustics up from a photon map traced from the lights at the start
+    /// of each render. Caustics converge far faster, at the cost of some blurring and bias.
+    PhotonMapping {
+        /// Number of photons emitted from the lights, of which only those reaching a diffuse
+        /// surface through mirrors or glass are kept
+        photons: u64,
+        /// Distance around each point that photons are gathered from. Larger is smoother but
+        /// blurrier.
+        radius: Float,
+    },
+}
+
+/// Where a path is relative to the last diffuse surface it looked caustics up at, so light
+/// already counted from the photon map isn't counted again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CausticPath {
+    /// No caustics looked up since the last scattering event other than a specular bounce
+    Untracked,
+    /// Just left a diffuse surface where caustics were looked up
+    Gathered,
+    /// Only specular bounces since leaving such a surface
+    Specular,
+}
+
+/// The photon map of a render, if it has one, and where a path is relative to it
+#[derive(Clone, Copy)]
+struct Caustics<'a> {
+    map: Option<&'a PhotonMap>,
+    path: CausticPath,
+}
+
+impl<'a> Caustics<'a> {
+    fn new(map: Option<&'a PhotonMap>) -> Self {
+        Self {
+            map,
+            path: CausticPath::Untracked,
+        }
+    }
+
+    fn with_path(self, path: CausticPath) -> Self {
+        Self { path, ..self }
+    }
+}
+
+/// Work done once at the start of each render and shared by all of its tiles
+pub(crate) struct FrameState {
+    /// Caustics traced from the lights, when photon mapping
+    photon_map: Option<PhotonMap>,
+    /// What the camera sees through each pixel, when primary visibility is per pixel and the
+    /// camera can be rasterized
+    visibility: Option<VisibilityBuffer>,
+}
+
+/// Surface properties at the first hit of the camera rays through a pixel, used for AOVs
+#[derive(Debug, Clone, Copy, Default)]
+struct FirstHit {
+    depth: Float,
+    normal: Vec3,
+    albedo: Vec3,
+    alpha: Float,
+}
+
+pub struct Solver<C: Camera, R: Rng + SeedableRng> {
     pub camera: C,
     pub resolution: UVec2,
+    /// Width of each pixel relative to its height, for anamorphic formats
+    pub pixel_aspect: Float,
     pub max_bounces: u64,
     pub samples: u64,
+    /// Bounce depth after which paths are randomly terminated, `None` to disable
+    pub roulette_depth: Option<u64>,
+    /// Maximum radiance of a single sample, `None` to disable
+    pub clamp: Option<Float>,
+    pub sampler: Sampler,
+    pub tile_size: u32,
+    pub tile_order: TileOrder,
+    /// Percentage of the resolution rendered beyond each edge of the frame
+    pub overscan: Float,
+    pub firefly_pass: Option<FireflyPass>,
+    /// Maximum number of bytes the render is allowed to use, checked by `prepare`
+    pub memory_budget: Option<usize>,
+    /// Encoding used when converting the film to an image
+    pub output_transform: OutputTransform,
+    /// Auxiliary outputs recorded alongside the beauty pass
+    pub aovs: Vec<Aov>,
+    /// Interval the shutter is open for, within the 0.0 to 1.0 range of animated transforms
+    pub shutter: (Float, Float),
+    pub primary_visibility: PrimaryVisibility,
+    pub integrator: Integrator,
+    /// Trace a single wavelength per sample, for dispersion in materials which have it
+    pub spectral: bool,
+    /// How far rays leaving a surface start from it
+    pub epsilon: EpsilonPolicy,
+    /// Leave the background out of camera rays, recording opacity in the alpha AOV, and
+    /// show shadow catchers only by what other objects cast onto them
+    pub transparent_background: bool,
+    /// Count the rays and intersection tests of each solve and print them at the end
+    pub stats: bool,
+    /// Also write the counts of each solve to this path as JSON
+    pub stats_path: Option<PathBuf>,
 
-    pub objects: Vec<&'a dyn Collideable<R>>,
-    pub sky: fn(DVec3) -> DVec3,
+    pub scene: Scene,
+    /// Background seen where rays leave the scene, unless an environment map is used
+    pub sky: fn(Vec3) -> Vec3,
+    /// Environment map and sun, sampled directly at diffuse surfaces
+    pub environment: Option<Environment>,
+    pub atmosphere: Option<Atmosphere>,
+
+    /// Type of the random number generator seeded for each sample
+    rng: PhantomData<fn() -> R>,
 }
 
-impl<'a, C: Camera, R: Rng + SeedableRng> Solver<'a, C, R> {
+// A host can render several solvers at once from different threads, or the same solver more
+// than once, and objects can be shared between their scenes with `Arc`
+#[allow(dead_code)]
+const _: () = {
+    fn assert_send_sync<T: Send + Sync>() {}
+    fn solver_is_send_sync<C: Camera + Send + Sync, R: Rng + SeedableRng>() {
+        assert_send_sync::<Solver<C, R>>();
+        assert_send_sync::<Render<'_, C, R>>();
+    }
+};
+
+impl<C: Camera, R: Rng + SeedableRng> Solver<C, R> {
     pub fn new(camera: C, resolution: UVec2) -> Self {
         Self {
             camera,
             resolution,
+            pixel_aspect: 1.0,
             max_bounces: 0,
             samples: 1,
+            roulette_depth: None,
+            clamp: None,
+            sampler: Sampler::Random,
+            tile_size: 32,
+            tile_order: TileOrder::Spiral,
+            overscan: 0.0,
+            firefly_pass: None,
+            memory_budget: None,
+            output_transform: OutputTransform::Linear,
+            aovs: Vec::new(),
+            shutter: (0.0, 0.0),
+            primary_visibility: PrimaryVisibility::PerSample,
+            integrator: Integrator::PathTracing,
+            spectral: false,
+            epsilon: EpsilonPolicy::default(),
+            transparent_background: false,
+            stats: false,
+            stats_path: None,
+
+            scene: Scene::new(),
+            sky: |d| Vec3::new(0.7, 0.7, 1.0) * (d.y + 0.2),
+            environment: None,
+            atmosphere: None,
 
-            objects: Vec::new(),
-            sky: |d| DVec3::new(0.7, 0.7, 1.0) * (d.y + 0.2),
+            rng: PhantomData,
         }
     }
 
+    /// Render pixels `pixel_aspect` times as wide as they are high, e.g. 2.0 for 2x
+    /// anamorphic. The camera frames the image as it will look once stretched for display.
+    pub fn with_pixel_aspect(mut self, pixel_aspect: Float) -> Self {
+        self.pixel_aspect = pixel_aspect;
+        self
+    }
+
     pub fn with_max_bounces(mut self, max_bounces: u64) -> Self {
         self.max_bounces = max_bounces;
         self
@@ -44,145 +231,1079 @@ impl<'a, C: Camera, R: Rng + SeedableRng> Solver<'a, C, R> {
         self
     }
 
+    /// Randomly terminate paths once they reach `depth` bounces, weighting the survivors
+    /// so the result stays unbiased. `max_bounces` is still respected as a hard limit.
+    pub fn with_russian_roulette(mut self, depth: u64) -> Self {
+        self.roulette_depth = Some(depth);
+        self
+    }
+
+    /// Clamp the radiance of each individual sample to reduce fireflies.
+    pub fn with_clamp(mut self, clamp: Float) -> Self {
+        self.clamp = Some(clamp);
+        self
+    }
+
+    /// Start rays leaving surfaces further from them, for scenes with acne at glancing angles,
+    /// or closer, for scenes where light leaks through thin objects
+    pub fn with_epsilon(mut self, epsilon: EpsilonPolicy) -> Self {
+        self.epsilon = epsilon;
+        self
+    }
+
+    pub fn with_sampler(mut self, sampler: Sampler) -> Self {
+        self.sampler = sampler;
+        self
+    }
+
+    pub fn with_tile_size(mut self, tile_size: u32) -> Self {
+        self.tile_size = tile_size;
+        self
+    }
+
+    pub fn with_tile_order(mut self, tile_order: TileOrder) -> Self {
+        self.tile_order = tile_order;
+        self
+    }
+
+    /// Render `percent` extra pixels beyond each edge of the frame. The extra pixels are
+    /// kept in the film's data window, e.g. for reframing in post-production.
+    pub fn with_overscan(mut self, percent: Float) -> Self {
+        self.overscan = percent;
+        self
+    }
+
+    /// After rendering, re-render pixels which are more than `threshold` times brighter than
+    /// their neighbours using `samples` samples each.
+    pub fn with_firefly_pass(mut self, threshold: Float, samples: u64) -> Self {
+        self.firefly_pass = Some(FireflyPass { threshold, samples });
+        self
+    }
+
+    /// Fill the space outside of objects with fog
+    pub fn with_atmosphere(mut self, atmosphere: Atmosphere) -> Self {
+        self.atmosphere = Some(atmosphere);
+        self
+    }
+
+    /// Light the scene with an environment map and/or sun. Without a map, the sky is still
+    /// used as the background.
+    pub fn with_environment(mut self, environment: Environment) -> Self {
+        self.environment = Some(environment);
+        self
+    }
+
+    pub fn with_output_transform(mut self, output_transform: OutputTransform) -> Self {
+        self.output_transform = output_transform;
+        self
+    }
+
+    /// Also record `aov` in the rendered film
+    pub fn with_aov(mut self, aov: Aov) -> Self {
+        if !self.aovs.contains(&aov) {
+            self.aovs.push(aov);
+        }
+        self
+    }
+
+    /// Open the shutter from `open` to `close`, blurring objects and cameras which move
+    /// during that time. Animated transforms move from time 0.0 to 1.0.
+    pub fn with_shutter(mut self, open: Float, close: Float) -> Self {
+        self.shutter = (open, close);
+        self
+    }
+
+    pub fn with_primary_visibility(mut self, primary_visibility: PrimaryVisibility) -> Self {
+        self.primary_visibility = primary_visibility;
+        self
+    }
+
+    pub fn with_integrator(mut self, integrator: Integrator) -> Self {
+        self.integrator = integrator;
+        self
+    }
+
+    /// Render spectrally, so dispersive materials split light into its colours. Needs more
+    /// samples than RGB rendering for the same amount of colour noise.
+    pub fn with_spectral(mut self, spectral: bool) -> Self {
+        self.spectral = spectral;
+        self
+    }
+
+    pub fn with_memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Render with a transparent background for compositing, also recording the alpha AOV
+    pub fn with_transparent_background(mut self) -> Self {
+        self.transparent_background = true;
+        self.with_aov(Aov::Alpha)
+    }
+
+    /// Print statistics about the work done at the end of each solve
+    pub fn with_stats(mut self) -> Self {
+        self.stats = true;
+        self
+    }
+
+    /// Print statistics about the work done at the end of each solve, and write them to
+    /// `path` as JSON
+    pub fn with_stats_json<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.stats = true;
+        self.stats_path = Some(path.into());
+        self
+    }
+
+    /// Estimate the memory the render will use, failing if it exceeds the memory budget.
+    /// Should be called once the scene is built and before rendering.
+    pub fn prepare(&self) -> Result<MemoryReport, MemoryBudgetError> {
+        let report = self.memory_report();
+        match self.memory_budget {
+            Some(budget) if report.total() > budget => Err(MemoryBudgetError { report, budget }),
+            _ => Ok(report),
+        }
+    }
+
+    pub fn memory_report(&self) -> MemoryReport {
+        MemoryReport {
+            scene: self.scene.memory_usage(),
+            environment: self
+                .environment
+                .as_ref()
+                .map_or(0, Environment::memory_usage),
+            film: Film::memory_required(
+                self.resolution + self.overscan_pixels() * 2,
+                self.aovs.len(),
+            ),
+        }
+    }
+
+    pub fn frame_format(&self) -> FrameFormat {
+        FrameFormat {
+            resolution: self.resolution,
+            pixel_aspect: self.pixel_aspect,
+        }
+    }
+
+    /// Choose the level of detail of every object for the camera's view at the middle of the
+    /// shutter interval. Called at the start of every render.
+    pub fn select_levels_of_detail(&self) {
+        let time = (self.shutter.0 + self.shutter.1) / 2.0;
+        if let Some(view) = self.camera.lod_view(self.frame_format(), time) {
+            self.scene.select_levels_of_detail(&view, time);
+        }
+    }
+
+    /// Prepare for a render with `seed`, choosing levels of detail, tracing the photon map and
+    /// rasterizing primary visibility as needed
+    pub(crate) fn start_frame(&self, seed: u64) -> FrameState {
+        self.select_levels_of_detail();
+        let photon_map = match self.integrator {
+            Integrator::PathTracing => None,
+            Integrator::PhotonMapping { photons, radius } => {
+                Some(trace_photons(self, photons, radius, seed))
+            }
+        };
+
+        let visibility = match (self.primary_visibility, self.camera.raster_projection()) {
+            (PrimaryVisibility::PerPixel, Some(projection)) => {
+                let overscan = self.overscan_pixels();
+                Some(VisibilityBuffer::new(
+                    &self.scene,
+                    projection,
+                    self.frame_format(),
+                    -overscan.as_ivec2(),
+                    self.resolution + overscan * 2,
+                    (self.shutter.0 + self.shutter.1) / 2.0,
+                ))
+            }
+            _ => None,
+        };
+
+        FrameState {
+            photon_map,
+            visibility,
+        }
+    }
+
+    /// A black film for the frame, including any overscan
+    pub(crate) fn new_film(&self) -> Film {
+        let mut film = Film::new(self.resolution, self.overscan_pixels(), &self.aovs);
+        film.pixel_aspect = self.pixel_aspect;
+        film
+    }
+
+    /// Number of extra pixels rendered beyond each edge of the frame
+    pub fn overscan_pixels(&self) -> UVec2 {
+        (Vec2::new(self.resolution.x as Float, self.resolution.y as Float) * self.overscan / 100.0)
+            .round()
+            .as_uvec2()
+    }
+
     pub fn solve(&self, seed: u64) -> RgbImage {
-        let mut img = RgbImage::new(self.resolution.x, self.resolution.y);
+        self.solve_film(seed).to_rgb_image(self.output_transform)
+    }
 
-        let bar = ProgressBar::new(self.resolution.x as u64 * self.resolution.y as u64);
+    /// Render the full film, including any overscan
+    pub fn solve_film(&self, seed: u64) -> Film {
+        let film = self.overscan_pixels() * 2 + self.resolution;
+        let bar = ProgressBar::new(film.x as u64 * film.y as u64);
+        if self.stats {
+            stats::start();
+        }
 
-        let mut rng = R::seed_from_u64(seed);
+        let film = self.solve_with(seed, &CancellationToken::new(), |progress| {
+            bar.inc(progress.tile.pixel_count())
+        });
 
-        for x in 0..self.resolution.x {
-            for y in 0..self.resolution.y {
-                let mut sample = DVec3::ZERO;
-                for _ in 0..self.samples {
-                    let ray = self.camera.outgoing_ray(
-                        self.resolution.clone(),
-                        IVec2::new(x as i32, y as i32),
-                        &mut rng,
-                    );
-
-                    sample += self.sample(ray, 0, &mut rng);
+        bar.finish();
+
+        if let Some(stats) = stats::finish() {
+            println!("{}", stats);
+            if let Some(path) = &self.stats_path {
+                if let Err(e) = stats.write_json(path) {
+                    eprintln!("Failed to write statistics to {}: {}", path.display(), e);
                 }
+            }
+        }
+
+        film
+    }
 
-                let avg_scale = 1.0 / self.samples as f64;
-                let pixel = img.get_pixel_mut(x, self.resolution.y - y - 1);
-                pixel.0[0] = ((sample.x * avg_scale).clamp(0.0, 1.0) * 255.0) as u8;
-                pixel.0[1] = ((sample.y * avg_scale).clamp(0.0, 1.0) * 255.0) as u8;
-                pixel.0[2] = ((sample.z * avg_scale).clamp(0.0, 1.0) * 255.0) as u8;
+    /// Render the full film on the GPU, falling back to [`solve_film`](Self::solve_film) if
+    /// no GPU is available or the scene uses features the GPU backend doesn't support
+    #[cfg(feature = "gpu")]
+    pub fn solve_film_gpu(&self, seed: u64) -> Film {
+        match crate::gpu::render(self, seed) {
+            Ok(film) => film,
+            Err(e) => {
+                eprintln!("Rendering on the CPU instead: {}", e);
+                self.solve_film(seed)
             }
-            bar.inc(self.resolution.x as u64);
         }
+    }
 
-        bar.finish();
+    /// Render every frame of `animation`, calling `state` with the frame number and time in
+    /// seconds to update the scene before rendering, and `on_frame` with each finished film.
+    /// When the shutter is open, `state` is also evaluated at the start of the next frame so
+    /// anything that moves during the frame is motion blurred. Each frame is rendered with the
+    /// seed given by [`Animation::frame_seed`].
+    pub fn solve_animation<F, G>(
+        &mut self,
+        seed: u64,
+        animation: &Animation,
+        mut state: F,
+        mut on_frame: G,
+    ) where
+        F: FnMut(u32, Float) -> SceneState<C>,
+        G: FnMut(u32, &Film),
+    {
+        let blur = self.shutter.0 != self.shutter.1;
 
-        img
+        for frame in animation.frames.clone() {
+            let time = animation.time(frame);
+            let start = state(frame, time);
+            let end = if blur {
+                state(frame, animation.time(frame + 1))
+            } else {
+                SceneState::default()
+            };
+
+            for &(node, transform) in &start.transforms {
+                let end = end
+                    .transforms
+                    .iter()
+                    .find(|(n, _)| *n == node)
+                    .map(|(_, t)| *t)
+                    .unwrap_or(transform);
+                self.scene
+                    .set_transform(node, AnimatedTransform::new(transform, end));
+            }
+            if let Some(camera) = start.camera {
+                self.camera = camera;
+            }
+
+            println!("Rendering frame {}...", frame);
+            let film = self.solve_film(animation.frame_seed(seed, frame));
+            on_frame(frame, &film);
+        }
     }
 
-    fn sample(&self, ray: Ray, bounce: u64, rng: &mut R) -> DVec3 {
-        // Trace ray
-        let collision: Option<Collision<'_>> = self
-            .objects
+    /// Bake an irradiance probe at each of `positions` from `samples` rays in uniformly
+    /// distributed directions, e.g. for baked global illumination in a real-time engine
+    pub fn bake_probes(&self, positions: &[Vec3], samples: u64, seed: u64) -> Vec<IrradianceProbe> {
+        let time = (self.shutter.0 + self.shutter.1) / 2.0;
+
+        positions
             .iter()
-            .filter_map(|o| o.trace(&ray, rng))
-            .fold(None, |min, c| {
-                if min
-                    .as_ref()
-                    .map(|c: &Collision<'_>| c.t)
-                    .unwrap_or(f64::INFINITY)
-                    > c.t
-                {
-                    Some(c)
-                } else {
-                    min
+            .enumerate()
+            .map(|(i, &position)| {
+                let seed = hash(&[seed, i as u64]);
+                let radiance: Vec<(Vec3, Vec3)> = (0..samples)
+                    .map(|s| {
+                        let mut rng = R::seed_from_u64(hash(&[seed, s]));
+                        let mut sampler = PixelSampler::new(
+                            self.sampler,
+                            IVec2::new(i as i32, 0),
+                            seed,
+                            s,
+                            samples,
+                            &mut rng,
+                        );
+                        let dir = random_unit_vector(sampler.next_2d());
+                        let mut ray = Ray {
+                            origin: position,
+                            dir,
+                            time,
+                            wavelength: None,
+                            t_min: 0.0,
+                            t_max: Float::INFINITY,
+                        };
+                        let weight = self.sample_wavelength(&mut ray, &mut sampler);
+                        let sample = self.sample(ray, 0, &[], Caustics::new(None), &mut sampler);
+                        (dir, self.clamp_sample(weight * sample))
+                    })
+                    .collect();
+
+                IrradianceProbe::from_samples(position, &radiance)
+            })
+            .collect()
+    }
+
+    /// Start a render which is advanced one tile at a time by calling [`Render::step`]
+    pub fn render(&self, seed: u64) -> Render<'_, C, R> {
+        Render::new(self, seed)
+    }
+
+    /// Start a render on a worker thread, whose progress is polled as a
+    /// [`Stream`](futures_core::Stream) of events
+    pub fn render_stream(self: Arc<Self>, seed: u64) -> RenderStream
+    where
+        C: Send + Sync + 'static,
+        R: 'static,
+    {
+        RenderStream::new(self, seed)
+    }
+
+    /// Render the film tile by tile, calling `on_tile` after each tile is completed. If
+    /// `cancel` is triggered the render stops after the current tile and the partially
+    /// rendered film is returned.
+    pub fn solve_with<F: FnMut(&TileProgress<'_>)>(
+        &self,
+        seed: u64,
+        cancel: &CancellationToken,
+        mut on_tile: F,
+    ) -> Film {
+        let mut render = self.render(seed);
+
+        while !cancel.is_cancelled() {
+            match render.step() {
+                Some(RenderEvent::Tile {
+                    tile,
+                    completed,
+                    total,
+                }) => on_tile(&TileProgress {
+                    tile,
+                    completed,
+                    total,
+                    film: render.film(),
+                }),
+                Some(RenderEvent::FireflyPass { .. }) => {}
+                None => break,
+            }
+        }
+
+        render.into_film()
+    }
+
+    /// Render every pixel of `tile` into `film`. Each pixel gets its own random numbers
+    /// derived from `seed`, so the result doesn't depend on the order pixels are rendered in.
+    pub(crate) fn render_tile(&self, film: &mut Film, tile: &Tile, seed: u64, frame: &FrameState) {
+        for ty in tile.origin.y..tile.origin.y + tile.size.y {
+            for tx in tile.origin.x..tile.origin.x + tile.size.x {
+                let img_pixel = film.data_origin + UVec2::new(tx, ty).as_ivec2();
+                let (sample, first_hit) = self.render_pixel(
+                    self.camera_pixel(img_pixel),
+                    self.samples,
+                    pixel_seed(seed, img_pixel),
+                    frame,
+                );
+
+                film.set(img_pixel, sample);
+                film.set_aov(Aov::Depth, img_pixel, Vec3::splat(first_hit.depth));
+                film.set_aov(Aov::Normal, img_pixel, first_hit.normal);
+                film.set_aov(Aov::Albedo, img_pixel, first_hit.albedo);
+                film.set_aov(Aov::Alpha, img_pixel, Vec3::splat(first_hit.alpha));
+            }
+        }
+    }
+
+    /// Image space has y pointing down while the camera has it pointing up
+    fn camera_pixel(&self, img_pixel: IVec2) -> IVec2 {
+        IVec2::new(img_pixel.x, self.resolution.y as i32 - img_pixel.y - 1)
+    }
+
+    /// Find pixels much brighter than the median of their neighbours and render them again
+    /// with more samples. Returns the number of pixels re-rendered.
+    pub(crate) fn remove_fireflies(
+        &self,
+        film: &mut Film,
+        pass: &FireflyPass,
+        seed: u64,
+        frame: &FrameState,
+    ) -> usize {
+        let luminance = |v: Vec3| v.dot(Vec3::new(0.2126, 0.7152, 0.0722));
+
+        let mut fireflies = Vec::new();
+        for y in 0..film.data_size.y as i32 {
+            for x in 0..film.data_size.x as i32 {
+                let pixel = film.data_origin + IVec2::new(x, y);
+
+                let mut neighbours: Vec<Float> = (-1..=1)
+                    .flat_map(|dy| (-1..=1).map(move |dx| IVec2::new(dx, dy)))
+                    .filter(|&d| d != IVec2::ZERO && film.contains(pixel + d))
+                    .map(|d| luminance(film.get(pixel + d)))
+                    .collect();
+                if neighbours.is_empty() {
+                    continue;
                 }
+                neighbours.sort_by(Float::total_cmp);
+                let median = neighbours[neighbours.len() / 2];
+
+                // Floor the median so pixels next to pure black aren't all flagged
+                if luminance(film.get(pixel)) > pass.threshold * median.max(0.01) {
+                    fireflies.push(pixel);
+                }
+            }
+        }
+
+        // Use different random numbers to the first render of each pixel
+        let seed = hash(&[seed, 1]);
+        for &pixel in &fireflies {
+            let (sample, _) = self.render_pixel(
+                self.camera_pixel(pixel),
+                pass.samples,
+                pixel_seed(seed, pixel),
+                frame,
+            );
+            film.set(pixel, sample);
+        }
+
+        fireflies.len()
+    }
+
+    /// Average all samples of a single pixel. The first hit is only recorded when AOVs are
+    /// enabled.
+    fn render_pixel(
+        &self,
+        pixel: IVec2,
+        samples: u64,
+        seed: u64,
+        frame: &FrameState,
+    ) -> (Vec3, FirstHit) {
+        if self.primary_visibility == PrimaryVisibility::PerPixel {
+            return self.render_pixel_from_first_hit(pixel, samples, seed, frame);
+        }
+
+        let mut sample = Vec3::ZERO;
+        let mut first_hit = FirstHit::default();
+        let mut hits = 0;
+
+        for i in 0..samples {
+            let mut rng = R::seed_from_u64(hash(&[seed, i]));
+            let mut sampler = PixelSampler::new(self.sampler, pixel, seed, i, samples, &mut rng);
+            let time = if self.shutter.0 == self.shutter.1 {
+                self.shutter.0
+            } else {
+                self.shutter.0 + (self.shutter.1 - self.shutter.0) * sampler.next_1d()
+            };
+            let (ray, weight) = self.camera_ray(pixel, time, &mut sampler);
+            stats::record(|s| s.camera_rays += 1);
+
+            let collision = self.scene.trace(&ray, sampler.rng());
+            if !self.aovs.is_empty() {
+                match &collision {
+                    Some(c) => {
+                        first_hit.depth += c.t * ray.dir.length();
+                        first_hit.normal += c.normal;
+                        first_hit.albedo += c.material.colour_at(c.uv);
+                        hits += 1;
+                    }
+                    None => {
+                        first_hit.albedo += self.background(ray.dir).clamp(Vec3::ZERO, Vec3::ONE)
+                    }
+                }
+            }
+
+            let (radiance, alpha) =
+                self.shade_camera_ray(ray, collision, frame.photon_map.as_ref(), &mut sampler);
+            sample += self.clamp_sample(weight * radiance);
+            first_hit.alpha += alpha;
+        }
+
+        first_hit.depth = if hits > 0 {
+            first_hit.depth / hits as Float
+        } else {
+            Float::INFINITY
+        };
+        first_hit.normal = first_hit.normal.normalize_or_zero();
+        first_hit.albedo /= samples as Float;
+        first_hit.alpha /= samples as Float;
+
+        (sample / samples as Float, first_hit)
+    }
+
+    /// Render a pixel by finding the first hit once, through the centre of the shutter
+    /// interval, and only path tracing the secondary bounces of each sample from it
+    fn render_pixel_from_first_hit(
+        &self,
+        pixel: IVec2,
+        samples: u64,
+        seed: u64,
+        frame: &FrameState,
+    ) -> (Vec3, FirstHit) {
+        let mut rng = R::seed_from_u64(seed);
+        let mut sampler = PixelSampler::new(self.sampler, pixel, seed, 0, samples, &mut rng);
+        stats::record(|s| s.camera_rays += 1);
+        let rasterized = frame
+            .visibility
+            .as_ref()
+            .and_then(|visibility| visibility.trace(&self.scene, pixel, sampler.rng()));
+        let (ray, collision) = match rasterized {
+            Some(hit) => hit,
+            None => {
+                let time = (self.shutter.0 + self.shutter.1) / 2.0;
+                let ray = self
+                    .camera
+                    .outgoing_ray(self.frame_format(), pixel, time, &mut sampler);
+                let collision = self.scene.trace(&ray, sampler.rng());
+                (ray, collision)
+            }
+        };
+
+        let mut first_hit = match &collision {
+            Some(c) => FirstHit {
+                depth: c.t * ray.dir.length(),
+                normal: c.normal,
+                albedo: c.material.colour_at(c.uv),
+                alpha: 0.0,
+            },
+            None => FirstHit {
+                depth: Float::INFINITY,
+                normal: Vec3::ZERO,
+                albedo: self.background(ray.dir).clamp(Vec3::ZERO, Vec3::ONE),
+                alpha: 0.0,
+            },
+        };
+
+        let mut sample = Vec3::ZERO;
+        for i in 0..samples {
+            let mut rng = R::seed_from_u64(hash(&[seed, i]));
+            let mut sampler = PixelSampler::new(self.sampler, pixel, seed, i, samples, &mut rng);
+            let mut ray = ray.clone();
+            let weight = self.sample_wavelength(&mut ray, &mut sampler);
+            // Where the first hit is doesn't depend on the wavelength, but dispersion there does
+            let collision = collision.clone().map(|c| Collision {
+                ray: ray.clone(),
+                ..c
             });
+            let (radiance, alpha) =
+                self.shade_camera_ray(ray, collision, frame.photon_map.as_ref(), &mut sampler);
+            sample += self.clamp_sample(weight * radiance);
+            first_hit.alpha += alpha / samples as Float;
+        }
+
+        (sample / samples as Float, first_hit)
+    }
+
+    /// Estimate the light travelling back along a camera ray which has already been traced
+    /// against the scene, and its opacity for the alpha AOV
+    fn shade_camera_ray(
+        &self,
+        ray: Ray,
+        collision: Option<Collision<'_>>,
+        photon_map: Option<&PhotonMap>,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> (Vec3, Float) {
+        let caustics = Caustics::new(photon_map);
+        if !self.transparent_background {
+            return (self.shade(ray, collision, 0, &[], caustics, sampler), 1.0);
+        }
+
+        match collision {
+            None => {
+                stats::record(|s| s.paths += 1);
+                (Vec3::ZERO, 0.0)
+            }
+            Some(c) if c.material.shadow_catcher => {
+                stats::record(|s| s.paths += 1);
+                self.catch_shadow(&c, caustics, sampler)
+            }
+            Some(c) => (self.shade(ray, Some(c), 0, &[], caustics, sampler), 1.0),
+        }
+    }
+
+    /// Light reflected onto a shadow catcher by other objects, and its opacity, which is 1.0
+    /// where it's in shadow. The shadow is found towards a light sample of the environment if
+    /// there is one, otherwise towards the same diffuse direction used for reflections, so
+    /// under a plain sky it's the ambient occlusion of the surface.
+    fn catch_shadow(
+        &self,
+        c: &Collision<'_>,
+        caustics: Caustics<'_>,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> (Vec3, Float) {
+        let normal = if c.geometric_normal.dot(c.ray.dir) < 0.0 {
+            c.normal
+        } else {
+            -c.normal
+        };
+        let origin = c.spawn_origin(-c.ray.dir, &self.epsilon) + c.terminator_offset;
+        let towards = |dir| Ray {
+            origin,
+            dir,
+            time: c.ray.time,
+            wavelength: c.ray.wavelength,
+            t_min: 0.0,
+            t_max: Float::INFINITY,
+        };
+        // Other shadow catchers are part of the real surface, so don't cast shadows
+        let occluder = |ray: &Ray, sampler: &mut PixelSampler<'_, R>| {
+            self.scene
+                .trace(ray, sampler.rng())
+                .filter(|hit| !hit.material.shadow_catcher)
+        };
+
+        let dir = (normal + random_unit_vector(sampler.next_2d())).normalize_or_zero();
+        let bounce = towards(if dir == Vec3::ZERO { normal } else { dir });
+        let hit = occluder(&bounce, sampler);
+        let mut occluded = hit.is_some();
+        let reflected = match hit {
+            Some(hit) => {
+                c.material.colour_at(c.uv)
+                    * self.shade(bounce, Some(hit), 1, &[], caustics, sampler)
+            }
+            None => Vec3::ZERO,
+        };
+
+        let light = self
+            .environment
+            .as_ref()
+            .and_then(|env| env.sample(sampler.next_1d(), sampler.next_2d()));
+        if let Some((dir, _)) = light.filter(|(dir, _)| dir.dot(normal) > 0.0) {
+            stats::record(|s| s.shadow_rays += 1);
+            occluded = occluder(&towards(dir), sampler).is_some();
+        }
+
+        (reflected, if occluded { 1.0 } else { 0.0 })
+    }
+
+    /// Generate a camera ray through `pixel`, returning it with the weight converting its
+    /// radiance to RGB. In spectral mode the wavelength is picked first, so that lenses with
+    /// chromatic aberration can focus it.
+    fn camera_ray(
+        &self,
+        pixel: IVec2,
+        time: Float,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> (Ray, Vec3) {
+        let format = self.frame_format();
+        if !self.spectral {
+            return (
+                self.camera.outgoing_ray(format, pixel, time, sampler),
+                Vec3::ONE,
+            );
+        }
+
+        let wavelength = sample_wavelength(sampler.next_1d());
+        let ray = self
+            .camera
+            .outgoing_ray_at_wavelength(format, pixel, time, wavelength, sampler);
+        (ray, wavelength_weight(wavelength))
+    }
+
+    /// Follow light scattering through the translucent `medium` from `ray`, whose next hit is
+    /// `collision`, until it reaches the surface. Returns the final ray and its hit, with the
+    /// fraction of the light which made it there, or `None` if it never reaches the surface.
+    fn random_walk<'a>(
+        &'a self,
+        mut ray: Ray,
+        mut collision: Option<Collision<'a>>,
+        medium: &Material,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> Option<(Ray, Option<Collision<'a>>, Vec3)> {
+        /// Walks longer than this are dropped, losing a little energy in thick materials
+        const MAX_EVENTS: usize = 4096;
+
+        let subsurface = medium.subsurface?;
+        let phase = PhaseFunction::HenyeyGreenstein(subsurface.anisotropy);
+        let extinction = subsurface.scattering + medium.absorption;
+        let mut weight = Vec3::ONE;
+
+        for _ in 0..MAX_EVENTS {
+            let speed = ray.dir.length();
+            let max_distance = collision.as_ref()?.t * speed;
+
+            // The distance is sampled with the extinction of a random channel, and weighted by
+            // the average probability over all channels so each channel stays unbiased
+            let channel = ((sampler.next_1d() * 3.0) as usize).min(2);
+            let distance = sample_distance(extinction[channel], sampler.next_1d());
+            if distance >= max_distance {
+                let transmittance = (-extinction * max_distance).exp();
+                let pdf = transmittance.dot(Vec3::ONE) / 3.0;
+                return Some((ray, collision, weight * transmittance / pdf));
+            }
+
+            let transmittance = (-extinction * distance).exp();
+            let pdf = extinction.dot(transmittance) / 3.0;
+            weight *= subsurface.scattering * transmittance / pdf;
+
+            ray = Ray {
+                origin: ray.at(distance / speed),
+                dir: phase.sample(ray.dir / speed, sampler.next_2d()),
+                time: ray.time,
+                wavelength: ray.wavelength,
+                t_min: 0.0,
+                t_max: Float::INFINITY,
+            };
+            collision = self.scene.trace(&ray, sampler.rng());
+            stats::record(|s| s.bounces += 1);
+        }
+
+        None
+    }
+
+    /// Pick the wavelength carried by `ray` when rendering spectrally, returning the weight
+    /// converting its radiance to RGB
+    pub(crate) fn sample_wavelength(
+        &self,
+        ray: &mut Ray,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> Vec3 {
+        if !self.spectral {
+            return Vec3::ONE;
+        }
+
+        let wavelength = sample_wavelength(sampler.next_1d());
+        ray.wavelength = Some(wavelength);
+        wavelength_weight(wavelength)
+    }
+
+    /// Light arriving from outside the scene in direction `dir`
+    fn background(&self, dir: Vec3) -> Vec3 {
+        let dir = dir.normalize();
+        match &self.environment {
+            Some(env) => env.radiance(dir) + self.unsampled_background(env, dir),
+            None => (self.sky)(dir),
+        }
+    }
+
+    /// The part of the background which the environment doesn't sample directly
+    fn unsampled_background(&self, env: &Environment, dir: Vec3) -> Vec3 {
+        if env.map.is_some() {
+            Vec3::ZERO
+        } else {
+            (self.sky)(dir)
+        }
+    }
+
+    /// Light reflected by the perfectly diffuse surface hit by `c`, estimated by sampling both
+    /// the environment and the diffuse lobe and combining them with multiple importance
+    /// sampling. `caustic` is the state of the path leaving the surface. Must be multiplied by
+    /// the colour of the surface.
+    fn diffuse_with_environment(
+        &self,
+        c: &Collision<'_>,
+        bounce: u64,
+        media: &[&Material],
+        caustic: Caustics<'_>,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> Vec3 {
+        let env = self
+            .environment
+            .as_ref()
+            .expect("Only used with an environment");
+        let normal = if c.geometric_normal.dot(c.ray.dir) < 0.0 {
+            c.normal
+        } else {
+            -c.normal
+        };
+        let origin = c.spawn_origin(-c.ray.dir, &self.epsilon) + c.terminator_offset;
+        let incoming = &c.ray;
+        let mut radiance = Vec3::ZERO;
+
+        // Light sample, which only counts if nothing is in the way
+        if let Some((dir, light_pdf)) = env.sample(sampler.next_1d(), sampler.next_2d()) {
+            let cos = dir.dot(normal);
+            let shadow = Ray {
+                origin,
+                dir,
+                time: incoming.time,
+                wavelength: incoming.wavelength,
+                t_min: 0.0,
+                t_max: Float::INFINITY,
+            };
+            stats::record(|s| s.shadow_rays += u64::from(cos > 0.0));
+            if cos > 0.0 && self.scene.trace(&shadow, sampler.rng()).is_none() {
+                let diffuse_pdf = cos / PI;
+                radiance += env.radiance(dir) * diffuse_pdf / light_pdf
+                    * power_heuristic(light_pdf, diffuse_pdf);
+            }
+        }
+
+        // Cosine weighted diffuse sample, which continues the path as usual
+        let dir = (normal + random_unit_vector(sampler.next_2d())).normalize_or_zero();
+        let dir = if dir == Vec3::ZERO { normal } else { dir };
+        let ray = Ray {
+            origin,
+            dir,
+            time: incoming.time,
+            wavelength: incoming.wavelength,
+            t_min: 0.0,
+            t_max: Float::INFINITY,
+        };
+        match self.scene.trace(&ray, sampler.rng()) {
+            Some(c) => radiance += self.shade(ray, Some(c), bounce + 1, media, caustic, sampler),
+            None => {
+                stats::record(|s| s.bounces += 1);
+                let diffuse_pdf = dir.dot(normal).max(0.0) / PI;
+                radiance += env.radiance(dir) * power_heuristic(diffuse_pdf, env.pdf(dir))
+                    + self.unsampled_background(env, dir);
+            }
+        }
+
+        radiance
+    }
+
+    fn clamp_sample(&self, sample: Vec3) -> Vec3 {
+        match self.clamp {
+            Some(clamp) if sample.max_element() > clamp => {
+                // Scale rather than clamp each channel so the hue is preserved
+                sample * (clamp / sample.max_element())
+            }
+            _ => sample,
+        }
+    }
+
+    /// Trace `ray` and estimate the light travelling back along it. `media` is the stack of
+    /// refractive materials the ray is currently inside of, innermost last.
+    fn sample(
+        &self,
+        ray: Ray,
+        bounce: u64,
+        media: &[&Material],
+        caustic: Caustics<'_>,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> Vec3 {
+        // Trace ray
+        let collision: Option<Collision<'_>> = self.scene.trace(&ray, sampler.rng());
+        self.shade(ray, collision, bounce, media, caustic, sampler)
+    }
+
+    /// Estimate the light travelling back along `ray`, which has already been traced against
+    /// the scene to find `collision`
+    fn shade(
+        &self,
+        ray: Ray,
+        collision: Option<Collision<'_>>,
+        bounce: u64,
+        media: &[&Material],
+        caustic: Caustics<'_>,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> Vec3 {
+        stats::record(|s| match bounce {
+            0 => s.paths += 1,
+            _ => s.bounces += 1,
+        });
+
+        // Light inside a translucent material scatters until it reaches the surface again,
+        // otherwise it's only absorbed by the medium it's travelling through (Beer-Lambert)
+        let (ray, collision, transmittance, caustic) = match media.last() {
+            Some(medium) if medium.subsurface.is_some() => {
+                match self.random_walk(ray, collision, medium, sampler) {
+                    Some((ray, collision, transmittance)) => {
+                        let caustic = caustic.with_path(CausticPath::Untracked);
+                        (ray, collision, transmittance, caustic)
+                    }
+                    None => return Vec3::ZERO,
+                }
+            }
+            Some(medium) if medium.absorption != Vec3::ZERO => {
+                let distance = collision
+                    .as_ref()
+                    .map(|c| c.t * ray.dir.length())
+                    .unwrap_or(Float::INFINITY);
+                (
+                    ray,
+                    collision,
+                    (-medium.absorption * distance).exp(),
+                    caustic,
+                )
+            }
+            _ => (ray, collision, Vec3::ONE, caustic),
+        };
+
+        // Scattering by the atmosphere, which only fills space outside of other objects
+        if let Some(atmosphere) = self.atmosphere.as_ref().filter(|_| media.is_empty()) {
+            let speed = ray.dir.length();
+            let max_distance = collision
+                .as_ref()
+                .map(|c| c.t * speed)
+                .unwrap_or(Float::INFINITY);
+
+            if let Some((start, end)) = atmosphere.extent(ray.origin, ray.dir / speed) {
+                let distance = start + sample_distance(atmosphere.density, sampler.next_1d());
+
+                if distance < end.min(max_distance) {
+                    if bounce >= self.max_bounces {
+                        return Vec3::ZERO;
+                    }
+
+                    let new_ray = Ray {
+                        origin: ray.at(distance / speed),
+                        dir: atmosphere.phase.sample(ray.dir / speed, sampler.next_2d()),
+                        time: ray.time,
+                        wavelength: ray.wavelength,
+                        t_min: 0.0,
+                        t_max: Float::INFINITY,
+                    };
+                    return atmosphere.albedo
+                        * self.sample(
+                            new_ray,
+                            bounce + 1,
+                            media,
+                            caustic.with_path(CausticPath::Untracked),
+                            sampler,
+                        );
+                }
+            }
+        }
 
         // No collision
         if collision.is_none() {
-            return (self.sky)(ray.dir);
+            return self.background(ray.dir) * transmittance;
         }
         let c = collision.expect("Just checked it was some...");
+        let colour = c.material.colour_at(c.uv);
 
         // Out of bounces
         if bounce >= self.max_bounces {
-            return DVec3::ZERO;
+            return Vec3::ZERO;
         }
 
-        // Calculate reflection/refraction ray
-        let transmission_ray;
-
-        // Snell's law for refraction ray
-        let n1;
-        let n2;
-        let directed_normal;
-
-        if c.normal.dot(c.ray.dir) < 0.0 {
-            // Incoming
-            n1 = 1.0;
-            n2 = c.material.refractive_index;
-            directed_normal = -c.normal;
+        // Light reaching a diffuse surface from a light through only specular bounces is
+        // already in the photon map
+        let luminance = if caustic.path == CausticPath::Specular
+            && c.material.luminance > 0.0
+            && caustic.map.is_some_and(|map| map.emits(c.material))
+        {
+            0.0
         } else {
-            // Outgoing
-            n1 = c.material.refractive_index;
-            n2 = 1.0;
-            directed_normal = c.normal;
-        }
+            c.material.luminance
+        };
 
-        let incidence_angle = c.ray.dir.angle_between(directed_normal);
-        let sin_a2 = n1 / n2 * incidence_angle.sin();
-        if sin_a2 > 1.0 {
-            // Internal reflection
-            transmission_ray = None;
-        } else {
-            // Fresnel equations for calculating amount of tranmission vs reflectance
-            let transmission_angle = sin_a2.asin();
+        // Russian roulette
+        let mut throughput = 1.0;
+        if self.roulette_depth.is_some_and(|depth| bounce >= depth) {
+            let survival = colour.max_element().clamp(0.05, 0.95);
+            if sampler.next_1d() > survival {
+                return colour * luminance * transmittance;
+            }
+            throughput = 1.0 / survival;
+        }
 
-            let cosi = incidence_angle.cos();
-            let cost = transmission_angle.cos();
-            let n1_cosi = n1 * cosi;
-            let n1_cost = n1 * cost;
-            let n2_cosi = n2 * cosi;
-            let n2_cost = n2 * cost;
+        // Volume scattering
+        if let Some(phase) = c.material.phase {
+            let new_ray = Ray {
+                origin: c.ray.at(c.t),
+                dir: phase.sample(c.ray.dir.normalize(), sampler.next_2d()),
+                time: ray.time,
+                wavelength: ray.wavelength,
+                t_min: 0.0,
+                t_max: Float::INFINITY,
+            };
 
-            let rs = ((n1_cosi - n2_cost) / (n1_cosi + n2_cost)).abs().powi(2);
-            let rp = ((n1_cost - n2_cosi) / (n1_cost + n2_cosi)).abs().powi(2);
+            let caustic = caustic.with_path(CausticPath::Untracked);
+            let sample = self.sample(new_ray, bounce + 1, media, caustic, sampler);
+            return (colour * sample * throughput + colour * luminance) * transmittance;
+        }
 
-            let r = (rs + rp) / 2.0;
+        // Calculate reflection/refraction ray
+        let interface = Interface::new(&c, media, ray.wavelength);
+        let transmission_ray = interface.sample_transmission(&c, sampler);
+        let transmitted = transmission_ray.is_some();
 
-            if rng.gen_range(0.0..1.0) < r {
-                transmission_ray = None;
-            } else {
-                transmission_ray = Some(transmission_angle);
+        // Caustics on diffuse surfaces, looked up from the photon map
+        let photon_map = caustic.map.filter(|_| gathers_photons(c.material));
+        let (caustics, caustic) = match photon_map {
+            Some(map) => {
+                let normal = if c.geometric_normal.dot(c.ray.dir) < 0.0 {
+                    c.normal
+                } else {
+                    -c.normal
+                };
+                let irradiance = map.irradiance(c.ray.at(c.t), normal);
+                (irradiance / PI, caustic.with_path(CausticPath::Gathered))
+            }
+            None => {
+                let diffusion = if transmitted {
+                    c.material.transmission_diffusion
+                } else {
+                    c.material.diffusion + c.material.fuzz
+                };
+                let path = match caustic.path {
+                    CausticPath::Gathered | CausticPath::Specular if diffusion == 0.0 => {
+                        CausticPath::Specular
+                    }
+                    _ => CausticPath::Untracked,
+                };
+                (Vec3::ZERO, caustic.with_path(path))
             }
+        };
+
+        // Sample the environment directly from diffuse surfaces. Fog isn't accounted for by
+        // the light samples, so this is only done without an atmosphere.
+        if self.environment.is_some()
+            && self.atmosphere.is_none()
+            && !transmitted
+            && c.material.diffusion >= 1.0
+        {
+            let sample = self.diffuse_with_environment(&c, bounce, media, caustic, sampler);
+            return (colour * (sample + caustics) * throughput + colour * luminance)
+                * transmittance;
         }
 
         let new_ray = if let Some(transmission_angle) = transmission_ray {
             // Transmit
-            let hit_pos = c.ray.at(c.t * 1.0001);
-
-            let outgoing_dir =
-                DQuat::from_axis_angle(c.ray.dir.cross(directed_normal), transmission_angle)
-                    * directed_normal;
+            let outgoing_dir = interface.refract(&c, transmission_angle);
+            let diffuse_target = interface.directed_normal + random_unit_vector(sampler.next_2d());
+            let dir = outgoing_dir.lerp(diffuse_target, c.material.transmission_diffusion);
 
             Ray {
-                origin: hit_pos,
-                dir: outgoing_dir,
+                origin: c.spawn_origin(dir, &self.epsilon),
+                dir,
+                time: ray.time,
+                wavelength: ray.wavelength,
+                t_min: 0.0,
+                t_max: Float::INFINITY,
             }
         } else {
             // Reflect
-            let hit_pos = c.ray.at(c.t * 0.9999);
-            let random_unit_vector = {
-                let theta = rng.gen_range(0.0..2.0 * PI);
-                let theta2 = rng.gen_range(0.0..2.0 * PI);
-                let x = theta.cos() * theta2.cos();
-                let y = theta.cos() * theta2.sin();
-                let z = theta.sin();
-                DVec3::new(x, y, z)
-            };
-
-            let reflect_target = ray.dir + c.normal * 2.0;
-            let mut diffuse_target = random_unit_vector;
-            if (hit_pos + c.normal).dot(c.ray.origin) > 0.0 {
+            let mut reflect_target = ray.dir + c.normal * 2.0;
+            if c.material.fuzz > 0.0 {
+                // Fuzzy metal, offset from the mirror direction by a point within a sphere
+                let offset = random_unit_vector(sampler.next_2d()) * sampler.next_1d().cbrt();
+                reflect_target = reflect_target.normalize() + offset * c.material.fuzz;
+                let outgoing = reflect_target.dot(c.geometric_normal);
+                if outgoing * c.ray.dir.dot(c.geometric_normal) >= 0.0 {
+                    // Scattered into the surface
+                    return colour * luminance * transmittance;
+                }
+            }
+            let mut diffuse_target = random_unit_vector(sampler.next_2d());
+            // Scatter back to the side of the surface the ray came from
+            if c.geometric_normal.dot(c.ray.dir) < 0.0 {
                 diffuse_target += c.normal;
             } else {
                 diffuse_target -= c.normal;
@@ -191,13 +1312,144 @@ impl<'a, C: Camera, R: Rng + SeedableRng> Solver<'a, C, R> {
             let actual_target = reflect_target.lerp(diffuse_target, c.material.diffusion);
 
             Ray {
-                origin: hit_pos,
+                origin: c.spawn_origin(actual_target, &self.epsilon) + c.terminator_offset,
                 dir: actual_target,
+                time: ray.time,
+                wavelength: ray.wavelength,
+                t_min: 0.0,
+                t_max: Float::INFINITY,
             }
         };
 
         // Propagate
-        let sample = self.sample(new_ray, bounce + 1, rng);
-        c.material.colour * sample + c.material.colour * c.material.luminance
+        let new_media = if transmitted {
+            &interface.transmitted_media[..]
+        } else {
+            media
+        };
+        let sample = self.sample(new_ray, bounce + 1, new_media, caustic, sampler);
+        (colour * (sample + caustics) * throughput + colour * luminance) * transmittance
+    }
+}
+
+/// Where a ray meets the surface of a material, between the medium it's travelling through and
+/// the one on the other side
+pub(crate) struct Interface<'a> {
+    /// Refractive index on the side the ray comes from
+    n1: Float,
+    /// Refractive index on the other side
+    n2: Float,
+    /// Normal pointing to the other side
+    directed_normal: Vec3,
+    /// Stack of media a transmitted ray is inside of, innermost last
+    pub(crate) transmitted_media: Vec<&'a Material>,
+}
+
+impl<'a> Interface<'a> {
+    pub(crate) fn new(
+        c: &Collision<'a>,
+        media: &[&'a Material],
+        wavelength: Option<Float>,
+    ) -> Self {
+        if c.geometric_normal.dot(c.ray.dir) < 0.0 {
+            // Incoming
+            let mut inside = media.to_vec();
+            inside.push(c.material);
+
+            Self {
+                n1: current_ior(media, wavelength),
+                n2: c.material.refractive_index_at(wavelength),
+                directed_normal: -c.normal,
+                transmitted_media: inside,
+            }
+        } else {
+            // Outgoing
+            let mut outside = media.to_vec();
+            if let Some(i) = outside.iter().rposition(|m| std::ptr::eq(*m, c.material)) {
+                outside.remove(i);
+            }
+
+            Self {
+                n1: c.material.refractive_index_at(wavelength),
+                n2: current_ior(&outside, wavelength),
+                directed_normal: c.normal,
+                transmitted_media: outside,
+            }
+        }
+    }
+
+    /// Choose whether the ray of `c` is transmitted or reflected, by the Fresnel equations.
+    /// Returns the angle of the transmitted ray from the normal, or `None` if it's reflected.
+    pub(crate) fn sample_transmission<R: Rng>(
+        &self,
+        c: &Collision<'_>,
+        sampler: &mut PixelSampler<'_, R>,
+    ) -> Option<Float> {
+        // Snell's law for refraction ray
+        let incidence_angle = c.ray.dir.angle_between(self.directed_normal);
+        let sin_a2 = self.n1 / self.n2 * incidence_angle.sin();
+        if sin_a2 > 1.0 {
+            // Internal reflection
+            return None;
+        }
+
+        // Fresnel equations for calculating amount of tranmission vs reflectance
+        let transmission_angle = sin_a2.asin();
+
+        let cosi = incidence_angle.cos();
+        let cost = transmission_angle.cos();
+        let n1_cosi = self.n1 * cosi;
+        let n1_cost = self.n1 * cost;
+        let n2_cosi = self.n2 * cosi;
+        let n2_cost = self.n2 * cost;
+
+        let rs = ((n1_cosi - n2_cost) / (n1_cosi + n2_cost)).abs().powi(2);
+        let rp = ((n1_cost - n2_cosi) / (n1_cost + n2_cosi)).abs().powi(2);
+
+        let r = (rs + rp) / 2.0;
+
+        if sampler.next_1d() < r {
+            None
+        } else {
+            Some(transmission_angle)
+        }
     }
+
+    /// Direction of the ray of `c` once refracted to `transmission_angle` from the normal
+    pub(crate) fn refract(&self, c: &Collision<'_>, transmission_angle: Float) -> Vec3 {
+        Quat::from_axis_angle(c.ray.dir.cross(self.directed_normal), transmission_angle)
+            * self.directed_normal
+    }
+}
+
+/// Seed for the random numbers of a pixel of the film, from the seed of the whole render
+fn pixel_seed(seed: u64, pixel: IVec2) -> u64 {
+    hash(&[seed, pixel.x as u64, pixel.y as u64])
+}
+
+/// Weight for a sample from a strategy with density `pdf` when combined with another strategy
+/// with density `other_pdf`
+fn power_heuristic(pdf: Float, other_pdf: Float) -> Float {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b > 0.0 {
+        a / (a + b)
+    } else {
+        0.0
+    }
+}
+
+/// Refractive index of the innermost medium, or of air if not inside any
+fn current_ior(media: &[&Material], wavelength: Option<Float>) -> Float {
+    media
+        .last()
+        .map(|m| m.refractive_index_at(wavelength))
+        .unwrap_or(1.0)
+}
+
+/// Maps a point in the unit square to a uniformly distributed direction on the unit sphere
+pub(crate) fn random_unit_vector(u: Vec2) -> Vec3 {
+    let z = 1.0 - 2.0 * u.x;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.y;
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
 }
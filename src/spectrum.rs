@@ -0,0 +1,94 @@
+//! Spectral rendering samples a single wavelength per camera ray, so effects which depend on
+//! wavelength such as dispersion split white light into colours. Materials and lights are
+//! still RGB; each sample is weighted by the colour of its wavelength so that averaging over
+//! all wavelengths gives back the RGB result.
+
+use std::sync::OnceLock;
+
+use crate::float::{Float, Mat3, Vec3};
+
+/// Shortest wavelength sampled, in nanometres
+pub const MIN_WAVELENGTH: Float = 380.0;
+/// Longest wavelength sampled, in nanometres
+pub const MAX_WAVELENGTH: Float = 780.0;
+
+/// CIE XYZ to linear sRGB, D65 white point
+const XYZ_TO_SRGB: Mat3 = Mat3::from_cols_array(&[
+    3.2404542, -0.9692660, 0.0556434, //
+    -1.5371385, 1.8760108, -0.2040259, //
+    -0.4985314, 0.0415560, 1.0572252, //
+]);
+
+/// How the refractive index of a material varies with wavelength. Wavelengths are in
+/// micrometres in both formulas, as is conventional for their coefficients.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dispersion {
+    /// n = a + b / λ²
+    Cauchy { a: Float, b: Float },
+    /// n² = 1 + Σ bᵢλ² / (λ² - cᵢ)
+    Sellmeier { b: [Float; 3], c: [Float; 3] },
+}
+
+impl Dispersion {
+    /// Schott N-BK7, the most common optical glass
+    pub const BK7: Dispersion = Dispersion::Sellmeier {
+        b: [1.03961212, 0.231792344, 1.01046945],
+        c: [0.00600069867, 0.0200179144, 103.560653],
+    };
+
+    /// Schott SF11, a dense flint glass with strong dispersion
+    pub const SF11: Dispersion = Dispersion::Sellmeier {
+        b: [1.73759695, 0.313747346, 1.89878101],
+        c: [0.013188707, 0.0623068142, 155.23629],
+    };
+
+    /// Refractive index at `wavelength` in nanometres
+    pub fn refractive_index(&self, wavelength: Float) -> Float {
+        let l2 = (wavelength / 1000.0).powi(2);
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / l2,
+            Dispersion::Sellmeier { b, c } => {
+                let sum: Float = b.iter().zip(c).map(|(b, c)| b * l2 / (l2 - c)).sum();
+                (1.0 + sum).sqrt()
+            }
+        }
+    }
+}
+
+/// Map `u` in the unit interval to a wavelength, uniformly between [`MIN_WAVELENGTH`] and
+/// [`MAX_WAVELENGTH`]
+pub fn sample_wavelength(u: Float) -> Float {
+    MIN_WAVELENGTH + (MAX_WAVELENGTH - MIN_WAVELENGTH) * u
+}
+
+/// Colour of a sample carrying light of a single `wavelength` chosen by [`sample_wavelength`].
+/// Averages to white over all wavelengths, so a constant spectrum renders as it would in RGB.
+pub fn wavelength_weight(wavelength: Float) -> Vec3 {
+    static NORMALISATION: OnceLock<Vec3> = OnceLock::new();
+    let normalisation = NORMALISATION.get_or_init(|| {
+        // Average colour of all wavelengths, integrated in 1nm steps
+        let steps = (MAX_WAVELENGTH - MIN_WAVELENGTH) as usize;
+        let sum: Vec3 = (0..steps)
+            .map(|i| XYZ_TO_SRGB * cie_xyz(MIN_WAVELENGTH + i as Float + 0.5))
+            .sum();
+        sum / steps as Float
+    });
+
+    XYZ_TO_SRGB * cie_xyz(wavelength) / *normalisation
+}
+
+/// CIE 1931 2° colour matching functions, using the multi-lobe fit from "Simple Analytic
+/// Approximations to the CIE XYZ Color Matching Functions" (Wyman et al. 2013)
+pub fn cie_xyz(wavelength: Float) -> Vec3 {
+    // Gaussian with different widths either side of its peak
+    let g = |mean: Float, below: Float, above: Float| {
+        let width = if wavelength < mean { below } else { above };
+        (-0.5 * ((wavelength - mean) / width).powi(2)).exp()
+    };
+
+    Vec3::new(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
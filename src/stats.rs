@@ -0,0 +1,140 @@
+//! Counters of the work done while rendering, for judging the effect of changes to the
+//! intersection code and samplers. Collection is off unless enabled with
+//! [`Solver::with_stats`](crate::solver::Solver::with_stats), and only counts work done on the
+//! thread which started it, so renders running at once on different threads are counted
+//! separately.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt::Display,
+    io::Write,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+thread_local! {
+    static COLLECTING: RefCell<Option<(Instant, RenderStats)>> = const { RefCell::new(None) };
+}
+
+/// Work done by a single render
+#[derive(Debug, Clone, Default)]
+pub struct RenderStats {
+    /// Every ray traced against the scene, including camera and shadow rays
+    pub rays: u64,
+    pub camera_rays: u64,
+    pub shadow_rays: u64,
+    /// Number of paths started, one per sample
+    pub paths: u64,
+    /// Total number of times a path continued from a surface or the atmosphere
+    pub bounces: u64,
+    /// Intersection tests by kind of primitive, e.g. "Sphere" or "Triangle"
+    pub intersection_tests: BTreeMap<&'static str, u64>,
+    pub elapsed: Duration,
+}
+
+impl RenderStats {
+    pub fn average_bounce_depth(&self) -> f64 {
+        if self.paths == 0 {
+            return 0.0;
+        }
+        self.bounces as f64 / self.paths as f64
+    }
+
+    pub fn rays_per_second(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds == 0.0 {
+            return 0.0;
+        }
+        self.rays as f64 / seconds
+    }
+
+    pub fn intersection_test_count(&self) -> u64 {
+        self.intersection_tests.values().sum()
+    }
+
+    /// Write the statistics as JSON
+    pub fn write_json<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+
+        writeln!(file, "{{")?;
+        writeln!(file, "  \"rays\": {},", self.rays)?;
+        writeln!(file, "  \"camera_rays\": {},", self.camera_rays)?;
+        writeln!(file, "  \"shadow_rays\": {},", self.shadow_rays)?;
+        writeln!(file, "  \"paths\": {},", self.paths)?;
+        writeln!(file, "  \"bounces\": {},", self.bounces)?;
+        writeln!(
+            file,
+            "  \"average_bounce_depth\": {},",
+            self.average_bounce_depth()
+        )?;
+        writeln!(file, "  \"intersection_tests\": {{")?;
+        for (i, (kind, count)) in self.intersection_tests.iter().enumerate() {
+            let separator = if i + 1 < self.intersection_tests.len() {
+                ","
+            } else {
+                ""
+            };
+            writeln!(file, "    \"{}\": {}{}", kind, count, separator)?;
+        }
+        writeln!(file, "  }},")?;
+        writeln!(file, "  \"seconds\": {},", self.elapsed.as_secs_f64())?;
+        writeln!(file, "  \"rays_per_second\": {}", self.rays_per_second())?;
+        writeln!(file, "}}")?;
+
+        file.flush()
+    }
+}
+
+impl Display for RenderStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Rays: {}", self.rays)?;
+        writeln!(f, "  Camera: {}", self.camera_rays)?;
+        writeln!(f, "  Shadow: {}", self.shadow_rays)?;
+        writeln!(
+            f,
+            "Paths: {} (average depth {:.2})",
+            self.paths,
+            self.average_bounce_depth()
+        )?;
+        writeln!(f, "Intersection tests: {}", self.intersection_test_count())?;
+        for (kind, count) in &self.intersection_tests {
+            writeln!(f, "  {kind}: {count}")?;
+        }
+        write!(
+            f,
+            "Time: {:.2?} ({:.0} rays/s)",
+            self.elapsed,
+            self.rays_per_second()
+        )
+    }
+}
+
+/// Start counting on this thread, discarding anything counted so far
+pub(crate) fn start() {
+    COLLECTING.with(|c| *c.borrow_mut() = Some((Instant::now(), RenderStats::default())));
+}
+
+/// Stop counting on this thread, returning the counts since [`start`]
+pub(crate) fn finish() -> Option<RenderStats> {
+    COLLECTING.with(|c| {
+        c.borrow_mut().take().map(|(start, mut stats)| {
+            stats.elapsed = start.elapsed();
+            stats
+        })
+    })
+}
+
+/// Update the counts with `f`, if counting is enabled on this thread
+pub(crate) fn record<F: FnOnce(&mut RenderStats)>(f: F) {
+    COLLECTING.with(|c| {
+        if let Some((_, stats)) = c.borrow_mut().as_mut() {
+            f(stats);
+        }
+    })
+}
+
+/// Count `count` intersection tests against primitives of kind `kind`
+pub(crate) fn record_tests(kind: &'static str, count: u64) {
+    record(|stats| *stats.intersection_tests.entry(kind).or_default() += count);
+}
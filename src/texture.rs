@@ -0,0 +1,107 @@
+use std::{collections::HashSet, fmt::Debug, path::Path, sync::Arc};
+
+use crate::{
+    colour::InputTransform,
+    float::{Float, Vec2, Vec3},
+};
+
+/// An image applied to surfaces by their UV coordinates. UVs follow glTF, with (0, 0) at the
+/// top left of the image, and wrap around outside the 0.0 to 1.0 range.
+pub struct Texture {
+    pub width: usize,
+    pub height: usize,
+    /// Linear values, row by row from the top
+    pixels: Vec<Vec3>,
+}
+
+impl Texture {
+    pub fn new(width: usize, height: usize, pixels: Vec<Vec3>) -> Self {
+        assert_eq!(pixels.len(), width * height, "Texture has the wrong size");
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    /// Load an image, converting it to linear values with `transform`. Use
+    /// [`InputTransform::Raw`] for normal and bump maps.
+    pub fn open<P: AsRef<Path>>(path: P, transform: InputTransform) -> image::ImageResult<Self> {
+        Ok(Self::from_image(&image::open(path)?, transform))
+    }
+
+    /// Convert a decoded image to linear values with `transform`
+    pub fn from_image(image: &image::DynamicImage, transform: InputTransform) -> Self {
+        let image = image.to_rgb32f();
+        let pixels = image
+            .pixels()
+            .map(|p| {
+                transform.to_linear(Vec3::new(p.0[0] as Float, p.0[1] as Float, p.0[2] as Float))
+            })
+            .collect();
+        Self::new(image.width() as usize, image.height() as usize, pixels)
+    }
+
+    /// Bilinearly filtered value at `uv`
+    pub fn sample(&self, uv: Vec2) -> Vec3 {
+        let x = uv.x * self.width as Float - 0.5;
+        let y = uv.y * self.height as Float - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+
+        let texel = |dx: i64, dy: i64| {
+            let x = (x0 as i64 + dx).rem_euclid(self.width as i64) as usize;
+            let y = (y0 as i64 + dy).rem_euclid(self.height as i64) as usize;
+            self.pixels[y * self.width + x]
+        };
+        let top = texel(0, 0).lerp(texel(1, 0), fx);
+        let bottom = texel(0, 1).lerp(texel(1, 1), fx);
+        top.lerp(bottom, fy)
+    }
+
+    /// Change in the red channel per unit of u and v at `uv`, over one texel
+    pub fn gradient(&self, uv: Vec2) -> Vec2 {
+        let du = 1.0 / self.width as Float;
+        let dv = 1.0 / self.height as Float;
+        Vec2::new(
+            (self.sample(uv + Vec2::new(du, 0.0)).x - self.sample(uv - Vec2::new(du, 0.0)).x)
+                / (2.0 * du),
+            (self.sample(uv + Vec2::new(0.0, dv)).x - self.sample(uv - Vec2::new(0.0, dv)).x)
+                / (2.0 * dv),
+        )
+    }
+
+    /// Bytes used by the pixel data
+    pub fn memory_usage(&self) -> usize {
+        self.pixels.capacity() * std::mem::size_of::<Vec3>()
+    }
+}
+
+impl Debug for Texture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Texture")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Bytes used by the pixel data of `textures`, counting a texture shared by several materials
+/// once
+pub fn distinct_memory_usage<'a>(textures: impl IntoIterator<Item = &'a Arc<Texture>>) -> usize {
+    let mut seen = HashSet::new();
+    textures
+        .into_iter()
+        .filter(|texture| seen.insert(Arc::as_ptr(texture)))
+        .map(|texture| texture.memory_usage())
+        .sum()
+}
+
+/// A height map which tilts the shading normal as if the surface were raised by the red
+/// channel of `texture`
+#[derive(Debug, Clone)]
+pub struct BumpMap {
+    pub texture: Arc<Texture>,
+    /// Height of the surface where the texture is 1.0, in the units of the UV coordinates
+    pub strength: Float,
+}
@@ -0,0 +1,134 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use glam::UVec2;
+
+use crate::{
+    film::Film,
+    float::{Float, Vec2},
+};
+
+/// A rectangular region of the rendered image, in pixels from the top left of the film's data
+/// window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tile {
+    pub origin: UVec2,
+    pub size: UVec2,
+}
+
+impl Tile {
+    pub fn pixel_count(&self) -> u64 {
+        self.size.x as u64 * self.size.y as u64
+    }
+}
+
+/// The order in which tiles are rendered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileOrder {
+    /// Left to right, top to bottom
+    Scanline,
+    /// Outwards from the centre of the image
+    Spiral,
+    /// Along a Hilbert curve, keeping consecutive tiles close together
+    Hilbert,
+}
+
+/// Passed to the progress callback every time a tile is completed
+pub struct TileProgress<'a> {
+    pub tile: Tile,
+    pub completed: usize,
+    pub total: usize,
+    /// The film rendered so far. Tiles which haven't been rendered yet are black.
+    pub film: &'a Film,
+}
+
+/// Shared flag used to stop a render from another thread. The render finishes the tile it is
+/// currently working on and then returns the partially rendered film.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Split an image of `resolution` into tiles of at most `tile_size` pixels square, in the given
+/// order.
+pub fn generate_tiles(resolution: UVec2, tile_size: u32, order: TileOrder) -> Vec<Tile> {
+    let tile_size = tile_size.max(1);
+    let grid = (resolution + tile_size - 1) / tile_size;
+
+    let tile = |x: u32, y: u32| {
+        let origin = UVec2::new(x, y) * tile_size;
+        Tile {
+            origin,
+            size: (origin + tile_size).min(resolution) - origin,
+        }
+    };
+
+    match order {
+        TileOrder::Scanline => (0..grid.y)
+            .flat_map(|y| (0..grid.x).map(move |x| (x, y)))
+            .map(|(x, y)| tile(x, y))
+            .collect(),
+        TileOrder::Spiral => {
+            let centre = (Vec2::new(grid.x as Float, grid.y as Float) - 1.0) / 2.0;
+            let mut coords: Vec<(u32, u32)> = (0..grid.y)
+                .flat_map(|y| (0..grid.x).map(move |x| (x, y)))
+                .collect();
+
+            // Order by which ring around the centre the tile is in, then by angle within the ring
+            let key = |&(x, y): &(u32, u32)| {
+                let dx = x as Float - centre.x;
+                let dy = y as Float - centre.y;
+                let ring = dx.abs().max(dy.abs()).round();
+                (ring, dy.atan2(dx))
+            };
+            coords.sort_by(|a, b| key(a).partial_cmp(&key(b)).expect("Tile keys are finite"));
+
+            coords.into_iter().map(|(x, y)| tile(x, y)).collect()
+        }
+        TileOrder::Hilbert => {
+            let n = grid.max_element().next_power_of_two();
+            (0..n as u64 * n as u64)
+                .map(|d| hilbert_to_xy(n, d))
+                .filter(|&(x, y)| x < grid.x && y < grid.y)
+                .map(|(x, y)| tile(x, y))
+                .collect()
+        }
+    }
+}
+
+/// Convert a distance along a Hilbert curve filling an `n` by `n` grid into grid coordinates
+fn hilbert_to_xy(n: u32, d: u64) -> (u32, u32) {
+    let (mut x, mut y) = (0, 0);
+    let mut t = d;
+    let mut s = 1;
+    while s < n {
+        let rx = 1 & (t / 2) as u32;
+        let ry = 1 & (t as u32 ^ rx);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (x, y)
+}
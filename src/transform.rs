@@ -0,0 +1,65 @@
+use crate::float::{Affine3, Float};
+
+/// A transform which moves between two keyframes over the course of the shutter interval, at
+/// times 0.0 and 1.0. Used for motion blur.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnimatedTransform {
+    pub start: Affine3,
+    pub end: Affine3,
+}
+
+impl AnimatedTransform {
+    /// A transform which doesn't move
+    pub fn fixed(transform: Affine3) -> Self {
+        Self {
+            start: transform,
+            end: transform,
+        }
+    }
+
+    pub fn new(start: Affine3, end: Affine3) -> Self {
+        Self { start, end }
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.start != self.end
+    }
+
+    /// The transform at `time`, interpolating the scale, rotation and translation of the
+    /// keyframes separately so rotations don't shear.
+    pub fn at(&self, time: Float) -> Affine3 {
+        if !self.is_animated() || time <= 0.0 {
+            return self.start;
+        }
+        if time >= 1.0 {
+            return self.end;
+        }
+
+        let (s0, r0, t0) = self.start.to_scale_rotation_translation();
+        let (s1, r1, t1) = self.end.to_scale_rotation_translation();
+
+        Affine3::from_scale_rotation_translation(
+            s0.lerp(s1, time),
+            r0.slerp(r1, time),
+            t0.lerp(t1, time),
+        )
+    }
+}
+
+impl From<Affine3> for AnimatedTransform {
+    fn from(transform: Affine3) -> Self {
+        Self::fixed(transform)
+    }
+}
+
+impl std::ops::Mul for AnimatedTransform {
+    type Output = AnimatedTransform;
+
+    /// Compose the keyframes of two transforms
+    fn mul(self, rhs: Self) -> Self::Output {
+        Self {
+            start: self.start * rhs.start,
+            end: self.end * rhs.end,
+        }
+    }
+}
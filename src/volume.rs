@@ -0,0 +1,141 @@
+use std::sync::Arc;
+
+use rand::{Rng, RngCore};
+
+use crate::{
+    bvh::Aabb,
+    collidable::{Collideable, Collision},
+    float::{consts::PI, Float, Vec2, Vec3, Vec4},
+    material::Material,
+    ray::Ray,
+    report::ObjectInfo,
+};
+
+/// Distribution of directions light is scattered in by a participating medium
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PhaseFunction {
+    /// Scatters equally in all directions
+    Isotropic,
+    /// Henyey-Greenstein with asymmetry `g` in (-1, 1). Positive values scatter forwards,
+    /// negative values backwards.
+    HenyeyGreenstein(Float),
+}
+
+impl PhaseFunction {
+    /// Sample an outgoing direction for light travelling in `dir`, which must be normalised.
+    pub fn sample(&self, dir: Vec3, u: Vec2) -> Vec3 {
+        let g = match self {
+            PhaseFunction::Isotropic => 0.0,
+            PhaseFunction::HenyeyGreenstein(g) => *g,
+        };
+
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u.x
+        } else {
+            let s = (1.0 - g * g) / (1.0 + g - 2.0 * g * u.x);
+            (1.0 + g * g - s * s) / (2.0 * g)
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * u.y;
+
+        let (tangent, bitangent) = dir.any_orthonormal_pair();
+        (tangent * phi.cos() * sin_theta + bitangent * phi.sin() * sin_theta + dir * cos_theta)
+            .normalize()
+    }
+}
+
+/// Sample the distance light travels through a medium of `density` before scattering
+pub fn sample_distance(density: Float, u: Float) -> Float {
+    -(1.0 - u).ln() / density
+}
+
+/// A medium of constant density filling the inside of a closed boundary object, e.g. smoke
+/// or mist. The material's `phase` is used to scatter light, so it should be set.
+pub struct Volume<B> {
+    pub boundary: B,
+    /// Chance of scattering per unit distance
+    pub density: Float,
+    pub material: Arc<Material>,
+}
+
+impl<B: Collideable> Collideable for Volume<B> {
+    fn trace(&self, ray: &Ray, rng: &mut dyn RngCore) -> Option<Collision<'_>> {
+        // The boundary is traced beyond the end of the ray to tell if the ray starts inside
+        let mut boundary_ray = Ray {
+            t_max: Float::INFINITY,
+            ..ray.clone()
+        };
+        let first = self.boundary.trace(&boundary_ray, rng)?;
+
+        // Find where the ray enters and leaves the boundary, or if it starts inside
+        let (entry, exit) = if first.geometric_normal.dot(ray.dir) > 0.0 {
+            (ray.t_min.max(0.0), first.t)
+        } else {
+            boundary_ray.t_min = first.t;
+            let exit = self
+                .boundary
+                .trace(&boundary_ray, rng)
+                .map(|c| c.t)
+                .unwrap_or(Float::INFINITY);
+            (first.t, exit)
+        };
+
+        let speed = ray.dir.length();
+        let distance = sample_distance(self.density, rng.gen_range(0.0..1.0));
+        if distance > (exit - entry) * speed || entry + distance / speed > ray.t_max {
+            return None;
+        }
+
+        Some(Collision {
+            ray: ray.clone(),
+            t: entry + distance / speed,
+            normal: -ray.dir / speed,
+            geometric_normal: -ray.dir / speed,
+            uv: Vec2::ZERO,
+            tangent: Vec4::ZERO,
+            terminator_offset: Vec3::ZERO,
+            material: &self.material,
+        })
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.boundary.bounds()
+    }
+
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of_val(self) - std::mem::size_of::<B>() + self.boundary.memory_usage()
+    }
+
+    fn info(&self) -> ObjectInfo {
+        ObjectInfo::with_material("Volume", &self.material)
+    }
+}
+
+/// A layer of fog filling the space below `height` that isn't inside another object
+#[derive(Debug, Clone, Copy)]
+pub struct Atmosphere {
+    /// Chance of scattering per unit distance
+    pub density: Float,
+    pub albedo: Vec3,
+    pub phase: PhaseFunction,
+    /// Height of the top of the fog layer, can be infinite
+    pub height: Float,
+}
+
+impl Atmosphere {
+    /// The range of distances along the normalised `dir` from `origin` which are inside the
+    /// fog layer, if any.
+    pub fn extent(&self, origin: Vec3, dir: Vec3) -> Option<(Float, Float)> {
+        if dir.y == 0.0 {
+            return (origin.y < self.height).then_some((0.0, Float::INFINITY));
+        }
+
+        let crossing = (self.height - origin.y) / dir.y;
+        match (origin.y < self.height, dir.y > 0.0) {
+            (true, true) => Some((0.0, crossing)),
+            (true, false) => Some((0.0, Float::INFINITY)),
+            (false, true) => None,
+            (false, false) => Some((crossing, Float::INFINITY)),
+        }
+    }
+}